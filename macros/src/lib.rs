@@ -0,0 +1,42 @@
+//! Test attributes for [tpom](https://docs.rs/tpom), re-exported by it with the `macros`
+//! feature.
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, ItemFn, LitStr};
+
+/// Runs a test with the wall clocks frozen at a time, as read by `tpom::live::parse_time`:
+///
+/// ```ignore
+/// #[tpom::frozen_time("2022-03-01T00:00:00Z")]
+/// fn runs_in_2022() {
+///     // SystemTime::now() is 2022-03-01 here
+/// }
+/// ```
+///
+/// Stands for `#[test]`, which may also be given. The test holds `tpom::testing`'s lock, so
+/// other tests using it wait for it, and the clock is restored as it returns or panics.
+#[proc_macro_attribute]
+pub fn frozen_time(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let at = parse_macro_input!(attr as LitStr);
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = parse_macro_input!(item as ItemFn);
+    let test = if attrs.iter().any(|a| a.path().is_ident("test")) {
+        quote!()
+    } else {
+        quote!(#[test])
+    };
+    quote! {
+        #test
+        #(#attrs)*
+        #vis #sig {
+            let _tpom_frozen = ::tpom::testing::frozen_time(#at)
+                .unwrap_or_else(|e| panic!("Could not freeze the time at {:?}: {}", #at, e));
+            #block
+        }
+    }
+    .into()
+}
@@ -0,0 +1,15 @@
+//! Mocks the time of a pre-built program, with no changes to it:
+//!
+//! ```sh
+//! TPOM_FREEZE=1000000000 LD_PRELOAD=target/release/libtpom_preload.so date
+//! ```
+//!
+//! See [`tpom::preload`] for the variables read.
+use small_ctor::ctor;
+
+#[ctor]
+unsafe fn apply() {
+    if let Err(e) = tpom::preload::install() {
+        eprintln!("tpom-preload: not mocking time: {}", e);
+    }
+}
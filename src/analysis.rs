@@ -0,0 +1,172 @@
+//! A report on a vDSO image, for any architecture: typically one dumped with `tpom dump` on
+//! a kernel at hand, to see what tpom would make of it, or to attach to a bug report.
+//!
+//! ```no_run
+//! let image = std::fs::read("/tmp/vdso-1234").unwrap();
+//! let v = tpom::vdso::vDSO::from_bytes(&image).unwrap();
+//! println!("{}", tpom::analysis::analyze(&v));
+//! ```
+use crate::opcodes;
+use crate::vdso::{self, vDSO, Alignment, ElfClass, Symbol};
+use crate::Kind;
+use std::fmt;
+
+/// What [`analyze`] finds in an image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    /// Such as `x86_64`, from the ELF header.
+    pub architecture: String,
+    pub class: ElfClass,
+    pub alignment: Alignment,
+    /// Every function exported, as [`vDSO::symbols`].
+    pub symbols: Vec<Symbol>,
+    /// The length of tpom's jump on the image's architecture, if tpom has one for it.
+    pub jump: Option<usize>,
+    /// The time functions, in the order of `symbols`.
+    pub plan: Vec<Planned>,
+}
+
+/// How tpom would patch one time function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Planned {
+    pub name: String,
+    pub kind: Kind,
+    /// The bytes that can be overwritten: the padded size, up to the next symbol.
+    pub size: usize,
+    /// The bytes the jump needs, if tpom has one for the architecture.
+    pub needed: Option<usize>,
+}
+
+impl Planned {
+    /// Whether the jump fits, so the function can be patched in place.
+    pub fn fits(&self) -> bool {
+        self.needed.is_some_and(|needed| self.size >= needed)
+    }
+}
+
+/// Analyzes `v`, which need not be for this architecture, nor this process' class.
+pub fn analyze(v: &vDSO) -> Analysis {
+    let symbols: Vec<Symbol> = v.symbols().collect();
+    let jump = opcodes::jump_len(v.machine(), v.class());
+    let plan = symbols
+        .iter()
+        .filter_map(|sym| {
+            let kind = vdso::base_name_kind(&sym.name)?;
+            Some(Planned {
+                name: sym.name.clone(),
+                kind,
+                size: vdso::patchable_size(v.dynsyms(), sym),
+                needed: jump,
+            })
+        })
+        .collect();
+    Analysis {
+        architecture: v.architecture(),
+        class: v.class(),
+        alignment: v.alignment(),
+        symbols,
+        jump,
+        plan,
+    }
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let class = match self.class {
+            ElfClass::Elf32 => "ELF32",
+            ElfClass::Elf64 => "ELF64",
+        };
+        let alignment = match self.alignment {
+            Alignment::Section(a) => format!("{} bytes, from .text", a),
+            Alignment::Inferred(a) => format!("{} bytes, inferred without sections", a),
+        };
+        writeln!(f, "Architecture: {} ({})", self.architecture, class)?;
+        writeln!(f, "Alignment: {}", alignment)?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<28} {:<10} {:>8} {:>6}",
+            "SYMBOL", "VERSION", "OFFSET", "SIZE"
+        )?;
+        for sym in &self.symbols {
+            writeln!(
+                f,
+                "{:<28} {:<10} {:>#8x} {:>6}",
+                sym.name,
+                sym.version.as_deref().unwrap_or("-"),
+                sym.address,
+                sym.size
+            )?;
+        }
+        writeln!(f)?;
+        let Some(jump) = self.jump else {
+            return write!(
+                f,
+                "No patch plan: tpom has no jump for {}",
+                self.architecture
+            );
+        };
+        write!(f, "Patch plan, with a {}-byte jump:", jump)?;
+        for planned in &self.plan {
+            let outcome = match planned.fits() {
+                true => "overwrite in place".to_string(),
+                false => format!("too small by {} bytes", jump - planned.size),
+            };
+            write!(
+                f,
+                "\n  {:<28} {:<14} {:>6}  {}",
+                planned.name,
+                format!("{:?}", planned.kind),
+                planned.size,
+                outcome
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_analyze() {
+        let image = fs::read("src/test_files/test_vdso_elf_2").unwrap();
+        let analysis = analyze(&vDSO::from_bytes(&image).unwrap());
+        assert_eq!(analysis.architecture, "RISC-V");
+        assert_eq!(analysis.class, ElfClass::Elf64);
+        assert_eq!(analysis.symbols.len(), 7);
+        assert_eq!(analysis.jump, Some(20));
+        let planned: Vec<_> = analysis.plan.iter().map(|p| (p.kind, p.size)).collect();
+        assert_eq!(
+            planned,
+            vec![
+                (Kind::GetTimeOfDay, 200),
+                (Kind::ClockGetRes, 90),
+                (Kind::GetTime, 272)
+            ]
+        );
+        assert!(analysis.plan.iter().all(Planned::fits));
+        let report = analysis.to_string();
+        assert!(
+            report.starts_with("Architecture: RISC-V (ELF64)\n"),
+            "{}",
+            report
+        );
+        assert!(report.contains("with a 20-byte jump"), "{}", report);
+
+        let too_small = Planned {
+            name: "__vdso_time".to_string(),
+            kind: Kind::Time,
+            size: 8,
+            needed: Some(12),
+        };
+        assert!(!too_small.fits());
+        assert!(!Planned {
+            needed: None,
+            ..too_small
+        }
+        .fits());
+    }
+}
@@ -1,5 +1,7 @@
 use small_ctor::ctor;
 use std::error::Error;
+use std::fmt;
+use std::sync::OnceLock;
 
 #[derive(Debug, Copy, Clone)]
 pub struct AuxVecValues {
@@ -7,14 +9,116 @@ pub struct AuxVecValues {
     pub(crate) page_size: usize,
 }
 
+/// The key of an auxiliary vector entry; see getauxval(3) for their meaning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key(pub libc::c_ulong);
+
+pub const AT_NULL: Key = Key(0);
+pub const AT_PHDR: Key = Key(3);
+pub const AT_PHENT: Key = Key(4);
+pub const AT_PHNUM: Key = Key(5);
+pub const AT_PAGESZ: Key = Key(6);
+pub const AT_BASE: Key = Key(7);
+pub const AT_FLAGS: Key = Key(8);
+pub const AT_ENTRY: Key = Key(9);
+pub const AT_UID: Key = Key(11);
+pub const AT_EUID: Key = Key(12);
+pub const AT_GID: Key = Key(13);
+pub const AT_EGID: Key = Key(14);
+pub const AT_PLATFORM: Key = Key(15);
+pub const AT_HWCAP: Key = Key(16);
+pub const AT_CLKTCK: Key = Key(17);
+pub const AT_SECURE: Key = Key(23);
+pub const AT_BASE_PLATFORM: Key = Key(24);
+pub const AT_RANDOM: Key = Key(25);
+pub const AT_HWCAP2: Key = Key(26);
+pub const AT_EXECFN: Key = Key(31);
+pub const AT_SYSINFO_EHDR: Key = Key(33);
+pub const AT_MINSIGSTKSZ: Key = Key(51);
+
+impl Key {
+    fn name(self) -> Option<&'static str> {
+        let name = match self {
+            AT_NULL => "AT_NULL",
+            AT_PHDR => "AT_PHDR",
+            AT_PHENT => "AT_PHENT",
+            AT_PHNUM => "AT_PHNUM",
+            AT_PAGESZ => "AT_PAGESZ",
+            AT_BASE => "AT_BASE",
+            AT_FLAGS => "AT_FLAGS",
+            AT_ENTRY => "AT_ENTRY",
+            AT_UID => "AT_UID",
+            AT_EUID => "AT_EUID",
+            AT_GID => "AT_GID",
+            AT_EGID => "AT_EGID",
+            AT_PLATFORM => "AT_PLATFORM",
+            AT_HWCAP => "AT_HWCAP",
+            AT_CLKTCK => "AT_CLKTCK",
+            AT_SECURE => "AT_SECURE",
+            AT_BASE_PLATFORM => "AT_BASE_PLATFORM",
+            AT_RANDOM => "AT_RANDOM",
+            AT_HWCAP2 => "AT_HWCAP2",
+            AT_EXECFN => "AT_EXECFN",
+            AT_SYSINFO_EHDR => "AT_SYSINFO_EHDR",
+            AT_MINSIGSTKSZ => "AT_MINSIGSTKSZ",
+            _ => return None,
+        };
+        Some(name)
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// Errors while looking up the auxiliary vector.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuxvError {
+    /// The auxiliary vector has no entry for this key.
+    MissingEntry(Key),
+    /// The auxiliary vector could not be read.
+    Unreadable(String),
+    /// Every way of reading the auxiliary vector failed; the error of each, in the order
+    /// they were tried.
+    AllFailed(Vec<(&'static str, AuxvError)>),
+}
+
+impl fmt::Display for AuxvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuxvError::MissingEntry(key) => write!(f, "no auxiliary vector entry {}", key),
+            AuxvError::Unreadable(e) => write!(f, "could not read the auxiliary vector: {}", e),
+            AuxvError::AllFailed(errors) => {
+                write!(f, "could not read the auxiliary vector")?;
+                for (source, e) in errors {
+                    write!(f, "; {}: {}", source, e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for AuxvError {}
+
 extern "C" {
     static environ: *const *const u8;
 }
 
-unsafe fn get_auxv_ptr() -> *const usize {
+/// `None` if there is no environment to walk past: static-PIE binaries and early
+/// constructors may run before `environ` is set, and `clearenv()` may null it.
+unsafe fn get_auxv_ptr() -> Option<*const usize> {
     // the auxiliary vector is right behind the environment variables, which
     // is an array of strings, delimited by a nullpointer.
     let mut env_entry_ptr = environ;
+    if env_entry_ptr.is_null() {
+        return None;
+    }
 
     while !(*env_entry_ptr).is_null() {
         env_entry_ptr = env_entry_ptr.offset(1);
@@ -22,44 +126,241 @@ unsafe fn get_auxv_ptr() -> *const usize {
 
     env_entry_ptr = env_entry_ptr.offset(1);
 
-    return std::mem::transmute::<*const *const u8, *const usize>(env_entry_ptr);
+    Some(std::mem::transmute::<*const *const u8, *const usize>(
+        env_entry_ptr,
+    ))
 }
 
-pub(crate) fn read_aux_vec() -> Result<AuxVecValues, Box<dyn Error>> {
-    Ok(unsafe { AUX.unwrap() })
+/// The values found at startup, or why they could not be found: in sandboxes that hide
+/// the auxiliary vector, the vDSO is unusable but the process must still start.
+pub(crate) fn read_aux_vec() -> Result<AuxVecValues, AuxvError> {
+    AUX.get_or_init(discover).clone()
 }
-//#[cfg_attr(any(target_os = "linux"), link_section = ".init_array")]
-static mut AUX: Option<AuxVecValues> = None; // TODO once?
+static AUX: OnceLock<Result<AuxVecValues, AuxvError>> = OnceLock::new();
 
-#[ctor]
-unsafe fn store_auxv() {
-    // The auxiliary vector is an array of key:value tuples, represented as [usize, usize]
-    // The end is delimited by having the key == AT_NULL
-    let mut out = unsafe { get_auxv_ptr() };
+/// Picks the values we need out of the auxiliary vector's key:value pairs.
+fn find_values(pairs: impl Iterator<Item = (usize, usize)>) -> Result<AuxVecValues, AuxvError> {
     let mut ptr = 0;
     let mut pagesize = 0;
-    unsafe {
-        while *out != libc::AT_NULL as usize {
-            let key = *out;
-            let val = *out.offset(1);
-            if key == libc::AT_SYSINFO_EHDR as usize {
-                ptr = val;
-            }
-            if key == libc::AT_PAGESZ as usize {
-                pagesize = val;
-            }
-            out = out.offset(2);
+    for (key, val) in pairs {
+        match Key(key as libc::c_ulong) {
+            AT_NULL => break,
+            AT_SYSINFO_EHDR => ptr = val,
+            AT_PAGESZ => pagesize = val,
+            _ => {}
         }
     }
     if ptr == 0 {
-        panic!("Could not find vDSO base");
+        return Err(AuxvError::MissingEntry(AT_SYSINFO_EHDR));
     }
     if pagesize == 0 {
-        panic!("Could not find page size");
+        return Err(AuxvError::MissingEntry(AT_PAGESZ));
     }
-
-    AUX = Some(AuxVecValues {
+    Ok(AuxVecValues {
         vdso_base: ptr,
         page_size: pagesize,
-    });
+    })
+}
+
+/// Asks libc, which saved the auxiliary vector at startup; unaffected by changes to `environ`.
+fn from_getauxval() -> Result<AuxVecValues, AuxvError> {
+    find_values(
+        [AT_SYSINFO_EHDR, AT_PAGESZ]
+            .iter()
+            .map(|k| (k.0 as usize, unsafe { libc::getauxval(k.0) } as usize)),
+    )
+}
+
+/// The kernel's copy of the auxiliary vector, as pairs of native words.
+fn proc_pairs() -> Result<Vec<(usize, usize)>, AuxvError> {
+    let data = std::fs::read("/proc/self/auxv")
+        .map_err(|e| AuxvError::Unreadable(format!("/proc/self/auxv: {}", e)))?;
+    let word = std::mem::size_of::<usize>();
+    let words = data
+        .chunks_exact(word)
+        .map(|w| usize::from_ne_bytes(w.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    Ok(words.chunks_exact(2).map(|p| (p[0], p[1])).collect())
+}
+
+fn from_proc() -> Result<AuxVecValues, AuxvError> {
+    find_values(proc_pairs()?.into_iter())
+}
+
+/// Walks the auxiliary vector past the environment, up to AT_NULL; only correct while
+/// `environ` is still the block the kernel set up.
+unsafe fn environ_pairs() -> Result<Vec<(usize, usize)>, AuxvError> {
+    // The auxiliary vector is an array of key:value tuples, represented as [usize, usize]
+    // The end is delimited by having the key == AT_NULL
+    let mut out = unsafe { get_auxv_ptr() }
+        .ok_or_else(|| AuxvError::Unreadable("environ is null".to_string()))?;
+    let mut pairs = vec![];
+    unsafe {
+        while *out != AT_NULL.0 as usize {
+            // The kernel provides a few dozen entries; past that, this isn't the vector
+            if pairs.len() == MAX_ENTRIES {
+                return Err(AuxvError::Unreadable("no AT_NULL past environ".to_string()));
+            }
+            pairs.push((*out, *out.offset(1)));
+            out = out.offset(2);
+        }
+    }
+    Ok(pairs)
+}
+
+const MAX_ENTRIES: usize = 256;
+
+unsafe fn from_environ() -> Result<AuxVecValues, AuxvError> {
+    find_values(unsafe { environ_pairs() }?.into_iter())
+}
+
+/// The value of the auxiliary vector entry `key`.
+///
+/// ```
+/// let hwcap = tpom::auxv::get(tpom::auxv::AT_HWCAP);
+/// assert!(hwcap.is_ok());
+/// ```
+pub fn get(key: Key) -> Result<usize, AuxvError> {
+    // getauxval returns 0 both for missing entries and for entries that are 0
+    unsafe { *libc::__errno_location() = 0 };
+    let val = unsafe { libc::getauxval(key.0) } as usize;
+    if val != 0 || unsafe { *libc::__errno_location() } != libc::ENOENT {
+        return Ok(val);
+    }
+    iter()?
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+        .ok_or(AuxvError::MissingEntry(key))
+}
+
+/// Every entry of the auxiliary vector, without the final AT_NULL. Read from
+/// /proc/self/auxv, or from past the environment if /proc is not available.
+pub fn iter() -> Result<impl Iterator<Item = (Key, usize)>, AuxvError> {
+    let pairs = match proc_pairs() {
+        Ok(pairs) => pairs,
+        Err(e) => {
+            log::debug!("{}, reading the auxiliary vector past environ", e);
+            unsafe { environ_pairs() }?
+        }
+    };
+    Ok(pairs
+        .into_iter()
+        .map(|(k, v)| (Key(k as libc::c_ulong), v))
+        .take_while(|(k, _)| *k != AT_NULL))
+}
+
+/// The `[vdso]` mapping's start and end, per /proc/self/maps.
+pub(crate) fn vdso_mapping() -> Result<(usize, usize), AuxvError> {
+    let maps = std::fs::read_to_string("/proc/self/maps")
+        .map_err(|e| AuxvError::Unreadable(format!("/proc/self/maps: {}", e)))?;
+    find_vdso_mapping(&maps).ok_or(AuxvError::MissingEntry(AT_SYSINFO_EHDR))
+}
+
+/// The `[vdso]` mapping's start and end among `maps`, in the format of /proc/pid/maps.
+pub(crate) fn find_vdso_mapping(maps: &str) -> Option<(usize, usize)> {
+    maps.lines()
+        .filter(|line| line.ends_with("[vdso]"))
+        .find_map(|line| {
+            let range = line.split(' ').next()?;
+            let (start, end) = range.split_once('-')?;
+            let start = usize::from_str_radix(start, 16).ok()?;
+            let end = usize::from_str_radix(end, 16).ok()?;
+            Some((start, end))
+        })
+}
+
+/// Without an auxiliary vector, the vDSO can still be found among the process' mappings;
+/// the page size then comes from sysconf.
+fn from_maps() -> Result<AuxVecValues, AuxvError> {
+    let (start, _) = vdso_mapping()?;
+    let pagesize = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if pagesize <= 0 {
+        return Err(AuxvError::MissingEntry(AT_PAGESZ));
+    }
+    Ok(AuxVecValues {
+        vdso_base: start,
+        page_size: pagesize as usize,
+    })
+}
+
+type Strategy = fn() -> Result<AuxVecValues, AuxvError>;
+
+/// Tries each way of reading the auxiliary vector, from the most to the least reliable.
+fn discover() -> Result<AuxVecValues, AuxvError> {
+    let mut errors = vec![];
+    let strategies: [(&'static str, Strategy); 4] = [
+        ("getauxval", from_getauxval),
+        ("/proc/self/auxv", from_proc),
+        ("environ", || unsafe { from_environ() }),
+        ("/proc/self/maps", from_maps),
+    ];
+    for (name, strategy) in strategies {
+        match strategy() {
+            Ok(avv) => return Ok(avv),
+            Err(e) => errors.push((name, e)),
+        }
+    }
+    Err(AuxvError::AllFailed(errors))
+}
+
+#[ctor]
+unsafe fn store_auxv() {
+    // Read early, before the program gets a chance to replace `environ`
+    let _ = AUX.set(discover());
+    crate::vdso::snapshot_pristine();
+    #[cfg(feature = "ctor")]
+    crate::preload::bootstrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sources_agree() {
+        let avv = from_getauxval().unwrap();
+        for other in [
+            read_aux_vec().unwrap(),
+            from_proc().unwrap(),
+            unsafe { from_environ() }.unwrap(),
+            from_maps().unwrap(),
+        ] {
+            assert_eq!(
+                (avv.vdso_base, avv.page_size),
+                (other.vdso_base, other.page_size)
+            );
+        }
+    }
+
+    #[test]
+    fn test_find_values() {
+        let pairs = [(AT_PAGESZ.0 as usize, 0x1000), (0, 0)];
+        assert_eq!(
+            find_values(pairs.into_iter()).unwrap_err(),
+            AuxvError::MissingEntry(AT_SYSINFO_EHDR)
+        );
+        // Entries past AT_NULL are ignored
+        let pairs = [
+            (AT_SYSINFO_EHDR.0 as usize, 0x7000),
+            (0, 0),
+            (AT_PAGESZ.0 as usize, 0x1000),
+        ];
+        let err = find_values(pairs.into_iter()).unwrap_err();
+        assert_eq!(err, AuxvError::MissingEntry(AT_PAGESZ));
+        assert_eq!(err.to_string(), "no auxiliary vector entry AT_PAGESZ");
+    }
+
+    #[test]
+    fn test_get_and_iter() {
+        let avv = from_getauxval().unwrap();
+        assert_eq!(get(AT_SYSINFO_EHDR), Ok(avv.vdso_base));
+        assert_eq!(get(AT_PAGESZ), Ok(avv.page_size));
+        let entries = iter().unwrap().collect::<Vec<_>>();
+        assert!(entries.contains(&(AT_PAGESZ, avv.page_size)));
+        assert!(!entries.iter().any(|(k, _)| *k == AT_NULL));
+        // Not a key the kernel uses
+        assert_eq!(get(Key(1000)), Err(AuxvError::MissingEntry(Key(1000))));
+        assert_eq!(AT_HWCAP.to_string(), "AT_HWCAP");
+        assert_eq!(Key(1000).to_string(), "1000");
+    }
 }
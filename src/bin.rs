@@ -1,35 +1,211 @@
-use std::{error::Error, time::SystemTime};
+//! `tpom-run`: runs a program with its time mocked, as faketime(1) does.
+use std::ffi::OsString;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitCode, ExitStatus};
 
-use tpom::{vdso, Kind, TVDSOFun, Time, TimeSpec, TimeVal};
+use tpom::config::Config;
+use tpom::live::{parse_duration, parse_time, MOCKED_CLOCKS};
+#[cfg(feature = "preload")]
+use tpom::process::CommandExt;
 
-extern crate tpom;
+const USAGE: &str = "\
+Usage: tpom-run [--freeze TIME | --offset DURATION] [--speed FACTOR] [--preload LIBRARY] -- PROGRAM [ARGS...]
 
-fn myclock(_clockid: i32) -> TimeSpec {
-    TimeSpec {
-        seconds: 111,
-        nanos: 333,
-    }
+Runs PROGRAM with its wall clocks mocked; exits as it does, or with 125 if it can't be run.
+
+  --freeze TIME      Start at TIME, a date such as 2021-01-01T00:00:00Z, seconds since
+                     the epoch or now +3d; the time stands still unless --speed is also
+                     given
+  --offset DURATION  Start this far away from the real time, in seconds or as 1h30m,
+                     possibly negative
+  --speed FACTOR     Run at this factor of the real time
+  --preload LIBRARY  Load the tpom-preload library at LIBRARY into PROGRAM, rather than
+                     tracing it and every program it runs to patch them once loaded;
+                     only for dynamically linked programs";
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    config: Config,
+    preload: Option<OsString>,
+    program: Vec<OsString>,
 }
 
-fn mygttod() -> TimeVal {
-    TimeVal {
-        seconds: 1,
-        micros: 3,
+fn parse_args(mut args: impl Iterator<Item = OsString>) -> Result<Options, String> {
+    let (mut start, mut offset, mut speed, mut preload) = (None, None, None, None);
+    let program = loop {
+        let Some(arg) = args.next() else {
+            return Err("no program to run".to_string());
+        };
+        let arg = arg.to_string_lossy().into_owned();
+        if arg == "--" {
+            break args.collect::<Vec<_>>();
+        }
+        let flag = arg.as_str();
+        if !matches!(flag, "--freeze" | "--offset" | "--speed" | "--preload") {
+            return Err(format!("unknown option {:?}", flag));
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", flag))?;
+        let text = value.to_string_lossy();
+        match flag {
+            "--freeze" => start = Some(parse_time(&text).map_err(|e| e.to_string())?),
+            "--offset" => offset = Some(seconds(flag, &text)?),
+            "--speed" => match text.parse::<f64>() {
+                Ok(s) if s.is_finite() && s >= 0.0 => speed = Some(s),
+                _ => return Err(format!("--speed {:?} is not a non-negative factor", text)),
+            },
+            _ => preload = Some(value),
+        }
+    };
+    if program.is_empty() {
+        return Err("no program to run".to_string());
     }
+    if start.is_some() && offset.is_some() {
+        return Err("--freeze and --offset are exclusive".to_string());
+    }
+    let config = Config {
+        start,
+        offset: offset.unwrap_or(0),
+        speed: speed.unwrap_or(if start.is_some() { 0.0 } else { 1.0 }),
+    };
+    Ok(Options {
+        config,
+        preload,
+        program,
+    })
+}
+
+fn seconds(flag: &str, text: &str) -> Result<i64, String> {
+    parse_duration(flag, text)
+        .map(|n| n as i64)
+        .map_err(|e| e.to_string())
 }
 
-fn my_time() -> Time {
-    666
+#[cfg(feature = "preload")]
+fn run_preloaded(
+    command: &mut Command,
+    library: OsString,
+    config: &Config,
+) -> Result<ExitStatus, String> {
+    let config = tpom::preload::Config {
+        start: config.start.map(i128::from),
+        offset: config.offset.into(),
+        speed: config.speed,
+    };
+    command
+        .preload(library, &config)
+        .status()
+        .map_err(|e| e.to_string())
 }
 
-pub fn main() -> Result<(), Box<dyn Error>> {
-    println!("Now: {:?}", SystemTime::now());
-    println!("Executing");
-    let v = vdso::vDSO::read()?;
-    let og = v.entry(Kind::GetTime).ok_or("Could not find clock")?;
-    let backup = og.overwrite(myclock);
-    println!("Done, Now: {:?}, restoring", SystemTime::now());
-    backup.restore();
-    println!("Restored, Now: {:?}", SystemTime::now());
-    Ok(())
+#[cfg(not(feature = "preload"))]
+fn run_preloaded(
+    _command: &mut Command,
+    _library: OsString,
+    _config: &Config,
+) -> Result<ExitStatus, String> {
+    Err("--preload needs tpom built with the preload feature".to_string())
+}
+
+/// Patches every program of the tree once loaded, to read the clock from a page this
+/// process keeps.
+#[cfg(target_arch = "x86_64")]
+fn run_patched(command: &mut Command, config: &Config) -> Result<ExitStatus, String> {
+    use tpom::stubs::Stub;
+    use tpom::Kind;
+
+    config.apply().map_err(|e| e.to_string())?;
+    let page = tpom::live::page().map_err(|e| e.to_string())?;
+    tpom::follow::run(command, |target| {
+        let stub = Stub::Control(target.share(page)?);
+        target.install(Kind::GetTime, &stub)?;
+        target.install(Kind::GetTimeOfDay, &stub)?;
+        // Not every vDSO has `time`; libc derives it from `clock_gettime` then
+        if let Err(e) = target.install(Kind::Time, &stub) {
+            log::debug!("Not patching time: {}", e);
+        }
+        Ok(())
+    })
+    .map_err(|e| e.to_string())
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn run_patched(_command: &mut Command, _config: &Config) -> Result<ExitStatus, String> {
+    Err("only --preload is supported on this architecture".to_string())
+}
+
+fn run(options: Options) -> Result<ExitCode, String> {
+    let mut command = Command::new(&options.program[0]);
+    command.args(&options.program[1..]);
+    let status = match options.preload {
+        Some(library) => run_preloaded(&mut command, library, &options.config)?,
+        None => run_patched(&mut command, &options.config)?,
+    };
+    let code = status
+        .code()
+        .or_else(|| status.signal().map(|s| 128 + s))
+        .unwrap_or(125);
+    Ok(ExitCode::from(code as u8))
+}
+
+pub fn main() -> ExitCode {
+    let mut args = std::env::args_os().skip(1).peekable();
+    if args.peek().is_some_and(|a| a == "--help" || a == "-h") {
+        println!("{}", USAGE);
+        println!("\nOnly {:?} follow the mocked time.", MOCKED_CLOCKS);
+        return ExitCode::SUCCESS;
+    }
+    match parse_args(args).and_then(run) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("tpom-run: {}\n\n{}", e, USAGE);
+            ExitCode::from(125)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse(&["--freeze", "2021-01-01T00:00:00Z", "--", "date", "+%s"]).unwrap();
+        assert_eq!(
+            options.config,
+            Config {
+                start: Some(1_609_459_200_000_000_000),
+                offset: 0,
+                speed: 0.0
+            }
+        );
+        assert_eq!(options.program, ["date", "+%s"]);
+        let options = parse(&["--offset", "-60", "--speed", "2", "--", "true"]).unwrap();
+        assert_eq!(
+            (options.config.offset, options.config.speed),
+            (-60_000_000_000, 2.0)
+        );
+        assert_eq!(
+            parse(&["--freeze", "1000", "--", "true"])
+                .unwrap()
+                .config
+                .start,
+            Some(1_000_000_000_000)
+        );
+        for bad in [
+            &["--"][..],
+            &["true"],
+            &["--freeze", "soon", "--", "true"],
+            &["--freeze", "1", "--offset", "1", "--", "true"],
+            &["--speed", "-1", "--", "true"],
+            &["--freeze"],
+        ] {
+            assert!(parse(bad).is_err(), "{:?}", bad);
+        }
+    }
 }
@@ -0,0 +1,185 @@
+//! Records the intercepted calls, through an [`crate::observers`] observer, as a Chrome
+//! trace: a JSON file that `chrome://tracing` and the Perfetto UI open, to see on a timeline
+//! when the code under test read which clock, and how far the mock was from the real time.
+//!
+//! ```no_run
+//! tpom::chrome_trace::start().unwrap();
+//! // ... patch, run the code under test ...
+//! tpom::chrome_trace::stop();
+//! tpom::chrome_trace::save("/tmp/tpom-trace.json").unwrap();
+//! ```
+//!
+//! Every call is an instant event on its thread, at the real monotonic time, with the clock
+//! and the time returned; `clock_gettime` calls also update a counter per clock, of the
+//! seconds between the time returned and the real one.
+use crate::error::Error;
+use crate::live::{self, format_seconds};
+use crate::observers::{self, Observation};
+use crate::{recent, Kind};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+/// The calls kept, beyond which they are dropped.
+pub const MAX_EVENTS: usize = 1 << 20;
+
+/// A call, when it was made.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Event {
+    /// Real `CLOCK_MONOTONIC` nanoseconds.
+    at: i64,
+    tid: i32,
+    call: Observation,
+    /// The real time of the clock asked for, for `clock_gettime`.
+    real: Option<i64>,
+}
+
+pub(crate) struct Trace {
+    observer: Option<u64>,
+    events: Vec<Event>,
+    dropped: u64,
+}
+
+static TRACE: Mutex<Trace> = Mutex::new(Trace {
+    observer: None,
+    events: vec![],
+    dropped: 0,
+});
+
+/// Holds the trace, keeping it unchanged; see [`crate::fork`].
+pub(crate) fn lock() -> MutexGuard<'static, Trace> {
+    TRACE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn record(call: &Observation) {
+    let at = live::real(libc::CLOCK_MONOTONIC);
+    let real = match (call.kind, call.clockid) {
+        (Kind::GetTime, Some(clockid)) => Some(live::real(clockid)),
+        _ => None,
+    };
+    let event = Event {
+        at,
+        tid: recent::tid(),
+        call: *call,
+        real,
+    };
+    let mut trace = lock();
+    if trace.events.len() >= MAX_EVENTS {
+        trace.dropped += 1;
+        return;
+    }
+    trace.events.push(event);
+}
+
+/// Records every intercepted call from now on, after those recorded before; does nothing if
+/// already recording. Takes one of the [`observers::MAX_OBSERVERS`].
+pub fn start() -> Result<(), Error> {
+    // The observers are never locked while the trace is, as in `record`
+    if lock().observer.is_some() {
+        return Ok(());
+    }
+    let id = observers::add(record)?;
+    let raced = lock().observer.replace(id);
+    if let Some(raced) = raced {
+        lock().observer = Some(raced);
+        observers::remove(id);
+    }
+    Ok(())
+}
+
+/// Stops recording, keeping the calls recorded.
+pub fn stop() {
+    let id = lock().observer.take();
+    if let Some(id) = id {
+        observers::remove(id);
+    }
+}
+
+/// Forgets the calls recorded.
+pub fn clear() {
+    let mut trace = lock();
+    trace.events.clear();
+    trace.dropped = 0;
+}
+
+/// How many calls were dropped, past [`MAX_EVENTS`].
+pub fn dropped() -> u64 {
+    lock().dropped
+}
+
+/// Writes the calls recorded as a trace to `out`.
+pub fn write_to(mut out: impl Write) -> io::Result<()> {
+    let events = lock().events.clone();
+    let pid = std::process::id();
+    out.write_all(b"{\"displayTimeUnit\":\"ns\",\"traceEvents\":[")?;
+    let mut first = true;
+    let mut separate = |out: &mut dyn Write| -> io::Result<()> {
+        if !std::mem::take(&mut first) {
+            out.write_all(b",")?;
+        }
+        out.write_all(b"\n")
+    };
+    for e in &events {
+        let ts = micros(e.at);
+        let name = function(e.call.kind);
+        let value = e.call.value.seconds as i128 * 1_000_000_000 + e.call.value.nanos as i128;
+        separate(&mut out)?;
+        write!(
+            out,
+            "{{\"name\":\"{}\",\"cat\":\"tpom\",\"ph\":\"i\",\"s\":\"t\",\"ts\":{},\"pid\":{},\"tid\":{},\"args\":{{",
+            name, ts, pid, e.tid
+        )?;
+        if let Some(clockid) = e.call.clockid {
+            write!(out, "\"clockid\":{},", clockid)?;
+        }
+        write!(out, "\"value\":\"{}\"}}}}", format_seconds(value))?;
+        if let (Some(real), Some(clockid)) = (e.real, e.call.clockid) {
+            separate(&mut out)?;
+            write!(
+                out,
+                "{{\"name\":\"clock {} offset\",\"cat\":\"tpom\",\"ph\":\"C\",\"ts\":{},\"pid\":{},\"args\":{{\"seconds\":{}}}}}",
+                clockid,
+                ts,
+                pid,
+                (value - real as i128) as f64 / 1e9
+            )?;
+        }
+    }
+    out.write_all(b"\n]}\n")
+}
+
+/// Writes the calls recorded as a trace to a file at `path`, replacing it.
+pub fn save(path: impl AsRef<Path>) -> Result<(), Error> {
+    let os = |e: io::Error| Error::Os("write", e.raw_os_error().unwrap_or(0));
+    let file = File::create(path).map_err(os)?;
+    let mut out = BufWriter::new(file);
+    write_to(&mut out).map_err(os)?;
+    out.flush().map_err(os)
+}
+
+fn function(kind: Kind) -> &'static str {
+    match kind {
+        Kind::GetTime => "clock_gettime",
+        Kind::Time => "time",
+        Kind::ClockGetRes => "clock_getres",
+        Kind::GetTimeOfDay => "gettimeofday",
+    }
+}
+
+/// Nanoseconds as the microseconds of a trace, keeping their precision.
+fn micros(nanos: i64) -> String {
+    format!("{}.{:03}", nanos / 1000, nanos % 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_units() {
+        assert_eq!(micros(1_234_567), "1234.567");
+        assert_eq!(micros(999), "0.999");
+        assert_eq!(function(Kind::GetTimeOfDay), "gettimeofday");
+    }
+}
@@ -0,0 +1,100 @@
+//! Predicts which clock reads the vDSO answers in userspace.
+//!
+//! The vDSO only reads the clock itself when the kernel's clocksource can be read from
+//! userspace; otherwise, and for clocks it doesn't implement, it makes the syscall. Code
+//! that knows this (some language runtimes, or anything calling `syscall(2)`) may skip the
+//! vDSO for those clocks, and so also skip a patched vDSO.
+use crate::error::Error;
+use std::fs;
+
+const SYSFS: &str = "/sys/devices/system/clocksource/clocksource0";
+
+/// Clocksources with a userspace-readable counter (a `vclock_mode` in the kernel).
+const VDSO_CLOCKSOURCES: &[&str] = &[
+    "tsc",
+    "kvm-clock",
+    "hyperv_clocksource_tsc_page",
+    "arch_sys_counter",
+    "riscv_clocksource",
+];
+
+/// How the vDSO would serve a clock read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockPath {
+    /// Read in userspace; patching the vDSO intercepts it.
+    Vdso,
+    /// The vDSO function makes the syscall.
+    Syscall,
+}
+
+fn read_sysfs(file: &'static str) -> Result<String, Error> {
+    fs::read_to_string(format!("{}/{}", SYSFS, file))
+        .map(|s| s.trim().to_string())
+        .map_err(|e| Error::Os(file, e.raw_os_error().unwrap_or(0)))
+}
+
+/// The clocksource in use, such as `tsc`, `hpet` or `kvm-clock`.
+pub fn current() -> Result<String, Error> {
+    read_sysfs("current_clocksource")
+}
+
+/// The clocksources the kernel could switch to.
+pub fn available() -> Result<Vec<String>, Error> {
+    Ok(read_sysfs("available_clocksource")?
+        .split_whitespace()
+        .map(str::to_string)
+        .collect())
+}
+
+/// How the vDSO would serve `clock_gettime(clockid)` with the current clocksource. If the
+/// clocksource can't be read, it is assumed to be usable from userspace.
+pub fn path(clockid: libc::clockid_t) -> ClockPath {
+    predict(current().ok().as_deref(), clockid)
+}
+
+fn predict(clocksource: Option<&str>, clockid: libc::clockid_t) -> ClockPath {
+    match clockid {
+        // Read from the last tick; no counter involved
+        libc::CLOCK_REALTIME_COARSE | libc::CLOCK_MONOTONIC_COARSE => ClockPath::Vdso,
+        libc::CLOCK_REALTIME
+        | libc::CLOCK_MONOTONIC
+        | libc::CLOCK_BOOTTIME
+        | libc::CLOCK_TAI
+        | libc::CLOCK_MONOTONIC_RAW => match clocksource {
+            Some(cs) if !VDSO_CLOCKSOURCES.contains(&cs) => ClockPath::Syscall,
+            _ => ClockPath::Vdso,
+        },
+        // CPU-time, alarm and dynamic (negative) clocks
+        _ => ClockPath::Syscall,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_predict() {
+        assert_eq!(predict(Some("tsc"), libc::CLOCK_REALTIME), ClockPath::Vdso);
+        assert_eq!(
+            predict(Some("hpet"), libc::CLOCK_MONOTONIC),
+            ClockPath::Syscall
+        );
+        assert_eq!(
+            predict(Some("hpet"), libc::CLOCK_MONOTONIC_COARSE),
+            ClockPath::Vdso
+        );
+        assert_eq!(
+            predict(Some("tsc"), libc::CLOCK_PROCESS_CPUTIME_ID),
+            ClockPath::Syscall
+        );
+        assert_eq!(predict(None, libc::CLOCK_BOOTTIME), ClockPath::Vdso);
+    }
+
+    #[test]
+    fn test_current_is_available() {
+        if let (Ok(current), Ok(available)) = (current(), available()) {
+            assert!(available.contains(&current));
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Mock parameters read from a file, for long-running services tested by hand: the
+//! [`crate::live`] clock follows the file, and [`watch`] applies every change to it as it is
+//! saved; [`crate::signals::reload_on_hangup`] reloads it on `SIGHUP` instead.
+//!
+//! The file is TOML, with the keys of the `TPOM_*` variables, in lowercase:
+//!
+//! ```toml
+//! # Seconds since the epoch the clock starts at; it stands still unless `speed` is set
+//! freeze = 1608841800
+//! # Or: seconds away from the real time
+//! # offset = -3600
+//! speed = 2.0
+//! ```
+//!
+//! A string may stand for a number, as in `freeze = "2030-01-01"` or `offset = "-3d"`: the
+//! times and durations of [`live::parse_time`] and [`live::parse_duration`]. Only
+//! `key = value` lines and comments are read. An empty file is the real time.
+use crate::control::State;
+use crate::error::Error;
+use crate::live::{self, parse_duration, parse_time};
+use std::ffi::OsString;
+use std::fs;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// The parameters of a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// Nanoseconds since the epoch the clock starts at; `None` for the real time.
+    pub start: Option<i64>,
+    /// Nanoseconds added to the start.
+    pub offset: i64,
+    pub speed: f64,
+}
+
+impl Config {
+    /// Reads the contents of a file.
+    pub fn parse(text: &str) -> Result<Config, Error> {
+        let (mut freeze, mut offset, mut speed) = (None, None, None);
+        for (n, line) in text.lines().enumerate() {
+            let invalid = |what: &str| Error::InvalidConfig(format!("line {}: {}", n + 1, what));
+            let line = line.split_once('#').map_or(line, |(l, _)| l).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(invalid("expected key = value"));
+            };
+            let value = value.trim();
+            let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                Some(text) => text.to_string(),
+                None => value.replace('_', ""),
+            };
+            let key = key.trim();
+            let value = value.strip_prefix('+').unwrap_or(&value);
+            let slot = match key {
+                "freeze" => &mut freeze,
+                "offset" => &mut offset,
+                "speed" => &mut speed,
+                _ => return Err(invalid(&format!("unknown key {:?}", key))),
+            };
+            if slot.replace(value.to_string()).is_some() {
+                return Err(invalid(&format!("{} is set twice", key)));
+            }
+        }
+        if freeze.is_some() && offset.is_some() {
+            return Err(Error::InvalidConfig(
+                "freeze and offset are exclusive".to_string(),
+            ));
+        }
+        let start = freeze.map(|v| parse_time(&v)).transpose()?;
+        let offset = offset.map_or(Ok(0), |v| parse_duration("offset", &v).map(|s| s as i64))?;
+        let speed = match speed {
+            Some(v) => match v.parse::<f64>() {
+                Ok(speed) if speed.is_finite() && speed >= 0.0 => speed,
+                _ => {
+                    return Err(Error::InvalidConfig(format!(
+                        "speed={:?} is not a non-negative factor",
+                        v
+                    )))
+                }
+            },
+            None if start.is_some() => 0.0,
+            None => 1.0,
+        };
+        Ok(Config {
+            start,
+            offset,
+            speed,
+        })
+    }
+
+    /// Sets the [`crate::live`] clock to these parameters, in one change; returns its state
+    /// afterwards.
+    pub fn apply(&self) -> Result<State, Error> {
+        let page = live::page()?;
+        let now = self
+            .start
+            .unwrap_or_else(|| live::real(libc::CLOCK_REALTIME))
+            + self.offset;
+        if self.speed == 0.0 {
+            page.freeze(live::timespec(now));
+        } else {
+            page.run(live::timespec(now), self.speed);
+        }
+        Ok(page.state())
+    }
+}
+
+/// Reads the file at `path` and applies it.
+pub fn load(path: impl AsRef<Path>) -> Result<State, Error> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path)
+        .map_err(|e| Error::InvalidConfig(format!("can't read {}: {}", path.display(), e)))?;
+    Config::parse(&text)?.apply()
+}
+
+/// Loads the file at `path`, then loads it again whenever it is written or replaced, for
+/// the rest of the process' life, from a thread of its own. A file that fails to load
+/// later leaves the clock as it was.
+pub fn watch(path: impl AsRef<Path>) -> Result<State, Error> {
+    let path = path.as_ref().to_path_buf();
+    let state = load(&path)?;
+    // Editors often save by renaming a new file over the old one, so the directory is
+    // watched rather than the file
+    let Some(name) = path.file_name().map(|n| n.to_os_string()) else {
+        return Err(Error::InvalidConfig(format!(
+            "{} is not a file",
+            path.display()
+        )));
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(Error::last_os_error("inotify_init1"));
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let dir_c = std::ffi::CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| Error::InvalidConfig(format!("{} has a NUL", dir.display())))?;
+    let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+    if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), dir_c.as_ptr(), mask) } < 0 {
+        return Err(Error::last_os_error("inotify_add_watch"));
+    }
+    thread::Builder::new()
+        .name("tpom-config".to_string())
+        .spawn(move || follow(fd, &path, &name))
+        .map_err(|e| Error::Os("spawn", e.raw_os_error().unwrap_or(0)))?;
+    Ok(state)
+}
+
+/// Reloads `path` on every event of the inotify `fd` naming it.
+fn follow(fd: OwnedFd, path: &Path, name: &OsString) {
+    let header = std::mem::size_of::<libc::inotify_event>();
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let n = unsafe {
+            libc::read(
+                fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if n < 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) {
+            continue;
+        }
+        if n <= 0 {
+            log::error!(
+                "Could not read changes to {}, no longer watching it",
+                path.display()
+            );
+            return;
+        }
+        let mut changed = false;
+        let mut events = &buf[..n as usize];
+        while events.len() >= header {
+            let event =
+                unsafe { std::ptr::read_unaligned(events.as_ptr() as *const libc::inotify_event) };
+            let end = header + event.len as usize;
+            // The name is padded with NULs
+            let event_name = events[header..end].split(|&b| b == 0).next().unwrap_or(&[]);
+            changed |= OsString::from_vec(event_name.to_vec()) == *name;
+            events = &events[end..];
+        }
+        if changed {
+            match load(path) {
+                Ok(state) => log::info!("Reloaded {}: {}", path.display(), state),
+                Err(e) => log::error!("Not reloading {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NANOS: i64 = 1_000_000_000;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Config::parse(""),
+            Ok(Config {
+                start: None,
+                offset: 0,
+                speed: 1.0
+            })
+        );
+        let text = "# frozen\nfreeze = 1_000 # seconds\n\n";
+        assert_eq!(
+            Config::parse(text),
+            Ok(Config {
+                start: Some(1000 * NANOS),
+                offset: 0,
+                speed: 0.0
+            })
+        );
+        assert_eq!(
+            Config::parse("offset = -1.5\nspeed = 2.0"),
+            Ok(Config {
+                start: None,
+                offset: -1_500_000_000,
+                speed: 2.0
+            })
+        );
+        assert_eq!(
+            Config::parse("freeze = \"2030-01-01\"\nspeed = 1"),
+            Ok(Config {
+                start: Some(1_893_456_000 * NANOS),
+                offset: 0,
+                speed: 1.0
+            })
+        );
+        assert_eq!(
+            Config::parse("offset = \"-1h30m\""),
+            Ok(Config {
+                start: None,
+                offset: -5400 * NANOS,
+                speed: 1.0
+            })
+        );
+        for bad in [
+            "freeze",
+            "clock = 1",
+            "speed = 1\nspeed = 2",
+            "freeze = 1\noffset = 1",
+            "speed = -1",
+            "freeze = \"soon\"",
+        ] {
+            assert!(Config::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! Mock parameters in a page of shared memory, read by [`crate::stubs::Stub::Control`] on
+//! every call, so that they can change while the stubs are installed.
+//!
+//! The page is a memfd: other processes map it from `/proc/<pid>/fd/<fd>` of the process
+//! that created it, with [`ControlPage::open`] or [`crate::remote::Remote::share`]. The
+//! wall clocks are mocked from it; the time is computed in nanoseconds, as an `i64`.
+//!
+//! The parameters are behind a seqlock, so that readers never see half of an update: a
+//! writer makes the sequence odd while changing them, and readers retry if it was odd or
+//! changed while they read.
+use crate::error::Error;
+use crate::live::format_seconds;
+use crate::trampolines::raw_clock_gettime;
+use crate::TimeSpec;
+use std::fmt;
+use std::fs;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::sync::atomic::{fence, AtomicI64, AtomicU64, Ordering};
+
+const PAGE: usize = 4096;
+
+/// The page's layout, which the stubs' code depends on.
+#[repr(C)]
+struct Block {
+    /// Odd while the parameters are being changed.
+    sequence: AtomicU64,
+    /// Whether the time is `value`, rather than running from it.
+    frozen: AtomicI64,
+    /// The mocked time when the real time was `anchor`, in nanoseconds.
+    value: AtomicI64,
+    anchor: AtomicI64,
+    /// The rate of the mocked time, in 32.32 fixed point.
+    scale: AtomicI64,
+}
+
+/// The parameters of a page, as of one moment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct State {
+    /// The mocked time, in nanoseconds since the epoch.
+    pub now: i64,
+    pub frozen: bool,
+    /// The rate of the mocked time, when not frozen.
+    pub speed: f64,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "time={} frozen={} speed={}",
+            format_seconds(self.now as i128),
+            self.frozen,
+            self.speed
+        )
+    }
+}
+
+/// A mapping of the control page; the mocked time only changes through its methods.
+pub struct ControlPage {
+    fd: OwnedFd,
+    block: *const Block,
+}
+
+// The block is only accessed through atomics
+unsafe impl Send for ControlPage {}
+unsafe impl Sync for ControlPage {}
+
+impl ControlPage {
+    /// Creates a page, which starts with the real time.
+    pub fn new() -> Result<ControlPage, Error> {
+        let fd = unsafe { libc::memfd_create(c"tpom-control".as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::last_os_error("memfd_create"));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        if unsafe { libc::ftruncate(fd.as_raw_fd(), PAGE as libc::off_t) } != 0 {
+            return Err(Error::last_os_error("ftruncate"));
+        }
+        let page = ControlPage::map(fd)?;
+        page.reset();
+        Ok(page)
+    }
+
+    /// Maps the page created by another process, such as `/proc/<pid>/fd/<fd>`.
+    pub fn open(path: impl AsRef<Path>) -> Result<ControlPage, Error> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| Error::Os("open", e.raw_os_error().unwrap_or(0)))?;
+        ControlPage::map(file.into())
+    }
+
+    fn map(fd: OwnedFd) -> Result<ControlPage, Error> {
+        let block = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                PAGE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if block == libc::MAP_FAILED {
+            return Err(Error::last_os_error("mmap"));
+        }
+        Ok(ControlPage {
+            fd,
+            block: block as *const Block,
+        })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+
+    /// Where the page is mapped in this process.
+    pub fn address(&self) -> usize {
+        self.block as usize
+    }
+
+    fn block(&self) -> &Block {
+        unsafe { &*self.block }
+    }
+
+    /// Changes the parameters with `f`, as the only writer: others, in this process or not,
+    /// wait for it to finish.
+    fn write(&self, f: impl FnOnce(&Block)) {
+        let block = self.block();
+        let mut sequence = block.sequence.load(Ordering::Relaxed);
+        loop {
+            if sequence % 2 == 1 {
+                std::hint::spin_loop();
+                sequence = block.sequence.load(Ordering::Relaxed);
+                continue;
+            }
+            match block.sequence.compare_exchange_weak(
+                sequence,
+                sequence + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => sequence = current,
+            }
+        }
+        fence(Ordering::Release);
+        f(block);
+        block.sequence.store(sequence + 2, Ordering::Release);
+    }
+
+    /// The time stops at `at`.
+    pub fn freeze(&self, at: TimeSpec) {
+        self.write(|block| {
+            block.value.store(to_nanos(&at), Ordering::Relaxed);
+            block.frozen.store(1, Ordering::Relaxed);
+        });
+    }
+
+    /// Reads the parameters as the stubs do, retrying while a writer changes them.
+    pub fn state(&self) -> State {
+        let block = self.block();
+        let (frozen, value, anchor, scale) = loop {
+            let sequence = block.sequence.load(Ordering::Acquire);
+            if sequence % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let read = (
+                block.frozen.load(Ordering::Relaxed) != 0,
+                block.value.load(Ordering::Relaxed),
+                block.anchor.load(Ordering::Relaxed),
+                block.scale.load(Ordering::Relaxed),
+            );
+            fence(Ordering::Acquire);
+            if block.sequence.load(Ordering::Relaxed) == sequence {
+                break read;
+            }
+        };
+        State {
+            now: at(frozen, value, anchor, scale, real_now()),
+            frozen,
+            speed: scale as f64 / (1u64 << 32) as f64,
+        }
+    }
+
+    /// The time is `now` from this moment on, and runs `speed` times as fast as the real one.
+    pub fn run(&self, now: TimeSpec, speed: f64) {
+        let scale = (speed * (1u64 << 32) as f64) as i64;
+        let anchor = real_now();
+        self.write(|block| {
+            block.value.store(to_nanos(&now), Ordering::Relaxed);
+            block.anchor.store(anchor, Ordering::Relaxed);
+            block.scale.store(scale, Ordering::Relaxed);
+            block.frozen.store(0, Ordering::Relaxed);
+        });
+    }
+
+    /// The time jumps by `nanos`, frozen or not.
+    pub fn advance(&self, nanos: i64) {
+        self.write(|block| {
+            let value = block.value.load(Ordering::Relaxed);
+            block.value.store(value + nanos, Ordering::Relaxed);
+        });
+    }
+
+    /// The time runs `speed` times as fast as the real one from this moment on, from where
+    /// it is; a frozen time stays frozen until [`ControlPage::run`].
+    pub fn scale(&self, speed: f64) {
+        let scale = (speed * (1u64 << 32) as f64) as i64;
+        let real = real_now();
+        self.write(|block| {
+            let now = at(
+                block.frozen.load(Ordering::Relaxed) != 0,
+                block.value.load(Ordering::Relaxed),
+                block.anchor.load(Ordering::Relaxed),
+                block.scale.load(Ordering::Relaxed),
+                real,
+            );
+            block.value.store(now, Ordering::Relaxed);
+            block.anchor.store(real, Ordering::Relaxed);
+            block.scale.store(scale, Ordering::Relaxed);
+        });
+    }
+
+    /// The time is the real time, shifted by `nanos`.
+    pub fn offset(&self, nanos: i64) {
+        let now = real_now() + nanos;
+        self.run(
+            TimeSpec {
+                seconds: now.div_euclid(1_000_000_000),
+                nanos: now.rem_euclid(1_000_000_000),
+            },
+            1.0,
+        );
+    }
+
+    /// The time is the real time.
+    pub fn reset(&self) {
+        self.offset(0);
+    }
+}
+
+impl Drop for ControlPage {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.block as *mut libc::c_void, PAGE) };
+    }
+}
+
+/// The mocked time when the real one is `real`, as the stubs compute it.
+fn at(frozen: bool, value: i64, anchor: i64, scale: i64, real: i64) -> i64 {
+    if frozen {
+        return value;
+    }
+    value + (((real - anchor) as i128 * scale as i128) >> 32) as i64
+}
+
+fn to_nanos(ts: &TimeSpec) -> i64 {
+    ts.seconds * 1_000_000_000 + ts.nanos
+}
+
+/// The real wall time, even while this process' clock is mocked.
+fn real_now() -> i64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    raw_clock_gettime(libc::CLOCK_REALTIME, &mut ts);
+    ts.tv_sec * 1_000_000_000 + ts.tv_nsec
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state() {
+        let page = ControlPage::new().unwrap();
+        assert!((page.state().now - real_now()).abs() < 1_000_000_000);
+        assert!(!page.state().frozen);
+
+        page.freeze(TimeSpec {
+            seconds: 1000,
+            nanos: 0,
+        });
+        page.advance(500);
+        page.scale(2.0);
+        let state = page.state();
+        assert_eq!(
+            (state.now, state.frozen, state.speed),
+            (1_000_000_000_500, true, 2.0)
+        );
+        assert_eq!(state.to_string(), "time=1000.000000500 frozen=true speed=2");
+
+        page.run(
+            TimeSpec {
+                seconds: 1000,
+                nanos: 0,
+            },
+            0.0,
+        );
+        page.advance(-1_000_000_000);
+        page.scale(1.0);
+        let now = page.state().now;
+        assert!(
+            (999_000_000_000..1_000_000_000_000).contains(&now),
+            "{}",
+            now
+        );
+    }
+}
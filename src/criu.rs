@@ -0,0 +1,90 @@
+//! Checkpointing a patched process with CRIU.
+//!
+//! A dump would save the patched vDSO together with the rest of the memory, and on
+//! restore CRIU maps the vDSO of the new kernel, possibly elsewhere, and redirects the old
+//! one to it. Whether the patches survive depends on which, so a process about to be dumped
+//! takes them off with [`pre_dump`], and puts them back with [`Checkpoint::post_restore`],
+//! wherever the vDSO is by then.
+//!
+//! GOT patches, the seccomp backend and time namespaces live in the process' memory or in
+//! its CRIU images, and need nothing.
+//!
+//! ```no_run
+//! let checkpoint = tpom::criu::pre_dump().unwrap();
+//! // ... `criu dump --leave-running`, or be restored ...
+//! if checkpoint.restored() {
+//!     println!("Restored by CRIU");
+//! }
+//! checkpoint.post_restore().unwrap();
+//! ```
+use crate::error::Error;
+use crate::registry;
+use crate::vdso::vDSO;
+use std::fs;
+
+/// The patches taken off by [`pre_dump`], to put back.
+#[must_use = "the patches stay off until post_restore"]
+pub struct Checkpoint {
+    /// When the process started, as a restored process is started anew.
+    started: Option<u64>,
+}
+
+/// Writes back the original code of every patched vDSO function, leaked ones included. They
+/// stay claimed, and their callbacks installed, until [`Checkpoint::post_restore`].
+#[track_caller]
+pub fn pre_dump() -> Result<Checkpoint, Error> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("pre_dump").entered();
+    registry::suspend()?;
+    Ok(Checkpoint {
+        started: start_time(),
+    })
+}
+
+impl Checkpoint {
+    /// Whether this process was restored from an image since [`pre_dump`]. Restored
+    /// processes keep their pid, but get the start time of their restore.
+    pub fn restored(&self) -> bool {
+        self.started.is_some() && start_time() != self.started
+    }
+
+    /// Patches every function again, in the vDSO mapped now. Also for a process that was
+    /// dumped and left running instead, or not dumped at all.
+    #[track_caller]
+    pub fn post_restore(self) -> Result<(), Error> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("post_restore").entered();
+        let v = vDSO::read_remapped()
+            .map_err(|e| Error::UnsupportedPlatform(format!("can't read the vDSO: {}", e)))?;
+        registry::resume(&v)
+    }
+}
+
+/// The start time of this process, in clock ticks since boot.
+fn start_time() -> Option<u64> {
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name may have spaces or parentheses, and comes before it; it is the 22nd
+    // field, the 20th after the name
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_start_time() {
+        let started = start_time().unwrap();
+        assert!(started > 0);
+        assert_eq!(start_time(), Some(started));
+        assert!(!Checkpoint {
+            started: Some(started)
+        }
+        .restored());
+        assert!(Checkpoint {
+            started: Some(started + 1)
+        }
+        .restored());
+    }
+}
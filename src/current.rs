@@ -0,0 +1,75 @@
+use crate::trampolines::{callback, clock_callback, run_callback};
+use crate::trampolines::{CLOCK_GTOD_CB, TIME_CB};
+use crate::{got, registry, Kind, Time, TimeSpec, TimeVal};
+
+/// The clocks [`current_mock`] asks about.
+const CLOCKS: [libc::clockid_t; 9] = [
+    libc::CLOCK_REALTIME,
+    libc::CLOCK_MONOTONIC,
+    libc::CLOCK_PROCESS_CPUTIME_ID,
+    libc::CLOCK_THREAD_CPUTIME_ID,
+    libc::CLOCK_MONOTONIC_RAW,
+    libc::CLOCK_REALTIME_COARSE,
+    libc::CLOCK_MONOTONIC_COARSE,
+    libc::CLOCK_BOOTTIME,
+    libc::CLOCK_TAI,
+];
+
+/// What the installed callbacks answer, as of one moment; `None` where the real time is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurrentMock {
+    /// The kinds of function currently replaced, in the vDSO or through the GOT.
+    pub patched: Vec<Kind>,
+    /// `clock_gettime` of each of the kernel's clocks.
+    pub clocks: Vec<(libc::clockid_t, Option<TimeSpec>)>,
+    pub time_of_day: Option<TimeVal>,
+    pub time: Option<Time>,
+}
+
+impl CurrentMock {
+    /// What `clock_gettime(clockid)` answers, if it is mocked.
+    pub fn clock(&self, clockid: libc::clockid_t) -> Option<TimeSpec> {
+        self.clocks
+            .iter()
+            .find(|(id, _)| *id == clockid)
+            .and_then(|(_, ts)| *ts)
+    }
+}
+
+/// Calls the installed callbacks directly, rather than through the patched functions, so
+/// tests can check the mock without reading the time back. A callback that panics or is
+/// not installed counts as not mocking.
+pub fn current_mock() -> CurrentMock {
+    let patched: Vec<Kind> = [
+        Kind::GetTime,
+        Kind::Time,
+        Kind::ClockGetRes,
+        Kind::GetTimeOfDay,
+    ]
+    .into_iter()
+    .filter(|&kind| registry::kind_patched(kind) || got::is_redirected(kind))
+    .collect();
+    let live = |kind| patched.contains(&kind);
+
+    let clocks = CLOCKS
+        .iter()
+        .map(|&clockid| {
+            let ts = clock_callback(clockid)
+                .filter(|_| live(Kind::GetTime))
+                .and_then(|cb| run_callback("clock_gettime", cb as *const (), || cb(clockid)));
+            (clockid, ts)
+        })
+        .collect();
+    let time_of_day = callback(&CLOCK_GTOD_CB)
+        .filter(|_| live(Kind::GetTimeOfDay))
+        .and_then(|cb| run_callback("gettimeofday", cb as *const (), cb));
+    let time = callback(&TIME_CB)
+        .filter(|_| live(Kind::Time))
+        .and_then(|cb| run_callback("time", cb as *const (), cb));
+    CurrentMock {
+        patched,
+        clocks,
+        time_of_day,
+        time,
+    }
+}
@@ -0,0 +1,218 @@
+//! Disassembles vDSO functions with capstone, as the kernel built them and as they are now,
+//! to see what tpom changed:
+//!
+//! ```no_run
+//! let v = tpom::vdso::vDSO::read().unwrap();
+//! println!("{}", v.disassemble("__vdso_clock_gettime").unwrap());
+//! ```
+use crate::elf::{self, ElfClass};
+use crate::error::Error;
+use crate::registry;
+use crate::vdso::{self, vDSO};
+use capstone::prelude::*;
+use std::fmt;
+
+/// One decoded instruction, or bytes capstone could not decode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    /// Offset from the start of the vDSO.
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+    /// Such as `jmp 0x7b0`, or `.byte 0x90` for undecodable bytes.
+    pub text: String,
+}
+
+/// A function's code, as found by [`vDSO::disassemble`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Disassembly {
+    pub symbol: String,
+    pub offset: usize,
+    /// The code before tpom patched it; the current code if it isn't patched.
+    pub original: Vec<Instruction>,
+    /// The code in memory now; for offline images, the code in the image.
+    pub installed: Vec<Instruction>,
+}
+
+impl Disassembly {
+    /// Whether the installed code differs from the original.
+    pub fn changed(&self) -> bool {
+        self.original != self.installed
+    }
+}
+
+impl vDSO {
+    /// Disassembles `symbol`, padding included, up to the next symbol. The original code is
+    /// the one saved when it was patched in this process; the vDSO of another process, read
+    /// with [`vDSO::read_from_pid`], is shown as it is, and can be compared with this one's.
+    pub fn disassemble(&self, symbol: &str) -> Result<Disassembly, Error> {
+        let dynsyms = self.dynsyms();
+        let sym = dynsyms
+            .iter()
+            .find(|s| s.name == symbol)
+            .ok_or_else(|| Error::UnknownSymbol(symbol.to_string()))?;
+        let size = vdso::patchable_size(dynsyms, sym);
+        let installed = match self.is_live() {
+            // Reads the mapping rather than the snapshot, which may predate the patch
+            true => unsafe {
+                std::slice::from_raw_parts((self.base() + sym.address) as *const u8, size)
+            }
+            .to_vec(),
+            false => self.symbol_code(sym.address, size).to_vec(),
+        };
+        let original = match self.is_live() {
+            true => registry::original(self.base() + sym.address),
+            false => None,
+        }
+        .unwrap_or_else(|| installed.clone());
+
+        let (cs, unit) = disassembler(self.machine(), self.class())?;
+        Ok(Disassembly {
+            symbol: sym.name.clone(),
+            offset: sym.address,
+            original: decode(&cs, unit, &original, sym.address)?,
+            installed: decode(&cs, unit, &installed, sym.address)?,
+        })
+    }
+}
+
+/// A disassembler for the architecture, and its smallest instruction length.
+fn disassembler(machine: u16, class: ElfClass) -> Result<(Capstone, usize), Error> {
+    let built = match (machine, class) {
+        (elf::EM_X86_64, ElfClass::Elf64) => Capstone::new()
+            .x86()
+            .mode(arch::x86::ArchMode::Mode64)
+            .build()
+            .map(|cs| (cs, 1)),
+        (elf::EM_386, ElfClass::Elf32) => Capstone::new()
+            .x86()
+            .mode(arch::x86::ArchMode::Mode32)
+            .build()
+            .map(|cs| (cs, 1)),
+        (elf::EM_AARCH64, ElfClass::Elf64) => Capstone::new()
+            .arm64()
+            .mode(arch::arm64::ArchMode::Arm)
+            .build()
+            .map(|cs| (cs, 4)),
+        (elf::EM_RISCV, ElfClass::Elf64) => Capstone::new()
+            .riscv()
+            .mode(arch::riscv::ArchMode::RiscV64)
+            .extra_mode([arch::riscv::ArchExtraMode::RiscVC].into_iter())
+            .build()
+            .map(|cs| (cs, 2)),
+        _ => {
+            return Err(Error::UnsupportedPlatform(format!(
+                "no disassembler for {}",
+                elf::machine_name(machine)
+            )))
+        }
+    };
+    built.map_err(|e| Error::UnsupportedPlatform(format!("capstone: {}", e)))
+}
+
+/// Decodes `code`, found at `offset`, skipping `unit` bytes past anything undecodable, such
+/// as the address in an AArch64 jump.
+fn decode(
+    cs: &Capstone,
+    unit: usize,
+    code: &[u8],
+    offset: usize,
+) -> Result<Vec<Instruction>, Error> {
+    let mut ret = vec![];
+    let mut at = 0;
+    while at < code.len() {
+        let insns = cs
+            .disasm_all(&code[at..], (offset + at) as u64)
+            .map_err(|e| Error::UnsupportedPlatform(format!("capstone: {}", e)))?;
+        for i in insns.iter() {
+            ret.push(Instruction {
+                offset: i.address() as usize,
+                bytes: i.bytes().to_vec(),
+                text: format!(
+                    "{} {}",
+                    i.mnemonic().unwrap_or("?"),
+                    i.op_str().unwrap_or("")
+                )
+                .trim_end()
+                .to_string(),
+            });
+            at += i.bytes().len();
+        }
+        if at < code.len() && insns.is_empty() {
+            let bytes = &code[at..(at + unit).min(code.len())];
+            let text = bytes
+                .iter()
+                .map(|b| format!("{:#04x}", b))
+                .collect::<Vec<_>>();
+            ret.push(Instruction {
+                offset: offset + at,
+                bytes: bytes.to_vec(),
+                text: format!(".byte {}", text.join(", ")),
+            });
+            at += bytes.len();
+        }
+    }
+    Ok(ret)
+}
+
+fn listing(f: &mut fmt::Formatter<'_>, code: &[Instruction]) -> fmt::Result {
+    for i in code {
+        let bytes: Vec<String> = i.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        writeln!(f, "  {:>6x}:  {:<30} {}", i.offset, bytes.join(" "), i.text)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} at {:#x}", self.symbol, self.offset)?;
+        writeln!(f, "original:")?;
+        listing(f, &self.original)?;
+        if !self.changed() {
+            return write!(f, "installed: unchanged");
+        }
+        writeln!(f, "installed:")?;
+        listing(f, &self.installed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble_offline() {
+        let image = std::fs::read("src/test_files/test_vdso_elf_1").unwrap();
+        let v = vDSO::from_bytes(&image).unwrap();
+        let d = v.disassemble("__vdso_clock_gettime").unwrap();
+        assert_eq!(d.offset, 0xc10);
+        assert!(!d.changed());
+        assert_eq!(d.original[0].text, "jmp 0x7b0");
+        assert_eq!(d.original[0].bytes, [0xe9, 0x9b, 0xfb, 0xff, 0xff]);
+        let listing = d.to_string();
+        assert!(listing.contains("c10:  e9 9b fb ff ff"), "{}", listing);
+        assert!(listing.ends_with("installed: unchanged"), "{}", listing);
+        assert_eq!(
+            v.disassemble("nope"),
+            Err(Error::UnknownSymbol("nope".to_string()))
+        );
+
+        let image = std::fs::read("src/test_files/test_vdso_elf_2").unwrap();
+        let v = vDSO::from_bytes(&image).unwrap();
+        let d = v.disassemble("__vdso_rt_sigreturn").unwrap();
+        assert_eq!(d.original[0].text, "addi a7, zero, 0x8b");
+    }
+
+    #[test]
+    fn test_decode_skips_data() {
+        let cs = disassembler(elf::EM_AARCH64, ElfClass::Elf64).unwrap().0;
+        let stub = std::fs::read("tests/files/aarch64_0x12ff34ff56ff78ff.bin").unwrap();
+        let code = decode(&cs, 4, &stub, 0x100).unwrap();
+        let text: Vec<&str> = code.iter().map(|i| i.text.as_str()).collect();
+        assert_eq!(text[..2], ["ldr x0, #0x108", "br x0"]);
+        assert_eq!(
+            code.iter().map(|i| i.bytes.len()).sum::<usize>(),
+            stub.len()
+        );
+        assert!(disassembler(40, ElfClass::Elf32).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use crate::error::Error;
+use crate::vdso::{vDSO, Symbol};
+use crate::{clocksource, platform, Backend, Kind};
+use std::ffi::CStr;
+use std::fmt;
+use std::fs;
+
+/// What [`doctor`] finds out about the system; `None` where it couldn't be read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnosis {
+    /// The kernel release, such as `6.1.0-13-amd64`.
+    pub kernel: Option<String>,
+    /// The machine, such as `x86_64`, as reported by the kernel.
+    pub architecture: Option<String>,
+    pub clocksource: Option<String>,
+    /// Whether SELinux is enforcing; `None` without SELinux.
+    pub selinux_enforcing: Option<bool>,
+    /// The YAMA `ptrace_scope`, which limits [`crate::remote`] above 0; `None` without YAMA.
+    pub ptrace_scope: Option<u32>,
+    /// Why [`platform::probe`] rejects the system, if it does.
+    pub unsupported: Option<String>,
+    /// Where the vDSO is mapped, and its size.
+    pub vdso: Option<(usize, usize)>,
+    pub symbols: Vec<Symbol>,
+    /// The kinds of function [`vDSO::entry`] finds.
+    pub kinds: Vec<Kind>,
+}
+
+impl Diagnosis {
+    /// The backend to patch this process with: the vDSO where it can be patched and has
+    /// `clock_gettime`, the GOT otherwise.
+    pub fn backend(&self) -> Backend {
+        match self.unsupported.is_none() && self.kinds.contains(&Kind::GetTime) {
+            true => Backend::Vdso,
+            false => Backend::Got,
+        }
+    }
+}
+
+/// Collects what tpom's behaviour depends on, to attach to bug reports or to pick a
+/// [`Backend`] with [`Diagnosis::backend`]. Nothing is patched, and nothing fails: what
+/// can't be read is left out.
+pub fn doctor() -> Diagnosis {
+    let (kernel, architecture) = uname();
+    let v = vDSO::read().ok();
+    Diagnosis {
+        kernel,
+        architecture,
+        clocksource: clocksource::current().ok(),
+        selinux_enforcing: read_number("/sys/fs/selinux/enforce").map(|e| e == 1),
+        ptrace_scope: read_number("/proc/sys/kernel/yama/ptrace_scope"),
+        unsupported: match platform::probe() {
+            Err(Error::UnsupportedPlatform(why)) => Some(why),
+            _ => None,
+        },
+        vdso: v.as_ref().map(|v| (v.base(), v.image().len())),
+        symbols: v
+            .as_ref()
+            .map(|v| v.symbols().collect())
+            .unwrap_or_default(),
+        kinds: v.as_ref().map(vDSO::kinds).unwrap_or_default(),
+    }
+}
+
+/// The kernel release and machine.
+fn uname() -> (Option<String>, Option<String>) {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return (None, None);
+    }
+    let field = |f: &[libc::c_char]| {
+        let s = unsafe { CStr::from_ptr(f.as_ptr()) };
+        Some(s.to_string_lossy().into_owned())
+    };
+    (field(&uts.release), field(&uts.machine))
+}
+
+fn read_number(path: &str) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let or_unknown = |v: &Option<String>| v.clone().unwrap_or_else(|| "unknown".to_string());
+        writeln!(f, "Kernel: {}", or_unknown(&self.kernel))?;
+        writeln!(f, "Architecture: {}", or_unknown(&self.architecture))?;
+        writeln!(f, "Clocksource: {}", or_unknown(&self.clocksource))?;
+        let selinux = match self.selinux_enforcing {
+            Some(true) => "enforcing",
+            Some(false) => "permissive",
+            None => "absent",
+        };
+        writeln!(f, "SELinux: {}", selinux)?;
+        let yama = match self.ptrace_scope {
+            Some(scope) => format!("ptrace_scope {}", scope),
+            None => "absent".to_string(),
+        };
+        writeln!(f, "YAMA: {}", yama)?;
+        let platform = match &self.unsupported {
+            Some(why) => format!("unsupported, {}", why),
+            None => "supported".to_string(),
+        };
+        writeln!(f, "Platform: {}", platform)?;
+        match self.vdso {
+            Some((base, size)) => writeln!(f, "vDSO: {:#x}, {} bytes", base, size)?,
+            None => writeln!(f, "vDSO: unreadable")?,
+        }
+        for sym in &self.symbols {
+            writeln!(
+                f,
+                "  {} {} {:#x} {}",
+                sym.name,
+                sym.version.as_deref().unwrap_or("-"),
+                sym.address,
+                sym.size
+            )?;
+        }
+        write!(f, "Backend: {:?}", self.backend())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_doctor() {
+        let diagnosis = doctor();
+        assert!(diagnosis.kernel.is_some());
+        assert_eq!(
+            diagnosis.architecture.as_deref(),
+            Some(std::env::consts::ARCH)
+        );
+        let (base, size) = diagnosis.vdso.unwrap();
+        assert!(base > 0 && size > 0);
+        assert!(!diagnosis.symbols.is_empty());
+        assert_eq!(diagnosis.backend(), Backend::Vdso);
+        let report = diagnosis.to_string();
+        assert!(report.starts_with("Kernel: "), "{}", report);
+        assert!(report.ends_with("Backend: Vdso"), "{}", report);
+
+        let qemu = Diagnosis {
+            unsupported: Some("qemu-user".to_string()),
+            ..diagnosis.clone()
+        };
+        assert_eq!(qemu.backend(), Backend::Got);
+        let no_gettime = Diagnosis {
+            kinds: vec![Kind::Time],
+            ..diagnosis
+        };
+        assert_eq!(no_gettime.backend(), Backend::Got);
+    }
+}
@@ -0,0 +1,520 @@
+//! The small part of ELF needed to find the symbols of a vDSO image.
+//!
+//! Parsing is done by goblin when the `goblin` feature is enabled (the default), or by a
+//! minimal built-in parser otherwise, which keeps the dependency tree down to `libc`.
+use crate::error::Error;
+
+pub(crate) const SIZEOF_IDENT: usize = 16;
+pub(crate) const ELFMAG: &[u8; 4] = b"\x7FELF";
+pub(crate) const SELFMAG: usize = 4;
+pub(crate) const EI_CLASS: usize = 4;
+pub(crate) const EI_DATA: usize = 5;
+pub(crate) const ELFCLASS32: u8 = 1;
+pub(crate) const ELFCLASS64: u8 = 2;
+pub(crate) const ELFDATA2LSB: u8 = 1;
+pub(crate) const ELFDATA2MSB: u8 = 2;
+
+pub(crate) const EM_386: u16 = 3;
+pub(crate) const EM_X86_64: u16 = 62;
+pub(crate) const EM_AARCH64: u16 = 183;
+pub(crate) const EM_RISCV: u16 = 243;
+
+pub(crate) const PT_LOAD: u32 = 1;
+pub(crate) const PT_DYNAMIC: u32 = 2;
+pub(crate) const SHT_PROGBITS: u32 = 1;
+
+const DT_NULL: u64 = 0;
+const DT_HASH: u64 = 4;
+const DT_STRTAB: u64 = 5;
+const DT_SYMTAB: u64 = 6;
+const DT_STRSZ: u64 = 10;
+const DT_GNU_HASH: u64 = 0x6fff_fef5;
+const DT_VERSYM: u64 = 0x6fff_fff0;
+const DT_VERDEF: u64 = 0x6fff_fffc;
+const DT_VERDEFNUM: u64 = 0x6fff_fffd;
+
+/// Marks the version definition naming the object itself, rather than a version.
+const VER_FLG_BASE: u16 = 1;
+
+/// The ELF class of a vDSO image; a 32-bit process on a 64-bit kernel has an ELF32 vDSO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfClass {
+    Elf32,
+    Elf64,
+}
+
+impl ElfClass {
+    /// Reads the class from the identification bytes at the start of an ELF image.
+    pub(crate) fn from_ident(ident: &[u8]) -> Result<ElfClass, Error> {
+        if ident.len() < SIZEOF_IDENT || &ident[..SELFMAG] != ELFMAG {
+            return Err(Error::MalformedVdso("bad ELF magic".to_string()));
+        }
+        match ident[EI_CLASS] {
+            ELFCLASS32 => Ok(ElfClass::Elf32),
+            ELFCLASS64 => Ok(ElfClass::Elf64),
+            c => Err(Error::MalformedVdso(format!("unknown ELF class {}", c))),
+        }
+    }
+
+    /// Whether the image is little endian, from its identification bytes.
+    pub(crate) fn little_endian(ident: &[u8]) -> Result<bool, Error> {
+        match ident[EI_DATA] {
+            ELFDATA2LSB => Ok(true),
+            ELFDATA2MSB => Ok(false),
+            d => Err(Error::MalformedVdso(format!("unknown data encoding {}", d))),
+        }
+    }
+
+    pub(crate) fn header_size(self) -> usize {
+        match self {
+            ElfClass::Elf32 => 52,
+            ElfClass::Elf64 => 64,
+        }
+    }
+
+    fn word_size(self) -> usize {
+        match self {
+            ElfClass::Elf32 => 4,
+            ElfClass::Elf64 => 8,
+        }
+    }
+}
+
+pub(crate) fn machine_name(machine: u16) -> String {
+    match machine {
+        EM_386 => "i386".to_string(),
+        EM_X86_64 => "x86_64".to_string(),
+        EM_AARCH64 => "AArch64".to_string(),
+        EM_RISCV => "RISC-V".to_string(),
+        m => format!("EM_{}", m),
+    }
+}
+
+pub(crate) struct Header {
+    pub(crate) class: ElfClass,
+    pub(crate) little_endian: bool,
+    pub(crate) e_machine: u16,
+    pub(crate) e_phoff: usize,
+    pub(crate) e_phnum: usize,
+    pub(crate) e_phentsize: usize,
+    pub(crate) e_shoff: usize,
+    pub(crate) e_shnum: usize,
+    pub(crate) e_shentsize: usize,
+    pub(crate) e_shstrndx: usize,
+}
+
+pub(crate) struct ProgramHeader {
+    pub(crate) p_type: u32,
+    pub(crate) p_offset: u64,
+    pub(crate) p_vaddr: u64,
+    pub(crate) p_filesz: u64,
+}
+
+pub(crate) struct SectionHeader {
+    pub(crate) sh_name: usize,
+    pub(crate) sh_type: u32,
+    pub(crate) sh_offset: u64,
+    pub(crate) sh_size: u64,
+    pub(crate) sh_addralign: u64,
+}
+
+pub(crate) struct Sym {
+    pub(crate) st_name: usize,
+    pub(crate) st_value: u64,
+    pub(crate) st_size: u64,
+}
+
+/// The parts of the dynamic segment we need; addresses are virtual addresses.
+pub(crate) struct DynamicInfo {
+    pub(crate) hash: Option<u64>,
+    pub(crate) gnu_hash: Option<u64>,
+    pub(crate) symtab: u64,
+    pub(crate) strtab: u64,
+    pub(crate) strsz: usize,
+    pub(crate) versym: Option<u64>,
+    pub(crate) verdef: Option<u64>,
+    pub(crate) verdefnum: usize,
+}
+
+/// Reads fixed-size integers from an image in its own byte order.
+#[derive(Clone, Copy)]
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    class: ElfClass,
+    little_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8], header: &Header) -> Reader<'a> {
+        Reader {
+            data,
+            class: header.class,
+            little_endian: header.little_endian,
+        }
+    }
+
+    fn bytes<const N: usize>(&self, at: usize) -> Result<[u8; N], Error> {
+        at.checked_add(N)
+            .and_then(|end| self.data.get(at..end))
+            .and_then(|b| b.try_into().ok())
+            .ok_or_else(|| Error::MalformedVdso(format!("read out of bounds at {:#x}", at)))
+    }
+
+    pub(crate) fn u16(&self, at: usize) -> Result<u16, Error> {
+        let b = self.bytes(at)?;
+        Ok(if self.little_endian {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        })
+    }
+
+    pub(crate) fn u32(&self, at: usize) -> Result<u32, Error> {
+        let b = self.bytes(at)?;
+        Ok(if self.little_endian {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        })
+    }
+
+    fn u64(&self, at: usize) -> Result<u64, Error> {
+        let b = self.bytes(at)?;
+        Ok(if self.little_endian {
+            u64::from_le_bytes(b)
+        } else {
+            u64::from_be_bytes(b)
+        })
+    }
+
+    /// A 32-bit value in ELF32 and a 64-bit value in ELF64.
+    fn word(&self, at: usize) -> Result<u64, Error> {
+        match self.class {
+            ElfClass::Elf32 => self.u32(at).map(u64::from),
+            ElfClass::Elf64 => self.u64(at),
+        }
+    }
+
+    pub(crate) fn word_size(&self) -> usize {
+        self.class.word_size()
+    }
+}
+
+/// The NUL-terminated string at `at` in a string table.
+pub(crate) fn str_at(table: &[u8], at: usize) -> Option<&str> {
+    let s = table.get(at..)?;
+    let end = s.iter().position(|c| *c == 0)?;
+    std::str::from_utf8(&s[..end]).ok()
+}
+
+/// Reads the dynamic segment, if there is one.
+pub(crate) fn dynamic_info(
+    data: &[u8],
+    header: &Header,
+    phdrs: &[ProgramHeader],
+) -> Result<Option<DynamicInfo>, Error> {
+    let Some(dynamic) = phdrs.iter().find(|ph| ph.p_type == PT_DYNAMIC) else {
+        return Ok(None);
+    };
+    let r = Reader::new(data, header);
+    let w = r.word_size();
+    let mut info = DynamicInfo {
+        hash: None,
+        gnu_hash: None,
+        symtab: 0,
+        strtab: 0,
+        strsz: 0,
+        versym: None,
+        verdef: None,
+        verdefnum: 0,
+    };
+    // Each entry is a (d_tag, d_val) pair of words
+    let start = dynamic.p_offset as usize;
+    for at in (start..start + dynamic.p_filesz as usize).step_by(2 * w) {
+        let val = r.word(at + w)?;
+        match r.word(at)? {
+            DT_NULL => break,
+            DT_HASH => info.hash = Some(val),
+            DT_GNU_HASH => info.gnu_hash = Some(val),
+            DT_SYMTAB => info.symtab = val,
+            DT_STRTAB => info.strtab = val,
+            DT_STRSZ => info.strsz = val as usize,
+            DT_VERSYM => info.versym = Some(val),
+            DT_VERDEF => info.verdef = Some(val),
+            DT_VERDEFNUM => info.verdefnum = val as usize,
+            _ => {}
+        }
+    }
+    Ok(Some(info))
+}
+
+/// The `count` version definitions at `at`, as (index, name) pairs. Each `Elf_Verdef` is
+/// followed by its `Elf_Verdaux` entries, the first of which names the version.
+pub(crate) fn version_definitions(
+    r: Reader,
+    at: usize,
+    count: usize,
+    strtab: &[u8],
+) -> Result<Vec<(u16, String)>, Error> {
+    let mut ret = vec![];
+    let mut at = at;
+    for _ in 0..count {
+        let flags = r.u16(at + 2)?;
+        let ndx = r.u16(at + 4)?;
+        let aux = r.u32(at + 12)? as usize;
+        let name = r.u32(at + aux)? as usize;
+        if flags & VER_FLG_BASE == 0 {
+            let name = str_at(strtab, name).unwrap_or_default();
+            ret.push((ndx, name.to_string()));
+        }
+        match r.u32(at + 16)? {
+            0 => break,
+            next => at += next as usize,
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(feature = "goblin")]
+mod backend {
+    use super::*;
+    use goblin::container::{Container, Ctx, Endian};
+    use goblin::elf;
+
+    impl From<goblin::error::Error> for Error {
+        fn from(e: goblin::error::Error) -> Error {
+            // goblin's error is not `PartialEq` (nor `std::error::Error` without `std`)
+            Error::MalformedVdso(format!("{:?}", e))
+        }
+    }
+
+    fn ctx(header: &Header) -> Ctx {
+        let container = match header.class {
+            ElfClass::Elf32 => Container::Little,
+            ElfClass::Elf64 => Container::Big,
+        };
+        let endian = if header.little_endian {
+            Endian::Little
+        } else {
+            Endian::Big
+        };
+        Ctx::new(container, endian)
+    }
+
+    pub(crate) fn parse_header(data: &[u8]) -> Result<Header, Error> {
+        let class = ElfClass::from_ident(data)?;
+        let h = elf::Elf::parse_header(data)?;
+        Ok(Header {
+            class,
+            little_endian: ElfClass::little_endian(data)?,
+            e_machine: h.e_machine,
+            e_phoff: h.e_phoff as usize,
+            e_phnum: h.e_phnum as usize,
+            e_phentsize: h.e_phentsize as usize,
+            e_shoff: h.e_shoff as usize,
+            e_shnum: h.e_shnum as usize,
+            e_shentsize: h.e_shentsize as usize,
+            e_shstrndx: h.e_shstrndx as usize,
+        })
+    }
+
+    pub(crate) fn program_headers(
+        data: &[u8],
+        header: &Header,
+    ) -> Result<Vec<ProgramHeader>, Error> {
+        let phdrs = elf::ProgramHeader::parse(data, header.e_phoff, header.e_phnum, ctx(header))?;
+        Ok(phdrs
+            .iter()
+            .map(|ph| ProgramHeader {
+                p_type: ph.p_type,
+                p_offset: ph.p_offset,
+                p_vaddr: ph.p_vaddr,
+                p_filesz: ph.p_filesz,
+            })
+            .collect())
+    }
+
+    pub(crate) fn section_headers(
+        data: &[u8],
+        header: &Header,
+    ) -> Result<Vec<SectionHeader>, Error> {
+        let shdrs = elf::SectionHeader::parse(data, header.e_shoff, header.e_shnum, ctx(header))?;
+        Ok(shdrs
+            .iter()
+            .map(|sh| SectionHeader {
+                sh_name: sh.sh_name,
+                sh_type: sh.sh_type,
+                sh_offset: sh.sh_offset,
+                sh_size: sh.sh_size,
+                sh_addralign: sh.sh_addralign,
+            })
+            .collect())
+    }
+
+    pub(crate) fn symbols(
+        data: &[u8],
+        header: &Header,
+        offset: usize,
+        count: usize,
+    ) -> Result<Vec<Sym>, Error> {
+        let syms = elf::Symtab::parse(data, offset, count, ctx(header))?;
+        Ok(syms
+            .iter()
+            .map(|s| Sym {
+                st_name: s.st_name,
+                st_value: s.st_value,
+                st_size: s.st_size,
+            })
+            .collect())
+    }
+}
+
+#[cfg(not(feature = "goblin"))]
+mod backend {
+    use super::*;
+
+    pub(crate) fn parse_header(data: &[u8]) -> Result<Header, Error> {
+        let class = ElfClass::from_ident(data)?;
+        let little_endian = ElfClass::little_endian(data)?;
+        let mut header = Header {
+            class,
+            little_endian,
+            e_machine: 0,
+            e_phoff: 0,
+            e_phnum: 0,
+            e_phentsize: 0,
+            e_shoff: 0,
+            e_shnum: 0,
+            e_shentsize: 0,
+            e_shstrndx: 0,
+        };
+        let r = Reader::new(data, &header);
+        // Offsets past e_entry, which is a word
+        let (phoff, rest) = match class {
+            ElfClass::Elf32 => (28, 40),
+            ElfClass::Elf64 => (32, 52),
+        };
+        header.e_machine = r.u16(18)?;
+        header.e_phoff = r.word(phoff)? as usize;
+        header.e_shoff = r.word(phoff + r.word_size())? as usize;
+        header.e_phentsize = r.u16(rest + 2)? as usize;
+        header.e_phnum = r.u16(rest + 4)? as usize;
+        header.e_shentsize = r.u16(rest + 6)? as usize;
+        header.e_shnum = r.u16(rest + 8)? as usize;
+        header.e_shstrndx = r.u16(rest + 10)? as usize;
+        Ok(header)
+    }
+
+    pub(crate) fn program_headers(
+        data: &[u8],
+        header: &Header,
+    ) -> Result<Vec<ProgramHeader>, Error> {
+        let r = Reader::new(data, header);
+        let mut ret = vec![];
+        for i in 0..header.e_phnum {
+            let at = header.e_phoff + i * header.e_phentsize;
+            // ELF64 moves p_flags after p_type, to keep the words aligned
+            let ph = match header.class {
+                ElfClass::Elf32 => ProgramHeader {
+                    p_type: r.u32(at)?,
+                    p_offset: r.word(at + 4)?,
+                    p_vaddr: r.word(at + 8)?,
+                    p_filesz: r.word(at + 16)?,
+                },
+                ElfClass::Elf64 => ProgramHeader {
+                    p_type: r.u32(at)?,
+                    p_offset: r.word(at + 8)?,
+                    p_vaddr: r.word(at + 16)?,
+                    p_filesz: r.word(at + 32)?,
+                },
+            };
+            ret.push(ph);
+        }
+        Ok(ret)
+    }
+
+    pub(crate) fn section_headers(
+        data: &[u8],
+        header: &Header,
+    ) -> Result<Vec<SectionHeader>, Error> {
+        let r = Reader::new(data, header);
+        let w = r.word_size();
+        let mut ret = vec![];
+        for i in 0..header.e_shnum {
+            let at = header.e_shoff + i * header.e_shentsize;
+            // sh_name, sh_type, then sh_flags, sh_addr, sh_offset, sh_size as words,
+            // sh_link, sh_info and finally sh_addralign
+            ret.push(SectionHeader {
+                sh_name: r.u32(at)? as usize,
+                sh_type: r.u32(at + 4)?,
+                sh_offset: r.word(at + 8 + 2 * w)?,
+                sh_size: r.word(at + 8 + 3 * w)?,
+                sh_addralign: r.word(at + 16 + 4 * w)?,
+            });
+        }
+        Ok(ret)
+    }
+
+    pub(crate) fn symbols(
+        data: &[u8],
+        header: &Header,
+        offset: usize,
+        count: usize,
+    ) -> Result<Vec<Sym>, Error> {
+        let r = Reader::new(data, header);
+        let mut ret = vec![];
+        for i in 0..count {
+            let sym = match header.class {
+                ElfClass::Elf32 => {
+                    let at = offset + i * 16;
+                    Sym {
+                        st_name: r.u32(at)? as usize,
+                        st_value: r.word(at + 4)?,
+                        st_size: r.word(at + 8)?,
+                    }
+                }
+                ElfClass::Elf64 => {
+                    let at = offset + i * 24;
+                    Sym {
+                        st_name: r.u32(at)? as usize,
+                        st_value: r.word(at + 8)?,
+                        st_size: r.word(at + 16)?,
+                    }
+                }
+            };
+            ret.push(sym);
+        }
+        Ok(ret)
+    }
+}
+
+pub(crate) use backend::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_parse_header() {
+        let data = fs::read("src/test_files/test_vdso_elf_1").expect("Unable to read test file");
+        let header = parse_header(&data).unwrap();
+        assert_eq!(header.class, ElfClass::Elf64);
+        assert!(header.little_endian);
+        let phdrs = program_headers(&data, &header).unwrap();
+        assert_eq!(phdrs.len(), header.e_phnum);
+        assert!(phdrs.iter().any(|ph| ph.p_type == PT_LOAD));
+        let shdrs = section_headers(&data, &header).unwrap();
+        assert_eq!(shdrs.len(), header.e_shnum);
+        assert!(parse_header(&data[..8]).is_err());
+    }
+
+    #[test]
+    fn test_str_at() {
+        let table = b"\0.text\0.data\0";
+        assert_eq!(str_at(table, 1), Some(".text"));
+        assert_eq!(str_at(table, 7), Some(".data"));
+        assert_eq!(str_at(table, 0), Some(""));
+        assert_eq!(str_at(table, 13), None);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::Kind;
+use std::fmt;
+
+/// Errors returned while inspecting or patching the vDSO.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The symbol is already overwritten by a previous, not yet restored, patch.
+    AlreadyPatched(String),
+    /// The callback does not match the function being replaced.
+    WrongKind { expected: Kind, found: Kind },
+    /// The running kernel's vDSO does not provide this function.
+    NotFound(Kind),
+    /// The symbol has no room for the jump to the trampoline.
+    SymbolTooSmall {
+        name: String,
+        size: usize,
+        needed: usize,
+    },
+    /// The vDSO is not an ELF image we can parse.
+    MalformedVdso(String),
+    /// The vDSO is not built for the running process' class, endianness or architecture.
+    WrongArch(String),
+    /// The vDSO's program headers describe more data than is mapped.
+    InconsistentSize { computed: usize, mapped: usize },
+    /// The vDSO is emulated or absent on this system (gVisor, UML, qemu-user); why.
+    UnsupportedPlatform(String),
+    /// The vDSO was built with `vDSO::from_bytes`, so it is not mapped in this process.
+    Offline,
+    /// A configuration value (such as a `TPOM_*` environment variable) can't be used; why.
+    InvalidConfig(String),
+    /// The vDSO exports no symbol of this name.
+    UnknownSymbol(String),
+    /// The code a patch record replaces, or restores, is not at its symbol; the symbol.
+    PatchMismatch(String),
+    /// A system call failed with the given errno.
+    Os(&'static str, i32),
+}
+
+impl Error {
+    pub(crate) fn last_os_error(call: &'static str) -> Error {
+        Error::Os(
+            call,
+            std::io::Error::last_os_error().raw_os_error().unwrap_or(0),
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::AlreadyPatched(name) => write!(f, "symbol {} is already patched", name),
+            Error::WrongKind { expected, found } => {
+                write!(f, "expected a {:?} callback, got {:?}", expected, found)
+            }
+            Error::NotFound(kind) => write!(f, "no vDSO symbol for {:?}", kind),
+            Error::SymbolTooSmall { name, size, needed } => write!(
+                f,
+                "symbol {} is {} bytes, {} are needed to patch it",
+                name, size, needed
+            ),
+            Error::MalformedVdso(e) => write!(f, "malformed vDSO: {}", e),
+            Error::WrongArch(e) => write!(f, "vDSO is for the wrong architecture: {}", e),
+            Error::InconsistentSize { computed, mapped } => write!(
+                f,
+                "vDSO segments span {:#x} bytes but only {:#x} are mapped",
+                computed, mapped
+            ),
+            Error::UnsupportedPlatform(e) => write!(f, "unsupported platform: {}", e),
+            Error::Offline => write!(f, "vDSO is not the running process' one"),
+            Error::InvalidConfig(e) => write!(f, "invalid configuration: {}", e),
+            Error::UnknownSymbol(name) => write!(f, "the vDSO has no symbol {}", name),
+            Error::PatchMismatch(name) => {
+                write!(f, "symbol {} doesn't hold the code the patch expects", name)
+            }
+            Error::Os(call, errno) => write!(
+                f,
+                "{} failed: {}",
+                call,
+                std::io::Error::from_raw_os_error(*errno)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,160 @@
+//! Runs a command with every process it starts mocked, such as a service and its workers.
+//!
+//! The whole tree is traced, and each new program gets its vDSO patched through a
+//! [`Remote`] as soon as it is loaded, before the dynamic loader gets past its first
+//! instruction. Forked processes inherit the patches, and are only followed, for the
+//! programs they run in turn.
+//!
+//! ```no_run
+//! use std::process::Command;
+//! use tpom::stubs::Stub;
+//! use tpom::{follow, Kind, TimeSpec};
+//!
+//! let status = follow::run(Command::new("make").arg("test"), |target| {
+//!     let frozen = TimeSpec {
+//!         seconds: 0,
+//!         nanos: 0,
+//!     };
+//!     target.install(Kind::GetTime, &Stub::Constant(frozen))
+//! })
+//! .unwrap();
+//! ```
+use crate::error::Error;
+use crate::remote::{self, ptrace, Remote};
+use std::collections::HashMap;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, ExitStatus};
+
+/// Runs `command`, calling `setup` to patch each program run in its process tree, and
+/// returns its exit status once every process in the tree has exited.
+///
+/// The tracees are waited for among the children of the calling thread only, but any other
+/// child of it is reaped as well: call it from a thread that has none.
+///
+/// If `setup` fails, the tree is killed and its error returned.
+pub fn run(
+    command: &mut Command,
+    mut setup: impl FnMut(&mut Remote) -> Result<(), Error>,
+) -> Result<ExitStatus, Error> {
+    unsafe {
+        command.pre_exec(|| {
+            // Stops on exec, before the program runs
+            if libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    };
+    let child = command
+        .spawn()
+        .map_err(|e| Error::Os("spawn", e.raw_os_error().unwrap_or(0)))?;
+    let root = child.id() as libc::pid_t;
+    let mut status = 0;
+    if unsafe { libc::waitpid(root, &mut status, libc::__WALL) } < 0 {
+        return Err(Error::last_os_error("waitpid"));
+    }
+    if !libc::WIFSTOPPED(status) {
+        return Ok(ExitStatus::from_raw(status));
+    }
+    let options = libc::PTRACE_O_TRACEFORK
+        | libc::PTRACE_O_TRACEVFORK
+        | libc::PTRACE_O_TRACECLONE
+        | libc::PTRACE_O_TRACEEXEC
+        | libc::PTRACE_O_EXITKILL;
+    if ptrace(libc::PTRACE_SETOPTIONS, root, 0, options as usize) != 0 {
+        let err = Error::last_os_error("ptrace");
+        unsafe { libc::kill(root, libc::SIGKILL) };
+        return Err(err);
+    }
+
+    // Whether each tracee was seen stopping for the first time, as new ones start stopped
+    let mut tracees = HashMap::from([(root, true)]);
+    let mut root_status = None;
+    // A program just loaded, and whether it is still in execve
+    let mut stop = Some((root, false));
+    loop {
+        if let Some((pid, in_exec)) = stop {
+            let res = if in_exec { step(pid) } else { Ok(()) };
+            if let Err(e) = res.and_then(|_| apply(pid, &mut setup)) {
+                for pid in tracees.keys() {
+                    unsafe { libc::kill(*pid, libc::SIGKILL) };
+                }
+                while unsafe { libc::waitpid(-1, &mut status, libc::__WALL | libc::__WNOTHREAD) }
+                    > 0
+                {}
+                return Err(e);
+            }
+            ptrace(libc::PTRACE_CONT, pid, 0, 0);
+        }
+        stop = None;
+
+        let pid = unsafe { libc::waitpid(-1, &mut status, libc::__WALL | libc::__WNOTHREAD) };
+        if pid < 0 {
+            if std::io::Error::last_os_error().raw_os_error() == Some(libc::ECHILD) {
+                break;
+            }
+            return Err(Error::last_os_error("waitpid"));
+        }
+        if !libc::WIFSTOPPED(status) {
+            tracees.remove(&pid);
+            if pid == root {
+                root_status = Some(status);
+            }
+            continue;
+        }
+        let signal = libc::WSTOPSIG(status);
+        let started = tracees.insert(pid, true).unwrap_or(false);
+        let deliver = match status >> 16 {
+            // A new process or thread, stopped before running
+            _ if !started && signal == libc::SIGSTOP => 0,
+            libc::PTRACE_EVENT_EXEC => {
+                stop = Some((pid, true));
+                continue;
+            }
+            libc::PTRACE_EVENT_FORK | libc::PTRACE_EVENT_VFORK | libc::PTRACE_EVENT_CLONE => {
+                let mut new: libc::c_ulong = 0;
+                let new_ptr = &mut new as *mut libc::c_ulong as usize;
+                if ptrace(libc::PTRACE_GETEVENTMSG, pid, 0, new_ptr) == 0 {
+                    tracees.entry(new as libc::pid_t).or_insert(false);
+                }
+                0
+            }
+            0 if is_signal(pid) => signal,
+            // A group stop, or another event
+            _ => 0,
+        };
+        ptrace(libc::PTRACE_CONT, pid, 0, deliver as usize);
+    }
+    Ok(ExitStatus::from_raw(root_status.unwrap_or(0)))
+}
+
+/// Patches the program `pid` just loaded, keeping the patches for its whole life.
+fn apply(
+    pid: libc::pid_t,
+    setup: &mut impl FnMut(&mut Remote) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut target = remote::attach_traced(pid)?;
+    setup(&mut target)?;
+    target.leak();
+    Ok(())
+}
+
+/// Runs the first instruction of the program stopped `pid` just loaded: execve sets rax on
+/// its way out, so no syscall can be run for it before that.
+fn step(pid: libc::pid_t) -> Result<(), Error> {
+    if ptrace(libc::PTRACE_SINGLESTEP, pid, 0, 0) != 0 {
+        return Err(Error::last_os_error("ptrace"));
+    }
+    let mut status = 0;
+    if unsafe { libc::waitpid(pid, &mut status, libc::__WALL) } < 0 {
+        return Err(Error::last_os_error("waitpid"));
+    }
+    Ok(())
+}
+
+/// Whether stopped `pid` is about to get a signal, as opposed to stopping with its group.
+fn is_signal(pid: libc::pid_t) -> bool {
+    let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+    let info_ptr = &mut info as *mut libc::siginfo_t as usize;
+    ptrace(libc::PTRACE_GETSIGINFO, pid, 0, info_ptr) == 0
+}
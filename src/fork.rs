@@ -0,0 +1,104 @@
+//! Keeps a forked child's copy of the mocking state usable.
+//!
+//! The child of `fork(2)` inherits the patched vDSO, so its clocks keep calling the
+//! trampolines, but it also inherits every lock in the state they read, as some other
+//! thread held it at the time of the fork: one that was taken is never released in the
+//! child, which then deadlocks on its first clock read. The handlers set by
+//! [`handle_fork`] take each lock around the fork instead, and let go of them on both sides.
+use crate::chrome_trace;
+use crate::journal;
+use crate::observers;
+use crate::recent;
+use crate::registry;
+use crate::sampling;
+use crate::trampolines::*;
+use crate::vdso::VDSO_MUTEX;
+use crate::{ClockGetResCb, ClockGetTimeCb, ClockGetTimeOfDayCb, TimeCb};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{MutexGuard, Once, RwLock, RwLockWriteGuard};
+
+static HANDLERS: Once = Once::new();
+static RESTORE: AtomicBool = AtomicBool::new(false);
+
+/// What a forked child does with the patches it inherits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AfterFork {
+    /// They stay, answered by the same callbacks as in the parent.
+    Keep,
+    /// Every patch is restored, leaked ones included, so that the child sees the real time.
+    /// The parent keeps its own.
+    Restore,
+}
+
+/// The locks taken by the forking thread, held across the fork.
+struct Held {
+    _registry: MutexGuard<'static, Vec<registry::Patched>>,
+    _vdso: MutexGuard<'static, i32>,
+    _journal: MutexGuard<'static, journal::Journal>,
+    _sampling: MutexGuard<'static, Option<sampling::Sites>>,
+    _trace: MutexGuard<'static, chrome_trace::Trace>,
+    _gtod: RwLockWriteGuard<'static, Option<ClockGetTimeOfDayCb>>,
+    _gt: RwLockWriteGuard<'static, Option<ClockGetTimeCb>>,
+    _res: RwLockWriteGuard<'static, Option<ClockGetResCb>>,
+    _time: RwLockWriteGuard<'static, Option<TimeCb>>,
+    _cpu: RwLockWriteGuard<'static, Option<ClockGetTimeCb>>,
+    _observers: RwLockWriteGuard<'static, observers::Slots>,
+}
+
+thread_local! {
+    static HELD: RefCell<Option<Held>> = const { RefCell::new(None) };
+}
+
+/// Registers `pthread_atfork(3)` handlers for the mocking state, and chooses what children
+/// forked from now on do with the patches. The handlers are registered once; later calls
+/// only change `child`.
+///
+/// Children made with `vfork(2)`, or `posix_spawn(3)` as [`std::process::Command`] does
+/// when it can, don't run the handlers: they run nothing but `exec`, which maps a fresh
+/// vDSO anyway.
+pub fn handle_fork(child: AfterFork) {
+    RESTORE.store(child == AfterFork::Restore, Ordering::Relaxed);
+    HANDLERS.call_once(|| {
+        let ret = unsafe { libc::pthread_atfork(Some(prepare), Some(parent), Some(in_child)) };
+        if ret != 0 {
+            log::error!("Could not register the fork handlers: error {}", ret);
+        }
+    });
+}
+
+fn write<T>(lock: &'static RwLock<T>) -> RwLockWriteGuard<'static, T> {
+    lock.write().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Waits for every other thread to be done with the state, and keeps it from them.
+extern "C" fn prepare() {
+    // The registry is held while writing to the vDSO; no other lock is taken with another
+    let held = Held {
+        _gtod: write(&CLOCK_GTOD_CB),
+        _gt: write(&CLOCK_GT_CB),
+        _res: write(&CLOCK_RES_CB),
+        _time: write(&TIME_CB),
+        _cpu: write(&CPU_CLOCK_CB),
+        _observers: write(&observers::OBSERVERS),
+        _registry: registry::lock(),
+        _vdso: VDSO_MUTEX.lock().unwrap_or_else(|e| e.into_inner()),
+        _journal: journal::lock(),
+        _sampling: sampling::lock(),
+        _trace: chrome_trace::lock(),
+    };
+    HELD.with(|h| *h.borrow_mut() = Some(held));
+}
+
+extern "C" fn parent() {
+    HELD.with(|h| h.borrow_mut().take());
+}
+
+/// Releases the locks, which the child got held by its only thread, the one that forked.
+extern "C" fn in_child() {
+    HELD.with(|h| h.borrow_mut().take());
+    recent::forked();
+    if RESTORE.load(Ordering::Relaxed) {
+        registry::restore_pristine();
+    }
+}
@@ -0,0 +1,238 @@
+//! Redirects the GOT entries of the loaded objects for the time functions, as an
+//! alternative to patching the vDSO, for programs whose libc doesn't call through the vDSO
+//! symbols.
+//!
+//! Only calls made through a relocation are redirected: calls within libc itself, and
+//! objects loaded after patching, keep the original function.
+use crate::error::Error;
+use crate::Kind;
+use std::ffi::CStr;
+use std::fs;
+use std::sync::Mutex;
+
+const DT_NULL: u64 = 0;
+const DT_PLTRELSZ: u64 = 2;
+const DT_STRTAB: u64 = 5;
+const DT_SYMTAB: u64 = 6;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_JMPREL: u64 = 23;
+
+#[cfg(target_arch = "x86_64")]
+const RELOCS: [u32; 2] = [6, 7]; // R_X86_64_GLOB_DAT, R_X86_64_JUMP_SLOT
+#[cfg(target_arch = "aarch64")]
+const RELOCS: [u32; 2] = [1025, 1026]; // R_AARCH64_GLOB_DAT, R_AARCH64_JUMP_SLOT
+#[cfg(target_arch = "riscv64")]
+const RELOCS: [u32; 2] = [2, 5]; // R_RISCV_64, R_RISCV_JUMP_SLOT
+
+const SIZEOF_RELA: usize = 24;
+const SIZEOF_SYM: usize = 24;
+
+/// Names of the functions currently redirected, which can't be redirected again.
+static PATCHED: Mutex<Vec<&'static str>> = Mutex::new(vec![]);
+
+/// The libc function a kind of callback replaces.
+pub(crate) fn symbol_name(kind: Kind) -> &'static str {
+    match kind {
+        Kind::GetTime => "clock_gettime",
+        Kind::Time => "time",
+        Kind::ClockGetRes => "clock_getres",
+        Kind::GetTimeOfDay => "gettimeofday",
+    }
+}
+
+/// Whether the libc function of `kind` is currently redirected.
+pub(crate) fn is_redirected(kind: Kind) -> bool {
+    let name = symbol_name(kind);
+    PATCHED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .contains(&name)
+}
+
+/// The rewritten GOT entries for one function, with their original values.
+pub(crate) struct GotPatch {
+    pub(crate) name: &'static str,
+    slots: Vec<(usize, usize)>,
+}
+
+impl GotPatch {
+    pub(crate) fn restore(&self) -> Result<(), Error> {
+        let mut res = Ok(());
+        for (slot, original) in &self.slots {
+            res = res.and(write_slot(*slot, *original));
+        }
+        PATCHED.lock().unwrap().retain(|n| *n != self.name);
+        res
+    }
+}
+
+/// Points every GOT entry for the function of `kind` at `target`.
+pub(crate) fn redirect(kind: Kind, target: usize) -> Result<GotPatch, Error> {
+    let name = symbol_name(kind);
+    let mut patched = PATCHED.lock().unwrap();
+    if patched.contains(&name) {
+        return Err(Error::AlreadyPatched(name.to_string()));
+    }
+    let slots = find_slots(name);
+    if slots.is_empty() {
+        return Err(Error::NotFound(kind));
+    }
+    let mut patch = GotPatch {
+        name,
+        slots: vec![],
+    };
+    for slot in slots {
+        let original = unsafe { std::ptr::read_volatile(slot as *const usize) };
+        if let Err(e) = write_slot(slot, target) {
+            let _ = patch.restore();
+            return Err(e);
+        }
+        patch.slots.push((slot, original));
+    }
+    patched.push(name);
+    Ok(patch)
+}
+
+struct Search {
+    name: &'static str,
+    slots: Vec<usize>,
+}
+
+/// The GOT entries referencing `name` in every loaded object but the vDSO.
+pub(crate) fn find_slots(name: &'static str) -> Vec<usize> {
+    let mut search = Search {
+        name,
+        slots: vec![],
+    };
+    unsafe {
+        libc::dl_iterate_phdr(
+            Some(visit_object),
+            &mut search as *mut Search as *mut libc::c_void,
+        )
+    };
+    search.slots
+}
+
+unsafe extern "C" fn visit_object(
+    info: *mut libc::dl_phdr_info,
+    _size: libc::size_t,
+    data: *mut libc::c_void,
+) -> libc::c_int {
+    let info = &*info;
+    let search = &mut *(data as *mut Search);
+    if !info.dlpi_name.is_null()
+        && CStr::from_ptr(info.dlpi_name)
+            .to_bytes()
+            .starts_with(b"linux-vdso")
+    {
+        return 0;
+    }
+    let base = info.dlpi_addr as usize;
+    let phdrs = std::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize);
+    if let Some(dynamic) = phdrs.iter().find(|ph| ph.p_type == libc::PT_DYNAMIC) {
+        slots_in(base, base + dynamic.p_vaddr as usize, search);
+    }
+    0
+}
+
+/// Walks the dynamic section at `dynamic` of the object loaded at `base`.
+unsafe fn slots_in(base: usize, dynamic: usize, search: &mut Search) {
+    // The loader usually relocates the pointers in the dynamic section in place
+    let adjust = |ptr: u64| {
+        let ptr = ptr as usize;
+        if ptr < base {
+            ptr + base
+        } else {
+            ptr
+        }
+    };
+    let (mut symtab, mut strtab) = (0, 0);
+    let mut tables = [(0, 0), (0, 0)];
+    let mut entry = dynamic as *const u64;
+    loop {
+        let (tag, val) = (*entry, *entry.add(1));
+        match tag {
+            DT_NULL => break,
+            DT_SYMTAB => symtab = adjust(val),
+            DT_STRTAB => strtab = adjust(val),
+            DT_JMPREL => tables[0].0 = adjust(val),
+            DT_PLTRELSZ => tables[0].1 = val as usize,
+            DT_RELA => tables[1].0 = adjust(val),
+            DT_RELASZ => tables[1].1 = val as usize,
+            _ => {}
+        }
+        entry = entry.add(2);
+    }
+    if symtab == 0 || strtab == 0 {
+        return;
+    }
+    for (table, size) in tables {
+        if table == 0 {
+            continue;
+        }
+        for i in 0..size / SIZEOF_RELA {
+            let rela = (table + i * SIZEOF_RELA) as *const u64;
+            let (offset, info) = (*rela, *rela.add(1));
+            let (sym, kind) = ((info >> 32) as usize, info as u32);
+            if sym == 0 || !RELOCS.contains(&kind) {
+                continue;
+            }
+            let st_name = *((symtab + sym * SIZEOF_SYM) as *const u32) as usize;
+            let name = CStr::from_ptr((strtab + st_name) as *const libc::c_char);
+            if name.to_bytes() == search.name.as_bytes() {
+                search.slots.push(base + offset as usize);
+            }
+        }
+    }
+}
+
+/// The protection of the mapping containing `addr`, per /proc/self/maps.
+fn protection(addr: usize) -> Option<libc::c_int> {
+    let maps = fs::read_to_string("/proc/self/maps").ok()?;
+    maps.lines().find_map(|line| {
+        let mut fields = line.split(' ');
+        let (start, end) = fields.next()?.split_once('-')?;
+        let start = usize::from_str_radix(start, 16).ok()?;
+        let end = usize::from_str_radix(end, 16).ok()?;
+        if !(start..end).contains(&addr) {
+            return None;
+        }
+        let perms = fields.next()?.as_bytes();
+        let mut prot = libc::PROT_NONE;
+        for (flag, c) in [
+            (libc::PROT_READ, b'r'),
+            (libc::PROT_WRITE, b'w'),
+            (libc::PROT_EXEC, b'x'),
+        ] {
+            if perms.contains(&c) {
+                prot |= flag;
+            }
+        }
+        Some(prot)
+    })
+}
+
+/// Writes a GOT entry, which is read-only after relocation with RELRO.
+fn write_slot(slot: usize, val: usize) -> Result<(), Error> {
+    let prot = protection(slot).unwrap_or(libc::PROT_READ);
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let page = slot & !(page_size - 1);
+    let writable = prot & libc::PROT_WRITE != 0;
+    if !writable
+        && unsafe {
+            libc::mprotect(
+                page as *mut libc::c_void,
+                page_size,
+                prot | libc::PROT_WRITE,
+            )
+        } != 0
+    {
+        return Err(Error::last_os_error("mprotect"));
+    }
+    unsafe { std::ptr::write_volatile(slot as *mut usize, val) };
+    if !writable && unsafe { libc::mprotect(page as *mut libc::c_void, page_size, prot) } != 0 {
+        return Err(Error::last_os_error("mprotect"));
+    }
+    Ok(())
+}
@@ -0,0 +1,189 @@
+//! Steers the [`crate::live`] clock over HTTP, for tests orchestrated from languages that
+//! speak it more easily than [`crate::socket`]'s protocol; the same [`Command`]s are
+//! accepted, and no framework is needed.
+//!
+//! | Request | Effect |
+//! |---------|--------|
+//! |`GET /time`|None|
+//! |`POST /freeze`, `/set`, `/advance`|As the command, with its seconds as the body|
+//! |`POST /scale`|As the command, with its factor as the body|
+//! |`POST /restore`|The time is the real time again|
+//!
+//! Every answer is a JSON object: the clock's state afterwards, such as
+//! `{"time": 1000.500000000, "nanos": 1000500000000, "frozen": true, "speed": 1}`, or an `error`.
+//!
+//! ```sh
+//! $ curl -d 3600 http://127.0.0.1:8080/advance
+//! ```
+use crate::control::State;
+use crate::error::Error;
+use crate::live::{self, format_seconds, Command};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+
+/// The largest request body read, as only a number is expected.
+const MAX_BODY: usize = 1024;
+
+/// Listens on `addr` for the rest of the process' life, from a thread of its own, and
+/// returns the address bound: with port 0, the one picked by the kernel. Requests are not
+/// authenticated, so bind to a loopback address unless the network is trusted.
+pub fn listen(addr: impl ToSocketAddrs) -> Result<SocketAddr, Error> {
+    let os_error = |call, e: std::io::Error| Error::Os(call, e.raw_os_error().unwrap_or(0));
+    let listener = TcpListener::bind(addr).map_err(|e| os_error("bind", e))?;
+    let local = listener
+        .local_addr()
+        .map_err(|e| os_error("getsockname", e))?;
+    thread::Builder::new()
+        .name("tpom-http".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        thread::spawn(move || serve(stream));
+                    }
+                    Err(e) => log::warn!("Could not accept a connection: {}", e),
+                }
+            }
+        })
+        .map_err(|e| os_error("spawn", e))?;
+    Ok(local)
+}
+
+/// Answers one request, closing the connection afterwards.
+fn serve(stream: TcpStream) {
+    let Ok(mut out) = stream.try_clone() else {
+        return;
+    };
+    let (status, body) = match read_request(&mut BufReader::new(stream)) {
+        Ok((method, path, body)) => answer(&method, &path, &body),
+        Err(e) => (400, error_json(&e)),
+    };
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        _ => "Method Not Allowed",
+    };
+    let _ = write!(
+        out,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+}
+
+/// The method, path and body of the request.
+fn read_request(reader: &mut impl BufRead) -> Result<(String, String, String), String> {
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|e| e.to_string())?;
+    let mut words = line.split_whitespace();
+    let (Some(method), Some(path)) = (words.next(), words.next()) else {
+        return Err("malformed request line".to_string());
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut length = 0;
+    loop {
+        line.clear();
+        reader.read_line(&mut line).map_err(|e| e.to_string())?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("bad Content-Length {:?}", value.trim()))?;
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return Err(format!("body over {} bytes", MAX_BODY));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+    let body = String::from_utf8(body).map_err(|_| "body is not UTF-8".to_string())?;
+    Ok((method, path, body))
+}
+
+/// The status and body answering `method` on `path`.
+fn answer(method: &str, path: &str, body: &str) -> (u16, String) {
+    let expected = match path {
+        "/time" => "GET",
+        "/freeze" | "/set" | "/advance" | "/scale" | "/restore" => "POST",
+        _ => return (404, error_json(&format!("no such path {}", path))),
+    };
+    if method != expected {
+        return (405, error_json(&format!("{} needs {}", path, expected)));
+    }
+    let command = match path {
+        "/time" => Ok(Command::Status),
+        _ => format!("{} {}", &path[1..], body).parse::<Command>(),
+    };
+    match command.and_then(live::execute) {
+        Ok(state) => (200, state_json(&state)),
+        Err(e) => (400, error_json(&e.to_string())),
+    }
+}
+
+fn state_json(state: &State) -> String {
+    format!(
+        "{{\"time\": {}, \"nanos\": {}, \"frozen\": {}, \"speed\": {}}}",
+        format_seconds(state.now as i128),
+        state.now,
+        state.frozen,
+        state.speed
+    )
+}
+
+fn error_json(message: &str) -> String {
+    let mut escaped = String::new();
+    for c in message.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    format!("{{\"error\": \"{}\"}}", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_request() {
+        let request = "POST /advance HTTP/1.1\r\nHost: x\r\ncontent-length: 2\r\n\r\n60";
+        assert_eq!(
+            read_request(&mut request.as_bytes()),
+            Ok(("POST".to_string(), "/advance".to_string(), "60".to_string()))
+        );
+        assert!(read_request(&mut "\r\n".as_bytes()).is_err());
+        let huge = "POST /set HTTP/1.1\r\nContent-Length: 99999\r\n\r\n";
+        assert!(read_request(&mut huge.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_answer() {
+        assert_eq!(answer("GET", "/nope", "").0, 404);
+        assert_eq!(answer("GET", "/advance", "1").0, 405);
+        assert_eq!(answer("POST", "/advance", "soon").0, 400);
+        assert_eq!(error_json("a \"b\"\n"), r#"{"error": "a \"b\"\u000a"}"#);
+        let state = State {
+            now: -1,
+            frozen: false,
+            speed: 0.5,
+        };
+        assert_eq!(
+            state_json(&state),
+            r#"{"time": -0.000000001, "nanos": -1, "frozen": false, "speed": 0.5}"#
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! A record of every write tpom makes to the vDSO, for finding out after the fact which
+//! code left the clock mocked in a process shared by many tests.
+//!
+//! Nothing is recorded until [`keep_in_memory`] or [`append_to`] is called:
+//!
+//! ```no_run
+//! tpom::journal::append_to("/tmp/tpom.journal").unwrap();
+//! // ... tests ...
+//! for entry in tpom::journal::entries() {
+//!     println!("{}", entry);
+//! }
+//! ```
+//!
+//! Each line of the file is an entry, as it is displayed:
+//!
+//! ```text
+//! 1700000000.123456789 patch __vdso_clock_gettime 9a0b3c64e2f1d7a8 tests/pub.rs:42:10 main
+//! ```
+//!
+//! The timestamp is the real time, whatever the mock, and the caller the line that called
+//! tpom's API: for patches restored by [`crate::restore_on_panic`], a fork or
+//! [`crate::criu`], the place in tpom that restored them.
+use crate::error::Error;
+use crate::live::{self, format_seconds};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::panic::Location;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+/// A write to the vDSO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// A function was replaced.
+    Patch,
+    /// Its original code was written back.
+    Restore,
+}
+
+/// One recorded write.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// Nanoseconds since the epoch, in the real time.
+    pub at: i64,
+    pub operation: Operation,
+    pub symbol: String,
+    /// The FNV-1a hash of the bytes written.
+    pub hash: u64,
+    /// The file, line and column of the call made to tpom.
+    pub caller: String,
+    /// The name of the calling thread, if it has one.
+    pub thread: Option<String>,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operation = match self.operation {
+            Operation::Patch => "patch",
+            Operation::Restore => "restore",
+        };
+        write!(
+            f,
+            "{} {} {} {:016x} {} {}",
+            format_seconds(self.at as i128),
+            operation,
+            self.symbol,
+            self.hash,
+            self.caller,
+            self.thread.as_deref().unwrap_or("-")
+        )
+    }
+}
+
+/// Where entries go.
+pub(crate) struct Journal {
+    memory: Option<Vec<Entry>>,
+    file: Option<File>,
+}
+
+static JOURNAL: Mutex<Journal> = Mutex::new(Journal {
+    memory: None,
+    file: None,
+});
+
+/// Holds the journal, keeping it unchanged; see [`crate::fork`].
+pub(crate) fn lock() -> MutexGuard<'static, Journal> {
+    JOURNAL.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Keeps every entry from now on, for [`entries`].
+pub fn keep_in_memory() {
+    lock().memory.get_or_insert_with(Vec::new);
+}
+
+/// Appends every entry from now on to the file at `path`, creating it if needed; replaces
+/// the file set before, if any. Entries are also kept in memory if [`keep_in_memory`] was
+/// called.
+pub fn append_to(path: impl AsRef<Path>) -> Result<(), Error> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| Error::Os("open", e.raw_os_error().unwrap_or(0)))?;
+    lock().file = Some(file);
+    Ok(())
+}
+
+/// Stops recording, and forgets the entries kept in memory.
+pub fn stop() {
+    let mut journal = lock();
+    journal.memory = None;
+    journal.file = None;
+}
+
+/// The entries kept in memory, oldest first.
+pub fn entries() -> Vec<Entry> {
+    lock().memory.clone().unwrap_or_default()
+}
+
+/// Records that `bytes` were written over `symbol`, if the journal is on.
+pub(crate) fn note(operation: Operation, symbol: &str, bytes: &[u8], caller: &Location) {
+    let mut journal = lock();
+    if journal.memory.is_none() && journal.file.is_none() {
+        return;
+    }
+    let entry = Entry {
+        at: live::real(libc::CLOCK_REALTIME),
+        operation,
+        symbol: symbol.to_string(),
+        hash: fnv1a(bytes),
+        caller: caller.to_string(),
+        thread: std::thread::current().name().map(str::to_string),
+    };
+    if let Some(file) = &mut journal.file {
+        if let Err(e) = writeln!(file, "{}", entry) {
+            log::warn!("Could not write to the journal: {}", e);
+        }
+    }
+    if let Some(memory) = &mut journal.memory {
+        memory.push(entry);
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        let entry = Entry {
+            at: 1_700_000_000_123_456_789,
+            operation: Operation::Restore,
+            symbol: "__vdso_time".to_string(),
+            hash: 0xab,
+            caller: "tests/pub.rs:42:10".to_string(),
+            thread: None,
+        };
+        assert_eq!(
+            entry.to_string(),
+            "1700000000.123456789 restore __vdso_time 00000000000000ab tests/pub.rs:42:10 -"
+        );
+    }
+}
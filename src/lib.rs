@@ -26,7 +26,7 @@
 //!
 //! let v = vdso::vDSO::read().unwrap();
 //! let og = v.entry(Kind::GetTime).ok_or("Could not find clock").unwrap();
-//! let backup = og.overwrite(myclock);
+//! let backup = og.overwrite(myclock).unwrap();
 //!
 //! // Clock is frozen; all calls to time return the same values
 //! let time_a = SystemTime::now();
@@ -34,24 +34,93 @@
 //! assert_eq!(time_a, time_b);
 //!
 //! // Restore clock; all calls to time return unique values
-//! backup.restore();
+//! backup.restore().unwrap();
 //! let time_c = SystemTime::now();
 //! let time_d = SystemTime::now();
 //! assert_ne!(time_c, time_d);
 //! ```
 
+pub mod analysis;
 pub mod auxv;
+pub mod chrome_trace;
+pub mod clocksource;
+pub mod config;
+pub mod control;
+pub mod criu;
+mod current;
+#[cfg(feature = "disassembler")]
+pub mod disassembly;
+mod doctor;
+mod elf;
+mod error;
+pub mod follow;
+mod fork;
+mod got;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod journal;
+pub mod live;
+pub mod observers;
 mod opcodes;
+#[cfg(feature = "otel")]
+mod otel;
+mod panic;
+pub mod patchfile;
+pub mod platform;
+#[cfg(feature = "preload")]
+pub mod preload;
+pub mod process;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+mod recent;
+mod registry;
+pub mod remote;
+pub mod sampling;
+pub mod schedule;
+#[cfg(feature = "seccomp")]
+pub mod seccomp;
+mod session;
+pub mod signals;
+#[cfg(feature = "socket")]
+pub mod socket;
+mod stats;
+pub mod strict;
+#[cfg(target_arch = "x86_64")]
+pub mod stubs;
+pub mod testing;
+mod time_report;
+pub mod timens;
 pub(crate) mod trampolines;
+#[cfg(target_arch = "x86_64")]
+pub mod tsc;
 pub mod vdso;
+#[cfg(feature = "seccomp")]
+pub mod virtual_time;
+pub mod vvar;
 
+pub use crate::current::{current_mock, CurrentMock};
+pub use crate::doctor::{doctor, Diagnosis};
+pub use crate::error::Error;
+pub use crate::fork::{handle_fork, AfterFork};
+use crate::journal::Operation;
+pub use crate::panic::restore_on_panic;
+use crate::patchfile::PatchRecord;
+pub use crate::recent::{recent_calls, RecentCall, RECENT_CALLS};
+pub use crate::session::{Backend, Plan, Session, Step};
+pub use crate::stats::{count_per_thread, reset_stats, stats, thread_stats, Stats};
+pub use crate::testing::{test_guard, TestGuard};
+pub use crate::time_report::{reset_time_report, time_report, TimeReport};
+pub use crate::trampolines::take_callback_panic;
 use crate::trampolines::*;
 use crate::vdso::vDSO;
+#[cfg(feature = "macros")]
+pub use tpom_macros::frozen_time;
 
 pub type Time = libc::time_t; // as libc::time_t
 
 /// Return type for `ClockGetTime` and `ClockGetRes`; maps to
 /// [libc::timespec](https://docs.rs/libc/0.2.56/libc/struct.timespec.html).
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct TimeSpec {
     pub seconds: Time,
     pub nanos: i64, // as libc::c_long
@@ -59,6 +128,7 @@ pub struct TimeSpec {
 
 /// Return type for `ClockGetTimeOfDay`; maps to
 /// [libc::timeval](https://docs.rs/libc/0.2.56/libc/struct.timeval.html).
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct TimeVal {
     pub seconds: Time,
     pub micros: i64, // as libc::suseconds_t
@@ -75,24 +145,67 @@ pub type ClockGetResCb = fn(i32) -> TimeSpec;
 /// Considered infallible
 pub type ClockGetTimeOfDayCb = fn() -> TimeVal; // FIXME: Needs to take a TZ
 
+/// A user function, tagged with the vDSO function it replaces.
+#[derive(Clone, Copy)]
+pub enum Callback {
+    GetTime(ClockGetTimeCb),
+    Time(TimeCb),
+    ClockGetRes(ClockGetResCb),
+    GetTimeOfDay(ClockGetTimeOfDayCb),
+}
+
+impl Callback {
+    pub fn kind(&self) -> Kind {
+        match self {
+            Callback::GetTime(_) => Kind::GetTime,
+            Callback::Time(_) => Kind::Time,
+            Callback::ClockGetRes(_) => Kind::ClockGetRes,
+            Callback::GetTimeOfDay(_) => Kind::GetTimeOfDay,
+        }
+    }
+
+    /// Stores the user function where its trampoline will look for it, and returns the
+    /// trampoline's address.
+    fn install(self) -> usize {
+        match self {
+            Callback::GetTime(cb) => *CLOCK_GT_CB.write().unwrap() = Some(cb),
+            Callback::Time(cb) => *TIME_CB.write().unwrap() = Some(cb),
+            Callback::ClockGetRes(cb) => *CLOCK_RES_CB.write().unwrap() = Some(cb),
+            Callback::GetTimeOfDay(cb) => *CLOCK_GTOD_CB.write().unwrap() = Some(cb),
+        }
+        trampoline(self.kind())
+    }
+}
+
+/// The address of the trampoline calling the user function of `kind`.
+pub(crate) fn trampoline(kind: Kind) -> usize {
+    match kind {
+        Kind::GetTime => my_clockgettime as *const () as usize,
+        Kind::Time => my_time as *const () as usize,
+        Kind::ClockGetRes => my_clockgetres as *const () as usize,
+        Kind::GetTimeOfDay => my_gettimeofday as *const () as usize,
+    }
+}
+
+/// A patchable vDSO function. Owns everything it needs to overwrite the function, so it
+/// can be stored or sent to other threads independently of the [`vDSO`] it came from.
 #[derive(Clone)]
-pub struct VDSOFun<'a> {
+pub struct VDSOFun {
     pub name: String,
+    pub kind: Kind,
     addr: usize,
     size: usize,
-    v: &'a vDSO,
-}
-
-pub struct BackupEntry<'a> {
-    v: &'a VDSOFun<'a>,
-    data: Vec<u8>,
+    v: vDSO,
 }
 
-pub struct GTVdso<'a> {
-    v: VDSOFun<'a>,
+/// The original code of an overwritten function; `Send + 'static` like [`VDSOFun`].
+pub struct BackupEntry {
+    v: VDSOFun,
+    /// The registry claim for this patch.
+    id: u64,
 }
 
-#[derive(PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Kind {
     GetTime,
     Time,
@@ -100,29 +213,126 @@ pub enum Kind {
     GetTimeOfDay,
 }
 
-impl<'a> BackupEntry<'a> {
-    pub fn restore(&self) {
-        self.v.v.overwrite(self.v.addr, &self.data)
+impl BackupEntry {
+    /// Writes back the original code. Restoring a patch that is no longer installed (as it
+    /// was restored already, possibly by the panic hook) does nothing.
+    #[track_caller]
+    pub fn restore(&self) -> Result<(), Error> {
+        let caller = std::panic::Location::caller();
+        // The registry's copy, which is up to date if the vDSO moved; see `criu`
+        let Some((v, data)) = registry::release(self.id) else {
+            log::warn!(
+                "{} is not patched by this entry, not restoring",
+                self.v.name
+            );
+            return Ok(());
+        };
+        v.v.overwrite(v.addr, &data)?;
+        journal::note(Operation::Restore, &v.name, &data, caller);
+        #[cfg(feature = "tracing")]
+        tracing::info!(symbol = %v.name, %caller, "restored");
+        Ok(())
+    }
+
+    /// The patch, as a record for [`patchfile::save`]. The stub jumps to this process'
+    /// trampolines, so the record can only be applied again here.
+    pub fn record(&self) -> PatchRecord {
+        let v = &self.v;
+        PatchRecord {
+            symbol: v.name.clone(),
+            offset: v.addr,
+            original: v.v.symbol_code(v.addr, v.size).to_vec(),
+            stub: opcodes::generate_opcodes(trampoline(v.kind), v.size),
+        }
+    }
+
+    /// Keeps the patch installed for the remainder of the process. The symbol stays marked
+    /// as patched, so it can't be overwritten again.
+    pub fn leak(self) {
+        registry::persist(self.id);
     }
 }
 
-pub trait TVDSOFun {
-    fn overwrite(&self, cb: ClockGetTimeCb) -> BackupEntry;
+impl VDSOFun {
+    pub(crate) fn abs_addr(&self) -> usize {
+        self.v.base() + self.addr
+    }
+
+    /// Whether tpom's jump fits in the function, padding included; overwriting it fails
+    /// with [`Error::SymbolTooSmall`] otherwise.
+    pub fn patchable(&self) -> Result<(), Error> {
+        // The trampoline's address is only known once installed, but the stub's length
+        // doesn't depend on it
+        let needed = opcodes::generate_opcodes(0, 0).len();
+        if self.size < needed {
+            return Err(Error::SymbolTooSmall {
+                name: self.name.clone(),
+                size: self.size,
+                needed,
+            });
+        }
+        Ok(())
+    }
 }
 
-fn _overwrite<'a>(v: &'a VDSOFun, trampoline: usize) -> BackupEntry<'a> {
-    let opcodes = opcodes::generate_opcodes(trampoline, v.size);
-    let backup = v.v.symbol_code(&v.name);
-    v.v.overwrite(v.addr, &opcodes);
-    BackupEntry {
-        v,
-        data: backup.to_owned(),
+pub trait TVDSOFun {
+    fn kind(&self) -> Kind;
+
+    /// Replaces the vDSO function with `cb`, which must be of the same [`Kind`].
+    ///
+    /// Fails with [`Error::AlreadyPatched`] if the symbol is currently overwritten, as its
+    /// code would be the trampoline jump rather than the original function.
+    #[track_caller]
+    fn overwrite_with(&self, cb: Callback) -> Result<BackupEntry, Error>;
+
+    /// Shorthand for `GetTime` and `ClockGetRes`, which share the callback signature.
+    #[track_caller]
+    fn overwrite(&self, cb: ClockGetTimeCb) -> Result<BackupEntry, Error> {
+        match self.kind() {
+            Kind::ClockGetRes => self.overwrite_with(Callback::ClockGetRes(cb)),
+            _ => self.overwrite_with(Callback::GetTime(cb)),
+        }
     }
+
+    fn is_patched(&self) -> bool;
 }
-impl<'a> TVDSOFun for GTVdso<'a> {
-    fn overwrite(&self, cb: ClockGetTimeCb) -> BackupEntry {
-        let mut w = CLOCK_GT_CB.write().unwrap();
-        *w = Some(cb);
-        _overwrite(&self.v, my_clockgettime as *const () as usize)
+
+impl TVDSOFun for VDSOFun {
+    fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    #[track_caller]
+    fn overwrite_with(&self, cb: Callback) -> Result<BackupEntry, Error> {
+        let caller = std::panic::Location::caller();
+        if cb.kind() != self.kind {
+            return Err(Error::WrongKind {
+                expected: self.kind,
+                found: cb.kind(),
+            });
+        }
+        if !self.v.is_live() {
+            return Err(Error::Offline);
+        }
+        self.patchable()?;
+        let backup = self.v.symbol_code(self.addr, self.size);
+        let id = registry::claim(self, backup)?;
+        let trampoline = cb.install();
+        let opcodes = opcodes::generate_opcodes(trampoline, self.size);
+        if let Err(e) = self.v.overwrite(self.addr, &opcodes) {
+            registry::release(id);
+            return Err(e);
+        }
+        journal::note(Operation::Patch, &self.name, &opcodes, caller);
+        #[cfg(feature = "tracing")]
+        tracing::info!(symbol = %self.name, kind = ?self.kind, %caller, "patched");
+        Ok(BackupEntry {
+            v: self.clone(),
+            id,
+        })
+    }
+
+    fn is_patched(&self) -> bool {
+        registry::is_patched(self.abs_addr())
     }
 }
@@ -0,0 +1,555 @@
+//! A mocked clock that can be changed while installed, by this process or, through
+//! [`Command`]s, from outside it.
+//!
+//! The clock is kept in a [`ControlPage`], created on first use: share [`page`] with remote
+//! targets through [`crate::remote::Remote::share`] to steer them along. Install it with the
+//! callbacks in [`callbacks`]; as with [`crate::stubs::Stub::Control`], only the wall clocks
+//! are mocked.
+//!
+//! ```no_run
+//! use tpom::{live, vdso, Session};
+//!
+//! let v = vdso::vDSO::read().unwrap();
+//! let mut session = Session::new(&v);
+//! session.apply_all(&live::callbacks()).unwrap();
+//! live::execute("advance 3600".parse().unwrap()).unwrap();
+//! ```
+use crate::control::{ControlPage, State};
+use crate::error::Error;
+use crate::trampolines::raw_clock_gettime;
+use crate::{Callback, Time, TimeSpec, TimeVal};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+const NANOS: i64 = 1_000_000_000;
+
+static PAGE: OnceLock<ControlPage> = OnceLock::new();
+
+/// The page holding the clock, which starts at the real time.
+pub fn page() -> Result<&'static ControlPage, Error> {
+    if let Some(page) = PAGE.get() {
+        return Ok(page);
+    }
+    // Of two threads racing here, the page of the second is dropped
+    let page = ControlPage::new()?;
+    Ok(PAGE.get_or_init(|| page))
+}
+
+/// The page, if something used it already.
+#[cfg_attr(not(feature = "prometheus"), allow(dead_code))]
+pub(crate) fn existing_page() -> Option<&'static ControlPage> {
+    PAGE.get()
+}
+
+/// A change to the clock, as read from text such as `advance 60`: a word, and a time (as
+/// [`parse_time`] reads it), a duration ([`parse_duration`]) or a factor for it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// `freeze [time]`: stops the time, at this many nanoseconds since the epoch, or
+    /// where it is.
+    Freeze(Option<i64>),
+    /// `set <time>`: the time jumps here, and keeps running or stays frozen.
+    Set(i64),
+    /// `advance <duration>`: the time jumps by this many nanoseconds, possibly negative.
+    Advance(i64),
+    /// `scale <factor>`: the time runs this many times as fast as the real one.
+    Scale(f64),
+    /// `restore`: the time is the real time again.
+    Restore,
+    /// `status`: changes nothing.
+    Status,
+}
+
+impl FromStr for Command {
+    type Err = Error;
+
+    fn from_str(line: &str) -> Result<Command, Error> {
+        let line = line.trim();
+        let (word, arg) = match line.split_once(char::is_whitespace) {
+            Some((word, arg)) => (word, Some(arg.trim())),
+            None => (line, None),
+        };
+        let needed = || Error::InvalidConfig(format!("{} needs a time", word));
+        let none = |command: Command| match arg {
+            Some(_) => Err(Error::InvalidConfig(format!("{} takes no argument", word))),
+            None => Ok(command),
+        };
+        match word {
+            "freeze" => Ok(Command::Freeze(arg.map(parse_time).transpose()?)),
+            "set" => Ok(Command::Set(parse_time(arg.ok_or_else(needed)?)?)),
+            "advance" => {
+                let by = parse_duration(word, arg.ok_or_else(needed)?)?;
+                Ok(Command::Advance(i64::try_from(by).map_err(|_| {
+                    Error::InvalidConfig(format!("{:?} is out of range", line))
+                })?))
+            }
+            "scale" => match arg.map(str::parse::<f64>) {
+                Some(Ok(speed)) if speed.is_finite() && speed >= 0.0 => Ok(Command::Scale(speed)),
+                _ => Err(Error::InvalidConfig(format!(
+                    "scale needs a non-negative factor, not {:?}",
+                    arg.unwrap_or("")
+                ))),
+            },
+            "restore" => none(Command::Restore),
+            "status" => none(Command::Status),
+            _ => Err(Error::InvalidConfig(format!("unknown command {:?}", word))),
+        }
+    }
+}
+
+/// Applies `command` to the clock, returning its state afterwards.
+pub fn execute(command: Command) -> Result<State, Error> {
+    let page = page()?;
+    match command {
+        Command::Freeze(at) => page.freeze(timespec(at.unwrap_or_else(|| page.state().now))),
+        Command::Set(at) => {
+            let state = page.state();
+            if state.frozen {
+                page.freeze(timespec(at));
+            } else {
+                page.run(timespec(at), state.speed);
+            }
+        }
+        Command::Advance(by) => page.advance(by),
+        Command::Scale(speed) => page.scale(speed),
+        Command::Restore => page.reset(),
+        Command::Status => return Ok(page.state()),
+    }
+    #[cfg(feature = "tracing")]
+    tracing::info!(?command, "mocked clock changed");
+    crate::schedule::changed();
+    let state = page.state();
+    #[cfg(feature = "otel")]
+    crate::otel::clock_changed(command, &state);
+    Ok(state)
+}
+
+/// The clocks following the mocked time; the others read the kernel.
+pub const MOCKED_CLOCKS: [i32; 3] = [
+    libc::CLOCK_REALTIME,
+    libc::CLOCK_REALTIME_COARSE,
+    libc::CLOCK_TAI,
+];
+
+/// The clock's parameters, to carry to a later run or another process and continue from.
+/// With the `serde` feature, it can be serialized.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MockState {
+    /// The mocked time when captured, in nanoseconds since the epoch.
+    pub now: i64,
+    /// The real time when captured; a running clock continues as if it had kept running
+    /// since.
+    pub real: i64,
+    pub frozen: bool,
+    pub speed: f64,
+    /// The clocks following the mocked time, as [`MOCKED_CLOCKS`].
+    pub clocks: Vec<i32>,
+}
+
+impl MockState {
+    /// The parameters of the clock now.
+    pub fn capture() -> Result<MockState, Error> {
+        let state = page()?.state();
+        Ok(MockState {
+            now: state.now,
+            real: real(libc::CLOCK_REALTIME),
+            frozen: state.frozen,
+            speed: state.speed,
+            clocks: MOCKED_CLOCKS.to_vec(),
+        })
+    }
+
+    /// How far the mocked time is ahead of the real time.
+    pub fn offset(&self) -> i64 {
+        self.now - self.real
+    }
+
+    /// The mocked time when the real time is `real`.
+    fn at(&self, real: i64) -> i64 {
+        if self.frozen {
+            self.now
+        } else {
+            self.now + ((real - self.real) as f64 * self.speed) as i64
+        }
+    }
+
+    /// Sets the clock to continue from these parameters; fails if they route other clocks.
+    pub fn restore(&self) -> Result<State, Error> {
+        let mut clocks = self.clocks.clone();
+        clocks.sort_unstable();
+        let mut mocked = MOCKED_CLOCKS.to_vec();
+        mocked.sort_unstable();
+        if clocks != mocked {
+            return Err(Error::InvalidConfig(format!(
+                "only the clocks {:?} can follow the mocked time, not {:?}",
+                MOCKED_CLOCKS, self.clocks
+            )));
+        }
+        let page = page()?;
+        let now = timespec(self.at(real(libc::CLOCK_REALTIME)));
+        #[cfg(feature = "tracing")]
+        tracing::info!(state = ?self, "mocked clock restored");
+        if self.frozen {
+            page.freeze(now);
+            page.scale(self.speed);
+        } else {
+            page.run(now, self.speed);
+        }
+        Ok(page.state())
+    }
+}
+
+/// The callbacks reading the clock, to install with a [`crate::Session`].
+pub fn callbacks() -> [Callback; 3] {
+    [
+        Callback::GetTime(clock_gettime),
+        Callback::GetTimeOfDay(gettimeofday),
+        Callback::Time(time),
+    ]
+}
+
+pub(crate) fn timespec(nanos: i64) -> TimeSpec {
+    TimeSpec {
+        seconds: nanos.div_euclid(NANOS) as Time,
+        nanos: nanos.rem_euclid(NANOS),
+    }
+}
+
+/// The mocked wall time, or the real one if the page couldn't be created.
+fn mocked() -> i64 {
+    match page() {
+        Ok(page) => page.state().now,
+        Err(_) => real(libc::CLOCK_REALTIME),
+    }
+}
+
+pub(crate) fn real(clockid: libc::clockid_t) -> i64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    raw_clock_gettime(clockid, &mut ts);
+    ts.tv_sec * NANOS + ts.tv_nsec
+}
+
+fn clock_gettime(clockid: i32) -> TimeSpec {
+    // As MOCKED_CLOCKS
+    timespec(match clockid {
+        libc::CLOCK_REALTIME | libc::CLOCK_REALTIME_COARSE => mocked(),
+        libc::CLOCK_TAI => mocked() + real(libc::CLOCK_TAI) - real(libc::CLOCK_REALTIME),
+        _ => real(clockid),
+    })
+}
+
+fn gettimeofday() -> TimeVal {
+    let ts = timespec(mocked());
+    TimeVal {
+        seconds: ts.seconds,
+        micros: ts.nanos / 1000,
+    }
+}
+
+fn time() -> Time {
+    timespec(mocked()).seconds
+}
+
+/// Parses seconds, possibly negative or fractional, into nanoseconds; errors name what was
+/// parsed as `var`.
+pub fn parse_seconds(var: &str, val: &str) -> Result<i128, Error> {
+    let invalid = || Error::InvalidConfig(format!("{}={:?} is not a number of seconds", var, val));
+    let (negative, digits) = match val.trim().strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, val.trim()),
+    };
+    let (secs, frac) = digits.split_once('.').unwrap_or((digits, ""));
+    if secs.is_empty() && frac.is_empty() || frac.len() > 9 {
+        return Err(invalid());
+    }
+    let parse = |s: &str| -> Result<i128, Error> {
+        if s.is_empty() {
+            return Ok(0);
+        }
+        if !s.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        s.parse().map_err(|_| invalid())
+    };
+    let nanos = parse(secs)? * NANOS as i128 + parse(frac)? * 10i128.pow(9 - frac.len() as u32);
+    Ok(if negative { -nanos } else { nanos })
+}
+
+/// Parses a date such as `2021-01-01T00:00:00Z` into nanoseconds since the epoch: RFC 3339,
+/// with a space allowed instead of the `T`, and UTC if no offset is given. A day alone, such
+/// as `2030-01-01`, is its midnight UTC. Fails past 2262, the last year the nanoseconds fit
+/// in an `i64`.
+pub fn parse_datetime(text: &str) -> Result<i64, Error> {
+    let invalid = || Error::InvalidConfig(format!("{:?} is not a date", text));
+    let number = |s: &str| -> Result<i64, Error> {
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        s.parse().map_err(|_| invalid())
+    };
+    let text = text.trim();
+    let (day, time) = text
+        .split_once(['T', 't', ' '])
+        .unwrap_or((text, "00:00:00Z"));
+    let (time, offset) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        (time, 0)
+    } else if let Some(at) = time.rfind(['+', '-']) {
+        let (hours, minutes) = time[at + 1..].split_once(':').ok_or_else(invalid)?;
+        let offset = number(hours)? * 3600 + number(minutes)? * 60;
+        (
+            &time[..at],
+            if &time[at..=at] == "-" {
+                -offset
+            } else {
+                offset
+            },
+        )
+    } else {
+        (time, 0)
+    };
+    let mut day = day.splitn(3, '-').map(number);
+    let next = |parts: &mut dyn Iterator<Item = Result<i64, Error>>| {
+        parts.next().unwrap_or_else(|| Err(invalid()))
+    };
+    let (year, month, mday) = (next(&mut day)?, next(&mut day)?, next(&mut day)?);
+    let (time, frac) = time.split_once('.').unwrap_or((time, ""));
+    let mut time = time.splitn(3, ':').map(number);
+    let (hour, minute, second) = (next(&mut time)?, next(&mut time)?, next(&mut time)?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&mday)
+        || hour > 23
+        || minute > 59
+        || second > 60
+        || frac.len() > 9
+    {
+        return Err(invalid());
+    }
+    let frac = if frac.is_empty() {
+        0
+    } else {
+        number(frac)? * 10i64.pow(9 - frac.len() as u32)
+    };
+    // Days since the epoch of a proleptic Gregorian date, counting years from March so
+    // that leap days come last
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + mday - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    let seconds = days * 86400 + hour * 3600 + minute * 60 + second - offset;
+    seconds
+        .checked_mul(NANOS)
+        .and_then(|n| n.checked_add(frac))
+        .ok_or_else(|| Error::InvalidConfig(format!("{:?} is out of range", text)))
+}
+
+/// Parses a duration such as `3d`, `1h30m` or `-1.5 h` into nanoseconds: numbers, possibly
+/// fractional, each followed by one of the units `w`, `d`, `h`, `m` (or `min`), `s`, `ms`,
+/// `us` and `ns`, after an optional sign. Plain seconds, as for [`parse_seconds`], are read
+/// too; errors name what was parsed as `var`.
+pub fn parse_duration(var: &str, val: &str) -> Result<i128, Error> {
+    if let Ok(nanos) = parse_seconds(var, val) {
+        return Ok(nanos);
+    }
+    let invalid = || Error::InvalidConfig(format!("{}={:?} is not a duration", var, val));
+    let text = val.trim();
+    let (negative, mut rest) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let mut nanos: i128 = 0;
+    rest = rest.trim_start();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .ok_or_else(invalid)?;
+        let number = parse_seconds(var, &rest[..digits]).map_err(|_| invalid())?;
+        rest = rest[digits..].trim_start();
+        let letters = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let unit: i128 = match &rest[..letters] {
+            "w" => 7 * 86400 * NANOS as i128,
+            "d" => 86400 * NANOS as i128,
+            "h" => 3600 * NANOS as i128,
+            "m" | "min" => 60 * NANOS as i128,
+            "s" => NANOS as i128,
+            "ms" => 1_000_000,
+            "us" => 1000,
+            "ns" => 1,
+            _ => return Err(invalid()),
+        };
+        nanos = number
+            .checked_mul(unit)
+            .map(|n| n / NANOS as i128)
+            .and_then(|n| nanos.checked_add(n))
+            .ok_or_else(invalid)?;
+        rest = rest[letters..].trim_start();
+    }
+    Ok(if negative { -nanos } else { nanos })
+}
+
+/// Parses a time into nanoseconds since the epoch: seconds since it, a date for
+/// [`parse_datetime`], or `now`, possibly followed by a [`parse_duration`] after `+` or `-`,
+/// as in `now +3d`, which is relative to the real time.
+pub fn parse_time(text: &str) -> Result<i64, Error> {
+    let out_of_range = || Error::InvalidConfig(format!("{:?} is out of range", text));
+    if let Some(relative) = text.trim().strip_prefix("now") {
+        let relative = relative.trim_start();
+        let by = match relative.as_bytes().first() {
+            None => 0,
+            Some(b'+' | b'-') => parse_duration("now", relative)?,
+            Some(_) => {
+                return Err(Error::InvalidConfig(format!(
+                    "{:?} is not now + or - a duration",
+                    text
+                )))
+            }
+        };
+        return i64::try_from(real(libc::CLOCK_REALTIME) as i128 + by).map_err(|_| out_of_range());
+    }
+    match parse_seconds("time", text) {
+        Ok(nanos) => i64::try_from(nanos).map_err(|_| out_of_range()),
+        Err(_) => parse_datetime(text),
+    }
+}
+
+/// Formats nanoseconds as seconds, the way [`parse_seconds`] reads them.
+pub(crate) fn format_seconds(nanos: i128) -> String {
+    let sign = if nanos < 0 { "-" } else { "" };
+    let nanos = nanos.unsigned_abs();
+    format!(
+        "{}{}.{:09}",
+        sign,
+        nanos / NANOS as u128,
+        nanos % NANOS as u128
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_seconds() {
+        assert_eq!(parse_seconds("X", "12"), Ok(12 * NANOS as i128));
+        assert_eq!(parse_seconds("X", "-1.5"), Ok(-1_500_000_000));
+        assert_eq!(parse_seconds("X", ".000000001"), Ok(1));
+        for bad in ["", "-", ".", "1e3", "1.2.3", "1.0000000001", "+1"] {
+            assert!(parse_seconds("X", bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_mock_state() {
+        let mut state = MockState {
+            now: 1000 * NANOS,
+            real: 10 * NANOS,
+            frozen: false,
+            speed: 2.0,
+            clocks: MOCKED_CLOCKS.to_vec(),
+        };
+        assert_eq!(state.offset(), 990 * NANOS);
+        assert_eq!(state.at(15 * NANOS), 1010 * NANOS);
+        state.frozen = true;
+        assert_eq!(state.at(15 * NANOS), 1000 * NANOS);
+        state.clocks = vec![libc::CLOCK_MONOTONIC];
+        assert!(state.restore().is_err());
+    }
+
+    #[test]
+    fn test_parse_datetime() {
+        assert_eq!(parse_datetime("1970-01-01T00:00:00Z"), Ok(0));
+        assert_eq!(
+            parse_datetime("2000-03-01 00:00:00"),
+            Ok(951_868_800 * NANOS)
+        );
+        assert_eq!(
+            parse_datetime("2021-01-01T01:00:00.5+01:00"),
+            Ok(1_609_459_200 * NANOS + 500_000_000)
+        );
+        assert_eq!(parse_datetime("1969-12-31T23:00:00-01:00"), Ok(0));
+        assert_eq!(parse_time("1.5"), Ok(1_500_000_000));
+        assert_eq!(parse_time("1970-01-01T00:00:01Z"), Ok(NANOS));
+        assert_eq!(parse_datetime("2000-03-01"), Ok(951_868_800 * NANOS));
+        for bad in [
+            "2021-01",
+            "2021-13-01T00:00:00Z",
+            "2021-01-01T24:00:00Z",
+            "2021-01-01T00:00Z",
+            "yesterday",
+            "9999-01-01T00:00:00Z",
+        ] {
+            assert!(parse_datetime(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("X", "90"), Ok(90 * NANOS as i128));
+        assert_eq!(parse_duration("X", "3d"), Ok(3 * 86400 * NANOS as i128));
+        assert_eq!(parse_duration("X", "+1h30m"), Ok(5400 * NANOS as i128));
+        assert_eq!(parse_duration("X", "- 1.5 min"), Ok(-90 * NANOS as i128));
+        assert_eq!(
+            parse_duration("X", "1w 2ms 3us 4ns"),
+            Ok(604_800_002_003_004)
+        );
+        for bad in ["", "d", "3 days", "1h-1m", "1..5s", "+"] {
+            assert!(parse_duration("X", bad).is_err(), "{:?}", bad);
+        }
+        let now = real(libc::CLOCK_REALTIME);
+        let later = parse_time("now +3d").unwrap();
+        assert!((later - now - 3 * 86400 * NANOS).abs() < 60 * NANOS);
+        let earlier = parse_time(" now - 1h ").unwrap();
+        assert!((now - earlier - 3600 * NANOS).abs() < 60 * NANOS);
+        assert!(parse_time("now").unwrap() >= now);
+        assert!(parse_time("now 3d").is_err());
+        assert!(parse_time("nowhere").is_err());
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!("freeze".parse(), Ok(Command::Freeze(None)));
+        assert_eq!(
+            "freeze 1.5".parse(),
+            Ok(Command::Freeze(Some(1_500_000_000)))
+        );
+        assert_eq!(" set  1000 ".parse(), Ok(Command::Set(1000 * NANOS)));
+        assert_eq!("advance -60".parse(), Ok(Command::Advance(-60 * NANOS)));
+        assert_eq!(
+            "set 2030-01-01".parse(),
+            Ok(Command::Set(1_893_456_000 * NANOS))
+        );
+        assert_eq!("advance 1h 30m".parse(), Ok(Command::Advance(5400 * NANOS)));
+        assert!(matches!(
+            "freeze now +1d".parse(),
+            Ok(Command::Freeze(Some(at))) if at > real(libc::CLOCK_REALTIME)
+        ));
+        assert_eq!("scale 2".parse(), Ok(Command::Scale(2.0)));
+        assert_eq!("restore".parse(), Ok(Command::Restore));
+        assert_eq!("status".parse(), Ok(Command::Status));
+        for bad in [
+            "",
+            "jump 1",
+            "set",
+            "advance soon",
+            "scale -1",
+            "restore 1",
+            "set 1 2",
+        ] {
+            assert!(bad.parse::<Command>().is_err(), "{:?}", bad);
+        }
+    }
+}
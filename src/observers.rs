@@ -0,0 +1,153 @@
+//! Functions called with what every intercepted call returned, mocked or not, to see how
+//! the code under test uses time without changing its behaviour:
+//!
+//! ```
+//! use tpom::observers::{self, Observation};
+//!
+//! fn print(call: &Observation) {
+//!     eprintln!("{:?} {:?} -> {:?}", call.kind, call.clockid, call.value);
+//! }
+//!
+//! let id = observers::add(print).unwrap();
+//! // ... patch, run the code under test ...
+//! observers::remove(id);
+//! ```
+//!
+//! Observers run on the calling thread, inside `clock_gettime` and the like: as callbacks,
+//! they shouldn't block, and the vDSO calls they make themselves are answered by the kernel
+//! and not observed. A panicking observer is ignored.
+use crate::error::Error;
+use crate::trampolines::ReentrancyGuard;
+use crate::{Kind, TimeSpec};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::RwLock;
+
+/// How many observers can be added at once.
+pub const MAX_OBSERVERS: usize = 8;
+
+/// A call taken by tpom's trampolines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Observation {
+    pub kind: Kind,
+    /// The clock asked for, by `clock_gettime` and `clock_getres`.
+    pub clockid: Option<libc::clockid_t>,
+    /// The time returned, or the resolution for `clock_getres`; microseconds and seconds
+    /// are widened to nanoseconds.
+    pub value: TimeSpec,
+}
+
+pub type Observer = fn(&Observation);
+
+/// The observers added, with their ids.
+pub(crate) type Slots = [Option<(u64, Observer)>; MAX_OBSERVERS];
+
+/// Fixed slots, copied out before calling them, so that notifying never allocates nor holds
+/// the lock while user code runs.
+pub(crate) static OBSERVERS: RwLock<Slots> = RwLock::new([None; MAX_OBSERVERS]);
+/// Whether any slot is taken, to skip the lock on every call otherwise.
+static ANY: AtomicBool = AtomicBool::new(false);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Calls `observer` on every intercepted call from now on, until [`remove`]d with the
+/// returned id. Fails once [`MAX_OBSERVERS`] are added.
+pub fn add(observer: Observer) -> Result<u64, Error> {
+    let mut slots = OBSERVERS.write().unwrap_or_else(|e| e.into_inner());
+    let Some(slot) = slots.iter_mut().find(|s| s.is_none()) else {
+        return Err(Error::InvalidConfig(format!(
+            "at most {} observers can be added",
+            MAX_OBSERVERS
+        )));
+    };
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    *slot = Some((id, observer));
+    ANY.store(true, Ordering::Release);
+    Ok(id)
+}
+
+/// Stops calling the observer added as `id`; returns whether it was still added.
+pub fn remove(id: u64) -> bool {
+    let mut slots = OBSERVERS.write().unwrap_or_else(|e| e.into_inner());
+    let Some(slot) = slots.iter_mut().find(|s| s.is_some_and(|(i, _)| i == id)) else {
+        return false;
+    };
+    *slot = None;
+    ANY.store(slots.iter().any(Option::is_some), Ordering::Release);
+    true
+}
+
+/// Calls every observer with a call's result.
+pub(crate) fn notify(kind: Kind, clockid: Option<libc::clockid_t>, value: TimeSpec) {
+    if !ANY.load(Ordering::Acquire) {
+        return;
+    }
+    let Some(_guard) = ReentrancyGuard::enter("observer", notify as *const ()) else {
+        return;
+    };
+    let slots = *OBSERVERS.read().unwrap_or_else(|e| e.into_inner());
+    let call = Observation {
+        kind,
+        clockid,
+        value,
+    };
+    for (_, observer) in slots.iter().flatten() {
+        if panic::catch_unwind(AssertUnwindSafe(|| observer(&call))).is_err() {
+            log::debug!("Observer at {:p} panicked", *observer as *const ());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trampolines;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static SEEN: RefCell<Vec<Observation>> = const { RefCell::new(vec![]) };
+    }
+
+    fn record(call: &Observation) {
+        // Only the calls of the test's own thread
+        let _ = SEEN.try_with(|s| s.borrow_mut().push(*call));
+    }
+
+    fn panics(_call: &Observation) {
+        panic!("observer");
+    }
+
+    #[test]
+    fn test_observers() {
+        let id = add(record).unwrap();
+        let panicking = add(panics).unwrap();
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        assert_eq!(
+            trampolines::my_clockgettime(libc::CLOCK_MONOTONIC, &mut ts),
+            0
+        );
+        let mut t = 0;
+        trampolines::my_time(&mut t);
+        assert!(remove(id));
+        assert!(!remove(id));
+        assert!(remove(panicking));
+        trampolines::my_time(&mut t);
+
+        let seen = SEEN.with(|s| s.take());
+        assert_eq!(seen.len(), 2, "{:?}", seen);
+        assert_eq!(seen[0].kind, Kind::GetTime);
+        assert_eq!(seen[0].clockid, Some(libc::CLOCK_MONOTONIC));
+        assert_eq!(seen[0].value.seconds, ts.tv_sec);
+        assert_eq!(seen[0].value.nanos, ts.tv_nsec);
+        assert_eq!(seen[1].kind, Kind::Time);
+        assert_eq!(seen[1].clockid, None);
+
+        let ids: Vec<_> = (0..MAX_OBSERVERS).map_while(|_| add(record).ok()).collect();
+        assert!(add(record).is_err());
+        for id in ids {
+            remove(id);
+        }
+    }
+}
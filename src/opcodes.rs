@@ -1,3 +1,5 @@
+use crate::elf::{self, ElfClass};
+
 // TODO: maybe use inline asm + naked functions, then copy them directly?
 
 fn _generate_opcodes_riscv64(jmp_target: usize, symbol_len: usize) -> Vec<u8> {
@@ -78,7 +80,30 @@ fn _generate_opcodes_x86_64(jmp_target: usize, symbol_len: usize) -> Vec<u8> {
     let jmp = vec![0xFF, 0xE0];
     let nop = vec![0x90u8];
 
-    let mut opcodes: Vec<u8> = vec![mov_rax_imm, addr_bytes, jmp].concat();
+    let mut opcodes: Vec<u8> = [mov_rax_imm, addr_bytes, jmp].concat();
+    while symbol_len > opcodes.len() {
+        opcodes.extend(&nop);
+    }
+
+    opcodes
+}
+fn _generate_opcodes_i386(jmp_target: u32, symbol_len: usize) -> Vec<u8> {
+    /* As for x86_64, from `nasm -f elf32` on
+      ```
+           global  _start
+           section .text
+       _start:
+           mov		eax, 0x12ff34ff
+           jmp 		eax
+      ```
+    */
+    let addr_bytes = jmp_target.to_le_bytes().to_vec();
+
+    let mov_eax_imm = vec![0xB8];
+    let jmp = vec![0xFF, 0xE0];
+    let nop = vec![0x90u8];
+
+    let mut opcodes: Vec<u8> = [mov_eax_imm, addr_bytes, jmp].concat();
     while symbol_len > opcodes.len() {
         opcodes.extend(&nop);
     }
@@ -99,6 +124,37 @@ pub(crate) fn generate_opcodes(jmp_target: usize, symbol_len: usize) -> Vec<u8>
 pub(crate) fn generate_opcodes(jmp_target: usize, symbol_len: usize) -> Vec<u8> {
     _generate_opcodes_x86_64(jmp_target, symbol_len)
 }
+
+/// Like [`generate_opcodes`], for a 32-bit process, such as a remote target.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn generate_compat_opcodes(jmp_target: u32, symbol_len: usize) -> Vec<u8> {
+    _generate_opcodes_i386(jmp_target, symbol_len)
+}
+/// The length of the jump, before padding, for images of `machine` and `class`, whichever
+/// architecture this is; `None` where tpom has no jump.
+pub(crate) fn jump_len(machine: u16, class: ElfClass) -> Option<usize> {
+    let opcodes = match (machine, class) {
+        (elf::EM_X86_64, ElfClass::Elf64) => _generate_opcodes_x86_64(0, 0),
+        (elf::EM_AARCH64, ElfClass::Elf64) => _generate_opcodes_aarch64(0, 0),
+        (elf::EM_RISCV, ElfClass::Elf64) => _generate_opcodes_riscv64(0, 0),
+        (elf::EM_386, ElfClass::Elf32) => _generate_opcodes_i386(0, 0),
+        _ => return None,
+    };
+    Some(opcodes.len())
+}
+
+/// Whether `code` starts with a jump built by [`generate_opcodes`], to any address.
+pub(crate) fn is_jump(code: &[u8]) -> bool {
+    // The bytes that differ between two targets are the address
+    let a = generate_opcodes(0, 0);
+    let b = generate_opcodes(usize::MAX, 0);
+    code.len() >= a.len()
+        && a.iter()
+            .zip(&b)
+            .zip(code)
+            .all(|((a, b), c)| a != b || a == c)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::opcodes::*;
@@ -144,4 +200,21 @@ mod tests {
 
         assert_eq!(expected, _generate_opcodes_x86_64(0x12ff34ff56ff78ff, 12));
     }
+
+    #[test]
+    fn test_generate_i386_opcodes_no_padding() {
+        let expected = std::fs::read("tests/files/i386_0x12ff34ff.bin").unwrap();
+
+        assert_eq!(expected, _generate_opcodes_i386(0x12ff34ff, 7));
+    }
+
+    #[test]
+    fn test_jump_len() {
+        assert_eq!(jump_len(elf::EM_X86_64, ElfClass::Elf64), Some(12));
+        assert_eq!(jump_len(elf::EM_AARCH64, ElfClass::Elf64), Some(16));
+        assert_eq!(jump_len(elf::EM_RISCV, ElfClass::Elf64), Some(20));
+        assert_eq!(jump_len(elf::EM_386, ElfClass::Elf32), Some(7));
+        assert_eq!(jump_len(elf::EM_X86_64, ElfClass::Elf32), None);
+        assert_eq!(jump_len(40, ElfClass::Elf32), None);
+    }
 }
@@ -0,0 +1,41 @@
+//! OpenTelemetry events for the changes to the [`crate::live`] clock, so that traces of a
+//! test run show when its services' clocks were frozen, stepped or sped up.
+//!
+//! An event goes on the span active where the clock is changed, or, with none, on a span of
+//! its own from the global `tpom` tracer. Its name is the command, such as `tpom.advance`.
+use crate::control::State;
+use crate::live::{format_seconds, Command};
+use opentelemetry::trace::{get_active_span, Span, Tracer};
+use opentelemetry::{global, KeyValue};
+
+/// Reports `command`, after which the clock is in `state`.
+pub(crate) fn clock_changed(command: Command, state: &State) {
+    let (name, argument) = match command {
+        Command::Freeze(at) => ("tpom.freeze", at.map(|at| format_seconds(at as i128))),
+        Command::Set(at) => ("tpom.set", Some(format_seconds(at as i128))),
+        Command::Advance(by) => ("tpom.advance", Some(format_seconds(by as i128))),
+        Command::Scale(speed) => ("tpom.scale", Some(speed.to_string())),
+        Command::Restore => ("tpom.restore", None),
+        Command::Status => return,
+    };
+    let mut attributes = vec![
+        KeyValue::new("tpom.now", format_seconds(state.now as i128)),
+        KeyValue::new("tpom.frozen", state.frozen),
+        KeyValue::new("tpom.speed", state.speed),
+    ];
+    if let Some(argument) = argument {
+        attributes.push(KeyValue::new("tpom.argument", argument));
+    }
+    let on_active = get_active_span(|span| {
+        if !span.is_recording() {
+            return false;
+        }
+        span.add_event(name, attributes.clone());
+        true
+    });
+    if !on_active {
+        let mut span = global::tracer("tpom").start(name);
+        span.add_event(name, attributes);
+        span.end();
+    }
+}
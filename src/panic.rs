@@ -0,0 +1,19 @@
+use crate::registry;
+use std::sync::Once;
+
+static HOOK: Once = Once::new();
+
+/// Installs a panic hook that restores every patched vDSO function (except leaked ones)
+/// before running the previously installed hook.
+///
+/// A test that panics while time is mocked would otherwise leave every later test in the
+/// same process with the mock installed. Calling this more than once has no further effect.
+pub fn restore_on_panic() {
+    HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            registry::restore_all();
+            previous(info);
+        }));
+    });
+}
@@ -0,0 +1,269 @@
+//! Patches as plain files, to audit what tpom writes to a vDSO, keep it under version
+//! control, or replay it, here or through [`crate::remote`].
+//!
+//! A file holds one record per patched function: its symbol, offset from the start of the
+//! vDSO, original code and the code written over it, in hex.
+//!
+//! ```text
+//! tpom-patch 1
+//!
+//! symbol __vdso_clock_gettime
+//! offset 0xc10
+//! original 554889e54157415641554154534883ec
+//! stub 48b8efbeaddefeca0000ffe090909090
+//! ```
+//!
+//! Records come from [`crate::BackupEntry::record`] and [`crate::remote::Remote::records`].
+//! The stub is replayed as is: one jumping to this process' trampolines is meaningless in
+//! another process, while the self-contained stubs of [`crate::stubs`] can go anywhere.
+use crate::error::Error;
+use crate::journal::{self, Operation};
+use crate::registry;
+use crate::vdso::vDSO;
+use std::fmt::Write as _;
+use std::fs;
+use std::panic::Location;
+use std::path::Path;
+
+/// The first line of every file, with the version of the format.
+const HEADER: &str = "tpom-patch 1";
+
+/// The code of one function, before and after patching.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatchRecord {
+    pub symbol: String,
+    /// Offset of the patched code from the start of the vDSO.
+    pub offset: usize,
+    pub original: Vec<u8>,
+    /// As long as `original`, which it replaces.
+    pub stub: Vec<u8>,
+}
+
+/// The contents of a file holding `records`.
+pub fn format(records: &[PatchRecord]) -> String {
+    let mut out = HEADER.to_string();
+    out.push('\n');
+    for r in records {
+        let _ = write!(
+            out,
+            "\nsymbol {}\noffset {:#x}\noriginal {}\nstub {}\n",
+            r.symbol,
+            r.offset,
+            hex(&r.original),
+            hex(&r.stub)
+        );
+    }
+    out
+}
+
+/// Reads the contents of a file.
+pub fn parse(text: &str) -> Result<Vec<PatchRecord>, Error> {
+    let mut lines = text.lines().enumerate();
+    if lines.next().map(|(_, l)| l.trim()) != Some(HEADER) {
+        return Err(Error::InvalidConfig(format!(
+            "a patch file starts with {:?}",
+            HEADER
+        )));
+    }
+    let mut records = vec![];
+    let mut fields: [Option<String>; 4] = Default::default();
+    for (n, line) in lines {
+        let invalid = |what: &str| Error::InvalidConfig(format!("line {}: {}", n + 1, what));
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once(' ') else {
+            return Err(invalid("expected a key and a value"));
+        };
+        let i = match key {
+            "symbol" => 0,
+            "offset" => 1,
+            "original" => 2,
+            "stub" => 3,
+            _ => return Err(invalid(&format!("unknown key {:?}", key))),
+        };
+        if fields[i].replace(value.trim().to_string()).is_some() {
+            return Err(invalid(&format!("{} is set twice", key)));
+        }
+        if fields.iter().all(Option::is_some) {
+            let [symbol, offset, original, stub] = std::mem::take(&mut fields).map(Option::unwrap);
+            let record = PatchRecord {
+                offset: parse_offset(&offset).ok_or_else(|| invalid("bad offset"))?,
+                original: unhex(&original).ok_or_else(|| invalid("bad original code"))?,
+                stub: unhex(&stub).ok_or_else(|| invalid("bad stub"))?,
+                symbol,
+            };
+            if record.original.len() != record.stub.len() {
+                return Err(invalid("the stub is not as long as the original code"));
+            }
+            records.push(record);
+        }
+    }
+    if fields.iter().any(Option::is_some) {
+        return Err(Error::InvalidConfig(
+            "the last record is incomplete".to_string(),
+        ));
+    }
+    Ok(records)
+}
+
+/// Writes `records` to a file at `path`, replacing it.
+pub fn save(path: impl AsRef<Path>, records: &[PatchRecord]) -> Result<(), Error> {
+    fs::write(path, format(records)).map_err(|e| Error::Os("write", e.raw_os_error().unwrap_or(0)))
+}
+
+/// The records of the file at `path`.
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<PatchRecord>, Error> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path)
+        .map_err(|e| Error::InvalidConfig(format!("can't read {}: {}", path.display(), e)))?;
+    parse(&text)
+}
+
+/// Writes the stub of every record in the file at `path` over this process' vDSO, checking
+/// first that each function holds its original code. The functions stay patched, as with
+/// [`crate::BackupEntry::leak`], until [`revert_patch_file`].
+#[track_caller]
+pub fn apply_patch_file(path: impl AsRef<Path>) -> Result<Vec<PatchRecord>, Error> {
+    let caller = Location::caller();
+    let records = load(path)?;
+    let v = vDSO::read().map_err(|e| Error::UnsupportedPlatform(e.to_string()))?;
+    for r in &records {
+        check(&v, r, &r.original)?;
+    }
+    for r in &records {
+        let entry = v
+            .entry_by_name(&r.symbol)
+            .ok_or_else(|| Error::PatchMismatch(r.symbol.clone()))?;
+        let id = registry::claim(&entry, &r.original)?;
+        if let Err(e) = v.overwrite(r.offset, &r.stub) {
+            registry::release(id);
+            return Err(e);
+        }
+        registry::persist(id);
+        journal::note(Operation::Patch, &r.symbol, &r.stub, caller);
+        #[cfg(feature = "tracing")]
+        tracing::info!(symbol = %r.symbol, %caller, "patched from a patch file");
+    }
+    Ok(records)
+}
+
+/// Writes back the original code of every record in the file at `path`, checking first
+/// that each function holds its stub.
+#[track_caller]
+pub fn revert_patch_file(path: impl AsRef<Path>) -> Result<Vec<PatchRecord>, Error> {
+    let caller = Location::caller();
+    let records = load(path)?;
+    let v = vDSO::read().map_err(|e| Error::UnsupportedPlatform(e.to_string()))?;
+    for r in &records {
+        check(&v, r, &r.stub)?;
+    }
+    for r in records.iter().rev() {
+        v.overwrite(r.offset, &r.original)?;
+        registry::forget(v.base() + r.offset);
+        journal::note(Operation::Restore, &r.symbol, &r.original, caller);
+        #[cfg(feature = "tracing")]
+        tracing::info!(symbol = %r.symbol, %caller, "reverted a patch file");
+    }
+    Ok(records)
+}
+
+/// Fails with [`Error::PatchMismatch`] unless `v` has the record's symbol at its offset,
+/// holding `code` there.
+pub(crate) fn check(v: &vDSO, record: &PatchRecord, code: &[u8]) -> Result<(), Error> {
+    let mismatch = || Error::PatchMismatch(record.symbol.clone());
+    let found = v
+        .symbols()
+        .any(|s| s.name == record.symbol && s.address == record.offset);
+    let image = v.image();
+    let held = image.get(record.offset..record.offset + code.len());
+    if !found || held != Some(code) {
+        return Err(mismatch());
+    }
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn parse_offset(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> PatchRecord {
+        PatchRecord {
+            symbol: "__vdso_time".to_string(),
+            offset: 0xbe0,
+            original: vec![0x55, 0x48, 0x89, 0xe5],
+            stub: vec![0xb8, 0x01, 0x00, 0xc3],
+        }
+    }
+
+    #[test]
+    fn test_format_and_parse() {
+        let text = format(&[record(), record()]);
+        assert!(text.starts_with("tpom-patch 1\n\nsymbol __vdso_time\noffset 0xbe0\n"));
+        assert!(
+            text.contains("original 554889e5\nstub b80100c3\n"),
+            "{}",
+            text
+        );
+        assert_eq!(parse(&text), Ok(vec![record(), record()]));
+        assert_eq!(parse("tpom-patch 1\n"), Ok(vec![]));
+
+        for bad in [
+            "",
+            "tpom-patch 2\n",
+            "tpom-patch 1\nsymbol a\n",
+            "tpom-patch 1\nsymbol a\nsymbol b\n",
+            "tpom-patch 1\nsize 1\n",
+            "tpom-patch 1\nsymbol a\noffset 1\noriginal 00\nstub 0\n",
+            "tpom-patch 1\nsymbol a\noffset 1\noriginal 00\nstub 0000\n",
+            "tpom-patch 1\nsymbol a\noffset x\noriginal 00\nstub 00\n",
+        ] {
+            assert!(parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let image = fs::read("src/test_files/test_vdso_elf_1").unwrap();
+        let v = vDSO::from_bytes(&image).unwrap();
+        let time = v.symbols().find(|s| s.name == "__vdso_time").unwrap();
+        let original = image[time.address..time.address + 4].to_vec();
+        let r = PatchRecord {
+            offset: time.address,
+            original: original.clone(),
+            ..record()
+        };
+        assert_eq!(check(&v, &r, &original), Ok(()));
+        assert_eq!(
+            check(&v, &r, &r.stub),
+            Err(Error::PatchMismatch("__vdso_time".to_string()))
+        );
+        let elsewhere = PatchRecord {
+            offset: time.address + 16,
+            ..r.clone()
+        };
+        assert!(check(&v, &elsewhere, &original).is_err());
+    }
+}
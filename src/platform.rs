@@ -0,0 +1,120 @@
+//! Recognizes environments where the vDSO is emulated or absent, in which overwriting it
+//! would not intercept the process' clock reads (or would corrupt something else).
+use crate::auxv;
+use crate::error::Error;
+use std::fs;
+
+/// What the detection is based on; read from the running system by [`probe`].
+struct Facts {
+    version: String,
+    cpuinfo: String,
+    /// The auxiliary vector has AT_SYSINFO_EHDR.
+    has_ehdr: bool,
+    /// /proc/self/maps has a `[vdso]` mapping at AT_SYSINFO_EHDR.
+    vdso_mapped: bool,
+}
+
+/// Fails with [`Error::UnsupportedPlatform`] if the running system is one where the vDSO
+/// can't be patched: gVisor, User Mode Linux and qemu-user are recognized.
+///
+/// The checks are heuristics based on /proc; where it is unreadable, the platform is
+/// assumed to be supported.
+pub fn probe() -> Result<(), Error> {
+    let base = auxv::get(auxv::AT_SYSINFO_EHDR).ok();
+    let vdso_mapped = match auxv::vdso_mapping() {
+        Ok((start, _)) => base == Some(start),
+        Err(auxv::AuxvError::Unreadable(_)) => true,
+        Err(_) => false,
+    };
+    classify(&Facts {
+        version: fs::read_to_string("/proc/version").unwrap_or_default(),
+        cpuinfo: fs::read_to_string("/proc/cpuinfo").unwrap_or_default(),
+        has_ehdr: base.is_some(),
+        vdso_mapped,
+    })
+}
+
+fn classify(facts: &Facts) -> Result<(), Error> {
+    let unsupported = |reason: &str| Err(Error::UnsupportedPlatform(reason.to_string()));
+    // gVisor reports a fixed, fake kernel build
+    if facts
+        .version
+        .contains("#1 SMP Sun Jan 10 15:06:54 PST 2016")
+    {
+        return unsupported("gVisor provides its own vDSO, which the sandbox may reset");
+    }
+    if facts.cpuinfo.lines().any(|l| {
+        l.starts_with("vendor_id") && l.contains("User Mode Linux")
+            || l.starts_with("model name") && l.ends_with(": UML")
+    }) {
+        return unsupported("User Mode Linux has no vDSO");
+    }
+    if !facts.has_ehdr {
+        return unsupported("the kernel provides no vDSO");
+    }
+    // qemu-user loads its own vDSO image for the guest, which the kernel doesn't know of
+    if !facts.vdso_mapped {
+        return unsupported("the vDSO is not a kernel mapping, as under qemu-user");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn facts() -> Facts {
+        Facts {
+            version: "Linux version 6.1.0-13-amd64 (debian-kernel@lists.debian.org) #1 SMP"
+                .to_string(),
+            cpuinfo: "vendor_id\t: GenuineIntel\nmodel name\t: Intel(R) Xeon(R)\n".to_string(),
+            has_ehdr: true,
+            vdso_mapped: true,
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(&facts()), Ok(()));
+
+        let gvisor = Facts {
+            version: "Linux version 4.4.0 #1 SMP Sun Jan 10 15:06:54 PST 2016".to_string(),
+            ..facts()
+        };
+        assert!(matches!(
+            classify(&gvisor),
+            Err(Error::UnsupportedPlatform(_))
+        ));
+
+        let uml = Facts {
+            cpuinfo: "processor\t: 0\nvendor_id\t: User Mode Linux\nmodel name\t: UML\n"
+                .to_string(),
+            ..facts()
+        };
+        assert!(matches!(classify(&uml), Err(Error::UnsupportedPlatform(_))));
+
+        let qemu = Facts {
+            vdso_mapped: false,
+            ..facts()
+        };
+        assert!(matches!(
+            classify(&qemu),
+            Err(Error::UnsupportedPlatform(_))
+        ));
+
+        let no_vdso = Facts {
+            has_ehdr: false,
+            vdso_mapped: false,
+            ..facts()
+        };
+        assert!(matches!(
+            classify(&no_vdso),
+            Err(Error::UnsupportedPlatform(_))
+        ));
+    }
+
+    #[test]
+    fn test_probe_host() {
+        assert_eq!(probe(), Ok(()));
+    }
+}
@@ -0,0 +1,404 @@
+//! Mocks the time of a whole process from environment variables, for the `tpom-preload`
+//! shared object to apply when loaded with `LD_PRELOAD` into a program that doesn't use
+//! tpom itself.
+//!
+//! | Variable | Meaning |
+//! |-------------|-----|
+//! |`TPOM_FREEZE`|Start the clock at this time: seconds since the epoch, a date such as `2030-01-01` or `now +3d`; it stands still unless `TPOM_SPEED` is also set|
+//! |`TPOM_OFFSET`|Start the clock this far (possibly negative) away from the real time, in seconds or as a duration such as `-1h30m`|
+//! |`TPOM_SPEED`|Run the clock at this factor of the real time|
+//! |`FAKETIME`|A [libfaketime](https://github.com/wolfcw/libfaketime) spec, read if no `TPOM_*` variable is set; see [`Config::from_faketime`]|
+//!
+//! Seconds may be fractional; see [`crate::live::parse_time`] and
+//! [`crate::live::parse_duration`] for the other forms. Only the wall clocks (`CLOCK_REALTIME`, its coarse variant
+//! and `CLOCK_TAI`), `gettimeofday` and `time` are mocked; the other clocks read the kernel.
+//!
+//! With the `ctor` feature, any program linking tpom reads them too, as it is loaded.
+use crate::error::Error;
+use crate::live::{format_seconds, parse_datetime, parse_duration, parse_seconds, parse_time};
+use crate::trampolines::raw_clock_gettime;
+use crate::vdso::vDSO;
+use crate::{Callback, Session, Time, TimeSpec, TimeVal};
+use std::sync::OnceLock;
+
+const NANOS: i128 = 1_000_000_000;
+
+static CLOCK: OnceLock<Clock> = OnceLock::new();
+
+/// The mocked time, as parsed from the environment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// Nanoseconds since the epoch the clock starts at; `None` for the real time.
+    pub start: Option<i128>,
+    /// Nanoseconds added to the start.
+    pub offset: i128,
+    pub speed: f64,
+}
+
+/// A [`Config`] anchored to the real time it was installed at.
+#[derive(Debug, Clone, Copy)]
+struct Clock {
+    real_start: i128,
+    mock_start: i128,
+    speed: f64,
+}
+
+impl Clock {
+    fn new(config: &Config, real_start: i128) -> Clock {
+        Clock {
+            real_start,
+            mock_start: config.start.unwrap_or(real_start) + config.offset,
+            speed: config.speed,
+        }
+    }
+
+    fn at(&self, real: i128) -> i128 {
+        self.mock_start + ((real - self.real_start) as f64 * self.speed) as i128
+    }
+}
+
+impl Config {
+    /// Reads the `TPOM_*` variables through `var`; `None` if none is set.
+    pub fn parse(var: impl Fn(&str) -> Option<String>) -> Result<Option<Config>, Error> {
+        let (freeze, offset, speed) = (var("TPOM_FREEZE"), var("TPOM_OFFSET"), var("TPOM_SPEED"));
+        if freeze.is_none() && offset.is_none() && speed.is_none() {
+            return var("FAKETIME")
+                .map(|spec| Config::from_faketime(&spec))
+                .transpose();
+        }
+        if freeze.is_some() && offset.is_some() {
+            return Err(Error::InvalidConfig(
+                "TPOM_FREEZE and TPOM_OFFSET are exclusive".to_string(),
+            ));
+        }
+        let start = freeze
+            .as_deref()
+            .map(|v| parse_time(v).map(i128::from))
+            .transpose()?;
+        let offset = offset
+            .as_deref()
+            .map_or(Ok(0), |v| parse_duration("TPOM_OFFSET", v))?;
+        let speed = match speed {
+            Some(v) => match v.trim().parse::<f64>() {
+                Ok(speed) if speed.is_finite() && speed >= 0.0 => speed,
+                _ => {
+                    return Err(Error::InvalidConfig(format!(
+                        "TPOM_SPEED={:?} is not a non-negative factor",
+                        v
+                    )))
+                }
+            },
+            None if start.is_some() => 0.0,
+            None => 1.0,
+        };
+        Ok(Some(Config {
+            start,
+            offset,
+            speed,
+        }))
+    }
+
+    /// Reads libfaketime's syntax, so that its users can keep their `FAKETIME`:
+    ///
+    /// |Spec|Meaning|
+    /// |----|-------|
+    /// |`2020-12-24 20:30:00`|Frozen at this time|
+    /// |`@2020-12-24 20:30:00`|Starting at this time|
+    /// |`+15d`, `-2.5h`|Starting this far from the real time, in seconds or with a suffix of `m`, `h`, `d` or `y`|
+    /// |`x2.0`|At this factor of the real time; also after any of the above, as in `+15d x2.0`|
+    ///
+    /// Dates are UTC, where libfaketime reads them in the local timezone. Its `i` (advancing
+    /// on every call) and other modifiers are not supported.
+    ///
+    /// ```
+    /// use tpom::preload::Config;
+    ///
+    /// let config = Config::from_faketime("@2020-12-24 20:30:00 x2").unwrap();
+    /// assert_eq!(config.start, Some(1_608_841_800_000_000_000));
+    /// assert_eq!(config.speed, 2.0);
+    /// ```
+    pub fn from_faketime(spec: &str) -> Result<Config, Error> {
+        let invalid = |what: &str| Error::InvalidConfig(format!("FAKETIME={:?}: {}", spec, what));
+        let parse_date = |date: &str| parse_datetime(date).map(i128::from);
+        let (when, speed) = match spec.rfind('x') {
+            Some(i) => {
+                let factor = &spec[i + 1..];
+                match factor.trim().parse::<f64>() {
+                    Ok(speed) if speed.is_finite() && speed >= 0.0 => (&spec[..i], Some(speed)),
+                    _ => return Err(invalid("the speed is not a non-negative factor")),
+                }
+            }
+            None => (spec, None),
+        };
+        let when = when.trim();
+        let (start, offset, frozen) = match when.as_bytes().first() {
+            None => (None, 0, false),
+            Some(b'+' | b'-') => {
+                let (number, unit) = match when.as_bytes()[when.len() - 1] {
+                    b'm' => (&when[..when.len() - 1], 60),
+                    b'h' => (&when[..when.len() - 1], 3600),
+                    b'd' => (&when[..when.len() - 1], 86400),
+                    b'y' => (&when[..when.len() - 1], 365 * 86400),
+                    _ => (when, 1),
+                };
+                let number = number.strip_prefix('+').unwrap_or(number);
+                (None, parse_seconds("FAKETIME", number)? * unit, false)
+            }
+            Some(b'@') => (Some(parse_date(&when[1..])?), 0, false),
+            Some(_) => (Some(parse_date(when)?), 0, true),
+        };
+        Ok(Config {
+            start,
+            offset,
+            speed: speed.unwrap_or(if frozen { 0.0 } else { 1.0 }),
+        })
+    }
+
+    /// The `TPOM_*` variables that [`Config::parse`] reads back as this config.
+    pub fn vars(&self) -> Vec<(&'static str, String)> {
+        let when = match self.start {
+            Some(start) => ("TPOM_FREEZE", format_seconds(start + self.offset)),
+            None => ("TPOM_OFFSET", format_seconds(self.offset)),
+        };
+        vec![when, ("TPOM_SPEED", self.speed.to_string())]
+    }
+}
+
+fn real_nanos(clockid: libc::clockid_t) -> Option<i128> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if raw_clock_gettime(clockid, &mut ts) != 0 {
+        return None;
+    }
+    Some(ts.tv_sec as i128 * NANOS + ts.tv_nsec as i128)
+}
+
+fn mocked() -> i128 {
+    let real = real_nanos(libc::CLOCK_REALTIME).unwrap_or(0);
+    CLOCK.get().map_or(real, |clock| clock.at(real))
+}
+
+fn clock_gettime(clockid: i32) -> TimeSpec {
+    let nanos = match clockid {
+        libc::CLOCK_REALTIME | libc::CLOCK_REALTIME_COARSE | libc::CLOCK_TAI => {
+            let tai = if clockid == libc::CLOCK_TAI {
+                real_nanos(libc::CLOCK_TAI).unwrap_or(0)
+                    - real_nanos(libc::CLOCK_REALTIME).unwrap_or(0)
+            } else {
+                0
+            };
+            mocked() + tai
+        }
+        _ => real_nanos(clockid).unwrap_or(0),
+    };
+    TimeSpec {
+        seconds: nanos.div_euclid(NANOS) as Time,
+        nanos: nanos.rem_euclid(NANOS) as i64,
+    }
+}
+
+fn gettimeofday() -> TimeVal {
+    let nanos = mocked();
+    TimeVal {
+        seconds: nanos.div_euclid(NANOS) as Time,
+        micros: (nanos.rem_euclid(NANOS) / 1000) as i64,
+    }
+}
+
+fn time() -> Time {
+    mocked().div_euclid(NANOS) as Time
+}
+
+/// Applies the mocked time described by the process' environment, for the rest of its
+/// lifetime. Returns whether any `TPOM_*` variable was set; fails if one can't be parsed, or
+/// if the vDSO can't be patched. Installing more than once is an error.
+pub fn install() -> Result<bool, Box<dyn std::error::Error>> {
+    let Some(config) = Config::parse(|var| std::env::var(var).ok())? else {
+        return Ok(false);
+    };
+    let real_start =
+        real_nanos(libc::CLOCK_REALTIME).ok_or(Error::last_os_error("clock_gettime"))?;
+    CLOCK
+        .set(Clock::new(&config, real_start))
+        .map_err(|_| Error::AlreadyPatched("preload clock".to_string()))?;
+    let v = vDSO::read()?;
+    let mut session = Session::new(&v);
+    session.apply_all(&[
+        Callback::GetTime(clock_gettime),
+        Callback::GetTimeOfDay(gettimeofday),
+    ])?;
+    // Some architectures have no vDSO `time`; libc derives it from `clock_gettime` there
+    if let Err(e) = session.overwrite(Callback::Time(time)) {
+        log::debug!("Not patching time: {}", e);
+    }
+    session.leak();
+    Ok(true)
+}
+
+/// Installs the environment's clock before `main`, in programs built with the `ctor`
+/// feature; run by the constructor reading the auxiliary vector, after it. A failure is
+/// only logged, as the program's output is not tpom's to write to: with no logger set up
+/// yet, [`crate::current_mock`] tells whether the clock is mocked.
+#[cfg(feature = "ctor")]
+pub(crate) fn bootstrap() {
+    if let Err(e) = install() {
+        log::error!("Not mocking time: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name| {
+            vars.iter()
+                .find(|(k, _)| *k == name)
+                .map(|(_, v)| v.to_string())
+        }
+    }
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!(Config::parse(env(&[])), Ok(None));
+        assert_eq!(
+            Config::parse(env(&[("TPOM_FREEZE", "1000")])),
+            Ok(Some(Config {
+                start: Some(1000 * NANOS),
+                offset: 0,
+                speed: 0.0
+            }))
+        );
+        assert_eq!(
+            Config::parse(env(&[("TPOM_OFFSET", "-60"), ("TPOM_SPEED", "2")])),
+            Ok(Some(Config {
+                start: None,
+                offset: -60 * NANOS,
+                speed: 2.0
+            }))
+        );
+        assert_eq!(
+            Config::parse(env(&[("TPOM_FREEZE", "2030-01-01"), ("TPOM_SPEED", "1")])),
+            Ok(Some(Config {
+                start: Some(1_893_456_000 * NANOS),
+                offset: 0,
+                speed: 1.0
+            }))
+        );
+        assert_eq!(
+            Config::parse(env(&[("TPOM_OFFSET", "+3d")])).map(|c| c.map(|c| c.offset)),
+            Ok(Some(3 * 86400 * NANOS))
+        );
+        assert!(Config::parse(env(&[("TPOM_FREEZE", "1"), ("TPOM_OFFSET", "1")])).is_err());
+        assert!(Config::parse(env(&[("TPOM_SPEED", "-1")])).is_err());
+        assert!(Config::parse(env(&[("TPOM_SPEED", "fast")])).is_err());
+    }
+
+    #[test]
+    fn test_faketime() {
+        let date = 1_608_841_800 * NANOS;
+        assert_eq!(
+            Config::from_faketime("2020-12-24 20:30:00"),
+            Ok(Config {
+                start: Some(date),
+                offset: 0,
+                speed: 0.0
+            })
+        );
+        assert_eq!(
+            Config::from_faketime("@2020-12-24 20:30:00"),
+            Ok(Config {
+                start: Some(date),
+                offset: 0,
+                speed: 1.0
+            })
+        );
+        assert_eq!(
+            Config::from_faketime("+15d x2.0"),
+            Ok(Config {
+                start: None,
+                offset: 15 * 86400 * NANOS,
+                speed: 2.0
+            })
+        );
+        assert_eq!(
+            Config::from_faketime("-1.5h").unwrap().offset,
+            -5400 * NANOS
+        );
+        assert_eq!(Config::from_faketime("+90").unwrap().offset, 90 * NANOS);
+        assert_eq!(Config::from_faketime("x0.5").unwrap().speed, 0.5);
+        for bad in [
+            "2020-13-01 00:00:00",
+            "2020-12",
+            "+1w",
+            "i2.0",
+            "x-1",
+            "yesterday",
+        ] {
+            assert!(Config::from_faketime(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(
+            Config::parse(env(&[("FAKETIME", "+60")]))
+                .unwrap()
+                .unwrap()
+                .offset,
+            60 * NANOS
+        );
+        let both = [("TPOM_FREEZE", "1000"), ("FAKETIME", "+60")];
+        assert_eq!(
+            Config::parse(env(&both)).unwrap().unwrap().start,
+            Some(1000 * NANOS)
+        );
+    }
+
+    #[test]
+    fn test_vars() {
+        let vars = |config: Config| {
+            let vars = config.vars();
+            Config::parse(|name| {
+                vars.iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.clone())
+            })
+        };
+        for config in [
+            Config {
+                start: Some(1000 * NANOS + 5),
+                offset: 0,
+                speed: 0.0,
+            },
+            Config {
+                start: None,
+                offset: -1_500_000_000,
+                speed: 2.5,
+            },
+        ] {
+            assert_eq!(vars(config), Ok(Some(config)));
+        }
+        let shifted = Config {
+            start: Some(1000 * NANOS),
+            offset: -NANOS,
+            speed: 1.0,
+        };
+        assert_eq!(vars(shifted).unwrap().unwrap().start, Some(999 * NANOS));
+    }
+
+    #[test]
+    fn test_clock() {
+        let frozen = Config::parse(env(&[("TPOM_FREEZE", "1000")]))
+            .unwrap()
+            .unwrap();
+        let clock = Clock::new(&frozen, 5 * NANOS);
+        assert_eq!(clock.at(5 * NANOS), 1000 * NANOS);
+        assert_eq!(clock.at(50 * NANOS), 1000 * NANOS);
+
+        let fast = Config::parse(env(&[("TPOM_OFFSET", "10"), ("TPOM_SPEED", "2")]))
+            .unwrap()
+            .unwrap();
+        let clock = Clock::new(&fast, 5 * NANOS);
+        assert_eq!(clock.at(5 * NANOS), 15 * NANOS);
+        assert_eq!(clock.at(6 * NANOS), 17 * NANOS);
+    }
+}
@@ -0,0 +1,134 @@
+//! Spawns programs with their time already mocked.
+//!
+//! `exec` maps a fresh vDSO, so a child never keeps the patches of the process that
+//! started it. [`CommandExt`] arranges for the program to be mocked anyway, in one of three
+//! ways: preloading the `tpom-preload` library, shifting its monotonic clocks in a time
+//! namespace (see [`crate::timens`]), or patching it through a [`Remote`] once loaded.
+//!
+//! ```no_run
+//! use std::process::Command;
+//! use tpom::process::CommandExt;
+//! use tpom::TimeSpec;
+//!
+//! let day = TimeSpec {
+//!     seconds: 86400,
+//!     nanos: 0,
+//! };
+//! let zero = TimeSpec {
+//!     seconds: 0,
+//!     nanos: 0,
+//! };
+//! let status = Command::new("cat")
+//!     .arg("/proc/uptime")
+//!     .time_namespace(zero, day)
+//!     .status()
+//!     .unwrap();
+//! ```
+use crate::error::Error;
+#[cfg(feature = "preload")]
+use crate::preload::Config;
+use crate::remote::{self, ptrace, Remote};
+use crate::{timens, TimeSpec};
+#[cfg(feature = "preload")]
+use std::ffi::{OsStr, OsString};
+use std::os::unix::process::CommandExt as _;
+use std::process::{Child, Command};
+
+/// Mocking for the program of a [`Command`].
+pub trait CommandExt {
+    /// Mocks the program's wall clocks as `config` says, by preloading the `tpom-preload`
+    /// library at `library` into it. Only works for dynamically linked programs; any other
+    /// `LD_PRELOAD` set for the command, or inherited, is kept.
+    #[cfg(feature = "preload")]
+    fn preload(&mut self, library: impl AsRef<OsStr>, config: &Config) -> &mut Command;
+
+    /// Runs the program in a new time namespace, with `CLOCK_MONOTONIC` and
+    /// `CLOCK_BOOTTIME` shifted by these offsets; the program's children share it. Spawning
+    /// fails without the privileges of [`timens::unshare`].
+    fn time_namespace(&mut self, monotonic: TimeSpec, boottime: TimeSpec) -> &mut Command;
+
+    /// Spawns the program, calling `setup` to patch it once loaded, before it runs its first
+    /// instruction; the patches stay for its whole life. Its own children are not patched:
+    /// see [`crate::follow`] for that.
+    ///
+    /// The program is traced until then, so the command can't be spawned again. If `setup`
+    /// fails, the program is killed and its error returned.
+    fn spawn_patched(
+        &mut self,
+        setup: impl FnOnce(&mut Remote) -> Result<(), Error>,
+    ) -> Result<Child, Error>;
+}
+
+impl CommandExt for Command {
+    #[cfg(feature = "preload")]
+    fn preload(&mut self, library: impl AsRef<OsStr>, config: &Config) -> &mut Command {
+        let set = self
+            .get_envs()
+            .find(|(k, _)| *k == "LD_PRELOAD")
+            .map(|(_, v)| v.map(OsStr::to_os_string));
+        let others = set.unwrap_or_else(|| std::env::var_os("LD_PRELOAD"));
+        let mut preload = OsString::from(library.as_ref());
+        if let Some(others) = others.filter(|o| !o.is_empty()) {
+            preload.push(":");
+            preload.push(others);
+        }
+        self.env("LD_PRELOAD", preload);
+        self.envs(config.vars())
+    }
+
+    fn time_namespace(&mut self, monotonic: TimeSpec, boottime: TimeSpec) -> &mut Command {
+        unsafe {
+            self.pre_exec(move || {
+                // The program is exec'd by this process, which has to enter the namespace
+                // its children would
+                timens::unshare_with(&monotonic, &boottime)
+                    .and_then(|_| timens::enter())
+                    .map_err(|e| match e {
+                        Error::Os(_, errno) => std::io::Error::from_raw_os_error(errno),
+                        e => std::io::Error::other(e.to_string()),
+                    })
+            })
+        }
+    }
+
+    fn spawn_patched(
+        &mut self,
+        setup: impl FnOnce(&mut Remote) -> Result<(), Error>,
+    ) -> Result<Child, Error> {
+        unsafe {
+            self.pre_exec(|| {
+                // Stops on exec, before the program runs
+                if libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            })
+        };
+        let mut child = self
+            .spawn()
+            .map_err(|e| Error::Os("spawn", e.raw_os_error().unwrap_or(0)))?;
+        let pid = child.id() as libc::pid_t;
+        let res = (|| {
+            let mut status = 0;
+            if unsafe { libc::waitpid(pid, &mut status, libc::__WALL) } < 0 {
+                return Err(Error::last_os_error("waitpid"));
+            }
+            if !libc::WIFSTOPPED(status) {
+                return Err(Error::Os("ptrace", libc::ESRCH));
+            }
+            let mut target = remote::attach_traced(pid)?;
+            setup(&mut target)?;
+            target.leak();
+            if ptrace(libc::PTRACE_DETACH, pid, 0, 0) != 0 {
+                return Err(Error::last_os_error("ptrace"));
+            }
+            Ok(())
+        })();
+        if let Err(e) = res {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+        Ok(child)
+    }
+}
@@ -0,0 +1,157 @@
+//! The call counters of [`crate::stats`] and the parameters of the mock, in the Prometheus
+//! text format, for a service's own metrics endpoint to serve; there is no server here.
+//!
+//! ```
+//! let body = tpom::prometheus::encode();
+//! assert!(body.contains("# TYPE tpom_calls_total counter"));
+//! ```
+use crate::{current_mock, live, stats, Kind};
+use std::fmt::Write as _;
+
+const FUNCTIONS: [(Kind, &str); 4] = [
+    (Kind::GetTime, "clock_gettime"),
+    (Kind::Time, "time"),
+    (Kind::ClockGetRes, "clock_getres"),
+    (Kind::GetTimeOfDay, "gettimeofday"),
+];
+
+/// Every metric, as of now:
+///
+/// * `tpom_calls_total{function}` and `tpom_clock_calls_total{clockid}`, the calls
+///   intercepted; the clockid is `other` for the clocks of other processes and devices.
+/// * `tpom_patched{function}`, 1 for the functions replaced.
+/// * `tpom_mock_offset_seconds{clockid}`, how far each mocked clock is from the real one.
+/// * `tpom_live_speed` and `tpom_live_frozen`, the parameters of the [`crate::live`]
+///   clock, once it is used.
+pub fn encode() -> String {
+    let mut out = String::new();
+    let stats = stats();
+    header(&mut out, "tpom_calls_total", "counter", "Calls intercepted");
+    for (kind, name) in FUNCTIONS {
+        sample(
+            &mut out,
+            "tpom_calls_total",
+            "function",
+            name,
+            stats.calls(kind),
+        );
+    }
+    header(
+        &mut out,
+        "tpom_clock_calls_total",
+        "counter",
+        "Calls to clock_gettime and clock_getres, by clockid",
+    );
+    for (clockid, calls) in stats.clocks() {
+        let clockid = clockid.to_string();
+        sample(
+            &mut out,
+            "tpom_clock_calls_total",
+            "clockid",
+            &clockid,
+            calls,
+        );
+    }
+    let other = stats.other_clocks();
+    sample(
+        &mut out,
+        "tpom_clock_calls_total",
+        "clockid",
+        "other",
+        other,
+    );
+
+    let mock = current_mock();
+    header(
+        &mut out,
+        "tpom_patched",
+        "gauge",
+        "Whether the function is replaced",
+    );
+    for (kind, name) in FUNCTIONS {
+        let patched = mock.patched.contains(&kind) as u8;
+        sample(&mut out, "tpom_patched", "function", name, patched);
+    }
+    header(
+        &mut out,
+        "tpom_mock_offset_seconds",
+        "gauge",
+        "How far the mocked clock is ahead of the real one",
+    );
+    for (clockid, ts) in &mock.clocks {
+        let Some(ts) = ts else { continue };
+        let mocked = ts.seconds as i128 * 1_000_000_000 + ts.nanos as i128;
+        let offset = (mocked - live::real(*clockid) as i128) as f64 / 1e9;
+        let clockid = clockid.to_string();
+        sample(
+            &mut out,
+            "tpom_mock_offset_seconds",
+            "clockid",
+            &clockid,
+            offset,
+        );
+    }
+
+    if let Some(page) = live::existing_page() {
+        let state = page.state();
+        header(
+            &mut out,
+            "tpom_live_speed",
+            "gauge",
+            "Rate of the live clock",
+        );
+        let _ = writeln!(out, "tpom_live_speed {}", state.speed);
+        header(
+            &mut out,
+            "tpom_live_frozen",
+            "gauge",
+            "Whether the live clock is frozen",
+        );
+        let _ = writeln!(out, "tpom_live_frozen {}", state.frozen as u8);
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+fn sample(out: &mut String, name: &str, label: &str, value: &str, n: impl std::fmt::Display) {
+    let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, value, n);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trampolines;
+
+    #[test]
+    fn test_encode() {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        trampolines::my_clockgettime(libc::CLOCK_BOOTTIME, &mut ts);
+        let body = encode();
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines[0], "# HELP tpom_calls_total Calls intercepted");
+        assert_eq!(lines[1], "# TYPE tpom_calls_total counter");
+        assert!(lines[2].starts_with("tpom_calls_total{function=\"clock_gettime\"} "));
+        assert!(
+            body.contains("tpom_clock_calls_total{clockid=\"7\"} "),
+            "{}",
+            body
+        );
+        assert!(body.contains("tpom_clock_calls_total{clockid=\"other\"} "));
+        assert!(
+            body.contains("tpom_patched{function=\"time\"} 0\n"),
+            "{}",
+            body
+        );
+        // Every sample is a name, optional labels and a number
+        for line in lines.iter().filter(|l| !l.starts_with('#')) {
+            let (_, value) = line.rsplit_once(' ').unwrap();
+            assert!(value.parse::<f64>().is_ok(), "{}", line);
+        }
+    }
+}
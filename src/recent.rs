@@ -0,0 +1,171 @@
+use crate::trampolines::raw_syscall;
+use crate::{Kind, TimeSpec};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicI32, AtomicI64, AtomicU64, Ordering};
+
+/// How many calls [`recent_calls`] remembers.
+pub const RECENT_CALLS: usize = 256;
+
+/// An intercepted call, as kept by [`recent_calls`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecentCall {
+    /// The order of the call among all those intercepted in the process, from 0.
+    pub sequence: u64,
+    pub kind: Kind,
+    pub clockid: Option<libc::clockid_t>,
+    /// The time returned, as for [`crate::observers::Observation`].
+    pub value: TimeSpec,
+    /// The id of the calling thread, as `gettid(2)`.
+    pub tid: i32,
+}
+
+/// One call, behind a sequence lock: `seq` is odd while it is written, and twice the call's
+/// sequence plus 2 once it is.
+struct Slot {
+    seq: AtomicU64,
+    /// The kind in the low byte, the clockid above it, if bit 8 is set.
+    call: AtomicU64,
+    seconds: AtomicI64,
+    nanos: AtomicI64,
+    tid: AtomicI32,
+}
+
+static SLOTS: [Slot; RECENT_CALLS] = [const {
+    Slot {
+        seq: AtomicU64::new(0),
+        call: AtomicU64::new(0),
+        seconds: AtomicI64::new(0),
+        nanos: AtomicI64::new(0),
+        tid: AtomicI32::new(0),
+    }
+}; RECENT_CALLS];
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // 0 until the first call of the thread
+    static TID: Cell<i32> = const { Cell::new(0) };
+}
+
+const KINDS: [Kind; 4] = [
+    Kind::GetTime,
+    Kind::Time,
+    Kind::ClockGetRes,
+    Kind::GetTimeOfDay,
+];
+
+/// The id of the calling thread, cached.
+pub(crate) fn tid() -> i32 {
+    TID.try_with(|tid| {
+        if tid.get() == 0 {
+            tid.set(raw_syscall(libc::SYS_gettid, 0, 0, 0) as i32);
+        }
+        tid.get()
+    })
+    .unwrap_or(0)
+}
+
+/// Forgets the thread id, which changes in a forked child.
+pub(crate) fn forked() {
+    let _ = TID.try_with(|tid| tid.set(0));
+}
+
+/// Keeps a call, overwriting the oldest. Never waits: a call whose slot is being written by
+/// a call from `RECENT_CALLS` before is dropped.
+pub(crate) fn record(kind: Kind, clockid: Option<libc::clockid_t>, value: TimeSpec) {
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    let slot = &SLOTS[(n % RECENT_CALLS as u64) as usize];
+    let seq = slot.seq.load(Ordering::Relaxed);
+    if seq % 2 == 1
+        || slot
+            .seq
+            .compare_exchange(seq, 2 * n + 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+    {
+        return;
+    }
+    let kind = KINDS.iter().position(|k| *k == kind).unwrap_or(0) as u64;
+    let call = match clockid {
+        Some(id) => kind | 1 << 8 | (id as u32 as u64) << 9,
+        None => kind,
+    };
+    slot.call.store(call, Ordering::Relaxed);
+    slot.seconds.store(value.seconds, Ordering::Relaxed);
+    slot.nanos.store(value.nanos, Ordering::Relaxed);
+    slot.tid.store(tid(), Ordering::Relaxed);
+    slot.seq.store(2 * n + 2, Ordering::Release);
+}
+
+/// The last [`RECENT_CALLS`] calls tpom's trampolines took, mocked or not, oldest first.
+/// Calls being recorded while this reads are left out, so the sequence may have gaps.
+pub fn recent_calls() -> Vec<RecentCall> {
+    let mut calls: Vec<RecentCall> = SLOTS
+        .iter()
+        .filter_map(|slot| {
+            let seq = slot.seq.load(Ordering::Acquire);
+            if seq == 0 || seq % 2 == 1 {
+                return None;
+            }
+            let call = slot.call.load(Ordering::Relaxed);
+            let value = TimeSpec {
+                seconds: slot.seconds.load(Ordering::Relaxed),
+                nanos: slot.nanos.load(Ordering::Relaxed),
+            };
+            let tid = slot.tid.load(Ordering::Relaxed);
+            std::sync::atomic::fence(Ordering::Acquire);
+            if slot.seq.load(Ordering::Relaxed) != seq {
+                return None;
+            }
+            Some(RecentCall {
+                sequence: seq / 2 - 1,
+                kind: KINDS[(call & 0xff) as usize % KINDS.len()],
+                clockid: (call & 1 << 8 != 0).then_some((call >> 9) as u32 as libc::clockid_t),
+                value,
+                tid,
+            })
+        })
+        .collect();
+    calls.sort_by_key(|c| c.sequence);
+    calls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trampolines;
+
+    #[test]
+    fn test_recent_calls() {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        trampolines::my_clockgettime(libc::CLOCK_BOOTTIME, &mut ts);
+        trampolines::my_time(std::ptr::null_mut());
+        let mut pid_clock = 0;
+        assert_eq!(unsafe { libc::clock_getcpuclockid(0, &mut pid_clock) }, 0);
+        trampolines::my_clockgettime(pid_clock, &mut ts);
+
+        let tid = unsafe { libc::gettid() };
+        let mine: Vec<RecentCall> = recent_calls()
+            .into_iter()
+            .filter(|c| c.tid == tid)
+            .collect();
+        let n = mine.len();
+        assert!(n >= 3, "{:?}", mine);
+        let mine = &mine[n - 3..];
+        assert_eq!(mine[0].kind, Kind::GetTime);
+        assert_eq!(mine[0].clockid, Some(libc::CLOCK_BOOTTIME));
+        assert_eq!(mine[1].kind, Kind::Time);
+        assert_eq!(mine[1].clockid, None);
+        assert_eq!(mine[2].clockid, Some(pid_clock));
+        assert_eq!(mine[2].value.seconds, ts.tv_sec);
+        assert!(mine[0].sequence < mine[1].sequence && mine[1].sequence < mine[2].sequence);
+
+        for _ in 0..2 * RECENT_CALLS {
+            trampolines::my_time(std::ptr::null_mut());
+        }
+        let calls = recent_calls();
+        assert!(calls.len() <= RECENT_CALLS);
+        assert!(calls.windows(2).all(|w| w[0].sequence < w[1].sequence));
+    }
+}
@@ -0,0 +1,173 @@
+//! Process-wide record of which vDSO symbols are currently overwritten.
+//!
+//! Symbols are tracked by their absolute address, as aliases (`clock_gettime` and
+//! `__vdso_clock_gettime`) share the same code.
+use crate::error::Error;
+use crate::journal::{self, Operation};
+use crate::opcodes;
+use crate::vdso::vDSO;
+use crate::{trampoline, Kind, VDSOFun};
+use std::panic::Location;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+static PATCHED: Mutex<Vec<Patched>> = Mutex::new(vec![]);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) struct Patched {
+    /// Identifies this claim, as the same address may be claimed again once released.
+    id: u64,
+    addr: usize,
+    v: VDSOFun,
+    /// The original code, for restoring without the user's `BackupEntry`.
+    data: Vec<u8>,
+    /// Leaked patches are never released.
+    persistent: bool,
+}
+
+/// Marks the symbol `v` as patched, `data` being its original code; fails if it already was.
+/// Returns the id of the claim.
+pub(crate) fn claim(v: &VDSOFun, data: &[u8]) -> Result<u64, Error> {
+    let addr = v.abs_addr();
+    let mut patched = PATCHED.lock().unwrap();
+    if let Some(p) = patched.iter().find(|p| p.addr == addr) {
+        return Err(Error::AlreadyPatched(p.v.name.clone()));
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    patched.push(Patched {
+        id,
+        addr,
+        v: v.clone(),
+        data: data.to_owned(),
+        persistent: false,
+    });
+    Ok(id)
+}
+
+/// Drops the claim `id`, if still held, returning the function it was on and its original
+/// code.
+pub(crate) fn release(id: u64) -> Option<(VDSOFun, Vec<u8>)> {
+    let mut patched = PATCHED.lock().unwrap();
+    let i = patched.iter().position(|p| p.id == id && !p.persistent)?;
+    let p = patched.remove(i);
+    Some((p.v, p.data))
+}
+
+/// Holds the registry, keeping it unchanged; see [`crate::fork`].
+pub(crate) fn lock() -> MutexGuard<'static, Vec<Patched>> {
+    PATCHED.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub(crate) fn persist(id: u64) {
+    for p in PATCHED.lock().unwrap().iter_mut() {
+        if p.id == id {
+            p.persistent = true;
+        }
+    }
+}
+
+/// Drops the claim on `addr`, leaked or not; for patches undone by other means than their
+/// `BackupEntry`.
+pub(crate) fn forget(addr: usize) {
+    PATCHED.lock().unwrap().retain(|p| p.addr != addr);
+}
+
+/// The original code of the function patched at `addr`, if it is.
+#[cfg_attr(not(feature = "disassembler"), allow(dead_code))]
+pub(crate) fn original(addr: usize) -> Option<Vec<u8>> {
+    let patched = PATCHED.lock().unwrap();
+    patched
+        .iter()
+        .find(|p| p.addr == addr)
+        .map(|p| p.data.clone())
+}
+
+pub(crate) fn is_patched(addr: usize) -> bool {
+    PATCHED.lock().unwrap().iter().any(|p| p.addr == addr)
+}
+
+/// Whether a function of this kind is currently overwritten, so that its callback is live.
+pub(crate) fn kind_patched(kind: Kind) -> bool {
+    PATCHED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .any(|p| p.v.kind == kind)
+}
+
+/// [`kind_patched`], or `None` if the registry is locked; for signal handlers, which could
+/// otherwise deadlock with the thread they interrupted.
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+pub(crate) fn try_kind_patched(kind: Kind) -> Option<bool> {
+    let patched = PATCHED.try_lock().ok()?;
+    Some(patched.iter().any(|p| p.v.kind == kind))
+}
+
+/// Restores every patch that was not leaked. Used where the owning `BackupEntry`s are out
+/// of reach, such as a panic hook; tolerates a poisoned lock for that reason.
+#[track_caller]
+pub(crate) fn restore_all() {
+    let mut patched = PATCHED.lock().unwrap_or_else(|e| e.into_inner());
+    for p in patched.iter().filter(|p| !p.persistent) {
+        let _ = restore(p, Location::caller());
+    }
+    patched.retain(|p| p.persistent);
+}
+
+/// Restores every patch, leaked ones included, and forgets them all; for a forked child
+/// that should run unmocked.
+#[track_caller]
+pub(crate) fn restore_pristine() {
+    let mut patched = lock();
+    for p in patched.iter().rev() {
+        let _ = restore(p, Location::caller());
+    }
+    patched.clear();
+}
+
+/// Writes back the original code of every patch, leaked ones included, but keeps them all
+/// claimed, for [`resume`] to patch again.
+#[track_caller]
+pub(crate) fn suspend() -> Result<(), Error> {
+    let patched = lock();
+    let mut res = Ok(());
+    for p in patched.iter().rev() {
+        res = res.and(restore(p, Location::caller()));
+    }
+    res
+}
+
+/// Writes back the original code of `p`, logging failures.
+fn restore(p: &Patched, caller: &Location) -> Result<(), Error> {
+    match p.v.v.overwrite(p.v.addr, &p.data) {
+        Ok(()) => {
+            journal::note(Operation::Restore, &p.v.name, &p.data, caller);
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("Could not restore {}: {}", p.v.name, e);
+            Err(e)
+        }
+    }
+}
+
+/// Patches every claimed function again, in `v`, which may be mapped elsewhere than the
+/// vDSO they were claimed in. The claims are moved to `v`.
+#[track_caller]
+pub(crate) fn resume(v: &vDSO) -> Result<(), Error> {
+    let caller = Location::caller();
+    let mut patched = lock();
+    for p in patched.iter_mut() {
+        let f = v
+            .entry_by_name(&p.v.name)
+            .ok_or(Error::NotFound(p.v.kind))?;
+        let data = v.symbol_code(f.addr, f.size).to_vec();
+        let opcodes = opcodes::generate_opcodes(trampoline(f.kind), f.size);
+        v.overwrite(f.addr, &opcodes)?;
+        journal::note(Operation::Patch, &f.name, &opcodes, caller);
+        p.addr = f.abs_addr();
+        p.v = f;
+        p.data = data;
+    }
+    Ok(())
+}
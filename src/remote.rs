@@ -0,0 +1,544 @@
+//! Patches the vDSO of another process, such as a subprocess the test harness didn't
+//! compile.
+//!
+//! The target can't jump into this process' trampolines, so the code written is
+//! self-contained, supplied by the caller. Writes are made with `PTRACE_POKETEXT`, with every
+//! thread of the target stopped for the duration; in between, the target runs untraced.
+//! Attaching needs ptrace permission over the target: being its parent with Yama's
+//! `ptrace_scope` at 1 or less, or `CAP_SYS_PTRACE`.
+//!
+//! On x86_64, [`Remote::install`] writes the code for a [`Stub`] instead, mapping room for it
+//! in the target when the function is too small; [`Remote::share`] maps a [`ControlPage`]
+//! there, for stubs whose time can be changed without patching again. A 32-bit target is
+//! recognised by the class of its vDSO, and gets i386 stubs and system calls; its libc may
+//! call `__vdso_clock_gettime64`, which is not one of the supported functions.
+#[cfg(target_arch = "x86_64")]
+use crate::control::ControlPage;
+use crate::error::Error;
+#[cfg(target_arch = "x86_64")]
+use crate::opcodes;
+use crate::patchfile::{self, PatchRecord};
+#[cfg(target_arch = "x86_64")]
+use crate::stubs::{self, Stub};
+#[cfg(target_arch = "x86_64")]
+use crate::vdso::ElfClass;
+use crate::vdso::{self, vDSO, Symbol};
+use crate::Kind;
+use std::fs;
+use std::path::Path;
+
+const WORD: usize = std::mem::size_of::<libc::c_long>();
+#[cfg(target_arch = "x86_64")]
+const PAGE: usize = 4096;
+
+/// A patched function of the target, with its original code.
+struct RemotePatch {
+    name: String,
+    addr: usize,
+    original: Vec<u8>,
+    code: Vec<u8>,
+}
+
+/// The vDSO of process `pid`; patches made through it are restored on [`Remote::detach`]
+/// or when dropped.
+pub struct Remote {
+    pid: libc::pid_t,
+    base: usize,
+    v: vDSO,
+    patches: Vec<RemotePatch>,
+    /// Whether the target is traced, and stopped, by this thread already; see
+    /// [`crate::follow`].
+    traced: bool,
+    /// The page mapped for stubs, and how much of it is used.
+    #[cfg(target_arch = "x86_64")]
+    code: Option<(usize, usize)>,
+}
+
+/// Reads the vDSO of `pid`, as [`vDSO::read_from_pid`] does.
+pub fn attach(pid: libc::pid_t) -> Result<Remote, Error> {
+    attach_as(pid, false)
+}
+
+/// Like [`attach`], for a single-threaded target that this thread is tracing, stopped.
+pub(crate) fn attach_traced(pid: libc::pid_t) -> Result<Remote, Error> {
+    attach_as(pid, true)
+}
+
+fn attach_as(pid: libc::pid_t, traced: bool) -> Result<Remote, Error> {
+    let (base, v) = vdso::read_mapping(pid)?;
+    Ok(Remote {
+        pid,
+        base,
+        v,
+        patches: vec![],
+        traced,
+        #[cfg(target_arch = "x86_64")]
+        code: None,
+    })
+}
+
+impl Remote {
+    pub fn pid(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    /// Where the target's vDSO is mapped.
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// The target's vDSO, as read on [`attach`]; offline, as it is not ours.
+    pub fn vdso(&self) -> &vDSO {
+        &self.v
+    }
+
+    /// Replaces the target's function for `kind` with `code`, which must fit in the symbol
+    /// and only depend on its own address.
+    pub fn overwrite(&mut self, kind: Kind, code: &[u8]) -> Result<(), Error> {
+        let entry = self.v.entry(kind).ok_or(Error::NotFound(kind))?;
+        if code.len() > entry.size {
+            return Err(Error::SymbolTooSmall {
+                name: entry.name,
+                size: entry.size,
+                needed: code.len(),
+            });
+        }
+        let addr = self.base + entry.addr;
+        if self.patches.iter().any(|p| p.addr == addr) {
+            return Err(Error::AlreadyPatched(entry.name));
+        }
+        stopped(self.pid, self.traced, || poke(self.pid, addr, code))?;
+        self.patches.push(RemotePatch {
+            name: entry.name,
+            addr,
+            original: self.v.symbol_code(entry.addr, code.len()).to_vec(),
+            code: code.to_vec(),
+        });
+        Ok(())
+    }
+
+    /// The patches made through this `Remote`, as records for [`patchfile::save`]. A stub
+    /// placed out of line is recorded as the jump to it, which is only valid in this target.
+    pub fn records(&self) -> Vec<PatchRecord> {
+        self.patches
+            .iter()
+            .map(|p| PatchRecord {
+                symbol: p.name.clone(),
+                offset: p.addr - self.base,
+                original: p.original.clone(),
+                stub: p.code.clone(),
+            })
+            .collect()
+    }
+
+    /// Writes the stub of every record in the file at `path` over the target's vDSO, checking
+    /// first that each function holds its original code. The patches are restored like the
+    /// others made through this `Remote`.
+    pub fn apply_patch_file(&mut self, path: impl AsRef<Path>) -> Result<Vec<PatchRecord>, Error> {
+        let records = patchfile::load(path)?;
+        // Whatever this `Remote` wrote since attaching is not in its snapshot
+        let current = vdso::read_mapping(self.pid)?.1;
+        for r in &records {
+            patchfile::check(&current, r, &r.original)?;
+            if self.patches.iter().any(|p| p.addr == self.base + r.offset) {
+                return Err(Error::AlreadyPatched(r.symbol.clone()));
+            }
+        }
+        let (pid, base) = (self.pid, self.base);
+        stopped(pid, self.traced, || {
+            for r in &records {
+                poke(pid, base + r.offset, &r.stub)?;
+                self.patches.push(RemotePatch {
+                    name: r.symbol.clone(),
+                    addr: base + r.offset,
+                    original: r.original.clone(),
+                    code: r.stub.clone(),
+                });
+            }
+            Ok(())
+        })?;
+        Ok(records)
+    }
+
+    /// Writes back the original code of every record in the file at `path`, checking first
+    /// that each function of the target holds its stub, as left by an earlier
+    /// [`Remote::leak`].
+    pub fn revert_patch_file(&mut self, path: impl AsRef<Path>) -> Result<Vec<PatchRecord>, Error> {
+        let records = patchfile::load(path)?;
+        let current = vdso::read_mapping(self.pid)?.1;
+        for r in &records {
+            patchfile::check(&current, r, &r.stub)?;
+        }
+        let (pid, base) = (self.pid, self.base);
+        stopped(pid, self.traced, || {
+            for r in records.iter().rev() {
+                poke(pid, base + r.offset, &r.original)?;
+            }
+            Ok(())
+        })?;
+        self.patches
+            .retain(|p| !records.iter().any(|r| base + r.offset == p.addr));
+        // Later patches back up the code as it is now
+        self.v = vdso::read_mapping(pid)?.1;
+        Ok(records)
+    }
+
+    /// Answers the target's function for `kind` with `stub`. The stub is written over the
+    /// function if it fits, or else into a page mapped in the target, with the function
+    /// jumping to it. The page stays mapped after detaching, as a thread may be running it.
+    #[cfg(target_arch = "x86_64")]
+    pub fn install(&mut self, kind: Kind, stub: &Stub) -> Result<(), Error> {
+        let code = stubs::generate_for(self.v.class(), kind, stub)?;
+        let entry = self.v.entry(kind).ok_or(Error::NotFound(kind))?;
+        if code.len() <= entry.size {
+            return self.overwrite(kind, &code);
+        }
+        if self
+            .patches
+            .iter()
+            .any(|p| p.addr == self.base + entry.addr)
+        {
+            return Err(Error::AlreadyPatched(entry.name));
+        }
+        let addr = stopped(self.pid, self.traced, || self.place(&code))?;
+        if self.compat() {
+            // Mapped by a 32-bit process, the page is below 4 GiB
+            return self.overwrite(kind, &opcodes::generate_compat_opcodes(addr as u32, 0));
+        }
+        self.overwrite(kind, &opcodes::generate_opcodes(addr, 0))
+    }
+
+    /// Maps `page` in the target, returning its address there for [`Stub::Control`].
+    #[cfg(target_arch = "x86_64")]
+    pub fn share(&mut self, page: &ControlPage) -> Result<usize, Error> {
+        let path = format!("/proc/{}/fd/{}\0", std::process::id(), page.fd());
+        let (pid, compat) = (self.pid, self.compat());
+        stopped(pid, self.traced, || {
+            let path = self.place(path.as_bytes())?;
+            let flags = libc::O_RDWR | libc::O_CLOEXEC;
+            let args = [libc::AT_FDCWD as u64, path as u64, flags as u64, 0, 0, 0];
+            let fd = check("openat", syscall(pid, compat, libc::SYS_openat, args)?)?;
+            let args = [
+                0,
+                PAGE as u64,
+                libc::PROT_READ as u64,
+                libc::MAP_SHARED as u64,
+                fd,
+                0,
+            ];
+            let addr =
+                syscall(pid, compat, libc::SYS_mmap, args).and_then(|ret| check("mmap", ret));
+            syscall(pid, compat, libc::SYS_close, [fd, 0, 0, 0, 0, 0])?;
+            Ok(addr? as usize)
+        })
+    }
+
+    /// Copies `data` to the page mapped for stubs in stopped target, mapping another when
+    /// full.
+    #[cfg(target_arch = "x86_64")]
+    fn place(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let (page, used) = match self.code {
+            Some((page, used)) if used + data.len() <= PAGE => (page, used),
+            _ => (map(self.pid, self.compat())?, 0),
+        };
+        poke(self.pid, page + used, data)?;
+        // Keeps the stubs 16-byte aligned
+        self.code = Some((page, (used + data.len()).next_multiple_of(16)));
+        Ok(page + used)
+    }
+
+    /// Whether the target is a 32-bit process.
+    #[cfg(target_arch = "x86_64")]
+    fn compat(&self) -> bool {
+        self.v.class() == ElfClass::Elf32
+    }
+
+    /// Writes back the original code of every function, most recent first. Keeps going if
+    /// one fails, returning the first error.
+    pub fn restore_all(&mut self) -> Result<(), Error> {
+        if self.patches.is_empty() {
+            return Ok(());
+        }
+        let patches = std::mem::take(&mut self.patches);
+        stopped(self.pid, self.traced, || {
+            let mut res = Ok(());
+            for patch in patches.iter().rev() {
+                if let Err(e) = poke(self.pid, patch.addr, &patch.original) {
+                    log::error!("Could not restore {} in {}: {}", patch.name, self.pid, e);
+                    res = res.and(Err(e));
+                }
+            }
+            res
+        })
+    }
+
+    /// Writes back every byte of the target's vDSO that differs from `pristine`, the vDSO of
+    /// a process of the same class that patches nothing, such as this one: undoes patches
+    /// left by an earlier [`Remote::leak`], or by another tool. Returns the symbols that
+    /// differed; the patches made through this `Remote` are gone too.
+    pub fn restore_from(&mut self, pristine: &vDSO) -> Result<Vec<Symbol>, Error> {
+        if self.v.class() != pristine.class() {
+            return Err(Error::UnsupportedPlatform(format!(
+                "the vDSO of {} is not of the same class",
+                self.pid
+            )));
+        }
+        // The image read from a mapping includes its padding to the page
+        let len = self.v.image().len().min(pristine.image().len());
+        let (theirs, ours) = (&self.v.image()[..len], &pristine.image()[..len]);
+        let changed = self.v.diff(pristine);
+        let mut ranges = vec![];
+        let mut i = 0;
+        while i < ours.len() {
+            if theirs[i] == ours[i] {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < ours.len() && theirs[i] != ours[i] {
+                i += 1;
+            }
+            ranges.push(start..i);
+        }
+        let (pid, base) = (self.pid, self.base);
+        stopped(pid, self.traced, || {
+            for range in &ranges {
+                poke(pid, base + range.start, &ours[range.clone()])?;
+            }
+            Ok(())
+        })?;
+        self.patches.clear();
+        // Later patches back up the code as it is now
+        self.v = vdso::read_mapping(pid)?.1;
+        Ok(changed)
+    }
+
+    /// Restores every function and lets go of the target.
+    pub fn detach(mut self) -> Result<(), Error> {
+        self.restore_all()
+    }
+
+    /// Lets go of the target, keeping every patch installed for the remainder of its life.
+    pub fn leak(mut self) {
+        self.patches.clear();
+    }
+}
+
+impl Drop for Remote {
+    fn drop(&mut self) {
+        let _ = self.restore_all();
+    }
+}
+
+pub(crate) fn ptrace(
+    request: libc::c_uint,
+    tid: libc::pid_t,
+    addr: usize,
+    data: usize,
+) -> libc::c_long {
+    unsafe {
+        libc::ptrace(
+            request,
+            tid,
+            addr as *mut libc::c_void,
+            data as *mut libc::c_void,
+        )
+    }
+}
+
+/// Runs `f` with every thread of `pid` stopped under ptrace; if `traced`, they already are.
+fn stopped<T>(
+    pid: libc::pid_t,
+    traced: bool,
+    f: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    if traced {
+        return f();
+    }
+    let tasks = fs::read_dir(format!("/proc/{}/task", pid))
+        .map_err(|e| Error::Os("read /proc/pid/task", e.raw_os_error().unwrap_or(0)))?;
+    let tids: Vec<libc::pid_t> = tasks
+        .filter_map(|t| t.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    let mut seized = vec![];
+    let mut res = Ok(());
+    for tid in tids {
+        if ptrace(libc::PTRACE_SEIZE, tid, 0, 0) != 0 {
+            let err = Error::last_os_error("ptrace");
+            // Exited meanwhile
+            if err != Error::Os("ptrace", libc::ESRCH) {
+                res = Err(err);
+                break;
+            }
+            continue;
+        }
+        seized.push(tid);
+        if ptrace(libc::PTRACE_INTERRUPT, tid, 0, 0) != 0
+            || unsafe { libc::waitpid(tid, std::ptr::null_mut(), libc::__WALL) } < 0
+        {
+            res = Err(Error::last_os_error("ptrace"));
+            break;
+        }
+    }
+    let res = res.and_then(|_| f());
+    for tid in seized {
+        ptrace(libc::PTRACE_DETACH, tid, 0, 0);
+    }
+    res
+}
+
+fn peek_word(pid: libc::pid_t, addr: usize) -> Result<[u8; WORD], Error> {
+    unsafe { *libc::__errno_location() = 0 };
+    let val = ptrace(libc::PTRACE_PEEKTEXT, pid, addr, 0);
+    if val == -1 && unsafe { *libc::__errno_location() } != 0 {
+        return Err(Error::last_os_error("ptrace"));
+    }
+    Ok(val.to_ne_bytes())
+}
+
+/// Reads `len` bytes at `addr` of stopped `pid`.
+#[cfg(target_arch = "x86_64")]
+fn peek(pid: libc::pid_t, addr: usize, len: usize) -> Result<Vec<u8>, Error> {
+    let start = addr & !(WORD - 1);
+    let mut data = vec![];
+    for word_addr in (start..addr + len).step_by(WORD) {
+        data.extend(peek_word(pid, word_addr)?);
+    }
+    Ok(data[addr - start..][..len].to_vec())
+}
+
+/// Writes `data` at `addr` of stopped `pid`, a word at a time; the words at either end are
+/// read first to keep the bytes around.
+fn poke(pid: libc::pid_t, addr: usize, data: &[u8]) -> Result<(), Error> {
+    let start = addr & !(WORD - 1);
+    let end = (addr + data.len()).div_ceil(WORD) * WORD;
+    for word_addr in (start..end).step_by(WORD) {
+        let mut word = if word_addr < addr || word_addr + WORD > addr + data.len() {
+            peek_word(pid, word_addr)?
+        } else {
+            [0; WORD]
+        };
+        for (i, b) in word.iter_mut().enumerate() {
+            let at = word_addr + i;
+            if (addr..addr + data.len()).contains(&at) {
+                *b = data[at - addr];
+            }
+        }
+        let val = libc::c_long::from_ne_bytes(word);
+        if ptrace(libc::PTRACE_POKETEXT, pid, word_addr, val as usize) != 0 {
+            return Err(Error::last_os_error("ptrace"));
+        }
+    }
+    Ok(())
+}
+
+/// Maps a page for stubs in stopped `pid`, a 32-bit process if `compat`.
+#[cfg(target_arch = "x86_64")]
+fn map(pid: libc::pid_t, compat: bool) -> Result<usize, Error> {
+    let prot = libc::PROT_READ | libc::PROT_EXEC;
+    let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+    let args = [0, PAGE as u64, prot as u64, flags as u64, u64::MAX, 0];
+    Ok(check("mmap", syscall(pid, compat, libc::SYS_mmap, args)?)? as usize)
+}
+
+/// The value of a syscall that returns `-errno` on failure.
+#[cfg(target_arch = "x86_64")]
+fn check(call: &'static str, ret: i64) -> Result<u64, Error> {
+    if (-4095..0).contains(&ret) {
+        return Err(Error::Os(call, -ret as i32));
+    }
+    Ok(ret as u64)
+}
+
+/// Makes stopped `pid` run a system call, by writing `syscall; int3` where it stopped and
+/// pointing its registers at it; both are put back afterwards. Returns what the kernel did,
+/// `-errno` on failure.
+///
+/// If `compat`, `pid` is a 32-bit process, and runs the i386 syscall numbered like `nr`
+/// through `int 0x80` instead.
+#[cfg(target_arch = "x86_64")]
+fn syscall(pid: libc::pid_t, compat: bool, nr: libc::c_long, args: [u64; 6]) -> Result<i64, Error> {
+    let mut regs = unsafe { std::mem::zeroed::<libc::user_regs_struct>() };
+    let regs_ptr = &mut regs as *mut libc::user_regs_struct as usize;
+    if ptrace(libc::PTRACE_GETREGS, pid, 0, regs_ptr) != 0 {
+        return Err(Error::last_os_error("ptrace"));
+    }
+    let saved = regs;
+    let ip = regs.rip as usize;
+    let code = peek(pid, ip, 3)?;
+    if compat {
+        poke(pid, ip, &[0xcd, 0x80, 0xcc])?;
+        regs.rax = compat_number(nr) as u64;
+        [regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp] = args;
+    } else {
+        poke(pid, ip, &[0x0f, 0x05, 0xcc])?;
+        regs.rax = nr as u64;
+        [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9] = args;
+    }
+    // Keeps the kernel from restarting the syscall that was interrupted, if any
+    regs.orig_rax = u64::MAX;
+
+    let ret = (|| {
+        if ptrace(libc::PTRACE_SETREGS, pid, 0, regs_ptr) != 0 {
+            return Err(Error::last_os_error("ptrace"));
+        }
+        run_to_trap(pid)?;
+        if ptrace(libc::PTRACE_GETREGS, pid, 0, regs_ptr) != 0 {
+            return Err(Error::last_os_error("ptrace"));
+        }
+        if !compat {
+            return Ok(regs.rax as i64);
+        }
+        // Only -errno is negative: addresses may be above 2 GiB
+        let ret = regs.rax as u32;
+        if ret > -4096i32 as u32 {
+            return Ok(ret as i32 as i64);
+        }
+        Ok(ret as i64)
+    })();
+    poke(pid, ip, &code)?;
+    let saved_ptr = &saved as *const libc::user_regs_struct as usize;
+    if ptrace(libc::PTRACE_SETREGS, pid, 0, saved_ptr) != 0 {
+        return Err(Error::last_os_error("ptrace"));
+    }
+    ret
+}
+
+/// The i386 number of `nr`, for the syscalls made in targets.
+#[cfg(target_arch = "x86_64")]
+fn compat_number(nr: libc::c_long) -> libc::c_long {
+    match nr {
+        // mmap2, whose offset is in pages: only 0 is passed
+        libc::SYS_mmap => 192,
+        libc::SYS_openat => 295,
+        libc::SYS_close => 6,
+        _ => unreachable!("no i386 number for syscall {}", nr),
+    }
+}
+
+/// Resumes stopped `pid` until it hits a breakpoint; signals arriving meanwhile are
+/// delivered, and their handlers run.
+#[cfg(target_arch = "x86_64")]
+fn run_to_trap(pid: libc::pid_t) -> Result<(), Error> {
+    let mut signal = 0;
+    loop {
+        if ptrace(libc::PTRACE_CONT, pid, 0, signal as usize) != 0 {
+            return Err(Error::last_os_error("ptrace"));
+        }
+        let mut status = 0;
+        if unsafe { libc::waitpid(pid, &mut status, libc::__WALL) } < 0 {
+            return Err(Error::last_os_error("waitpid"));
+        }
+        if !libc::WIFSTOPPED(status) {
+            return Err(Error::Os("ptrace", libc::ESRCH));
+        }
+        signal = libc::WSTOPSIG(status);
+        if signal == libc::SIGTRAP {
+            return Ok(());
+        }
+        // Group stops and other ptrace events are not signals to deliver
+        if status >> 16 != 0 {
+            signal = 0;
+        }
+    }
+}
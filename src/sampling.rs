@@ -0,0 +1,170 @@
+//! Backtraces of one in every N intercepted calls, counted by call site, to find out where a
+//! program reads the clock so much:
+//!
+//! ```
+//! tpom::sampling::sample_every(100);
+//! // ... run the code under test, with the time functions patched ...
+//! tpom::sampling::sample_every(0);
+//! for site in tpom::sampling::sites().iter().take(3) {
+//!     println!("{} samples:\n{}", site.samples, site.frames.join("\n"));
+//! }
+//! ```
+//!
+//! Capturing and resolving a backtrace takes far longer than reading the clock: keep N
+//! large enough for the program to run at its usual pace.
+use crate::trampolines::ReentrancyGuard;
+use crate::Kind;
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// The frames kept per sample, from the caller of the time function out.
+const DEPTH: usize = 32;
+
+/// Backtraces with the same frames, and how many samples had them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Site {
+    pub kind: Kind,
+    /// Innermost first, as `function at file:line:column` where that is known.
+    pub frames: Vec<String>,
+    pub samples: u64,
+}
+
+/// 0 when off.
+static EVERY: AtomicU64 = AtomicU64::new(0);
+static CALLS: AtomicU64 = AtomicU64::new(0);
+/// The samples by kind, as its index in `KINDS`, and frames.
+pub(crate) type Sites = HashMap<(u8, Vec<String>), u64>;
+
+static SITES: Mutex<Option<Sites>> = Mutex::new(None);
+
+/// Holds the sites, keeping them unchanged; see [`crate::fork`].
+pub(crate) fn lock() -> MutexGuard<'static, Option<Sites>> {
+    SITES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Samples one in every `n` calls from now on; 0 stops sampling. The sites sampled so far
+/// are kept.
+pub fn sample_every(n: u64) {
+    CALLS.store(0, Ordering::Relaxed);
+    EVERY.store(n, Ordering::Relaxed);
+}
+
+/// The sites sampled, the most sampled first.
+pub fn sites() -> Vec<Site> {
+    let sites = lock();
+    let mut sites: Vec<Site> = sites
+        .iter()
+        .flatten()
+        .map(|((kind, frames), samples)| Site {
+            kind: KINDS[*kind as usize],
+            frames: frames.clone(),
+            samples: *samples,
+        })
+        .collect();
+    sites.sort_by(|a, b| b.samples.cmp(&a.samples).then(a.frames.cmp(&b.frames)));
+    sites
+}
+
+/// Forgets the sites sampled.
+pub fn clear() {
+    *lock() = None;
+}
+
+const KINDS: [Kind; 4] = [
+    Kind::GetTime,
+    Kind::Time,
+    Kind::ClockGetRes,
+    Kind::GetTimeOfDay,
+];
+
+/// Counts a call taken by a trampoline, sampling it if its turn has come.
+pub(crate) fn tick(kind: Kind) {
+    let every = EVERY.load(Ordering::Relaxed);
+    if every == 0 || !CALLS.fetch_add(1, Ordering::Relaxed).is_multiple_of(every) {
+        return;
+    }
+    // Resolving symbols may read the time, which is then not sampled
+    let Some(_guard) = ReentrancyGuard::enter("sampling", tick as *const ()) else {
+        return;
+    };
+    let frames = frames(&Backtrace::force_capture().to_string());
+    let kind = KINDS.iter().position(|k| *k == kind).unwrap_or(0) as u8;
+    *lock()
+        .get_or_insert_with(HashMap::new)
+        .entry((kind, frames))
+        .or_default() += 1;
+}
+
+/// The frames of a backtrace as displayed, without tpom's own.
+fn frames(backtrace: &str) -> Vec<String> {
+    let mut frames: Vec<String> = vec![];
+    for line in backtrace.lines().map(str::trim) {
+        match line.split_once(": ") {
+            Some((n, function)) if n.chars().all(|c| c.is_ascii_digit()) => {
+                frames.push(function.to_string())
+            }
+            _ => {
+                if let (Some(frame), Some(at)) = (frames.last_mut(), line.strip_prefix("at ")) {
+                    *frame = format!("{} at {}", frame, at);
+                }
+            }
+        }
+    }
+    frames
+        .into_iter()
+        .skip_while(|f| {
+            f.starts_with("tpom::sampling::tick") || f.starts_with("tpom::trampolines::")
+        })
+        .take(DEPTH)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trampolines;
+
+    #[test]
+    fn test_frames() {
+        let backtrace = "   0: tpom::sampling::tick
+             at ./src/sampling.rs:80:18
+   1: tpom::trampolines::my_time
+   2: app::main
+             at ./src/main.rs:3:5
+   3: std::rt::lang_start";
+        assert_eq!(
+            frames(backtrace),
+            ["app::main at ./src/main.rs:3:5", "std::rt::lang_start"]
+        );
+    }
+
+    fn reads_the_time() {
+        trampolines::my_time(std::ptr::null_mut());
+    }
+
+    #[test]
+    fn test_sampling() {
+        sample_every(1);
+        for _ in 0..3 {
+            reads_the_time();
+        }
+        sample_every(0);
+        reads_the_time();
+        let sites = sites();
+        let site = sites
+            .iter()
+            .find(|s| s.frames.iter().any(|f| f.contains("reads_the_time")))
+            .unwrap();
+        assert_eq!(site.kind, Kind::Time);
+        assert_eq!(site.samples, 3);
+        assert!(
+            site.frames[0].starts_with("tpom::sampling::tests::reads_the_time at "),
+            "{:?}",
+            site.frames
+        );
+        clear();
+        assert!(super::sites().is_empty());
+    }
+}
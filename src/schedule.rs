@@ -0,0 +1,206 @@
+//! Events at moments of the [`crate::live`] clock, for tests that treat it as a small
+//! discrete-event engine: advance the time, and whatever was due by then runs.
+//!
+//! ```no_run
+//! use tpom::live::{self, Command};
+//! use tpom::schedule;
+//!
+//! live::execute(Command::Freeze(Some(1_000_000_000_000))).unwrap();
+//! schedule::at(1_060_000_000_000, || println!("a minute later")).unwrap();
+//! live::execute(Command::Advance(60_000_000_000)).unwrap();
+//! ```
+//!
+//! The events run on a thread of their own, in the order of their times, once the clock
+//! reaches them: shortly after a jump by [`crate::live::execute`], and within [`POLL`] of
+//! any other change, such as from another process sharing the page.
+use crate::control::State;
+use crate::error::Error;
+use crate::live;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+/// The longest the clock is left unread while events are pending.
+pub const POLL: Duration = Duration::from_millis(10);
+
+/// An event, for [`cancel`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventId(u64);
+
+enum Action {
+    Call(Box<dyn FnOnce() + Send>),
+    Wake(Arc<Mutex<Reaching>>),
+}
+
+struct Event {
+    at: i64,
+    id: EventId,
+    action: Action,
+}
+
+/// The state shared by a [`Reached`] and its event.
+#[derive(Default)]
+struct Reaching {
+    done: bool,
+    waker: Option<Waker>,
+}
+
+static EVENTS: Mutex<Vec<Event>> = Mutex::new(vec![]);
+static CHANGED: Condvar = Condvar::new();
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+/// Whether the events' thread is running.
+static STARTED: Mutex<bool> = Mutex::new(false);
+
+/// Runs `f` once the clock reaches `at`, in nanoseconds since the epoch; at once if it is
+/// already past.
+pub fn at(at: i64, f: impl FnOnce() + Send + 'static) -> Result<EventId, Error> {
+    schedule(at, Action::Call(Box::new(f)))
+}
+
+/// Forgets an event that has not run yet; returns whether there was one.
+pub fn cancel(id: EventId) -> bool {
+    let mut events = EVENTS.lock().unwrap();
+    let before = events.len();
+    events.retain(|e| e.id != id);
+    events.len() != before
+}
+
+/// A future resolving once the clock reaches `at`; dropping it cancels the event.
+pub fn reached(at: i64) -> Result<Reached, Error> {
+    let reaching = Arc::new(Mutex::new(Reaching::default()));
+    let id = schedule(at, Action::Wake(reaching.clone()))?;
+    Ok(Reached { id, reaching })
+}
+
+/// The future of [`reached`].
+pub struct Reached {
+    id: EventId,
+    reaching: Arc<Mutex<Reaching>>,
+}
+
+impl Future for Reached {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut reaching = self.reaching.lock().unwrap();
+        if reaching.done {
+            return Poll::Ready(());
+        }
+        reaching.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Reached {
+    fn drop(&mut self) {
+        cancel(self.id);
+    }
+}
+
+/// Wakes the events' thread, to read the clock again after a change.
+pub(crate) fn changed() {
+    CHANGED.notify_all();
+}
+
+fn schedule(at: i64, action: Action) -> Result<EventId, Error> {
+    // The thread can't report the page failing, so that is checked here
+    live::page()?;
+    let mut started = STARTED.lock().unwrap();
+    if !*started {
+        thread::Builder::new()
+            .name("tpom-schedule".to_string())
+            .spawn(run)
+            .map_err(|e| Error::Os("spawn", e.raw_os_error().unwrap_or(0)))?;
+        *started = true;
+    }
+    drop(started);
+    let id = EventId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    EVENTS.lock().unwrap().push(Event { at, id, action });
+    changed();
+    Ok(id)
+}
+
+fn run() {
+    let mut events = EVENTS.lock().unwrap();
+    loop {
+        let Ok(state) = live::execute(live::Command::Status) else {
+            return;
+        };
+        let (mut due, pending): (Vec<Event>, Vec<Event>) =
+            events.drain(..).partition(|e| e.at <= state.now);
+        *events = pending;
+        if !due.is_empty() {
+            // Outside the lock, so that the events can schedule others
+            drop(events);
+            due.sort_by_key(|e| (e.at, e.id.0));
+            for event in due {
+                fire(event);
+            }
+            events = EVENTS.lock().unwrap();
+            continue;
+        }
+        let next = events.iter().map(|e| e.at).min();
+        events = match next {
+            Some(next) => CHANGED.wait_timeout(events, wait(next, &state)).unwrap().0,
+            None => CHANGED.wait(events).unwrap(),
+        };
+    }
+}
+
+fn fire(event: Event) {
+    match event.action {
+        Action::Call(f) => {
+            if std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).is_err() {
+                log::error!(
+                    "The event at {} panicked",
+                    live::format_seconds(event.at as i128)
+                );
+            }
+        }
+        Action::Wake(reaching) => {
+            let mut reaching = reaching.lock().unwrap();
+            reaching.done = true;
+            if let Some(waker) = reaching.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// How long until the clock, as of `state`, reaches `next`, up to [`POLL`].
+fn wait(next: i64, state: &State) -> Duration {
+    if state.frozen || state.speed == 0.0 {
+        return POLL;
+    }
+    let real = (next - state.now) as f64 / state.speed;
+    Duration::from_nanos(real.ceil() as u64).min(POLL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wait() {
+        let state = |frozen, speed| State {
+            now: 1_000_000,
+            frozen,
+            speed,
+        };
+        assert_eq!(
+            wait(2_000_000, &state(false, 1.0)),
+            Duration::from_millis(1)
+        );
+        assert_eq!(
+            wait(2_000_000, &state(false, 4.0)),
+            Duration::from_micros(250)
+        );
+        assert_eq!(wait(i64::MAX, &state(false, 1.0)), POLL);
+        assert_eq!(wait(2_000_000, &state(true, 1.0)), POLL);
+        assert_eq!(wait(2_000_000, &state(false, 0.0)), POLL);
+    }
+}
@@ -0,0 +1,500 @@
+//! Answers the clock syscalls from the registered callbacks, for the reads the vDSO doesn't
+//! serve itself (unsupported clock ids, clocksources not readable from userspace) and for
+//! code that makes the syscalls directly.
+//!
+//! The syscalls are trapped with a seccomp filter returning `SECCOMP_RET_USER_NOTIF`, and
+//! answered by a supervisor thread. Syscalls whose vDSO function is not patched go through
+//! to the kernel unchanged. Sleeps are trapped too, for [`crate::virtual_time`].
+use crate::error::Error;
+use crate::registry;
+use crate::strict::{self, Reason};
+use crate::trampolines::{
+    callback, is_cpu_clock, raw_syscall_address, run_callback, CLOCK_GTOD_CB, CLOCK_GT_CB,
+    CPU_CLOCK_CB,
+};
+use crate::virtual_time::{self, Setting, TimerId};
+use crate::{ClockGetTimeCb, Kind};
+use std::sync::{mpsc, RwLock};
+use std::thread;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(target_arch = "riscv64")]
+const AUDIT_ARCH: u32 = 0xc000_00f3;
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+
+/// `clock_nanosleep` flag for an absolute deadline.
+const TIMER_ABSTIME: libc::c_int = 1;
+
+// Offsets in `struct seccomp_data`; the instruction pointer's low half first, as every
+// supported architecture is little-endian
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+const IP_OFFSET: u32 = 8;
+
+/// The syscalls answered from the callbacks, or skipped or kept in virtual time. Not every
+/// architecture has `time` and `nanosleep`.
+fn trapped() -> Vec<libc::c_long> {
+    let mut nrs = vec![
+        libc::SYS_clock_gettime,
+        libc::SYS_gettimeofday,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_setitimer,
+        libc::SYS_getitimer,
+        libc::SYS_timer_create,
+        libc::SYS_timer_settime,
+        libc::SYS_timer_gettime,
+        libc::SYS_timer_getoverrun,
+        libc::SYS_timer_delete,
+        libc::SYS_adjtimex,
+        libc::SYS_clock_adjtime,
+    ];
+    #[cfg(target_arch = "x86_64")]
+    nrs.extend([libc::SYS_time, libc::SYS_nanosleep, libc::SYS_alarm]);
+    nrs
+}
+
+fn stmt(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+/// Notifies for the trapped syscalls of our own architecture, allows everything else, and
+/// tpom's own syscalls.
+fn filter() -> Vec<libc::sock_filter> {
+    let nrs = trapped();
+    let n = nrs.len() as u8;
+    let ip = raw_syscall_address() as u64;
+    // Jumps are relative to the next instruction; "allow" is at 7 + n, "notify" after it
+    let mut prog = vec![
+        stmt(BPF_LD_W_ABS, ARCH_OFFSET),
+        jump(BPF_JEQ_K, AUDIT_ARCH, 0, n + 5),
+        stmt(BPF_LD_W_ABS, IP_OFFSET),
+        jump(BPF_JEQ_K, ip as u32, 0, 2),
+        stmt(BPF_LD_W_ABS, IP_OFFSET + 4),
+        jump(BPF_JEQ_K, (ip >> 32) as u32, n + 1, 0),
+        stmt(BPF_LD_W_ABS, NR_OFFSET),
+    ];
+    for (i, nr) in nrs.iter().enumerate() {
+        prog.push(jump(BPF_JEQ_K, *nr as u32, n - i as u8, 0));
+    }
+    prog.push(stmt(BPF_RET_K, libc::SECCOMP_RET_ALLOW));
+    prog.push(stmt(BPF_RET_K, libc::SECCOMP_RET_USER_NOTIF));
+    prog
+}
+
+/// Traps the clock syscalls of the calling thread, and of the threads it creates from now
+/// on, answering them from the callbacks installed by patching the vDSO.
+///
+/// Seccomp filters can't be removed: this lasts for the lifetime of the thread. Threads
+/// that already exist are not affected, so call this early, from the main thread.
+pub fn install() -> Result<(), Error> {
+    // The supervisor must exist before the filter, or its own syscalls would be trapped
+    let (tx, rx) = mpsc::channel::<libc::c_int>();
+    thread::Builder::new()
+        .name("tpom-seccomp".to_string())
+        .spawn(move || {
+            if let Ok(fd) = rx.recv() {
+                supervise(fd);
+            }
+        })
+        .map_err(|e| Error::Os("spawn", e.raw_os_error().unwrap_or(0)))?;
+
+    let mut prog = filter();
+    let fprog = libc::sock_fprog {
+        len: prog.len() as u16,
+        filter: prog.as_mut_ptr(),
+    };
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(Error::last_os_error("prctl"));
+    }
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_NEW_LISTENER,
+            &fprog as *const libc::sock_fprog,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::last_os_error("seccomp"));
+    }
+    // The supervisor only stops with the process
+    let _ = tx.send(fd as libc::c_int);
+    Ok(())
+}
+
+/// What `adjtimex(2)` reports for a clock.
+pub struct ClockStatus {
+    /// The return value: `TIME_OK`, `TIME_INS`, ..., `TIME_ERROR`.
+    pub state: i32,
+    pub timex: libc::timex,
+}
+
+/// Considered infallible
+pub type AdjtimexCb = fn(clockid: i32) -> ClockStatus;
+
+static ADJTIMEX_CB: RwLock<Option<AdjtimexCb>> = RwLock::new(None);
+
+/// Answers `adjtimex` and `clock_adjtime` from `cb`, or from the kernel again with `None`,
+/// to feed NTP-aware code a sync status, frequency or error estimate. Adjustments requested
+/// meanwhile are not applied; the call reports the mocked status as if they were.
+pub fn mock_adjtimex(cb: Option<AdjtimexCb>) {
+    *ADJTIMEX_CB.write().unwrap() = cb;
+}
+
+/// Answers `clock_gettime` on the CPU-time clocks (`CLOCK_PROCESS_CPUTIME_ID`,
+/// `CLOCK_THREAD_CPUTIME_ID` and those from `clock_getcpuclockid(3)`) from `cb`, or from the
+/// kernel again with `None`. The vDSO never serves these clocks, so this needs [`install`],
+/// but not a patched `GetTime`; where it is patched, `cb` also answers them there.
+pub fn mock_cpu_clocks(cb: Option<ClockGetTimeCb>) {
+    *CPU_CLOCK_CB.write().unwrap() = cb;
+}
+
+fn supervise(fd: libc::c_int) {
+    loop {
+        let mut req: libc::seccomp_notif = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(fd, libc::SECCOMP_IOCTL_NOTIF_RECV, &mut req) } != 0 {
+            if std::io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) {
+                continue;
+            }
+            log::error!(
+                "seccomp supervisor stopping: {}",
+                std::io::Error::last_os_error()
+            );
+            return;
+        }
+        let mut resp = match answer(&req.data) {
+            Some(Ok(val)) => libc::seccomp_notif_resp {
+                id: req.id,
+                val,
+                error: 0,
+                flags: 0,
+            },
+            Some(Err(errno)) => libc::seccomp_notif_resp {
+                id: req.id,
+                val: 0,
+                error: -errno,
+                flags: 0,
+            },
+            None => libc::seccomp_notif_resp {
+                id: req.id,
+                val: 0,
+                error: 0,
+                flags: libc::SECCOMP_USER_NOTIF_FLAG_CONTINUE as u32,
+            },
+        };
+        // Fails if the trapped thread was interrupted meanwhile, which is fine
+        unsafe { libc::ioctl(fd, libc::SECCOMP_IOCTL_NOTIF_SEND, &mut resp) };
+    }
+}
+
+/// Writes `val` at `addr` of our own address space, which the trapped thread may have
+/// passed as garbage: failing with EFAULT, like the kernel would, rather than crashing.
+fn write_out<T>(addr: u64, val: &T) -> Result<(), i32> {
+    let local = libc::iovec {
+        iov_base: val as *const T as *mut libc::c_void,
+        iov_len: std::mem::size_of::<T>(),
+    };
+    let remote = libc::iovec {
+        iov_base: addr as *mut libc::c_void,
+        iov_len: std::mem::size_of::<T>(),
+    };
+    let written = unsafe { libc::process_vm_writev(libc::getpid(), &local, 1, &remote, 1, 0) };
+    if written != std::mem::size_of::<T>() as isize {
+        return Err(libc::EFAULT);
+    }
+    Ok(())
+}
+
+/// Reads a `T` the trapped thread passed at `addr`; see [`write_out`].
+fn read_in<T>(addr: u64) -> Result<T, i32> {
+    let mut val = std::mem::MaybeUninit::<T>::uninit();
+    let local = libc::iovec {
+        iov_base: val.as_mut_ptr() as *mut libc::c_void,
+        iov_len: std::mem::size_of::<T>(),
+    };
+    let remote = libc::iovec {
+        iov_base: addr as *mut libc::c_void,
+        iov_len: std::mem::size_of::<T>(),
+    };
+    let read = unsafe { libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0) };
+    if read != std::mem::size_of::<T>() as isize {
+        return Err(libc::EFAULT);
+    }
+    Ok(unsafe { val.assume_init() })
+}
+
+/// Skips a sleep for `req`, if virtual time is enabled.
+fn sleep(clockid: libc::clockid_t, absolute: bool, req: u64) -> Option<Result<i64, i32>> {
+    if !virtual_time::is_enabled() {
+        return None;
+    }
+    let ts = match read_in::<libc::timespec>(req) {
+        Ok(ts) => ts,
+        Err(errno) => return Some(Err(errno)),
+    };
+    if ts.tv_sec < 0 || !(0..1_000_000_000).contains(&ts.tv_nsec) {
+        return Some(Err(libc::EINVAL));
+    }
+    let nanos = ts.tv_sec as i128 * 1_000_000_000 + ts.tv_nsec as i128;
+    virtual_time::sleep(clockid, absolute, nanos).then_some(Ok(0))
+}
+
+fn nanos(tv_sec: libc::time_t, sub: i64, unit: i64) -> i128 {
+    tv_sec as i128 * 1_000_000_000 + (sub * unit) as i128
+}
+
+fn timespec(nanos: i128) -> libc::timespec {
+    libc::timespec {
+        tv_sec: (nanos / 1_000_000_000) as libc::time_t,
+        tv_nsec: (nanos % 1_000_000_000) as i64,
+    }
+}
+
+fn timeval(nanos: i128) -> libc::timeval {
+    // Rounded up, so that a pending timer doesn't read as disarmed
+    let micros = (nanos + 999) / 1000;
+    libc::timeval {
+        tv_sec: (micros / 1_000_000) as libc::time_t,
+        tv_usec: (micros % 1_000_000) as libc::suseconds_t,
+    }
+}
+
+/// Writes the previous setting of an interval timer, if asked for.
+fn write_itimerval(addr: u64, setting: Setting) -> Result<i64, i32> {
+    if addr != 0 {
+        let old = libc::itimerval {
+            it_interval: timeval(setting.interval),
+            it_value: timeval(setting.value),
+        };
+        write_out(addr, &old)?;
+    }
+    Ok(0)
+}
+
+fn write_itimerspec(addr: u64, setting: Setting) -> Result<i64, i32> {
+    if addr != 0 {
+        let old = libc::itimerspec {
+            it_interval: timespec(setting.interval),
+            it_value: timespec(setting.value),
+        };
+        write_out(addr, &old)?;
+    }
+    Ok(0)
+}
+
+/// Keeps the real-time interval timers and the POSIX timers on the shifted clocks, while
+/// virtual time is enabled; timers it created are handled until deleted.
+fn answer_timer(nr: libc::c_long, args: &[u64; 6]) -> Option<Result<i64, i32>> {
+    #[cfg(target_arch = "x86_64")]
+    if nr == libc::SYS_alarm && virtual_time::is_enabled() {
+        let setting = Setting {
+            value: args[0] as u32 as i128 * 1_000_000_000,
+            interval: 0,
+        };
+        let previous = virtual_time::timer_set(TimerId::Alarm, false, setting);
+        // Rounded to the nearest second, but pending alarms don't read as none
+        let secs = (previous.value + 500_000_000) / 1_000_000_000;
+        return Some(Ok(if previous.value > 0 { secs.max(1) } else { 0 } as i64));
+    }
+    let is_real = args[0] as libc::c_int == libc::ITIMER_REAL;
+    if nr == libc::SYS_setitimer && is_real && virtual_time::is_enabled() {
+        let new = match args[1] {
+            0 => Setting::default(),
+            addr => match read_in::<libc::itimerval>(addr) {
+                Ok(new) => Setting {
+                    value: nanos(new.it_value.tv_sec, new.it_value.tv_usec, 1000),
+                    interval: nanos(new.it_interval.tv_sec, new.it_interval.tv_usec, 1000),
+                },
+                Err(errno) => return Some(Err(errno)),
+            },
+        };
+        let previous = virtual_time::timer_set(TimerId::Alarm, false, new);
+        return Some(write_itimerval(args[2], previous));
+    }
+    if nr == libc::SYS_getitimer && is_real && virtual_time::is_enabled() {
+        return Some(write_itimerval(
+            args[1],
+            virtual_time::timer_get(TimerId::Alarm),
+        ));
+    }
+    if nr == libc::SYS_timer_create && virtual_time::is_enabled() {
+        let clockid = args[0] as libc::clockid_t;
+        if !matches!(
+            clockid,
+            libc::CLOCK_REALTIME | libc::CLOCK_MONOTONIC | libc::CLOCK_BOOTTIME
+        ) {
+            return None;
+        }
+        let ev = match args[1] {
+            0 => None,
+            addr => match read_in::<libc::sigevent>(addr) {
+                Ok(ev) => Some(ev),
+                Err(errno) => return Some(Err(errno)),
+            },
+        };
+        return Some(
+            virtual_time::timer_create(clockid, ev.as_ref()).and_then(|id| {
+                write_out(args[2], &id).inspect_err(|_| virtual_time::timer_delete(id))?;
+                Ok(0)
+            }),
+        );
+    }
+    let id = args[0] as libc::c_int;
+    let posix = [
+        libc::SYS_timer_settime,
+        libc::SYS_timer_gettime,
+        libc::SYS_timer_getoverrun,
+        libc::SYS_timer_delete,
+        libc::SYS_adjtimex,
+        libc::SYS_clock_adjtime,
+    ];
+    if !posix.contains(&nr) || !virtual_time::is_virtual_timer(id) {
+        return None;
+    }
+    if nr == libc::SYS_timer_settime {
+        let new = match read_in::<libc::itimerspec>(args[2]) {
+            Ok(new) => new,
+            Err(errno) => return Some(Err(errno)),
+        };
+        let setting = Setting {
+            value: nanos(new.it_value.tv_sec, new.it_value.tv_nsec, 1),
+            interval: nanos(new.it_interval.tv_sec, new.it_interval.tv_nsec, 1),
+        };
+        let absolute = args[1] as libc::c_int & TIMER_ABSTIME != 0;
+        let previous = virtual_time::timer_set(TimerId::Posix(id), absolute, setting);
+        return Some(write_itimerspec(args[3], previous));
+    }
+    if nr == libc::SYS_timer_gettime {
+        return Some(write_itimerspec(
+            args[1],
+            virtual_time::timer_get(TimerId::Posix(id)),
+        ));
+    }
+    if nr == libc::SYS_timer_getoverrun {
+        return Some(Ok(virtual_time::timer_getoverrun(id) as i64));
+    }
+    virtual_time::timer_delete(id);
+    Some(Ok(0))
+}
+
+/// The callback in `lock`, if its function is patched; a restored function's callback stays
+/// set.
+fn live<T: Copy>(kind: Kind, lock: &RwLock<Option<T>>) -> Option<T> {
+    if !registry::kind_patched(kind) {
+        return None;
+    }
+    callback(lock)
+}
+
+/// The syscall's return value or errno; `None` to let it through to the kernel.
+fn answer(data: &libc::seccomp_data) -> Option<Result<i64, i32>> {
+    let nr = data.nr as libc::c_long;
+    if nr == libc::SYS_clock_gettime {
+        let clockid = data.args[0] as libc::clockid_t;
+        let cpu = callback(&CPU_CLOCK_CB).filter(|_| is_cpu_clock(clockid));
+        let Some(cb) = cpu.or_else(|| live(Kind::GetTime, &CLOCK_GT_CB)) else {
+            strict::escaped("clock_gettime", Some(clockid), Reason::Unmocked);
+            return None;
+        };
+        let res = run_callback("clock_gettime", cb as *const (), || cb(clockid))?;
+        let ts = libc::timespec {
+            tv_sec: res.seconds,
+            tv_nsec: res.nanos,
+        };
+        return Some(write_out(data.args[1], &ts).map(|_| 0));
+    }
+    if nr == libc::SYS_gettimeofday {
+        let Some(cb) = live(Kind::GetTimeOfDay, &CLOCK_GTOD_CB) else {
+            strict::escaped("gettimeofday", None, Reason::Unmocked);
+            return None;
+        };
+        let res = run_callback("gettimeofday", cb as *const (), cb)?;
+        if data.args[0] == 0 {
+            return Some(Ok(0));
+        }
+        let tv = libc::timeval {
+            tv_sec: res.seconds,
+            tv_usec: res.micros,
+        };
+        return Some(write_out(data.args[0], &tv).map(|_| 0));
+    }
+    if nr == libc::SYS_clock_nanosleep {
+        let absolute = data.args[1] as libc::c_int & TIMER_ABSTIME != 0;
+        return sleep(data.args[0] as libc::clockid_t, absolute, data.args[2]);
+    }
+    #[cfg(target_arch = "x86_64")]
+    if nr == libc::SYS_nanosleep {
+        return sleep(libc::CLOCK_MONOTONIC, false, data.args[0]);
+    }
+    if nr == libc::SYS_adjtimex || nr == libc::SYS_clock_adjtime {
+        let cb = callback(&ADJTIMEX_CB)?;
+        let (clockid, buf) = if nr == libc::SYS_adjtimex {
+            (libc::CLOCK_REALTIME, data.args[0])
+        } else {
+            (data.args[0] as libc::clockid_t, data.args[1])
+        };
+        // The kernel would fail on a bad buffer before anything else
+        if let Err(errno) = read_in::<libc::timex>(buf) {
+            return Some(Err(errno));
+        }
+        let status = run_callback("adjtimex", cb as *const (), || cb(clockid))?;
+        return Some(write_out(buf, &status.timex).map(|_| status.state as i64));
+    }
+    if let Some(res) = answer_timer(nr, &data.args) {
+        return Some(res);
+    }
+    #[cfg(target_arch = "x86_64")]
+    if nr == libc::SYS_time {
+        let Some(cb) = live(Kind::Time, &crate::trampolines::TIME_CB) else {
+            strict::escaped("time", None, Reason::Unmocked);
+            return None;
+        };
+        let res = run_callback("time", cb as *const (), cb)?;
+        if data.args[0] != 0 {
+            if let Err(errno) = write_out(data.args[0], &res) {
+                return Some(Err(errno));
+            }
+        }
+        return Some(Ok(res));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_layout() {
+        let prog = filter();
+        let n = trapped().len();
+        let (nr, allow, notify) = (6, 7 + n, 8 + n);
+        assert_eq!(prog.len(), notify + 1);
+        assert_eq!(prog[allow].k, libc::SECCOMP_RET_ALLOW);
+        assert_eq!(prog[notify].k, libc::SECCOMP_RET_USER_NOTIF);
+        assert_eq!(2 + prog[1].jf as usize, allow);
+        // A different instruction pointer goes on to the syscall number
+        assert_eq!(4 + prog[3].jf as usize, nr);
+        assert_eq!(6 + prog[5].jt as usize, allow);
+        assert_eq!(6 + prog[5].jf as usize, nr);
+        for i in 0..n {
+            let at = nr + 1 + i;
+            assert_eq!(at + 1 + prog[at].jt as usize, notify);
+        }
+    }
+}
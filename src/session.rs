@@ -0,0 +1,340 @@
+use crate::got::{self, GotPatch};
+use crate::vdso::vDSO;
+use crate::{opcodes, registry};
+use crate::{BackupEntry, Callback, Error, Kind, TVDSOFun};
+use std::fmt;
+
+/// Where a [`Session`] installs its callbacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// Overwrites the vDSO functions; intercepts every caller of the vDSO.
+    #[default]
+    Vdso,
+    /// Rewrites the GOT entries of the loaded objects for the libc functions; for libcs
+    /// that don't call through the vDSO. Not restored by [`crate::restore_on_panic`].
+    Got,
+}
+
+enum Patch {
+    Vdso(BackupEntry),
+    Got(GotPatch),
+}
+
+impl Patch {
+    fn name(&self) -> &str {
+        match self {
+            Patch::Vdso(backup) => &backup.v.name,
+            Patch::Got(patch) => patch.name,
+        }
+    }
+
+    #[track_caller]
+    fn restore(&self) -> Result<(), Error> {
+        match self {
+            Patch::Vdso(backup) => backup.restore(),
+            Patch::Got(patch) => patch.restore(),
+        }
+    }
+}
+
+/// Owns every patch applied through it; all of them are restored on [`Session::restore_all`]
+/// or when the session is dropped.
+///
+/// ```
+/// use tpom::*;
+///
+/// fn myclock(_clockid: i32) -> TimeSpec {
+///     TimeSpec {
+///         seconds: 111,
+///         nanos: 333,
+///     }
+/// }
+///
+/// let v = vdso::vDSO::read().unwrap();
+/// let mut session = Session::new(&v);
+/// session.overwrite(Callback::GetTime(myclock)).unwrap();
+/// assert_eq!(std::time::SystemTime::now(), std::time::SystemTime::now());
+/// session.restore_all().unwrap();
+/// ```
+pub struct Session {
+    v: vDSO,
+    backend: Backend,
+    patches: Vec<Patch>,
+}
+
+impl Session {
+    pub fn new(v: &vDSO) -> Session {
+        Session::with_backend(v, Backend::Vdso)
+    }
+
+    pub fn with_backend(v: &vDSO, backend: Backend) -> Session {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(?backend, "new session");
+        Session {
+            v: v.clone(),
+            backend,
+            patches: vec![],
+        }
+    }
+
+    /// Replaces the function matching the callback's [`crate::Kind`].
+    #[track_caller]
+    pub fn overwrite(&mut self, cb: Callback) -> Result<(), Error> {
+        let patch = match self.backend {
+            Backend::Vdso => {
+                let entry = self.v.entry(cb.kind()).ok_or(Error::NotFound(cb.kind()))?;
+                Patch::Vdso(entry.overwrite_with(cb)?)
+            }
+            Backend::Got => Patch::Got(got::redirect(cb.kind(), cb.install())?),
+        };
+        self.patches.push(patch);
+        Ok(())
+    }
+
+    /// Replaces every function in `cbs`, or none of them: if one fails, those already
+    /// replaced by this call are restored before returning the error.
+    #[track_caller]
+    pub fn apply_all(&mut self, cbs: &[Callback]) -> Result<(), Error> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("apply_all", backend = ?self.backend).entered();
+        let applied = self.patches.len();
+        for cb in cbs {
+            if let Err(e) = self.overwrite(*cb) {
+                for patch in self.patches.drain(applied..).rev() {
+                    if let Err(e) = patch.restore() {
+                        log::error!("Could not roll back {}: {}", patch.name(), e);
+                    }
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps every patch installed for the remainder of the process; see
+    /// [`BackupEntry::leak`].
+    pub fn leak(mut self) {
+        for patch in self.patches.drain(..) {
+            // GOT patches stay installed unless restored
+            if let Patch::Vdso(backup) = patch {
+                backup.leak();
+            }
+        }
+    }
+
+    /// Restores every patch, most recent first. Keeps going if one fails, returning the
+    /// first error.
+    #[track_caller]
+    pub fn restore_all(&mut self) -> Result<(), Error> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("restore_all", backend = ?self.backend).entered();
+        let mut res = Ok(());
+        while let Some(patch) = self.patches.pop() {
+            if let Err(e) = patch.restore() {
+                log::error!("Could not restore {}: {}", patch.name(), e);
+                res = res.and(Err(e));
+            }
+        }
+        res
+    }
+}
+
+/// What [`Session::plan`] found a patch would do.
+#[derive(Debug, PartialEq)]
+pub struct Plan {
+    pub backend: Backend,
+    /// One per callback, in order.
+    pub steps: Vec<Step>,
+}
+
+impl Plan {
+    /// Whether [`Session::apply_all`] would succeed, unless the process changes meanwhile.
+    pub fn is_ok(&self) -> bool {
+        self.steps.iter().all(|s| s.error.is_none())
+    }
+}
+
+/// How one callback would be installed.
+#[derive(Debug, PartialEq)]
+pub struct Step {
+    pub kind: Kind,
+    /// The vDSO symbol or libc function replaced, if there is one.
+    pub symbol: Option<String>,
+    /// Where the bytes would be written: the function in the vDSO, or every GOT entry for
+    /// the libc function.
+    pub addresses: Vec<usize>,
+    /// The bytes overwritten at each address: the function's padded size, or a pointer.
+    pub size: usize,
+    /// The bytes the jump, or the pointer, needs.
+    pub needed: usize,
+    /// Why the patch would fail.
+    pub error: Option<Error>,
+}
+
+impl Session {
+    /// Works out what [`Session::apply_all`] would do with `cbs`, without writing anything
+    /// nor installing any callback.
+    pub fn plan(&self, cbs: &[Callback]) -> Plan {
+        let mut seen: Vec<Kind> = vec![];
+        let steps = cbs
+            .iter()
+            .map(|cb| {
+                let kind = cb.kind();
+                let mut step = match self.backend {
+                    Backend::Vdso => self.plan_vdso(kind),
+                    Backend::Got => plan_got(kind),
+                };
+                if step.error.is_none() && seen.contains(&kind) {
+                    let name = step.symbol.clone().unwrap_or_default();
+                    step.error = Some(Error::AlreadyPatched(name));
+                }
+                seen.push(kind);
+                step
+            })
+            .collect();
+        Plan {
+            backend: self.backend,
+            steps,
+        }
+    }
+
+    fn plan_vdso(&self, kind: Kind) -> Step {
+        let needed = opcodes::generate_opcodes(0, 0).len();
+        let Some(entry) = self.v.entry(kind) else {
+            return Step {
+                kind,
+                symbol: None,
+                addresses: vec![],
+                size: 0,
+                needed,
+                error: Some(Error::NotFound(kind)),
+            };
+        };
+        // In the order overwrite_with checks them
+        let error = match (self.v.is_live(), entry.patchable()) {
+            (false, _) => Some(Error::Offline),
+            (true, Err(e)) => Some(e),
+            (true, Ok(())) if registry::is_patched(entry.abs_addr()) => {
+                Some(Error::AlreadyPatched(entry.name.clone()))
+            }
+            (true, Ok(())) => None,
+        };
+        Step {
+            kind,
+            addresses: vec![entry.abs_addr()],
+            size: entry.size,
+            needed,
+            symbol: Some(entry.name),
+            error,
+        }
+    }
+}
+
+fn plan_got(kind: Kind) -> Step {
+    let name = got::symbol_name(kind);
+    let addresses = got::find_slots(name);
+    let error = if got::is_redirected(kind) {
+        Some(Error::AlreadyPatched(name.to_string()))
+    } else if addresses.is_empty() {
+        Some(Error::NotFound(kind))
+    } else {
+        None
+    };
+    Step {
+        kind,
+        symbol: Some(name.to_string()),
+        addresses,
+        size: std::mem::size_of::<usize>(),
+        needed: std::mem::size_of::<usize>(),
+        error,
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Plan, with the {:?} backend:", self.backend)?;
+        for step in &self.steps {
+            let at = match step.addresses.as_slice() {
+                [] => "-".to_string(),
+                [addr] => format!("{:#x}", addr),
+                [addr, more @ ..] => format!("{:#x} and {} more", addr, more.len()),
+            };
+            let outcome = match &step.error {
+                Some(e) => format!("fails: {}", e),
+                None => format!("writes {} of {} bytes", step.needed, step.size),
+            };
+            write!(
+                f,
+                "\n  {:<14} {:<28} {:<24} {}",
+                format!("{:?}", step.kind),
+                step.symbol.as_deref().unwrap_or("-"),
+                at,
+                outcome
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = self.restore_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TimeSpec;
+
+    fn myclock(_clockid: i32) -> TimeSpec {
+        TimeSpec {
+            seconds: 111,
+            nanos: 333,
+        }
+    }
+
+    #[test]
+    fn test_plan() {
+        let v = vDSO::read().unwrap();
+        let session = Session::new(&v);
+        let cbs = [Callback::GetTime(myclock), Callback::GetTime(myclock)];
+        let plan = session.plan(&cbs);
+        assert!(!plan.is_ok());
+        let [first, second] = &plan.steps[..] else {
+            panic!("{:?}", plan);
+        };
+        let entry = v.entry(Kind::GetTime).unwrap();
+        assert_eq!(first.symbol.as_deref(), Some(entry.name.as_str()));
+        assert_eq!(first.addresses, [entry.abs_addr()]);
+        assert_eq!(first.size, entry.size);
+        assert!(first.needed <= first.size);
+        assert_eq!(first.error, None);
+        assert_eq!(
+            second.error,
+            Some(Error::AlreadyPatched(entry.name.clone()))
+        );
+        assert!(session.plan(&cbs[..1]).is_ok());
+        let text = plan.to_string();
+        assert!(
+            text.starts_with("Plan, with the Vdso backend:\n  GetTime "),
+            "{}",
+            text
+        );
+        assert!(text.contains("fails: symbol"), "{}", text);
+
+        let image = std::fs::read("src/test_files/test_vdso_elf_1").unwrap();
+        let offline = Session::new(&vDSO::from_bytes(&image).unwrap());
+        let plan = offline.plan(&cbs[..1]);
+        assert_eq!(plan.steps[0].error, Some(Error::Offline));
+        assert_eq!(
+            plan.steps[0].symbol.as_deref(),
+            Some("__vdso_clock_gettime")
+        );
+
+        let got = Session::with_backend(&v, Backend::Got).plan(&cbs[..1]);
+        assert_eq!(got.steps[0].symbol.as_deref(), Some("clock_gettime"));
+        assert!(got.is_ok(), "{}", got);
+        assert!(!got.steps[0].addresses.is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+//! Steers the [`crate::live`] clock with signals, for poking a long-running process by
+//! hand, with no IPC to set up:
+//!
+//! ```no_run
+//! use tpom::live::Command;
+//! use tpom::signals;
+//!
+//! // `kill -USR1 <pid>` moves the time an hour ahead, `kill -USR2 <pid>` back to the real one
+//! signals::on_signal(libc::SIGUSR1, Command::Advance(3_600_000_000_000)).unwrap();
+//! signals::on_signal(libc::SIGUSR2, Command::Restore).unwrap();
+//! ```
+//!
+//! Daemons can also re-read their [`crate::config`] file on `SIGHUP`, with
+//! [`reload_on_hangup`].
+//!
+//! The commands run on a thread of their own, shortly after the signal: the handler only
+//! writes the signal number to a pipe, as taking the clock's locks there could deadlock.
+use crate::config;
+use crate::control::State;
+use crate::error::Error;
+use crate::live::{self, Command};
+use std::collections::HashMap;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// What a signal does.
+#[derive(Debug, Clone)]
+enum Action {
+    Run(Command),
+    Load(PathBuf),
+}
+
+static ACTIONS: Mutex<Option<HashMap<i32, Action>>> = Mutex::new(None);
+/// The end of the pipe the handler writes to, once created.
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Runs `command` whenever the process receives `signal`, replacing its handler and any
+/// command set for it before.
+pub fn on_signal(signal: i32, command: Command) -> Result<(), Error> {
+    handle(signal, Action::Run(command))
+}
+
+/// Loads the configuration file at `path`, then loads it again, replacing the whole clock
+/// at once, whenever the process receives `SIGHUP`. A file that fails to load then leaves
+/// the clock as it was.
+pub fn reload_on_hangup(path: impl AsRef<Path>) -> Result<State, Error> {
+    let path = path.as_ref().to_path_buf();
+    let state = config::load(&path)?;
+    handle(libc::SIGHUP, Action::Load(path))?;
+    Ok(state)
+}
+
+fn handle(signal: i32, action: Action) -> Result<(), Error> {
+    let mut actions = ACTIONS.lock().unwrap();
+    if actions.is_none() {
+        start()?;
+    }
+    actions
+        .get_or_insert_with(HashMap::new)
+        .insert(signal, action);
+
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = handler as *const () as usize;
+    action.sa_flags = libc::SA_RESTART;
+    if unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) } != 0 {
+        return Err(Error::last_os_error("sigaction"));
+    }
+    Ok(())
+}
+
+/// Creates the pipe, and the thread running the actions of the signals it gets.
+fn start() -> Result<(), Error> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(Error::last_os_error("pipe2"));
+    }
+    let reader = unsafe { OwnedFd::from_raw_fd(fds[0]) };
+    thread::Builder::new()
+        .name("tpom-signals".to_string())
+        .spawn(move || run(reader))
+        .map_err(|e| Error::Os("spawn", e.raw_os_error().unwrap_or(0)))?;
+    PIPE.store(fds[1], Ordering::Relaxed);
+    Ok(())
+}
+
+fn run(reader: OwnedFd) {
+    let mut signal = [0u8; 4];
+    loop {
+        let n = unsafe {
+            libc::read(
+                reader.as_raw_fd(),
+                signal.as_mut_ptr() as *mut libc::c_void,
+                signal.len(),
+            )
+        };
+        if n < 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) {
+            continue;
+        }
+        if n != signal.len() as isize {
+            log::error!("Could not read the signals received, no longer handling them");
+            return;
+        }
+        let signal = i32::from_ne_bytes(signal);
+        let action = ACTIONS
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|a| a.get(&signal).cloned());
+        match action {
+            Some(Action::Run(command)) => {
+                if let Err(e) = live::execute(command) {
+                    log::error!("Could not run {:?} for signal {}: {}", command, signal, e);
+                }
+            }
+            Some(Action::Load(path)) => match config::load(&path) {
+                Ok(state) => log::info!("Reloaded {}: {}", path.display(), state),
+                Err(e) => log::error!("Not reloading {}: {}", path.display(), e),
+            },
+            None => {}
+        }
+    }
+}
+
+extern "C" fn handler(signal: i32) {
+    // Preserves errno for the interrupted code; writes of 4 bytes to a pipe are atomic
+    let errno = unsafe { *libc::__errno_location() };
+    let bytes = signal.to_ne_bytes();
+    unsafe {
+        libc::write(
+            PIPE.load(Ordering::Relaxed),
+            bytes.as_ptr() as *const libc::c_void,
+            bytes.len(),
+        )
+    };
+    unsafe { *libc::__errno_location() = errno };
+}
@@ -0,0 +1,60 @@
+//! Steers the [`crate::live`] clock from outside the process, through an abstract Unix
+//! socket: black-box tests can then move the time of a service they only talk to.
+//!
+//! Each line received is a [`Command`], answered with a line: `ok` and the clock's state
+//! afterwards, or `error` and why.
+//!
+//! ```sh
+//! $ echo "advance 3600" | socat - ABSTRACT-CONNECT:my-service-clock
+//! ok time=1700003600.000000000 frozen=false speed=1
+//! ```
+use crate::error::Error;
+use crate::live::{self, Command};
+use std::io::{BufRead, BufReader, Write};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
+use std::thread;
+
+/// Listens on the abstract socket `name` for the rest of the process' life, from a thread
+/// of its own. Anyone in the network namespace can connect.
+pub fn listen(name: &str) -> Result<(), Error> {
+    let os_error = |call, e: std::io::Error| Error::Os(call, e.raw_os_error().unwrap_or(0));
+    let addr = SocketAddr::from_abstract_name(name).map_err(|e| os_error("bind", e))?;
+    let listener = UnixListener::bind_addr(&addr).map_err(|e| os_error("bind", e))?;
+    thread::Builder::new()
+        .name("tpom-socket".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        thread::spawn(move || serve(stream));
+                    }
+                    Err(e) => log::warn!("Could not accept a connection: {}", e),
+                }
+            }
+        })
+        .map_err(|e| os_error("spawn", e))?;
+    Ok(())
+}
+
+/// Answers the commands of one client, until it hangs up.
+fn serve(stream: UnixStream) {
+    let Ok(mut out) = stream.try_clone() else {
+        return;
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match line.parse::<Command>().and_then(live::execute) {
+            Ok(state) => format!("ok {}\n", state),
+            Err(e) => format!("error {}\n", e),
+        };
+        if out.write_all(reply.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
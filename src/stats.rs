@@ -0,0 +1,211 @@
+use crate::Kind;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Clockids counted one by one; the CPU-time clocks of other processes and threads, and the
+/// fd-based clocks, are counted together.
+const CLOCKS: usize = 16;
+const KINDS: [Kind; 4] = [
+    Kind::GetTime,
+    Kind::Time,
+    Kind::ClockGetRes,
+    Kind::GetTimeOfDay,
+];
+
+/// How many calls the trampolines took, since the process started or [`reset_stats`];
+/// whether a callback answered them or the syscall did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    calls: [u64; KINDS.len()],
+    clocks: [u64; CLOCKS],
+    other_clocks: u64,
+}
+
+impl Stats {
+    /// Calls to the function of `kind`.
+    pub fn calls(&self, kind: Kind) -> u64 {
+        self.calls[index(kind)]
+    }
+
+    /// Calls to `clock_gettime` and `clock_getres` for `clockid`.
+    pub fn clock(&self, clockid: libc::clockid_t) -> u64 {
+        match usize::try_from(clockid) {
+            Ok(i) if i < CLOCKS => self.clocks[i],
+            _ => 0,
+        }
+    }
+
+    /// Every clockid called, with its count, in ascending order.
+    pub fn clocks(&self) -> Vec<(libc::clockid_t, u64)> {
+        (0..CLOCKS)
+            .filter(|&i| self.clocks[i] > 0)
+            .map(|i| (i as libc::clockid_t, self.clocks[i]))
+            .collect()
+    }
+
+    /// Calls for the clockids [`Stats::clocks`] leaves out: those of other processes'
+    /// and threads' CPU time, and of devices.
+    pub fn other_clocks(&self) -> u64 {
+        self.other_clocks
+    }
+
+    /// Calls to every function.
+    pub fn total(&self) -> u64 {
+        self.calls.iter().sum()
+    }
+
+    /// The calls counted here and not in `earlier`, a snapshot taken before.
+    pub(crate) fn since(&self, earlier: &Stats) -> Stats {
+        let sub = |a: &[u64], b: &[u64], out: &mut [u64]| {
+            for ((o, a), b) in out.iter_mut().zip(a).zip(b) {
+                *o = a.saturating_sub(*b);
+            }
+        };
+        let mut stats = Stats::default();
+        sub(&self.calls, &earlier.calls, &mut stats.calls);
+        sub(&self.clocks, &earlier.clocks, &mut stats.clocks);
+        stats.other_clocks = self.other_clocks.saturating_sub(earlier.other_clocks);
+        stats
+    }
+}
+
+struct Counters {
+    calls: [AtomicU64; KINDS.len()],
+    clocks: [AtomicU64; CLOCKS],
+    other_clocks: AtomicU64,
+}
+
+static COUNTERS: Counters = Counters {
+    calls: [const { AtomicU64::new(0) }; KINDS.len()],
+    clocks: [const { AtomicU64::new(0) }; CLOCKS],
+    other_clocks: AtomicU64::new(0),
+};
+
+static PER_THREAD: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // `const`, holding no destructor, so it can be counted from within a vDSO call
+    static THREAD: Cell<Stats> = const {
+        Cell::new(Stats {
+            calls: [0; KINDS.len()],
+            clocks: [0; CLOCKS],
+            other_clocks: 0,
+        })
+    };
+}
+
+fn index(kind: Kind) -> usize {
+    match kind {
+        Kind::GetTime => 0,
+        Kind::Time => 1,
+        Kind::ClockGetRes => 2,
+        Kind::GetTimeOfDay => 3,
+    }
+}
+
+/// Counts a call taken by a trampoline.
+pub(crate) fn count(kind: Kind, clockid: Option<libc::clockid_t>) {
+    COUNTERS.calls[index(kind)].fetch_add(1, Ordering::Relaxed);
+    let slot = clockid.map(|id| usize::try_from(id).ok().filter(|&i| i < CLOCKS));
+    match slot {
+        Some(Some(i)) => COUNTERS.clocks[i].fetch_add(1, Ordering::Relaxed),
+        Some(None) => COUNTERS.other_clocks.fetch_add(1, Ordering::Relaxed),
+        None => 0,
+    };
+    if !PER_THREAD.load(Ordering::Relaxed) {
+        return;
+    }
+    let _ = THREAD.try_with(|t| {
+        let mut stats = t.take();
+        stats.calls[index(kind)] += 1;
+        match slot {
+            Some(Some(i)) => stats.clocks[i] += 1,
+            Some(None) => stats.other_clocks += 1,
+            None => {}
+        }
+        t.set(stats);
+    });
+}
+
+/// The calls taken in the whole process.
+pub fn stats() -> Stats {
+    let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+    Stats {
+        calls: COUNTERS.calls.each_ref().map(load),
+        clocks: COUNTERS.clocks.each_ref().map(load),
+        other_clocks: load(&COUNTERS.other_clocks),
+    }
+}
+
+/// The calls taken on this thread while [`count_per_thread`] was on.
+pub fn thread_stats() -> Stats {
+    THREAD.with(|t| {
+        let stats = t.take();
+        t.set(stats.clone());
+        stats
+    })
+}
+
+/// Also counts the calls of each thread, for [`thread_stats`]; off by default, as it costs
+/// a thread-local access on every call.
+pub fn count_per_thread(on: bool) {
+    PER_THREAD.store(on, Ordering::Relaxed);
+}
+
+/// Zeroes the counts of the process and of this thread.
+pub fn reset_stats() {
+    let zero = |c: &AtomicU64| c.store(0, Ordering::Relaxed);
+    COUNTERS.calls.iter().for_each(zero);
+    COUNTERS.clocks.iter().for_each(zero);
+    zero(&COUNTERS.other_clocks);
+    THREAD.with(|t| t.take());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trampolines;
+
+    #[test]
+    fn test_stats() {
+        let before = stats();
+        count_per_thread(true);
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        trampolines::my_clockgettime(libc::CLOCK_MONOTONIC, &mut ts);
+        trampolines::my_clockgettime(libc::CLOCK_MONOTONIC, &mut ts);
+        trampolines::my_clockgetres(libc::CLOCK_REALTIME, &mut ts);
+        trampolines::my_clockgettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts);
+        let mut pid_clock = 0;
+        assert_eq!(unsafe { libc::clock_getcpuclockid(0, &mut pid_clock) }, 0);
+        trampolines::my_clockgettime(pid_clock, &mut ts);
+        trampolines::my_time(std::ptr::null_mut());
+
+        let mine = thread_stats();
+        assert_eq!(mine.calls(Kind::GetTime), 4);
+        assert_eq!(mine.calls(Kind::ClockGetRes), 1);
+        assert_eq!(mine.calls(Kind::Time), 1);
+        assert_eq!(mine.calls(Kind::GetTimeOfDay), 0);
+        assert_eq!(mine.total(), 6);
+        assert_eq!(
+            mine.clocks(),
+            [
+                (libc::CLOCK_REALTIME, 1),
+                (libc::CLOCK_MONOTONIC, 2),
+                (libc::CLOCK_THREAD_CPUTIME_ID, 1)
+            ]
+        );
+        assert_eq!(mine.clock(pid_clock), 0);
+        assert_eq!(mine.other_clocks(), 1);
+
+        let after = stats();
+        assert!(after.calls(Kind::GetTime) >= before.calls(Kind::GetTime) + 4);
+        assert!(after.clock(libc::CLOCK_MONOTONIC) >= before.clock(libc::CLOCK_MONOTONIC) + 2);
+
+        count_per_thread(false);
+        trampolines::my_time(std::ptr::null_mut());
+        assert_eq!(thread_stats(), mine);
+    }
+}
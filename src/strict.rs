@@ -0,0 +1,133 @@
+//! Detects clock reads that escape the mock, answered with the real time instead: a
+//! trampoline whose callback is unset or panicked, or (with [`crate::seccomp`]) a clock
+//! syscall no callback answers.
+//!
+//! Reads through vDSO functions that are not patched never reach tpom, and can't be seen;
+//! patch every function the code under test may call.
+//!
+//! ```
+//! use tpom::strict::{self, Action};
+//!
+//! strict::enable(Action::Record);
+//! // ... run the code under test with time mocked ...
+//! strict::disable();
+//! assert_eq!(strict::take_escapes(), vec![]);
+//! ```
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+const OFF: u8 = 0;
+const RECORD: u8 = 1;
+const ABORT: u8 = 2;
+
+static MODE: AtomicU8 = AtomicU8::new(OFF);
+static ESCAPES: Mutex<Vec<Escape>> = Mutex::new(vec![]);
+
+/// What to do when a read escapes the mock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Keep it for [`take_escapes`], so that a test can fail on it.
+    Record,
+    /// Print a report to stderr and abort the process.
+    Abort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// The function is patched, but no callback is installed for it.
+    NoCallback,
+    /// The callback panicked; see [`crate::take_callback_panic`].
+    Panicked,
+    /// A syscall that no callback answers.
+    Unmocked,
+}
+
+/// A clock read answered with the real time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Escape {
+    pub function: &'static str,
+    pub clockid: Option<i32>,
+    pub reason: Reason,
+}
+
+impl fmt::Display for Escape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.function)?;
+        if let Some(clockid) = self.clockid {
+            write!(f, "(clock {})", clockid)?;
+        }
+        let why = match self.reason {
+            Reason::NoCallback => "no callback is installed",
+            Reason::Panicked => "the callback panicked",
+            Reason::Unmocked => "no callback answers the syscall",
+        };
+        write!(f, " read the real time: {}", why)
+    }
+}
+
+pub fn enable(action: Action) {
+    let mode = match action {
+        Action::Record => RECORD,
+        Action::Abort => ABORT,
+    };
+    MODE.store(mode, Ordering::SeqCst);
+}
+
+/// Stops checking; escapes recorded so far are kept.
+pub fn disable() {
+    MODE.store(OFF, Ordering::SeqCst);
+}
+
+/// The escapes recorded since the last call.
+pub fn take_escapes() -> Vec<Escape> {
+    std::mem::take(&mut *ESCAPES.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+/// Reports a read answered with the real time, as strict mode says.
+pub(crate) fn escaped(function: &'static str, clockid: Option<i32>, reason: Reason) {
+    let escape = Escape {
+        function,
+        clockid,
+        reason,
+    };
+    match MODE.load(Ordering::SeqCst) {
+        RECORD => ESCAPES
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(escape),
+        ABORT => {
+            eprintln!("tpom strict mode: {}", escape);
+            std::process::abort();
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        // The trampolines' tests may hit other escapes meanwhile
+        let ours = || -> Vec<Escape> {
+            take_escapes()
+                .into_iter()
+                .filter(|e| e.function == "test")
+                .collect()
+        };
+        escaped("test", None, Reason::NoCallback);
+        enable(Action::Record);
+        escaped("test", Some(1), Reason::Panicked);
+        disable();
+        escaped("test", None, Reason::NoCallback);
+        let escapes = ours();
+        assert_eq!(escapes.len(), 1);
+        assert_eq!(
+            escapes[0].to_string(),
+            "test(clock 1) read the real time: the callback panicked"
+        );
+        assert!(ours().is_empty());
+    }
+}
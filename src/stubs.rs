@@ -0,0 +1,551 @@
+//! Machine code answering a vDSO function by itself, for processes that can't jump into
+//! this one's trampolines (see [`crate::remote`]).
+//!
+//! A stub is position independent: it can be written over the function, or anywhere in the
+//! target with a jump to it. It answers every clock alike, ignoring `clockid`, and writes
+//! only the fields the function would; `gettimeofday`'s timezone is left untouched.
+use crate::error::Error;
+use crate::vdso::ElfClass;
+use crate::{Kind, TimeSpec};
+
+/// What a stub answers with.
+pub enum Stub {
+    /// Always the same time.
+    Constant(TimeSpec),
+    /// The [`TimeSpec`] at this address of the target, re-read on every call: the seconds,
+    /// then the nanoseconds, as two native `i64`.
+    Page(usize),
+    /// The time set through the [`crate::control::ControlPage`] at this address of the
+    /// target. The real time is read with a syscall, and only the wall clocks are mocked:
+    /// the others, and `clock_getres`, are answered by the kernel.
+    Control(usize),
+}
+
+/// The code of `stub` for the function of `kind`, for the running architecture.
+pub fn generate(kind: Kind, stub: &Stub) -> Vec<u8> {
+    _generate_x86_64(kind, stub)
+}
+
+/// Like [`generate`], for a process of `class`: an [`ElfClass::Elf32`] one is i386 code,
+/// answering with 32-bit times. [`Stub::Control`] is not supported there, and the other
+/// stubs fail if their time or address does not fit in 32 bits.
+pub fn generate_for(class: ElfClass, kind: Kind, stub: &Stub) -> Result<Vec<u8>, Error> {
+    match class {
+        ElfClass::Elf64 => Ok(_generate_x86_64(kind, stub)),
+        ElfClass::Elf32 => _generate_i386(kind, stub),
+    }
+}
+
+fn _generate_x86_64(kind: Kind, stub: &Stub) -> Vec<u8> {
+    if let Stub::Control(page) = stub {
+        return _generate_control_x86_64(kind, *page);
+    }
+    /* Assembled by hand; for `clock_gettime` with `Stub::Page`, for example:
+      ```
+          movabs  rcx, <page>
+          mov     rax, [rcx]
+          mov     [rsi], rax
+          mov     rax, [rcx + 8]
+          mov     [rsi + 8], rax
+          xor     eax, eax
+          ret
+      ```
+    */
+    let movabs_rcx = [0x48, 0xb9];
+    let movabs_rax = [0x48, 0xb8];
+    let mov_rax_rcx = [0x48, 0x8b, 0x01];
+    let mov_rax_rcx_8 = [0x48, 0x8b, 0x41, 0x08];
+    // xor edx, edx; mov r8d, 1000; div r8
+    let div_rax_1000 = [
+        0x31, 0xd2, 0x41, 0xb8, 0xe8, 0x03, 0x00, 0x00, 0x49, 0xf7, 0xf0,
+    ];
+    let xor_eax = [0x31, 0xc0];
+    let ret = [0xc3];
+
+    let mut opcodes = vec![];
+    let load = |opcodes: &mut Vec<u8>, field: usize, micros: bool| match stub {
+        Stub::Constant(ts) => {
+            let value = match field {
+                0 => ts.seconds,
+                _ if micros => ts.nanos / 1000,
+                _ => ts.nanos,
+            };
+            opcodes.extend(movabs_rax);
+            opcodes.extend(value.to_le_bytes());
+        }
+        Stub::Control(_) => unreachable!(),
+        Stub::Page(_) => {
+            opcodes.extend(if field == 0 {
+                &mov_rax_rcx[..]
+            } else {
+                &mov_rax_rcx_8[..]
+            });
+            if field != 0 && micros {
+                opcodes.extend(div_rax_1000);
+            }
+        }
+    };
+    if let Stub::Page(addr) = stub {
+        opcodes.extend(movabs_rcx);
+        opcodes.extend(addr.to_le_bytes());
+    }
+
+    // The pointer written to, and the `mov [ptr], rax` and `test ptr, ptr` on it
+    let (store, store_8, test) = match kind {
+        Kind::GetTime | Kind::ClockGetRes => (
+            [0x48, 0x89, 0x06],
+            [0x48, 0x89, 0x46, 0x08],
+            [0x48, 0x85, 0xf6],
+        ),
+        Kind::GetTimeOfDay | Kind::Time => (
+            [0x48, 0x89, 0x07],
+            [0x48, 0x89, 0x47, 0x08],
+            [0x48, 0x85, 0xff],
+        ),
+    };
+    let mut body = vec![];
+    if kind == Kind::Time {
+        // The seconds are returned too, so they are loaded before the null check
+        load(&mut opcodes, 0, false);
+        body.extend(store);
+    } else {
+        load(&mut body, 0, false);
+        body.extend(store);
+        load(&mut body, 1, kind == Kind::GetTimeOfDay);
+        body.extend(store_8);
+    }
+    if kind != Kind::GetTime {
+        // test ptr, ptr; jz over the body
+        opcodes.extend(test);
+        opcodes.extend([0x74, body.len() as u8]);
+    }
+    opcodes.extend(body);
+    if kind != Kind::Time {
+        opcodes.extend(xor_eax);
+    }
+    opcodes.extend(ret);
+    opcodes
+}
+
+fn _generate_i386(kind: Kind, stub: &Stub) -> Result<Vec<u8>, Error> {
+    let unsupported =
+        |what: String| Error::UnsupportedPlatform(format!("{} in a 32-bit stub", what));
+    /* Assembled by hand, as for x86_64; the arguments are on the stack, and `Stub::Page` reads
+      the low half of each field. For `clock_gettime` with it:
+      ```
+          mov     eax, [esp + 8]
+          mov     ecx, <page>
+          mov     edx, [ecx]
+          mov     [eax], edx
+          mov     edx, [ecx + 8]
+          mov     [eax + 4], edx
+          xor     eax, eax
+          ret
+      ```
+    */
+    let (seconds, nanos) = match stub {
+        Stub::Constant(ts) => (
+            i32::try_from(ts.seconds).map_err(|_| unsupported(format!("{}s", ts.seconds)))?,
+            ts.nanos as i32,
+        ),
+        Stub::Page(_) => (0, 0),
+        Stub::Control(_) => return Err(unsupported("Stub::Control".to_string())),
+    };
+    let page = match stub {
+        Stub::Page(addr) => {
+            Some(u32::try_from(*addr).map_err(|_| unsupported(format!("address {:#x}", addr)))?)
+        }
+        _ => None,
+    };
+    let ret = [0xc3];
+
+    let mut opcodes = vec![];
+    if kind == Kind::Time {
+        // The seconds are returned in eax, through ecx = t
+        opcodes.extend([0x8b, 0x4c, 0x24, 0x04]);
+        match page {
+            // mov edx, <page>; mov eax, [edx]
+            Some(addr) => {
+                opcodes.push(0xba);
+                opcodes.extend(addr.to_le_bytes());
+                opcodes.extend([0x8b, 0x02]);
+            }
+            // mov eax, <seconds>
+            None => {
+                opcodes.push(0xb8);
+                opcodes.extend(seconds.to_le_bytes());
+            }
+        }
+        // test ecx, ecx; jz over mov [ecx], eax
+        opcodes.extend([0x85, 0xc9, 0x74, 0x02, 0x89, 0x01]);
+        opcodes.extend(ret);
+        return Ok(opcodes);
+    }
+
+    // mov eax, <the pointer written to>
+    opcodes.extend(match kind {
+        Kind::GetTimeOfDay => [0x8b, 0x44, 0x24, 0x04],
+        _ => [0x8b, 0x44, 0x24, 0x08],
+    });
+    let mut body = vec![];
+    match page {
+        Some(addr) => {
+            // mov ecx, <page>
+            opcodes.push(0xb9);
+            opcodes.extend(addr.to_le_bytes());
+            // mov edx, [ecx]; mov [eax], edx
+            body.extend([0x8b, 0x11, 0x89, 0x10]);
+            if kind == Kind::GetTimeOfDay {
+                // push eax; mov eax, [ecx + 8]; xor edx, edx; mov ecx, 1000; div ecx;
+                // mov edx, eax; pop eax
+                body.extend([
+                    0x50, 0x8b, 0x41, 0x08, 0x31, 0xd2, 0xb9, 0xe8, 0x03, 0x00, 0x00, 0xf7, 0xf1,
+                    0x89, 0xc2, 0x58,
+                ]);
+            } else {
+                // mov edx, [ecx + 8]
+                body.extend([0x8b, 0x51, 0x08]);
+            }
+            // mov [eax + 4], edx
+            body.extend([0x89, 0x50, 0x04]);
+        }
+        None => {
+            let nanos = if kind == Kind::GetTimeOfDay {
+                nanos / 1000
+            } else {
+                nanos
+            };
+            // mov dword [eax], <seconds>; mov dword [eax + 4], <nanos>
+            body.extend([0xc7, 0x00]);
+            body.extend(seconds.to_le_bytes());
+            body.extend([0xc7, 0x40, 0x04]);
+            body.extend(nanos.to_le_bytes());
+        }
+    }
+    if kind != Kind::GetTime {
+        // test eax, eax; jz over the body
+        opcodes.extend([0x85, 0xc0, 0x74, body.len() as u8]);
+    }
+    opcodes.extend(body);
+    // xor eax, eax
+    opcodes.extend([0x31, 0xc0]);
+    opcodes.extend(ret);
+    Ok(opcodes)
+}
+
+fn _generate_control_x86_64(kind: Kind, page: usize) -> Vec<u8> {
+    /* Assembled with `as`; turns the real time at [rsi] into the mocked time, with the page
+      address in rcx. The parameters are read under the page's seqlock: again if they
+      changed meanwhile, after waiting for the writer if they are changing.
+      ```
+          movabs  rcx, <page>
+      1:  mov     r9, [rcx]               ; sequence
+          test    r9b, 1
+          jz      2f
+          pause
+          jmp     1b
+      2:  mov     rax, [rsi]
+          imul    rax, rax, 1000000000
+          add     rax, [rsi + 8]
+          cmp     qword ptr [rcx + 8], 0  ; frozen
+          jne     3f
+          sub     rax, [rcx + 24]         ; anchor
+          imul    qword ptr [rcx + 32]    ; scale
+          shrd    rax, rdx, 32
+          add     rax, [rcx + 16]         ; value
+          jmp     4f
+      3:  mov     rax, [rcx + 16]
+      4:  cmp     r9, [rcx]
+          jne     1b
+          cqo
+          mov     ecx, 1000000000
+          idiv    rcx
+          test    rdx, rdx
+          jns     5f
+          add     rdx, rcx
+          dec     rax
+      5:  mov     [rsi], rax
+          mov     [rsi + 8], rdx
+      ```
+      which leaves the seconds in rax and the nanoseconds in rdx. x86 doesn't reorder loads
+      with other loads, so the plain `mov`s are enough.
+    */
+    let transform = [
+        &[0x48, 0xb9][..],
+        &page.to_le_bytes(),
+        &[
+            0x4c, 0x8b, 0x09, 0x41, 0xf6, 0xc1, 0x01, 0x74, 0x04, 0xf3, 0x90, 0xeb, 0xf3, 0x48,
+            0x8b, 0x06, 0x48, 0x69, 0xc0, 0x00, 0xca, 0x9a, 0x3b, 0x48, 0x03, 0x46, 0x08, 0x48,
+            0x83, 0x79, 0x08, 0x00, 0x75, 0x13, 0x48, 0x2b, 0x41, 0x18, 0x48, 0xf7, 0x69, 0x20,
+            0x48, 0x0f, 0xac, 0xd0, 0x20, 0x48, 0x03, 0x41, 0x10, 0xeb, 0x04, 0x48, 0x8b, 0x41,
+            0x10, 0x4c, 0x3b, 0x09, 0x75, 0xc2, 0x48, 0x99, 0xb9, 0x00, 0xca, 0x9a, 0x3b, 0x48,
+            0xf7, 0xf9, 0x48, 0x85, 0xd2, 0x79, 0x06, 0x48, 0x01, 0xca, 0x48, 0xff, 0xc8, 0x48,
+            0x89, 0x06, 0x48, 0x89, 0x56, 0x08,
+        ],
+    ]
+    .concat();
+    // mov eax, SYS_clock_gettime; syscall
+    let clock_gettime = [0xb8, 0xe4, 0x00, 0x00, 0x00, 0x0f, 0x05];
+    // mov r8, rdi; xor edi, edi; lea rsi, [rsp - 16]: reads CLOCK_REALTIME into the red zone,
+    // keeping the caller's pointer in r8
+    let realtime_to_stack = [0x49, 0x89, 0xf8, 0x31, 0xff, 0x48, 0x8d, 0x74, 0x24, 0xf0];
+    let len = transform.len() as u8;
+    match kind {
+        Kind::GetTime => [
+            &clock_gettime[..],
+            /*
+                test    rax, rax
+                jnz     9f
+                test    edi, edi          ; CLOCK_REALTIME
+                je      8f
+                cmp     edi, 5            ; CLOCK_REALTIME_COARSE
+                je      8f
+                cmp     edi, 11           ; CLOCK_TAI
+                jne     9f
+            8:  <transform>
+                xor     eax, eax
+            9:  ret
+            */
+            &[0x48, 0x85, 0xc0, 0x75, len + 16],
+            &[0x85, 0xff, 0x74, 0x0a, 0x83, 0xff, 0x05, 0x74, 0x05],
+            &[0x83, 0xff, 0x0b, 0x75, len + 2],
+            &transform,
+            &[0x31, 0xc0, 0xc3],
+        ]
+        .concat(),
+        Kind::GetTimeOfDay => [
+            &realtime_to_stack[..],
+            &clock_gettime,
+            &transform,
+            /*
+                test    r8, r8
+                jz      9f
+                mov     [r8], rax
+                mov     rax, rdx
+                xor     edx, edx
+                mov     ecx, 1000
+                div     rcx
+                mov     [r8 + 8], rax
+            9:  xor     eax, eax
+                ret
+            */
+            &[
+                0x4d, 0x85, 0xc0, 0x74, 0x14, 0x49, 0x89, 0x00, 0x48, 0x89, 0xd0, 0x31, 0xd2,
+            ],
+            &[
+                0xb9, 0xe8, 0x03, 0x00, 0x00, 0x48, 0xf7, 0xf1, 0x49, 0x89, 0x40, 0x08,
+            ],
+            &[0x31, 0xc0, 0xc3],
+        ]
+        .concat(),
+        Kind::Time => [
+            &realtime_to_stack[..],
+            &clock_gettime,
+            &transform,
+            // test r8, r8; jz 9f; mov [r8], rax; 9: ret
+            &[0x4d, 0x85, 0xc0, 0x74, 0x03, 0x49, 0x89, 0x00, 0xc3],
+        ]
+        .concat(),
+        // mov eax, SYS_clock_getres; syscall; ret
+        Kind::ClockGetRes => vec![0xb8, 0xe5, 0x00, 0x00, 0x00, 0x0f, 0x05, 0xc3],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Maps `code` as executable in this process.
+    fn load(code: &[u8]) -> *const u8 {
+        unsafe {
+            let page = libc::mmap(
+                std::ptr::null_mut(),
+                4096,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            assert_ne!(page, libc::MAP_FAILED);
+            std::ptr::copy_nonoverlapping(code.as_ptr(), page as *mut u8, code.len());
+            libc::mprotect(page, 4096, libc::PROT_READ | libc::PROT_EXEC);
+            page as *const u8
+        }
+    }
+
+    #[test]
+    fn test_constant() {
+        let (seconds, nanos) = (0x1234_5678_9abc, 123_456_789);
+        let stub = Stub::Constant(TimeSpec { seconds, nanos });
+        unsafe {
+            let gettime: extern "C" fn(i32, *mut libc::timespec) -> i32 =
+                std::mem::transmute(load(&generate(Kind::GetTime, &stub)));
+            let mut out = std::mem::zeroed::<libc::timespec>();
+            assert_eq!(gettime(libc::CLOCK_REALTIME, &mut out), 0);
+            assert_eq!((out.tv_sec, out.tv_nsec), (seconds, nanos));
+
+            let getres: extern "C" fn(i32, *mut libc::timespec) -> i32 =
+                std::mem::transmute(load(&generate(Kind::ClockGetRes, &stub)));
+            assert_eq!(getres(libc::CLOCK_REALTIME, std::ptr::null_mut()), 0);
+
+            let gettimeofday: extern "C" fn(*mut libc::timeval, *mut libc::c_void) -> i32 =
+                std::mem::transmute(load(&generate(Kind::GetTimeOfDay, &stub)));
+            let mut out = std::mem::zeroed::<libc::timeval>();
+            assert_eq!(gettimeofday(&mut out, std::ptr::null_mut()), 0);
+            assert_eq!((out.tv_sec, out.tv_usec), (seconds, 123_456));
+            assert_eq!(gettimeofday(std::ptr::null_mut(), std::ptr::null_mut()), 0);
+
+            let time: extern "C" fn(*mut libc::time_t) -> libc::time_t =
+                std::mem::transmute(load(&generate(Kind::Time, &stub)));
+            let mut out = 0;
+            assert_eq!(time(&mut out), seconds);
+            assert_eq!(out, seconds);
+            assert_eq!(time(std::ptr::null_mut()), seconds);
+        }
+    }
+
+    #[test]
+    fn test_page() {
+        let mut page = Box::new([1000i64, 999_999_999]);
+        let stub = Stub::Page(page.as_ptr() as usize);
+        unsafe {
+            let gettime: extern "C" fn(i32, *mut libc::timespec) -> i32 =
+                std::mem::transmute(load(&generate(Kind::GetTime, &stub)));
+            let gettimeofday: extern "C" fn(*mut libc::timeval, *mut libc::c_void) -> i32 =
+                std::mem::transmute(load(&generate(Kind::GetTimeOfDay, &stub)));
+            let mut ts = std::mem::zeroed::<libc::timespec>();
+            let mut tv = std::mem::zeroed::<libc::timeval>();
+            gettime(libc::CLOCK_MONOTONIC, &mut ts);
+            gettimeofday(&mut tv, std::ptr::null_mut());
+            assert_eq!((ts.tv_sec, ts.tv_nsec), (1000, 999_999_999));
+            assert_eq!((tv.tv_sec, tv.tv_usec), (1000, 999_999));
+
+            std::ptr::write_volatile(&mut page[0], 2000);
+            gettime(libc::CLOCK_MONOTONIC, &mut ts);
+            assert_eq!(ts.tv_sec, 2000);
+        }
+    }
+
+    #[test]
+    fn test_control() {
+        let real = |clockid| {
+            let mut ts = unsafe { std::mem::zeroed::<libc::timespec>() };
+            crate::trampolines::raw_clock_gettime(clockid, &mut ts);
+            ts.tv_sec
+        };
+        let page = crate::control::ControlPage::new().unwrap();
+        let stub = Stub::Control(page.address());
+        unsafe {
+            let gettime: extern "C" fn(i32, *mut libc::timespec) -> i32 =
+                std::mem::transmute(load(&generate(Kind::GetTime, &stub)));
+            let gettimeofday: extern "C" fn(*mut libc::timeval, *mut libc::c_void) -> i32 =
+                std::mem::transmute(load(&generate(Kind::GetTimeOfDay, &stub)));
+            let time: extern "C" fn(*mut libc::time_t) -> libc::time_t =
+                std::mem::transmute(load(&generate(Kind::Time, &stub)));
+            let mut ts = std::mem::zeroed::<libc::timespec>();
+            let mut tv = std::mem::zeroed::<libc::timeval>();
+
+            assert!(time(std::ptr::null_mut()).abs_diff(real(libc::CLOCK_REALTIME)) <= 1);
+
+            page.freeze(TimeSpec {
+                seconds: 1000,
+                nanos: 5_000,
+            });
+            assert_eq!(gettime(libc::CLOCK_REALTIME, &mut ts), 0);
+            assert_eq!((ts.tv_sec, ts.tv_nsec), (1000, 5_000));
+            assert_eq!(gettimeofday(&mut tv, std::ptr::null_mut()), 0);
+            assert_eq!((tv.tv_sec, tv.tv_usec), (1000, 5));
+            assert_eq!(time(std::ptr::null_mut()), 1000);
+            // Not a wall clock
+            gettime(libc::CLOCK_MONOTONIC, &mut ts);
+            assert!(ts.tv_sec.abs_diff(real(libc::CLOCK_MONOTONIC)) <= 1);
+            assert_eq!(gettime(-1000, &mut ts), -libc::EINVAL);
+
+            page.offset(-3_600_000_000_000);
+            let expected = real(libc::CLOCK_REALTIME) - 3600;
+            assert!(time(std::ptr::null_mut()).abs_diff(expected) <= 1);
+
+            page.run(
+                TimeSpec {
+                    seconds: 1000,
+                    nanos: 0,
+                },
+                10.0,
+            );
+            std::thread::sleep(std::time::Duration::from_millis(150));
+            gettime(libc::CLOCK_TAI, &mut ts);
+            assert_eq!(ts.tv_sec, 1001);
+        }
+    }
+
+    #[test]
+    fn test_control_is_consistent() {
+        let page = crate::control::ControlPage::new().unwrap();
+        let stub = Stub::Control(page.address());
+        let gettime: extern "C" fn(i32, *mut libc::timespec) -> i32 =
+            unsafe { std::mem::transmute(load(&generate(Kind::GetTime, &stub))) };
+        let frozen = || TimeSpec {
+            seconds: 1000,
+            nanos: 0,
+        };
+        let running = || TimeSpec {
+            seconds: 2000,
+            nanos: 0,
+        };
+        page.freeze(frozen());
+
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    page.run(running(), 1.0);
+                    page.freeze(frozen());
+                }
+            });
+            // Half of an update would mix the frozen time into the running one, or the
+            // other way around. The real time read before an update may predate its anchor
+            let mut ts = unsafe { std::mem::zeroed::<libc::timespec>() };
+            let mut bad = None;
+            for _ in 0..100_000 {
+                gettime(libc::CLOCK_REALTIME, &mut ts);
+                let ts = (ts.tv_sec, ts.tv_nsec);
+                let ok = ts == (1000, 0) || (ts != (2000, 0) && (1999..2010).contains(&ts.0));
+                if !ok {
+                    bad = Some(ts);
+                    break;
+                }
+            }
+            done.store(true, std::sync::atomic::Ordering::Relaxed);
+            assert_eq!(bad, None);
+        });
+    }
+
+    #[test]
+    fn test_i386() {
+        let ts = TimeSpec {
+            seconds: 1234,
+            nanos: 5,
+        };
+        let stub = Stub::Constant(ts);
+        // mov eax, [esp + 8]; mov dword [eax], 1234; mov dword [eax + 4], 5; xor eax, eax; ret
+        assert_eq!(
+            generate_for(ElfClass::Elf32, Kind::GetTime, &stub),
+            Ok(vec![
+                0x8b, 0x44, 0x24, 0x08, 0xc7, 0x00, 0xd2, 0x04, 0x00, 0x00, 0xc7, 0x40, 0x04, 0x05,
+                0x00, 0x00, 0x00, 0x31, 0xc0, 0xc3
+            ])
+        );
+        assert_eq!(
+            generate_for(ElfClass::Elf64, Kind::GetTime, &stub),
+            Ok(generate(Kind::GetTime, &stub))
+        );
+
+        let y2038 = Stub::Constant(TimeSpec {
+            seconds: 1 << 31,
+            nanos: 0,
+        });
+        assert!(generate_for(ElfClass::Elf32, Kind::Time, &y2038).is_err());
+        assert!(generate_for(ElfClass::Elf32, Kind::Time, &Stub::Page(1 << 32)).is_err());
+        assert!(generate_for(ElfClass::Elf32, Kind::GetTime, &Stub::Control(0x1000)).is_err());
+    }
+}
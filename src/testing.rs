@@ -0,0 +1,178 @@
+//! Helpers for tests mocking the time, which all share the process' vDSO: see also the
+//! `#[tpom::frozen_time]` attribute, with the `macros` feature.
+//!
+//! ```
+//! use std::time::{Duration, SystemTime, UNIX_EPOCH};
+//!
+//! let frozen = tpom::testing::frozen_time("2022-03-01T00:00:00Z").unwrap();
+//! let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+//! assert_eq!(now, Duration::from_secs(1_646_092_800));
+//! drop(frozen);
+//! ```
+use crate::error::Error;
+use crate::live::{self, Command};
+use crate::stats::{self, Stats};
+use crate::{registry, vdso, Kind, Session};
+use std::sync::{Mutex, MutexGuard};
+
+/// Held by every [`TestGuard`], one at a time.
+static TESTS: Mutex<()> = Mutex::new(());
+
+/// The turn of a test to mock the time, until dropped; see [`test_guard`].
+pub struct TestGuard {
+    _lock: MutexGuard<'static, ()>,
+}
+
+/// Waits for the other tests of the process holding a guard, so that they don't patch the
+/// vDSO at once, then returns this test's. Dropping it restores every patch left, other than
+/// leaked ones, as the test returns or unwinds.
+///
+/// ```
+/// let _guard = tpom::test_guard();
+/// // ... patch, and test ...
+/// ```
+pub fn test_guard() -> TestGuard {
+    // A test panicking while it held the lock restored the time as its guard dropped
+    TestGuard {
+        _lock: TESTS.lock().unwrap_or_else(|e| e.into_inner()),
+    }
+}
+
+impl Drop for TestGuard {
+    #[track_caller]
+    fn drop(&mut self) {
+        registry::restore_all();
+    }
+}
+
+/// The wall clocks frozen, until dropped.
+pub struct FrozenTime {
+    // Dropped in this order: the patches, then the guard
+    _session: Session,
+    _guard: TestGuard,
+}
+
+/// Waits for the other tests holding a [`test_guard`], then freezes the [`live`] wall clocks at
+/// `at`, read by [`live::parse_time`]. Dropping the result, as a test returns or unwinds,
+/// restores them.
+#[track_caller]
+pub fn frozen_time(at: &str) -> Result<FrozenTime, Error> {
+    let at = live::parse_time(at)?;
+    let guard = test_guard();
+    let v = vdso::vDSO::read().map_err(|e| Error::UnsupportedPlatform(e.to_string()))?;
+    let mut session = Session::new(&v);
+    session.apply_all(&live::callbacks())?;
+    live::execute(Command::Freeze(Some(at)))?;
+    Ok(FrozenTime {
+        _session: session,
+        _guard: guard,
+    })
+}
+
+impl Drop for FrozenTime {
+    fn drop(&mut self) {
+        if let Err(e) = live::execute(Command::Restore) {
+            log::error!("Could not restore the mocked clock: {}", e);
+        }
+    }
+}
+
+/// Fails a test that read a clock it must not, or the time too often, from the calls
+/// counted by [`crate::stats`]: only the patched functions are counted.
+///
+/// ```
+/// use tpom::testing::ClockAssertions;
+///
+/// let clocks = ClockAssertions::new()
+///     .forbid(libc::CLOCK_REALTIME)
+///     .max_reads(10);
+/// // ... run the code under test ...
+/// clocks.assert();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClockAssertions {
+    start: Stats,
+    this_thread: bool,
+    forbidden: Vec<libc::clockid_t>,
+    max_reads: Option<u64>,
+}
+
+impl ClockAssertions {
+    /// Counts the calls of the whole process from now on, so the test should hold a
+    /// [`test_guard`] or run alone.
+    pub fn new() -> ClockAssertions {
+        ClockAssertions {
+            start: stats::stats(),
+            this_thread: false,
+            forbidden: vec![],
+            max_reads: None,
+        }
+    }
+
+    /// Counts only the calls of this thread from now on, turning
+    /// [`crate::count_per_thread`] on.
+    pub fn on_this_thread() -> ClockAssertions {
+        stats::count_per_thread(true);
+        ClockAssertions {
+            start: stats::thread_stats(),
+            this_thread: true,
+            ..ClockAssertions::new()
+        }
+    }
+
+    /// Allows no call for `clockid`; for `CLOCK_REALTIME`, also none to `time` and
+    /// `gettimeofday`, which read it.
+    pub fn forbid(mut self, clockid: libc::clockid_t) -> ClockAssertions {
+        self.forbidden.push(clockid);
+        self
+    }
+
+    /// Allows at most `n` reads of the time: calls to every function but `clock_getres`.
+    pub fn max_reads(mut self, n: u64) -> ClockAssertions {
+        self.max_reads = Some(n);
+        self
+    }
+
+    /// The calls counted so far.
+    pub fn calls(&self) -> Stats {
+        let now = if self.this_thread {
+            stats::thread_stats()
+        } else {
+            stats::stats()
+        };
+        now.since(&self.start)
+    }
+
+    /// What was not allowed, if anything.
+    pub fn violations(&self) -> Vec<String> {
+        let calls = self.calls();
+        let mut violations = vec![];
+        for &clockid in &self.forbidden {
+            let mut n = calls.clock(clockid);
+            if clockid == libc::CLOCK_REALTIME {
+                n += calls.calls(Kind::Time) + calls.calls(Kind::GetTimeOfDay);
+            }
+            if n > 0 {
+                violations.push(format!("clock {} was read {} times", clockid, n));
+            }
+        }
+        let reads = calls.total() - calls.calls(Kind::ClockGetRes);
+        if let Some(max) = self.max_reads.filter(|&max| reads > max) {
+            violations.push(format!("the time was read {} times, over {}", reads, max));
+        }
+        violations
+    }
+
+    /// Panics if anything was not allowed.
+    #[track_caller]
+    pub fn assert(&self) {
+        let violations = self.violations();
+        assert!(violations.is_empty(), "{}", violations.join("; "));
+    }
+}
+
+impl Default for ClockAssertions {
+    fn default() -> ClockAssertions {
+        ClockAssertions::new()
+    }
+}
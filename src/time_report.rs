@@ -0,0 +1,123 @@
+use crate::current_mock;
+use crate::live::real;
+use crate::TimeSpec;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::Duration;
+
+/// How much mocked time went by, against the real time, for a CI job to report how far a
+/// run got ahead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeReport {
+    /// Real time since the first mocked read of `CLOCK_REALTIME`.
+    pub wall: Duration,
+    /// Nanoseconds the mocked `CLOCK_REALTIME` moved since, negative if it went back.
+    pub simulated: i64,
+}
+
+impl TimeReport {
+    /// How many times as fast the mocked time went, on average.
+    pub fn ratio(&self) -> f64 {
+        self.simulated as f64 / self.wall.as_nanos().max(1) as f64
+    }
+}
+
+/// Set by the first mocked read; the others only update `LAST_MOCKED`.
+static STARTED: AtomicBool = AtomicBool::new(false);
+/// Real `CLOCK_MONOTONIC` and mocked `CLOCK_REALTIME` nanoseconds at the first mocked read.
+static FIRST_REAL: AtomicI64 = AtomicI64::new(0);
+static FIRST_MOCKED: AtomicI64 = AtomicI64::new(0);
+static LAST_MOCKED: AtomicI64 = AtomicI64::new(0);
+
+fn nanos(ts: TimeSpec) -> i64 {
+    ts.seconds * 1_000_000_000 + ts.nanos
+}
+
+/// Notes a mocked `CLOCK_REALTIME` read; only the first one makes a syscall.
+pub(crate) fn mocked(ts: TimeSpec) {
+    let mocked = nanos(ts);
+    LAST_MOCKED.store(mocked, Ordering::Relaxed);
+    if STARTED.load(Ordering::Relaxed) || STARTED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    FIRST_MOCKED.store(mocked, Ordering::Relaxed);
+    FIRST_REAL.store(real(libc::CLOCK_MONOTONIC), Ordering::Release);
+}
+
+/// Compares the mocked time with the real one since the mocked `CLOCK_REALTIME` was first
+/// read, or [`reset_time_report`]; `None` if it wasn't since. The mocked time is the one the
+/// callbacks answer now, or the last one read if they are no longer installed.
+pub fn time_report() -> Option<TimeReport> {
+    if !STARTED.load(Ordering::Acquire) {
+        return None;
+    }
+    let first_real = FIRST_REAL.load(Ordering::Acquire);
+    if first_real == 0 {
+        // The first read is still being noted
+        return None;
+    }
+    let now = current_mock()
+        .clock(libc::CLOCK_REALTIME)
+        .map(nanos)
+        .unwrap_or_else(|| LAST_MOCKED.load(Ordering::Relaxed));
+    let wall = (real(libc::CLOCK_MONOTONIC) - first_real).max(0);
+    Some(TimeReport {
+        wall: Duration::from_nanos(wall as u64),
+        simulated: now - FIRST_MOCKED.load(Ordering::Relaxed),
+    })
+}
+
+/// Starts the next report at the next mocked read.
+pub fn reset_time_report() {
+    FIRST_REAL.store(0, Ordering::Relaxed);
+    STARTED.store(false, Ordering::Release);
+}
+
+/// Such as `3d 4h 5m 6.500s`.
+fn human(nanos: i64) -> String {
+    let sign = if nanos < 0 { "-" } else { "" };
+    let nanos = nanos.unsigned_abs();
+    let secs = nanos / 1_000_000_000;
+    let frac = (nanos % 1_000_000_000) as f64 / 1e9;
+    let (d, h, m, s) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    let mut parts = vec![];
+    for (n, unit) in [(d, "d"), (h, "h"), (m, "m")] {
+        if n > 0 || !parts.is_empty() {
+            parts.push(format!("{}{}", n, unit));
+        }
+    }
+    parts.push(format!("{:.3}s", s as f64 + frac));
+    format!("{}{}", sign, parts.join(" "))
+}
+
+impl fmt::Display for TimeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "simulated {} in {} ({:.1}x)",
+            human(self.simulated),
+            human(self.wall.as_nanos() as i64),
+            self.ratio()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        assert_eq!(human(1_500_000_000), "1.500s");
+        assert_eq!(human(-61_000_000_000), "-1m 1.000s");
+        assert_eq!(human(30 * 86_400_000_000_000 + 1), "30d 0h 0m 0.000s");
+        let report = TimeReport {
+            wall: Duration::from_secs(42),
+            simulated: 30 * 86_400_000_000_000,
+        };
+        assert_eq!(
+            report.to_string(),
+            "simulated 30d 0h 0m 0.000s in 42.000s (61714.3x)"
+        );
+    }
+}
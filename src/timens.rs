@@ -0,0 +1,116 @@
+//! Shifts `CLOCK_MONOTONIC` and `CLOCK_BOOTTIME` through a time namespace (Linux 5.6+).
+//!
+//! Unlike patching the vDSO, the offsets are applied by the kernel, so they also hold for
+//! timerfd, epoll and futex timeouts, or /proc/uptime. `CLOCK_REALTIME` is not namespaced;
+//! patch it with a [`crate::Session`] as usual, which works the same inside the namespace.
+//!
+//! A new time namespace only applies to the children the calling thread creates after
+//! [`unshare`], and its offsets can only be set until the first of them starts. Unsharing needs `CAP_SYS_ADMIN`
+//! (possibly within a user namespace).
+use crate::error::Error;
+use crate::TimeSpec;
+use std::ffi::CString;
+use std::fs;
+
+/// The offsets of the calling thread's namespace: namespaces are per thread, and
+/// /proc/self would be the main thread's.
+fn offsets_path() -> String {
+    format!("/proc/{}/timens_offsets", unsafe { libc::gettid() })
+}
+
+/// Creates a time namespace for the children of the calling thread, with no offsets.
+pub fn unshare() -> Result<(), Error> {
+    if unsafe { libc::unshare(libc::CLONE_NEWTIME) } != 0 {
+        return Err(Error::last_os_error("unshare"));
+    }
+    Ok(())
+}
+
+/// Sets the offset of `clockid` (`CLOCK_MONOTONIC` or `CLOCK_BOOTTIME`) in the namespace
+/// created by [`unshare`]. Fails once a process has entered it.
+pub fn set_offset(clockid: libc::clockid_t, offset: &TimeSpec) -> Result<(), Error> {
+    let line = format!("{} {} {}\n", clockid, offset.seconds, offset.nanos);
+    fs::write(offsets_path(), line)
+        .map_err(|e| Error::Os("write timens_offsets", e.raw_os_error().unwrap_or(0)))
+}
+
+/// [`unshare`], then set both offsets.
+pub fn unshare_with(monotonic: &TimeSpec, boottime: &TimeSpec) -> Result<(), Error> {
+    unshare()?;
+    set_offset(libc::CLOCK_MONOTONIC, monotonic)?;
+    set_offset(libc::CLOCK_BOOTTIME, boottime)
+}
+
+/// Moves the calling process into the namespace its children are created in, so that the
+/// offsets apply to it too. The kernel only allows this for single-threaded processes.
+pub fn enter() -> Result<(), Error> {
+    let path = CString::new("/proc/self/ns/time_for_children").unwrap();
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(Error::last_os_error("open time_for_children"));
+    }
+    let res = unsafe { libc::setns(fd, libc::CLONE_NEWTIME) };
+    let err = Error::last_os_error("setns");
+    unsafe { libc::close(fd) };
+    if res != 0 {
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// The offsets of the calling thread's children's time namespace, by clock id; zero outside
+/// of one.
+pub fn offsets() -> Result<Vec<(libc::clockid_t, TimeSpec)>, Error> {
+    let contents = fs::read_to_string(offsets_path())
+        .map_err(|e| Error::Os("read timens_offsets", e.raw_os_error().unwrap_or(0)))?;
+    parse_offsets(&contents)
+}
+
+fn parse_offsets(contents: &str) -> Result<Vec<(libc::clockid_t, TimeSpec)>, Error> {
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let malformed = || {
+                log::debug!("Unexpected timens_offsets line {:?}", line);
+                Error::Os("parse timens_offsets", libc::EINVAL)
+            };
+            let mut fields = line.split_whitespace();
+            let clockid = match fields.next().ok_or_else(malformed)? {
+                "monotonic" => libc::CLOCK_MONOTONIC,
+                "boottime" => libc::CLOCK_BOOTTIME,
+                id => id.parse().map_err(|_| malformed())?,
+            };
+            let seconds = fields
+                .next()
+                .ok_or_else(malformed)?
+                .parse()
+                .map_err(|_| malformed())?;
+            let nanos = fields
+                .next()
+                .ok_or_else(malformed)?
+                .parse()
+                .map_err(|_| malformed())?;
+            Ok((clockid, TimeSpec { seconds, nanos }))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_offsets() {
+        let parsed =
+            parse_offsets("monotonic           0         0\nboottime        86400       500\n")
+                .unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].0, libc::CLOCK_MONOTONIC);
+        assert_eq!(
+            (parsed[1].0, parsed[1].1.seconds, parsed[1].1.nanos),
+            (libc::CLOCK_BOOTTIME, 86400, 500)
+        );
+        assert!(parse_offsets("monotonic 1\n").is_err());
+    }
+}
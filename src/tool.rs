@@ -0,0 +1,280 @@
+//! `tpom`: inspects and patches the vDSO of this process or, with `--pid`, another one.
+use std::process::ExitCode;
+use std::time::SystemTime;
+
+use tpom::live::{self, parse_time, Command};
+use tpom::vdso::vDSO;
+use tpom::Session;
+
+const USAGE: &str = "\
+Usage: tpom COMMAND [--pid PID] [ARGS...]
+
+Works on the vDSO of this process, or of process PID.
+
+Commands:
+  doctor               Report the kernel, clocksource, security modules and vDSO of this
+                       system, for bug reports
+  inspect              List the vDSO's symbols, and whether tpom can patch them
+  analyze [--file FILE]
+                       Report the symbols, alignment and patch plan of the vDSO, or of
+                       the image dumped at FILE, from any architecture
+  dump [FILE]          Write the vDSO's image to FILE, by default /tmp/vdso-PID
+  diff FILE            List the symbols whose code differs from the image dumped at FILE
+  patch --freeze TIME  Freeze the wall clocks at TIME, a date such as 2021-01-01T00:00:00Z,
+                       seconds since the epoch or now +3d: for good in process PID, or in this
+                       process, to show the time read, and restore them
+  restore              Undo patches left in process PID by earlier runs, or other tools";
+
+/// A command, with the options given after it.
+#[derive(Debug, PartialEq)]
+struct Invocation {
+    command: String,
+    pid: Option<libc::pid_t>,
+    freeze: Option<i64>,
+    file: Option<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Invocation, String> {
+    let command = args.next().ok_or("no command")?;
+    let (mut pid, mut freeze, mut file) = (None, None, None);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--pid" => {
+                let v = value()?;
+                pid = Some(v.parse().map_err(|_| format!("bad pid {:?}", v))?);
+            }
+            "--file" if file.is_none() => file = Some(value()?),
+            "--freeze" => freeze = Some(parse_time(&value()?).map_err(|e| e.to_string())?),
+            _ if !arg.starts_with('-') && file.is_none() => file = Some(arg),
+            _ => return Err(format!("unexpected argument {:?}", arg)),
+        }
+    }
+    match command.as_str() {
+        "doctor" if pid.is_none() && file.is_none() && freeze.is_none() => {}
+        "inspect" | "restore" if file.is_none() && freeze.is_none() => {}
+        "dump" if freeze.is_none() => {}
+        "diff" if file.is_some() && freeze.is_none() => {}
+        "analyze" if (file.is_none() || pid.is_none()) && freeze.is_none() => {}
+        "patch" if file.is_none() && freeze.is_some() => {}
+        "doctor" | "inspect" | "dump" | "restore" | "diff" | "analyze" | "patch" => {
+            return Err(format!("wrong arguments for {}", command))
+        }
+        _ => return Err(format!("unknown command {:?}", command)),
+    }
+    Ok(Invocation {
+        command,
+        pid,
+        freeze,
+        file,
+    })
+}
+
+fn read(pid: Option<libc::pid_t>) -> Result<vDSO, String> {
+    match pid {
+        Some(pid) => vDSO::read_from_pid(pid).map_err(|e| e.to_string()),
+        None => vDSO::read().map_err(|e| e.to_string()),
+    }
+}
+
+fn inspect(pid: Option<libc::pid_t>) -> Result<(), String> {
+    let v = read(pid)?;
+    let patched = v.patched_symbols();
+    // Another process' functions may hold stubs rather than tpom's jump
+    let changed = match pid {
+        Some(_) => v.diff(&read(None)?),
+        None => vec![],
+    };
+    println!(
+        "{:<28} {:<10} {:>8} {:>6}  STATUS",
+        "SYMBOL", "VERSION", "OFFSET", "SIZE"
+    );
+    for sym in v.symbols() {
+        let status = match v.entry_by_name(&sym.name) {
+            None => "-".to_string(),
+            Some(_) if patched.contains(&sym) => "patched".to_string(),
+            Some(_) if changed.iter().any(|c| c.name == sym.name) => "changed".to_string(),
+            Some(entry) => match entry.patchable() {
+                Ok(()) => "patchable".to_string(),
+                Err(e) => e.to_string(),
+            },
+        };
+        println!(
+            "{:<28} {:<10} {:>#8x} {:>6}  {}",
+            sym.name,
+            sym.version.as_deref().unwrap_or("-"),
+            sym.address,
+            sym.size,
+            status
+        );
+    }
+    Ok(())
+}
+
+fn analyze(pid: Option<libc::pid_t>, file: Option<&str>) -> Result<(), String> {
+    let v = match file {
+        Some(file) => {
+            let image = std::fs::read(file).map_err(|e| format!("can't read {}: {}", file, e))?;
+            vDSO::from_bytes(&image).map_err(|e| e.to_string())?
+        }
+        None => read(pid)?,
+    };
+    println!("{}", tpom::analysis::analyze(&v));
+    Ok(())
+}
+
+fn diff(pid: Option<libc::pid_t>, file: &str) -> Result<(), String> {
+    let image = std::fs::read(file).map_err(|e| format!("can't read {}: {}", file, e))?;
+    let dumped = vDSO::from_bytes(&image).map_err(|e| e.to_string())?;
+    for sym in read(pid)?.diff(&dumped) {
+        println!("{}", sym.name);
+    }
+    Ok(())
+}
+
+fn patch(pid: Option<libc::pid_t>, at: i64) -> Result<(), String> {
+    let Some(pid) = pid else {
+        let v = vDSO::read().map_err(|e| e.to_string())?;
+        let mut session = Session::new(&v);
+        session
+            .apply_all(&live::callbacks())
+            .map_err(|e| e.to_string())?;
+        live::execute(Command::Freeze(Some(at))).map_err(|e| e.to_string())?;
+        println!("Patched, now: {:?}", SystemTime::now());
+        session.restore_all().map_err(|e| e.to_string())?;
+        println!("Restored, now: {:?}", SystemTime::now());
+        return Ok(());
+    };
+    patch_remote(pid, at)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn patch_remote(pid: libc::pid_t, at: i64) -> Result<(), String> {
+    use tpom::stubs::Stub;
+    use tpom::{Kind, TimeSpec};
+
+    let mut target = tpom::remote::attach(pid).map_err(|e| e.to_string())?;
+    let stub = Stub::Constant(TimeSpec {
+        seconds: at.div_euclid(1_000_000_000),
+        nanos: at.rem_euclid(1_000_000_000),
+    });
+    for kind in [Kind::GetTime, Kind::GetTimeOfDay, Kind::Time] {
+        match target.install(kind, &stub) {
+            Ok(()) => println!("Patched {:?}", kind),
+            // Not every vDSO has `time`
+            Err(e) if kind == Kind::Time => println!("Not patching {:?}: {}", kind, e),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    target.leak();
+    Ok(())
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn patch_remote(_pid: libc::pid_t, _at: i64) -> Result<(), String> {
+    Err("patching other processes is only supported on x86_64".to_string())
+}
+
+fn restore(pid: Option<libc::pid_t>) -> Result<(), String> {
+    let pid = pid.ok_or("restore needs --pid: this process is never left patched")?;
+    let mut target = tpom::remote::attach(pid).map_err(|e| e.to_string())?;
+    let pristine = vDSO::read().map_err(|e| e.to_string())?;
+    for sym in target.restore_from(&pristine).map_err(|e| e.to_string())? {
+        println!("Restored {}", sym.name);
+    }
+    Ok(())
+}
+
+fn run(invocation: Invocation) -> Result<(), String> {
+    let pid = invocation.pid;
+    match invocation.command.as_str() {
+        "doctor" => {
+            println!("{}", tpom::doctor());
+            Ok(())
+        }
+        "inspect" => inspect(pid),
+        "dump" => {
+            let path = invocation.file.unwrap_or_else(|| {
+                format!(
+                    "/tmp/vdso-{}",
+                    pid.unwrap_or(std::process::id() as libc::pid_t)
+                )
+            });
+            read(pid)?
+                .dump_to(&path)
+                .map_err(|e| format!("can't write {}: {}", path, e))?;
+            println!("{}", path);
+            Ok(())
+        }
+        "analyze" => analyze(pid, invocation.file.as_deref()),
+        "diff" => diff(pid, invocation.file.as_deref().unwrap_or_default()),
+        "patch" => patch(pid, invocation.freeze.unwrap_or_default()),
+        _ => restore(pid),
+    }
+}
+
+pub fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().is_some_and(|a| a == "--help" || a == "-h") {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    match parse_args(args).and_then(run) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("tpom: {}\n\n{}", e, USAGE);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Invocation, String> {
+        parse_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            parse(&["patch", "--pid", "42", "--freeze", "1000"]),
+            Ok(Invocation {
+                command: "patch".to_string(),
+                pid: Some(42),
+                freeze: Some(1_000_000_000_000),
+                file: None,
+            })
+        );
+        assert_eq!(
+            parse(&["diff", "/tmp/vdso"]).unwrap().file.as_deref(),
+            Some("/tmp/vdso")
+        );
+        assert_eq!(
+            parse(&["analyze", "--file", "/tmp/vdso"])
+                .unwrap()
+                .file
+                .as_deref(),
+            Some("/tmp/vdso")
+        );
+        assert_eq!(
+            parse(&["dump", "out.bin"]).unwrap().file.as_deref(),
+            Some("out.bin")
+        );
+        for bad in [
+            &[][..],
+            &["frobnicate"],
+            &["diff"],
+            &["patch"],
+            &["inspect", "extra"],
+            &["inspect", "--pid", "me"],
+            &["inspect", "--verbose"],
+            &["analyze", "--file"],
+            &["doctor", "--pid", "1"],
+            &["analyze", "--file", "a", "--pid", "1"],
+        ] {
+            assert!(parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+}
@@ -1,57 +1,395 @@
+use crate::strict::{self, Reason};
+use crate::{observers, recent, sampling, stats, time_report, Kind, TimeSpec};
 use crate::{ClockGetResCb, ClockGetTimeCb, ClockGetTimeOfDayCb, TimeCb};
 use libc::{self, c_void};
+use std::any::Any;
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::{Mutex, RwLock};
 
 pub(crate) static CLOCK_GTOD_CB: RwLock<Option<ClockGetTimeOfDayCb>> = RwLock::new(None);
 pub(crate) static CLOCK_GT_CB: RwLock<Option<ClockGetTimeCb>> = RwLock::new(None);
 pub(crate) static CLOCK_RES_CB: RwLock<Option<ClockGetResCb>> = RwLock::new(None);
 pub(crate) static TIME_CB: RwLock<Option<TimeCb>> = RwLock::new(None);
+/// Answers the CPU-time clocks instead of `CLOCK_GT_CB`, if set.
+pub(crate) static CPU_CLOCK_CB: RwLock<Option<ClockGetTimeCb>> = RwLock::new(None);
+#[allow(dead_code)]
 pub(crate) static BACKUP_VDSO: Mutex<Vec<u8>> = Mutex::new(vec![]);
 
-/// Trampoline function between C and user's function. Panics if function was not set.
-pub(crate) extern "C" fn my_time(t: *mut libc::time_t) -> libc::time_t {
-    let res = TIME_CB.read().unwrap().unwrap()();
+static CALLBACK_PANIC: Mutex<Option<String>> = Mutex::new(None);
+
+thread_local! {
+    // Set while a user callback runs on this thread; `const` so that accessing it never
+    // allocates or registers a destructor from within a vDSO call.
+    static IN_CALLBACK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks the current thread as executing a user callback, for as long as it is alive.
+pub(crate) struct ReentrancyGuard;
+
+impl ReentrancyGuard {
+    /// Returns `None` if this thread is already inside a user callback; `name` and `cb`
+    /// identify the callback that re-entered the vDSO.
+    pub(crate) fn enter(name: &str, cb: *const ()) -> Option<ReentrancyGuard> {
+        if IN_CALLBACK.with(|f| f.replace(true)) {
+            log::debug!(
+                "Re-entrant call to {} from callback at {:p}, falling back to the syscall",
+                name,
+                cb
+            );
+            return None;
+        }
+        Some(ReentrancyGuard)
+    }
+}
+
+impl Drop for ReentrancyGuard {
+    fn drop(&mut self) {
+        IN_CALLBACK.with(|f| f.set(false));
+    }
+}
+
+/// Returns the message of the most recent panic in a user callback, clearing it.
+///
+/// A panic can't unwind through the vDSO's C callers, so it is caught in the trampoline
+/// and the call is answered by the real syscall instead.
+pub fn take_callback_panic() -> Option<String> {
+    CALLBACK_PANIC.lock().unwrap().take()
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+/// The currently set user function, if any. The lock is only poisoned if a writer panicked,
+/// which doesn't invalidate the `Option` it holds.
+pub(crate) fn callback<T: Copy>(lock: &RwLock<Option<T>>) -> Option<T> {
+    *lock.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// `CLOCK_PROCESS_CPUTIME_ID`, `CLOCK_THREAD_CPUTIME_ID`, and the clocks of other processes
+/// and threads from `clock_getcpuclockid(3)`; not the (also negative) fd-based clocks.
+pub(crate) fn is_cpu_clock(clockid: libc::clockid_t) -> bool {
+    const CLOCKFD: libc::clockid_t = 3;
+    match clockid {
+        libc::CLOCK_PROCESS_CPUTIME_ID | libc::CLOCK_THREAD_CPUTIME_ID => true,
+        id => id < 0 && id & 7 != CLOCKFD,
+    }
+}
+
+/// The callback answering `clock_gettime(clockid)`, if any.
+pub(crate) fn clock_callback(clockid: libc::clockid_t) -> Option<ClockGetTimeCb> {
+    if is_cpu_clock(clockid) {
+        if let Some(cb) = callback(&CPU_CLOCK_CB) {
+            return Some(cb);
+        }
+    }
+    callback(&CLOCK_GT_CB)
+}
+
+/// Runs the user callback `f`, returning `None` if it re-entered the vDSO or panicked.
+pub(crate) fn run_callback<T>(
+    name: &'static str,
+    cb: *const (),
+    f: impl FnOnce() -> T,
+) -> Option<T> {
+    let _guard = ReentrancyGuard::enter(name, cb)?;
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(res) => Some(res),
+        Err(payload) => {
+            let msg = panic_message(&*payload);
+            log::debug!("Callback for {} at {:p} panicked: {}", name, cb, msg);
+            *CALLBACK_PANIC.lock().unwrap_or_else(|e| e.into_inner()) = Some(msg);
+            strict::escaped(name, None, Reason::Panicked);
+            None
+        }
+    }
+}
+
+// tpom's own syscall instruction, which the seccomp filter lets through: the callbacks
+// make the real syscalls from threads that may be trapped.
+#[cfg(target_arch = "x86_64")]
+std::arch::global_asm!(
+    ".pushsection .text",
+    ".globl __tpom_syscall3",
+    ".hidden __tpom_syscall3",
+    "__tpom_syscall3:",
+    "mov rax, rdi",
+    "mov rdi, rsi",
+    "mov rsi, rdx",
+    "mov rdx, rcx",
+    "syscall",
+    ".globl __tpom_syscall3_ret",
+    ".hidden __tpom_syscall3_ret",
+    "__tpom_syscall3_ret:",
+    "ret",
+    ".popsection",
+);
+#[cfg(target_arch = "aarch64")]
+std::arch::global_asm!(
+    ".pushsection .text",
+    ".globl __tpom_syscall3",
+    ".hidden __tpom_syscall3",
+    "__tpom_syscall3:",
+    "mov x8, x0",
+    "mov x0, x1",
+    "mov x1, x2",
+    "mov x2, x3",
+    "svc #0",
+    ".globl __tpom_syscall3_ret",
+    ".hidden __tpom_syscall3_ret",
+    "__tpom_syscall3_ret:",
+    "ret",
+    ".popsection",
+);
+#[cfg(target_arch = "riscv64")]
+std::arch::global_asm!(
+    ".pushsection .text",
+    ".globl __tpom_syscall3",
+    ".hidden __tpom_syscall3",
+    "__tpom_syscall3:",
+    "mv a7, a0",
+    "mv a0, a1",
+    "mv a1, a2",
+    "mv a2, a3",
+    "ecall",
+    ".globl __tpom_syscall3_ret",
+    ".hidden __tpom_syscall3_ret",
+    "__tpom_syscall3_ret:",
+    "ret",
+    ".popsection",
+);
+
+extern "C" {
+    /// Returns the raw kernel result, `-errno` on failure, as the vDSO functions do.
+    fn __tpom_syscall3(nr: libc::c_long, a: usize, b: usize, c: usize) -> libc::c_long;
+    static __tpom_syscall3_ret: u8;
+}
+
+/// The address the kernel reports for syscalls made by [`raw_syscall`].
+#[cfg_attr(not(feature = "seccomp"), allow(dead_code))]
+pub(crate) fn raw_syscall_address() -> usize {
+    std::ptr::addr_of!(__tpom_syscall3_ret) as usize
+}
+
+pub(crate) fn raw_syscall(nr: libc::c_long, a: usize, b: usize, c: usize) -> libc::c_long {
+    unsafe { __tpom_syscall3(nr, a, b, c) }
+}
+
+pub(crate) fn raw_clock_gettime(clockid: libc::clockid_t, ts: *mut libc::timespec) -> libc::c_int {
+    raw_syscall(libc::SYS_clock_gettime, clockid as usize, ts as usize, 0) as libc::c_int
+}
+
+pub(crate) fn raw_clock_getres(clockid: libc::clockid_t, ts: *mut libc::timespec) -> libc::c_int {
+    raw_syscall(libc::SYS_clock_getres, clockid as usize, ts as usize, 0) as libc::c_int
+}
+
+pub(crate) fn raw_gettimeofday(tp: *mut libc::timeval, tz: *mut c_void) -> libc::c_int {
+    raw_syscall(libc::SYS_gettimeofday, tp as usize, tz as usize, 0) as libc::c_int
+}
+
+/// Not every architecture has a `time` syscall; derive it from `CLOCK_REALTIME` instead.
+pub(crate) fn raw_time(t: *mut libc::time_t) -> libc::time_t {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    raw_clock_gettime(libc::CLOCK_REALTIME, &mut ts);
     if !t.is_null() {
         unsafe {
-            *t = res;
+            *t = ts.tv_sec;
         }
     }
+    ts.tv_sec
+}
+
+/// Trampoline function between C and user's function. Uses the syscall if function was not set.
+pub(crate) extern "C" fn my_time(t: *mut libc::time_t) -> libc::time_t {
+    stats::count(Kind::Time, None);
+    sampling::tick(Kind::Time);
+    let res = time(t);
+    returned(
+        Kind::Time,
+        None,
+        TimeSpec {
+            seconds: res,
+            nanos: 0,
+        },
+    );
     res
 }
 
-/// Trampoline function between C and user's function. Panics if function was not set.
-pub(crate) extern "C" fn my_clockgettime(clockid: libc::clockid_t, ts: *mut libc::timespec) -> u32 {
-    if !ts.is_null() {
-        let res = CLOCK_GT_CB.read().unwrap().unwrap()(clockid);
+fn time(t: *mut libc::time_t) -> libc::time_t {
+    let Some(cb) = callback(&TIME_CB) else {
+        strict::escaped("time", None, Reason::NoCallback);
+        return raw_time(t);
+    };
+    let Some(res) = run_callback("time", cb as *const (), cb) else {
+        return raw_time(t);
+    };
+    if !t.is_null() {
         unsafe {
-            (*ts).tv_sec = res.seconds;
-            (*ts).tv_nsec = res.nanos;
+            *t = res;
         }
     }
+    res
+}
+
+/// Records what a call returned, for [`crate::recent_calls`] and the observers.
+fn returned(kind: Kind, clockid: Option<libc::clockid_t>, value: TimeSpec) {
+    recent::record(kind, clockid, value);
+    observers::notify(kind, clockid, value);
+}
+
+/// Records what a successful call wrote to `ts`.
+fn notify_timespec(
+    kind: Kind,
+    clockid: libc::clockid_t,
+    ret: libc::c_int,
+    ts: *const libc::timespec,
+) {
+    if ret != 0 || ts.is_null() {
+        return;
+    }
+    let ts = unsafe { *ts };
+    let value = TimeSpec {
+        seconds: ts.tv_sec,
+        nanos: ts.tv_nsec,
+    };
+    returned(kind, Some(clockid), value);
+}
+
+/// Trampoline function between C and user's function. Uses the syscall if function was not set.
+pub(crate) extern "C" fn my_clockgettime(
+    clockid: libc::clockid_t,
+    ts: *mut libc::timespec,
+) -> libc::c_int {
+    stats::count(Kind::GetTime, Some(clockid));
+    sampling::tick(Kind::GetTime);
+    let ret = clockgettime(clockid, ts);
+    notify_timespec(Kind::GetTime, clockid, ret, ts);
+    ret
+}
+
+fn clockgettime(clockid: libc::clockid_t, ts: *mut libc::timespec) -> libc::c_int {
+    let Some(cb) = clock_callback(clockid) else {
+        strict::escaped("clock_gettime", Some(clockid), Reason::NoCallback);
+        return raw_clock_gettime(clockid, ts);
+    };
+    if ts.is_null() {
+        return 0;
+    }
+    let Some(res) = run_callback("clock_gettime", cb as *const (), || cb(clockid)) else {
+        return raw_clock_gettime(clockid, ts);
+    };
+    if clockid == libc::CLOCK_REALTIME {
+        time_report::mocked(res);
+    }
+    unsafe {
+        (*ts).tv_sec = res.seconds;
+        (*ts).tv_nsec = res.nanos;
+    }
     0
 }
 
-/// Trampoline function between C and user's function. Panics if function was not set.
-pub(crate) extern "C" fn my_clockgetres(clockid: libc::clockid_t, ts: *mut libc::timespec) -> u32 {
-    if !ts.is_null() {
-        let res = CLOCK_RES_CB.read().unwrap().unwrap()(clockid);
-        unsafe {
-            (*ts).tv_sec = res.seconds;
-            (*ts).tv_nsec = res.nanos;
-        }
+/// Trampoline function between C and user's function. Uses the syscall if function was not set.
+pub(crate) extern "C" fn my_clockgetres(
+    clockid: libc::clockid_t,
+    ts: *mut libc::timespec,
+) -> libc::c_int {
+    stats::count(Kind::ClockGetRes, Some(clockid));
+    sampling::tick(Kind::ClockGetRes);
+    let ret = clockgetres(clockid, ts);
+    notify_timespec(Kind::ClockGetRes, clockid, ret, ts);
+    ret
+}
+
+fn clockgetres(clockid: libc::clockid_t, ts: *mut libc::timespec) -> libc::c_int {
+    let Some(cb) = callback(&CLOCK_RES_CB) else {
+        return raw_clock_getres(clockid, ts);
+    };
+    if ts.is_null() {
+        return 0;
+    }
+    let Some(res) = run_callback("clock_getres", cb as *const (), || cb(clockid)) else {
+        return raw_clock_getres(clockid, ts);
+    };
+    unsafe {
+        (*ts).tv_sec = res.seconds;
+        (*ts).tv_nsec = res.nanos;
     }
     0
 }
 
-/// Trampoline function between C and user's function. Panics if function was not set.
+/// Trampoline function between C and user's function. Uses the syscall if function was not set.
 /// Missing TZ support.
-pub(crate) extern "C" fn my_gettimeofday(tp: *mut libc::timeval, _tz: *mut c_void) {
+pub(crate) extern "C" fn my_gettimeofday(tp: *mut libc::timeval, tz: *mut c_void) -> libc::c_int {
+    stats::count(Kind::GetTimeOfDay, None);
+    sampling::tick(Kind::GetTimeOfDay);
+    let ret = gettimeofday(tp, tz);
+    if ret == 0 && !tp.is_null() {
+        let tv = unsafe { *tp };
+        let value = TimeSpec {
+            seconds: tv.tv_sec,
+            nanos: tv.tv_usec * 1000,
+        };
+        returned(Kind::GetTimeOfDay, None, value);
+    }
+    ret
+}
+
+fn gettimeofday(tp: *mut libc::timeval, tz: *mut c_void) -> libc::c_int {
+    let Some(cb) = callback(&CLOCK_GTOD_CB) else {
+        strict::escaped("gettimeofday", None, Reason::NoCallback);
+        return raw_gettimeofday(tp, tz);
+    };
     // TODO: Support TZ
-    if !tp.is_null() {
-        let res = CLOCK_GTOD_CB.read().unwrap().unwrap()();
-        unsafe {
-            (*tp).tv_sec = res.seconds;
-            (*tp).tv_usec = res.micros;
-        }
+    if tp.is_null() {
+        return 0;
+    }
+    let Some(res) = run_callback("gettimeofday", cb as *const (), cb) else {
+        return raw_gettimeofday(tp, tz);
+    };
+    unsafe {
+        (*tp).tv_sec = res.seconds;
+        (*tp).tv_usec = res.micros;
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unset_callback_uses_syscall() {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        assert_eq!(my_clockgettime(libc::CLOCK_REALTIME, &mut ts), 0);
+        assert!(ts.tv_sec > 0);
+
+        let mut t = 0;
+        assert!(my_time(&mut t) > 0);
+        assert!(t > 0);
+    }
+
+    #[test]
+    fn test_is_cpu_clock() {
+        assert!(is_cpu_clock(libc::CLOCK_PROCESS_CPUTIME_ID));
+        assert!(is_cpu_clock(libc::CLOCK_THREAD_CPUTIME_ID));
+        let mut pid_clock = 0;
+        assert_eq!(unsafe { libc::clock_getcpuclockid(0, &mut pid_clock) }, 0);
+        assert!(is_cpu_clock(pid_clock));
+        // FD_TO_CLOCKID(3)
+        assert!(!is_cpu_clock((!3 << 3) | 3));
+        assert!(!is_cpu_clock(libc::CLOCK_MONOTONIC));
     }
 }
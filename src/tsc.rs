@@ -0,0 +1,187 @@
+//! Emulates `rdtsc` and `rdtscp` for code that reads the TSC directly, bypassing the vDSO.
+//!
+//! [`trap`] makes the instructions fault (`PR_SET_TSC`), and a SIGSEGV handler answers them
+//! with the real TSC shifted by how far the mocked `CLOCK_MONOTONIC` is from the real one. So
+//! cycle counts agree with the installed `GetTime` callback, and with the vDSO functions left
+//! unpatched, which read the TSC themselves.
+//!
+//! The callback then also runs from the signal handler, where it must not take locks that
+//! the interrupted code may hold.
+use crate::error::Error;
+use crate::registry;
+use crate::trampolines::{callback, raw_clock_gettime, run_callback, CLOCK_GT_CB};
+use crate::{vvar, Kind};
+use std::arch::x86_64::{__rdtscp, _rdtsc};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+const RDTSC: [u8; 2] = [0x0f, 0x31];
+const RDTSCP: [u8; 3] = [0x0f, 0x01, 0xf9];
+/// `vdso_data::clock_mode` when the vDSO reads the TSC.
+const VDSO_CLOCKMODE_TSC: i32 = 1;
+
+/// TSC cycles per nanosecond, as a 32.32 fixed point number; 0 until measured.
+static CYCLES_PER_NS: AtomicU64 = AtomicU64::new(0);
+static PREVIOUS: OnceLock<libc::sigaction> = OnceLock::new();
+
+/// Makes `rdtsc` and `rdtscp` fault in the calling thread, and in the threads it creates
+/// from now on, emulating them from the mocked clock.
+pub fn trap() -> Result<(), Error> {
+    if CYCLES_PER_NS.load(Ordering::Relaxed) == 0 {
+        CYCLES_PER_NS.store(measure_rate(), Ordering::Relaxed);
+    }
+    install_handler()?;
+    if unsafe { libc::prctl(libc::PR_SET_TSC, libc::PR_TSC_SIGSEGV, 0, 0, 0) } != 0 {
+        return Err(Error::last_os_error("prctl"));
+    }
+    Ok(())
+}
+
+/// Lets the calling thread read the TSC directly again.
+pub fn untrap() -> Result<(), Error> {
+    if unsafe { libc::prctl(libc::PR_SET_TSC, libc::PR_TSC_ENABLE, 0, 0, 0) } != 0 {
+        return Err(Error::last_os_error("prctl"));
+    }
+    Ok(())
+}
+
+/// The TSC frequency in Hz, once measured by [`trap`].
+pub fn frequency() -> Option<u64> {
+    match CYCLES_PER_NS.load(Ordering::Relaxed) {
+        0 => None,
+        rate => Some(((rate as u128 * 1_000_000_000) >> 32) as u64),
+    }
+}
+
+fn monotonic_raw() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    raw_clock_gettime(libc::CLOCK_MONOTONIC_RAW, &mut ts);
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// The kernel's own conversion factor when the vDSO reads the TSC; otherwise measured over
+/// a short sleep.
+fn measure_rate() -> u64 {
+    if let Ok(data) = vvar::read() {
+        if data.clock_mode == VDSO_CLOCKMODE_TSC && data.mult > 0 {
+            // ns = cycles * mult >> shift
+            return (((1u128 << 32) << data.shift) / data.mult as u128) as u64;
+        }
+    }
+    let (c0, t0) = (unsafe { _rdtsc() }, monotonic_raw());
+    std::thread::sleep(Duration::from_millis(10));
+    let (c1, t1) = (unsafe { _rdtsc() }, monotonic_raw());
+    ((((c1 - c0) as u128) << 32) / (t1 - t0).max(1) as u128) as u64
+}
+
+fn install_handler() -> Result<(), Error> {
+    if PREVIOUS.get().is_some() {
+        return Ok(());
+    }
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = on_segv as *const () as usize;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+    let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sigaction(libc::SIGSEGV, &action, &mut previous) } != 0 {
+        return Err(Error::last_os_error("sigaction"));
+    }
+    let _ = PREVIOUS.set(previous);
+    Ok(())
+}
+
+/// Reads the real TSC, briefly letting this thread do so.
+fn real_tsc(with_aux: bool) -> (u64, u32) {
+    unsafe { libc::prctl(libc::PR_SET_TSC, libc::PR_TSC_ENABLE, 0, 0, 0) };
+    let mut aux = 0;
+    let tsc = unsafe {
+        if with_aux {
+            __rdtscp(&mut aux)
+        } else {
+            _rdtsc()
+        }
+    };
+    unsafe { libc::prctl(libc::PR_SET_TSC, libc::PR_TSC_SIGSEGV, 0, 0, 0) };
+    (tsc, aux)
+}
+
+/// Nanoseconds the mocked `CLOCK_MONOTONIC` is ahead of the real one; 0 when not mocked.
+fn mock_delta() -> i128 {
+    let Some(cb) = callback(&CLOCK_GT_CB) else {
+        return 0;
+    };
+    // A restored function's callback stays set
+    if registry::try_kind_patched(Kind::GetTime) == Some(false) {
+        return 0;
+    }
+    let Some(mock) = run_callback("rdtsc", cb as *const (), || cb(libc::CLOCK_MONOTONIC)) else {
+        return 0;
+    };
+    let mut real = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    raw_clock_gettime(libc::CLOCK_MONOTONIC, &mut real);
+    (mock.seconds as i128 - real.tv_sec as i128) * 1_000_000_000
+        + (mock.nanos as i128 - real.tv_nsec as i128)
+}
+
+/// The emulated TSC, and `IA32_TSC_AUX` for `rdtscp`.
+fn emulated(with_aux: bool) -> (u64, u32) {
+    let delta = mock_delta();
+    let (tsc, aux) = real_tsc(with_aux);
+    let rate = CYCLES_PER_NS.load(Ordering::Relaxed) as i128;
+    ((tsc as i128 + ((delta * rate) >> 32)) as u64, aux)
+}
+
+unsafe extern "C" fn on_segv(sig: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    // A trapped rdtsc is a general protection fault, reported by the kernel itself; other
+    // faults may be at an address that can't be read
+    if (*info).si_code == libc::SI_KERNEL {
+        let gregs = &mut (*(ctx as *mut libc::ucontext_t)).uc_mcontext.gregs;
+        let rip = gregs[libc::REG_RIP as usize] as *const u8;
+        let len = if std::slice::from_raw_parts(rip, 2) == RDTSC {
+            2
+        } else if std::slice::from_raw_parts(rip, 3) == RDTSCP {
+            3
+        } else {
+            0
+        };
+        if len > 0 {
+            let (tsc, aux) = emulated(len == 3);
+            gregs[libc::REG_RAX as usize] = (tsc & 0xffff_ffff) as i64;
+            gregs[libc::REG_RDX as usize] = (tsc >> 32) as i64;
+            if len == 3 {
+                gregs[libc::REG_RCX as usize] = aux as i64;
+            }
+            gregs[libc::REG_RIP as usize] += len;
+            return;
+        }
+    }
+    chain(sig, info, ctx);
+}
+
+/// Hands a fault that is not ours to the handler installed before us.
+unsafe fn chain(sig: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    let Some(previous) = PREVIOUS.get() else {
+        return;
+    };
+    match previous.sa_sigaction {
+        // Returning re-runs the faulting instruction, now with the previous disposition
+        libc::SIG_DFL | libc::SIG_IGN => {
+            libc::sigaction(sig, previous, std::ptr::null_mut());
+        }
+        handler if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+            let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                std::mem::transmute(handler);
+            handler(sig, info, ctx);
+        }
+        handler => {
+            let handler: extern "C" fn(libc::c_int) = std::mem::transmute(handler);
+            handler(sig);
+        }
+    }
+}
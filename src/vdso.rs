@@ -1,56 +1,264 @@
+use crate::elf::{self, Header, Reader, Sym};
 use crate::*;
 use cacheflush_sys;
 use core::slice;
-use goblin::elf::*;
-use goblin::strtab::Strtab;
-use std::error::Error;
+use std::error;
 use std::fs;
-use std::sync::Mutex;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex, OnceLock};
 
-static vdso_mutex: Mutex<i32> = Mutex::new(0);
+pub use crate::elf::ElfClass;
 
-#[derive(Debug, PartialEq)]
-pub(crate) struct DynSym {
-    pub(crate) name: String,
-    pub(crate) address: usize,
-    pub(crate) size: usize,
+pub(crate) static VDSO_MUTEX: Mutex<i32> = Mutex::new(0);
+/// The vDSO as tpom's constructor found it, before anything in `main` could patch it.
+static PRISTINE: OnceLock<Option<vDSO>> = OnceLock::new();
+
+/// What [`vDSO::verify_against`] finds: the symbols whose code differs from the baseline.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Integrity {
+    /// Overwritten by tpom, in this process, and not restored yet.
+    pub patched: Vec<Symbol>,
+    /// Changed by something else: another hooking library, a leaked patch of a `Remote`,
+    /// or a patch written without tpom knowing.
+    pub foreign: Vec<Symbol>,
+}
+
+impl Integrity {
+    /// Whether the vDSO is as the baseline.
+    pub fn is_clean(&self) -> bool {
+        self.patched.is_empty() && self.foreign.is_empty()
+    }
+}
+
+/// Compares the vDSO mapped now with the one mapped when the process started, as read
+/// by tpom's constructor. Hooks installed before that, such as by the constructor of
+/// another library, are part of the baseline; compare against the vDSO of a fresh
+/// process, with [`vDSO::read_from_pid`], to catch those.
+pub fn verify() -> Result<Integrity, Error> {
+    let baseline = PRISTINE.get().and_then(Option::as_ref).ok_or_else(|| {
+        Error::UnsupportedPlatform("the vDSO could not be read at startup".to_string())
+    })?;
+    let v = vDSO::read_remapped().map_err(|e| Error::UnsupportedPlatform(e.to_string()))?;
+    Ok(v.verify_against(baseline))
+}
+
+/// Keeps a copy of the vDSO for [`verify`]; called from the constructor.
+pub(crate) fn snapshot_pristine() {
+    let v = auxv::read_aux_vec()
+        .ok()
+        .and_then(|avv| vDSO::read_at(avv).ok());
+    let _ = PRISTINE.set(v);
+}
+
+/// A function exported by the vDSO.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    /// Offset of the function from the start of the vDSO.
+    pub address: usize,
+    /// Size of the function, padded to the alignment of its section.
+    pub size: usize,
+    /// The symbol version (`LINUX_2.6`, for example), if the vDSO has versioned symbols.
+    pub version: Option<String>,
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug)]
+/// A snapshot of the process' vDSO. Cheap to clone; clones share the snapshot.
+#[derive(Debug, Clone)]
 pub struct vDSO {
-    avv: auxv::AuxVecValues,
-    data: Vec<u8>,
+    /// `None` for images built with [`vDSO::from_bytes`], which can't be patched.
+    avv: Option<auxv::AuxVecValues>,
+    data: Arc<[u8]>,
+    symbols: Arc<OnceLock<Symbols>>,
 }
 
-#[cfg(target_pointer_width = "32")]
-const ELF_HDR_SIZE: usize = 52;
+/// The alignment functions are padded to in [`Symbol::size`], and where it comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Alignment {
+    /// The alignment of the `.text` section.
+    Section(u64),
+    /// The largest power of two dividing every symbol address, for images without sections.
+    Inferred(u64),
+}
 
+impl Alignment {
+    pub fn bytes(self) -> u64 {
+        match self {
+            Alignment::Section(a) | Alignment::Inferred(a) => a,
+        }
+    }
+}
+
+/// Everything derived from the symbol table, computed on first use.
+#[derive(Debug)]
+struct Symbols {
+    dynsyms: Vec<Symbol>,
+    /// For each `Kind` found: the index of its symbol in `dynsyms`, and its patchable size.
+    kinds: Vec<(Kind, usize, usize)>,
+    machine: u16,
+    alignment: Alignment,
+}
+
+impl Symbols {
+    fn new(dynsyms: Vec<Symbol>, machine: u16, alignment: Alignment) -> Symbols {
+        let kinds = dynsyms
+            .iter()
+            .enumerate()
+            .filter_map(|(i, ds)| {
+                symbol_kind(&ds.name).map(|k| (k, i, patchable_size(&dynsyms, ds)))
+            })
+            .collect();
+        Symbols {
+            dynsyms,
+            kinds,
+            machine,
+            alignment,
+        }
+    }
+}
+
+#[cfg(target_pointer_width = "32")]
+const ELF_CLASS: ElfClass = ElfClass::Elf32;
 #[cfg(target_pointer_width = "64")]
-const ELF_HDR_SIZE: usize = 64;
+const ELF_CLASS: ElfClass = ElfClass::Elf64;
+
+#[cfg(target_endian = "little")]
+const ELF_DATA: u8 = elf::ELFDATA2LSB;
+#[cfg(target_endian = "big")]
+const ELF_DATA: u8 = elf::ELFDATA2MSB;
+
+#[cfg(target_arch = "x86_64")]
+const ELF_MACHINE: u16 = elf::EM_X86_64;
+#[cfg(target_arch = "aarch64")]
+const ELF_MACHINE: u16 = elf::EM_AARCH64;
+#[cfg(target_arch = "riscv64")]
+const ELF_MACHINE: u16 = elf::EM_RISCV;
 
 impl vDSO {
-    pub fn read() -> Result<vDSO, Box<dyn Error>> {
+    pub fn read() -> Result<vDSO, Box<dyn error::Error>> {
+        platform::probe()?;
+        vDSO::read_at(auxv::read_aux_vec()?)
+    }
+
+    /// Reads the vDSO where /proc/self/maps has it now, rather than where the auxiliary
+    /// vector had it at startup; they differ in a process restored by CRIU.
+    pub(crate) fn read_remapped() -> Result<vDSO, Box<dyn error::Error>> {
+        let (base, _) = auxv::vdso_mapping()?;
         let auxvec = auxv::read_aux_vec()?;
+        vDSO::read_at(auxv::AuxVecValues {
+            vdso_base: base,
+            ..auxvec
+        })
+    }
 
-        // As the size of the vDSO is unknown, read first only the header which has constant size
-        let header_bytes: &[u8] =
-            unsafe { slice::from_raw_parts(&*(auxvec.vdso_base as *const u8), ELF_HDR_SIZE) };
-        let bare_header = Elf::parse_header(&header_bytes).unwrap();
-        // Having parsed the header, we can now calculate the len of the vDSO
-        let vdso_len = usize::from(bare_header.e_shnum * bare_header.e_shentsize)
-            + (bare_header.e_shoff as usize);
+    fn read_at(auxvec: auxv::AuxVecValues) -> Result<vDSO, Box<dyn error::Error>> {
+        // As the size of the vDSO is unknown, read first only the identification, which tells
+        // the size of the header
+        let ident: &[u8] =
+            unsafe { slice::from_raw_parts(&*(auxvec.vdso_base as *const u8), elf::SIZEOF_IDENT) };
+        let class = ElfClass::from_ident(ident)?;
+        let header_bytes: &[u8] = unsafe {
+            slice::from_raw_parts(&*(auxvec.vdso_base as *const u8), class.header_size())
+        };
+        check_identity(header_bytes)?;
+        let bare_header = elf::parse_header(header_bytes)?;
+        // The program headers follow; they describe what the kernel actually maps
+        let phdrs_len = bare_header.e_phoff + bare_header.e_phnum * bare_header.e_phentsize;
+        let phdr_bytes =
+            unsafe { slice::from_raw_parts(&*(auxvec.vdso_base as *const u8), phdrs_len) };
+        let phdrs = elf::program_headers(phdr_bytes, &bare_header)?;
+        let segments_len = phdrs
+            .iter()
+            .filter(|ph| ph.p_type == elf::PT_LOAD)
+            .map(|ph| (ph.p_offset + ph.p_filesz) as usize)
+            .max()
+            .unwrap_or(0);
+        let sections_len = bare_header.e_shoff + bare_header.e_shnum * bare_header.e_shentsize;
+        let mapped_len = mapping_len(auxvec.vdso_base);
+        let vdso_len = image_len(segments_len, sections_len, mapped_len, auxvec.page_size)?;
         // And with the len, we can read the right amount
         let vdso_bytes =
             unsafe { slice::from_raw_parts(&*(auxvec.vdso_base as *const u8), vdso_len) };
 
         Ok(vDSO {
+            avv: Some(auxvec),
             data: vdso_bytes.into(),
-            avv: auxvec,
+            symbols: Arc::new(OnceLock::new()),
         })
     }
 
-    pub(crate) fn change_mode(&self, write: bool) {
+    /// Builds a vDSO from an image that is not mapped in this process, such as one dumped on
+    /// another machine. It can be inspected, but patching it fails with [`Error::Offline`].
+    /// The image may be for any architecture; only its symbols are parsed.
+    pub fn from_bytes(data: &[u8]) -> Result<vDSO, Error> {
+        ElfClass::from_ident(data)?;
+        let v = vDSO {
+            avv: None,
+            data: data.into(),
+            symbols: Arc::new(OnceLock::new()),
+        };
+        // Parse now, so that a bad image is reported here rather than on first use
+        let symbols = v.parse_dynsyms()?;
+        let _ = v.symbols.set(symbols);
+        Ok(v)
+    }
+
+    /// Reads the vDSO of process `pid` through /proc/pid/maps and /proc/pid/mem, for
+    /// inspection: as with [`vDSO::from_bytes`], it can't be patched.
+    pub fn read_from_pid(pid: libc::pid_t) -> Result<vDSO, Error> {
+        Ok(read_mapping(pid)?.1)
+    }
+
+    /// The symbols whose code differs between this vDSO and `other`, or that `other` lacks.
+    /// Processes of the same class get the same image from the kernel, so against that of one
+    /// that patches nothing, these are the hooked functions.
+    pub fn diff(&self, other: &vDSO) -> Vec<Symbol> {
+        // The padded sizes may overlap the next symbol, which may be the one that differs
+        let code = |v: &vDSO, sym: &Symbol| {
+            let size = patchable_size(v.dynsyms(), sym);
+            v.data
+                .get(sym.address..sym.address + size)
+                .map(<[u8]>::to_vec)
+        };
+        self.symbols()
+            .filter(|sym| {
+                let theirs = other.dynsyms().iter().find(|o| o.name == sym.name);
+                theirs.is_none_or(|o| code(self, sym) != code(other, o))
+            })
+            .collect()
+    }
+
+    /// The symbols whose code differs from `baseline`, as [`vDSO::diff`], told apart by
+    /// whether tpom patched them in this process.
+    pub fn verify_against(&self, baseline: &vDSO) -> Integrity {
+        let mut integrity = Integrity::default();
+        for sym in self.diff(baseline) {
+            match self.is_live() && registry::is_patched(self.base() + sym.address) {
+                true => integrity.patched.push(sym),
+                false => integrity.foreign.push(sym),
+            }
+        }
+        integrity
+    }
+
+    /// The symbols overwritten with tpom's jump to a trampoline, as built for the running
+    /// architecture, by this process or another.
+    pub fn patched_symbols(&self) -> Vec<Symbol> {
+        self.symbols()
+            .filter(|sym| {
+                let code = &self.data[sym.address..(sym.address + sym.size).min(self.data.len())];
+                opcodes::is_jump(code)
+            })
+            .collect()
+    }
+
+    fn parsed(&self) -> &Symbols {
+        self.symbols
+            .get_or_init(|| self.parse_dynsyms().expect("bad elf"))
+    }
+
+    fn change_mode(&self, avv: &auxv::AuxVecValues, write: bool) -> Result<(), Error> {
         let mode = if write {
             libc::PROT_EXEC | libc::PROT_WRITE | libc::PROT_READ
         } else {
@@ -58,67 +266,137 @@ impl vDSO {
         };
         // As we need to mprotect() the vDSO and that can only be done in full pages, we need
         // to bump the vDSO length to the next page
-        let vdso_size_page_aligned =
-            (self.data.len() + self.avv.page_size - 1) & !(self.avv.page_size - 1);
-        unsafe {
+        let vdso_size_page_aligned = (self.data.len() + avv.page_size - 1) & !(avv.page_size - 1);
+        let ret = unsafe {
             libc::mprotect(
-                self.avv.vdso_base as *mut libc::c_void,
+                avv.vdso_base as *mut libc::c_void,
                 vdso_size_page_aligned,
                 mode,
-            );
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error("mprotect"));
         }
+        Ok(())
     }
 
-    pub(crate) fn dynsyms(&self) -> Vec<DynSym> {
-        let r = Elf::parse(&self.data).expect("bad elf");
+    pub(crate) fn dynsyms(&self) -> &[Symbol] {
+        &self.parsed().dynsyms
+    }
 
-        let mut align = 0;
-        let mut base = 0;
-        for h in r.section_headers {
-            let name = get_str_til_nul(&r.shdr_strtab, h.sh_name);
-            if h.sh_type == goblin::elf::section_header::SHT_PROGBITS && name == ".text" {
-                align = h.sh_addralign;
-                base = h.sh_addr - h.sh_offset;
+    /// Every function the vDSO exports, including aliases and those tpom can't patch.
+    pub fn symbols(&self) -> impl Iterator<Item = Symbol> + '_ {
+        self.dynsyms().iter().cloned()
+    }
+
+    /// The kinds of function that [`vDSO::entry`] finds in this vDSO.
+    pub fn kinds(&self) -> Vec<Kind> {
+        self.parsed().kinds.iter().map(|(k, _, _)| *k).collect()
+    }
+
+    /// Resolves only the dynamic symbols, reading the snapshot in place: the program headers
+    /// lead to PT_DYNAMIC, then DT_SYMTAB/DT_STRTAB, with the symbol count from the hash
+    /// table. Sections are optional, as the kernel is not required to provide them; they
+    /// are only consulted for the alignment of `.text`.
+    fn parse_dynsyms(&self) -> Result<Symbols, Error> {
+        let header = elf::parse_header(&self.data)?;
+        let r = Reader::new(&self.data, &header);
+        let phdrs = elf::program_headers(&self.data, &header)?;
+        let load = phdrs
+            .iter()
+            .find(|ph| ph.p_type == elf::PT_LOAD)
+            .ok_or_else(|| Error::MalformedVdso("no PT_LOAD segment".to_string()))?;
+        let base = load.p_vaddr - load.p_offset;
+        let info = elf::dynamic_info(&self.data, &header, &phdrs)?
+            .ok_or_else(|| Error::MalformedVdso("no PT_DYNAMIC segment".to_string()))?;
+        let count = match (info.hash, info.gnu_hash) {
+            (Some(hash), _) => sysv_hash_count(r, (hash - base) as usize)?,
+            (None, Some(hash)) => gnu_hash_count(r, (hash - base) as usize)?,
+            (None, None) => return Err(Error::MalformedVdso("no hash table".to_string())),
+        };
+
+        let symtab = elf::symbols(&self.data, &header, (info.symtab - base) as usize, count)?;
+        let strtab_at = (info.strtab - base) as usize;
+        let strtab = self
+            .data
+            .get(strtab_at..strtab_at + info.strsz)
+            .ok_or_else(|| Error::MalformedVdso("string table out of bounds".to_string()))?;
+        let versions = match (info.versym, info.verdef) {
+            (Some(versym), Some(verdef)) => {
+                let defs =
+                    elf::version_definitions(r, (verdef - base) as usize, info.verdefnum, strtab)?;
+                let mut versions = vec![];
+                for i in 0..count {
+                    // The top bit marks hidden symbols
+                    let ndx = r.u16((versym - base) as usize + 2 * i)? & 0x7fff;
+                    versions.push(defs.iter().find(|(n, _)| *n == ndx).map(|(_, v)| v.clone()));
+                }
+                versions
             }
-        }
-        let mut ret = vec![];
-        for ds in &r.dynsyms {
-            if ds.st_value == 0 {
-                continue;
+            _ => vec![None; count],
+        };
+        let align = match text_alignment(&self.data, &header) {
+            Some(align) => Alignment::Section(align),
+            None => {
+                log::debug!("No .text section in the vDSO, inferring the alignment");
+                Alignment::Inferred(inferred_alignment(&symtab))
             }
-            let sym_name = get_str_til_nul(&r.dynstrtab, ds.st_name);
-            let symsize = if (ds.st_size % align) == 0 {
-                ds.st_size
-            } else {
-                ds.st_size + (align - (ds.st_size % align))
-            };
-            ret.push(DynSym {
-                name: sym_name.as_str().to_string(),
-                address: (ds.st_value - base) as usize,
-                size: symsize as usize,
-            });
-        }
-        ret
+        };
+        let dynsyms = collect_dynsyms(&symtab, &versions, strtab, base, align.bytes());
+        Ok(Symbols::new(dynsyms, header.e_machine, align))
+    }
+
+    /// The ELF class of the image. Parsing works for either class, but only images of the
+    /// running process' class can be patched.
+    pub fn class(&self) -> ElfClass {
+        ElfClass::from_ident(&self.data).expect("vDSO was validated when read")
+    }
+
+    /// The architecture the image was built for, such as `x86_64`, from its ELF header.
+    pub fn architecture(&self) -> String {
+        elf::machine_name(self.machine())
     }
 
-    pub fn restore(&self) {
+    /// The ELF `e_machine` of the image.
+    pub(crate) fn machine(&self) -> u16 {
+        self.parsed().machine
+    }
+
+    /// The alignment the sizes of [`vDSO::symbols`] are padded to.
+    pub fn alignment(&self) -> Alignment {
+        self.parsed().alignment
+    }
+
+    /// Where the image is mapped; 0 for offline images.
+    pub(crate) fn base(&self) -> usize {
+        self.avv.map_or(0, |avv| avv.vdso_base)
+    }
+
+    /// Whether this is the running process' vDSO, as opposed to one built with
+    /// [`vDSO::from_bytes`].
+    pub fn is_live(&self) -> bool {
+        self.avv.is_some()
+    }
+
+    pub fn restore(&self) -> Result<(), Error> {
         self.overwrite(0, &self.data)
     }
-    pub(crate) fn symbol_code(&self, symbol_name: &str) -> &[u8] {
-        for sym in self.dynsyms() {
-            if sym.name == symbol_name {
-                return &self.data[sym.address..(sym.address + sym.size)];
-            }
-        }
-        unreachable!("Got illegal symbol name");
+    /// The whole image, as read.
+    pub(crate) fn image(&self) -> &[u8] {
+        &self.data
+    }
+    /// The original code at `symbol_address`, as read when the vDSO was loaded.
+    pub(crate) fn symbol_code(&self, symbol_address: usize, size: usize) -> &[u8] {
+        &self.data[symbol_address..(symbol_address + size)]
     }
     /// Overwrites the process' vDSO memory at offset `symbol_address` with `opcodes`.
     /// It is the caller's responsibility to provide the correct amount of data.
-    pub(crate) fn overwrite(&self, symbol_address: usize, opcodes: &[u8]) {
-        let dst_addr = self.avv.vdso_base + symbol_address;
+    pub(crate) fn overwrite(&self, symbol_address: usize, opcodes: &[u8]) -> Result<(), Error> {
+        let avv = self.avv.as_ref().ok_or(Error::Offline)?;
+        let dst_addr = avv.vdso_base + symbol_address;
 
-        let _guard = vdso_mutex.lock().unwrap();
-        self.change_mode(true);
+        let _guard = VDSO_MUTEX.lock().unwrap();
+        self.change_mode(avv, true)?;
         unsafe {
             std::ptr::copy_nonoverlapping(opcodes.as_ptr(), dst_addr as *mut u8, opcodes.len())
         };
@@ -126,74 +404,278 @@ impl vDSO {
         // We need to clear the instruction cache, otherwise it's possible that the old
         // instructions (the trampoline) get executed with the new data (the original vDSO
         // function)
-        self.change_mode(false);
-        unsafe {
-            cacheflush_sys::flush(dst_addr as *const u8, opcodes.len()).unwrap();
-        }
+        self.change_mode(avv, false)?;
+        unsafe { cacheflush_sys::flush(dst_addr as *const u8, opcodes.len()) }
+            .map_err(|e| Error::Os("cacheflush", e.raw_os_error().unwrap_or(0)))
     }
 
-    pub fn entry(&self, wanted: Kind) -> Option<impl TVDSOFun + '_> {
-        for ds in self.dynsyms() {
-            let v = VDSOFun {
-                name: ds.name.clone(),
-                addr: ds.address,
-                size: ds.size,
-                v: self,
-            };
-            let kind = match ds.name.as_str() {
-                // Per the man page:
-                // > "All of these symbols are also available without the "__vdso_" prefix, but you should ignore those."
-                #[cfg(target_arch = "aarch64")]
-                "__kernel_clock_gettime" => Some(Kind::GetTime),
-                #[cfg(target_arch = "aarch64")]
-                "__kernel_gettimeofday" => Some(Kind::GetTimeOfDay),
-                #[cfg(target_arch = "aarch64")]
-                "__kernel_clock_getres" => Some(Kind::ClockGetRes),
-
-                #[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
-                "__vdso_clock_gettime" => Some(Kind::GetTime),
-                #[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
-                "__vdso_gettimeofday" => Some(Kind::GetTimeOfDay),
-                #[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
-                "__vdso_clock_getres" => Some(Kind::ClockGetRes),
-                #[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
-                "__vdso_time" => Some(Kind::Time),
-
-                &_ => None,
-            };
-            if kind.is_none() {
-                continue;
-            }
-            if kind.as_ref() != Some(&wanted) {
-                continue;
-            }
+    pub fn entry(&self, wanted: Kind) -> Option<VDSOFun> {
+        let symbols = self.parsed();
+        let (kind, i, size) = symbols.kinds.iter().find(|(k, _, _)| *k == wanted)?;
+        let ds = &self.dynsyms()[*i];
+        Some(VDSOFun {
+            name: ds.name.clone(),
+            kind: *kind,
+            addr: ds.address,
+            size: *size,
+            v: self.clone(),
+        })
+    }
 
-            return Some(match kind {
-                None => unreachable!(),
-                Some(Kind::GetTime) => GTVdso { v },
-                Some(_) => todo!(),
-            });
-        }
-        None
+    /// Like [`vDSO::entry`], but for the symbol called `name`, which needn't be the one
+    /// `entry` would pick: any alias or prefix (`__vdso_`, `__kernel_`) of a supported
+    /// function is accepted. Its [`Kind`] is taken from the unprefixed name.
+    pub fn entry_by_name(&self, name: &str) -> Option<VDSOFun> {
+        let kind = symbol_kind(name).or_else(|| base_name_kind(name))?;
+        let dynsyms = self.dynsyms();
+        let ds = dynsyms.iter().find(|ds| ds.name == name)?;
+        Some(VDSOFun {
+            name: ds.name.clone(),
+            kind,
+            addr: ds.address,
+            size: patchable_size(dynsyms, ds),
+            v: self.clone(),
+        })
     }
 
+    /// Writes the image to `/tmp/vdso<suffix>`, panicking if it can't; see
+    /// [`vDSO::dump_to`] for other paths.
     pub fn dump(&self, suffix: Option<&str>) {
         let fname = format!("/tmp/vdso{}", suffix.unwrap_or(""));
-        fs::write(&fname, &self.data).unwrap_or_else(|_| panic!("Unable to write file {}", fname));
+        self.dump_to(&fname)
+            .unwrap_or_else(|_| panic!("Unable to write file {}", fname));
+    }
+
+    /// Writes the image to a file at `path`, replacing it, for [`vDSO::from_bytes`] to read.
+    pub fn dump_to(&self, path: impl AsRef<std::path::Path>) -> io::Result<()> {
+        fs::write(path, &self.data)
+    }
+
+    /// Writes the image to `out`, such as a socket or a test's buffer.
+    pub fn write_to(&self, mut out: impl io::Write) -> io::Result<()> {
+        out.write_all(&self.data)
     }
 }
 
-fn get_str_til_nul(s: &Strtab, at: usize) -> String {
-    let mut ret: String = "".to_string();
-    for c in s.get_at(at).unwrap().bytes() {
-        if c == 0 {
-            break;
+fn symbol_kind(name: &str) -> Option<Kind> {
+    match name {
+        // Per the man page:
+        // > "All of these symbols are also available without the "__vdso_" prefix, but you should ignore those."
+        #[cfg(target_arch = "aarch64")]
+        "__kernel_clock_gettime" => Some(Kind::GetTime),
+        #[cfg(target_arch = "aarch64")]
+        "__kernel_gettimeofday" => Some(Kind::GetTimeOfDay),
+        #[cfg(target_arch = "aarch64")]
+        "__kernel_clock_getres" => Some(Kind::ClockGetRes),
+
+        #[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
+        "__vdso_clock_gettime" => Some(Kind::GetTime),
+        #[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
+        "__vdso_gettimeofday" => Some(Kind::GetTimeOfDay),
+        #[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
+        "__vdso_clock_getres" => Some(Kind::ClockGetRes),
+        #[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
+        "__vdso_time" => Some(Kind::Time),
+
+        &_ => None,
+    }
+}
+
+/// The kind of a symbol by its name without prefix, regardless of the architecture.
+pub(crate) fn base_name_kind(name: &str) -> Option<Kind> {
+    let name = name
+        .strip_prefix("__vdso_")
+        .or_else(|| name.strip_prefix("__kernel_"))
+        .unwrap_or(name);
+    match name {
+        "clock_gettime" => Some(Kind::GetTime),
+        "gettimeofday" => Some(Kind::GetTimeOfDay),
+        "clock_getres" => Some(Kind::ClockGetRes),
+        "time" => Some(Kind::Time),
+        _ => None,
+    }
+}
+
+/// The alignment of the `.text` section, if there are section headers. Only the section
+/// headers and their string table are parsed.
+fn text_alignment(data: &[u8], header: &Header) -> Option<u64> {
+    if header.e_shnum == 0 {
+        return None;
+    }
+    let shdrs = elf::section_headers(data, header).ok()?;
+    let shstrtab = shdrs.get(header.e_shstrndx)?;
+    let shstrtab =
+        data.get(shstrtab.sh_offset as usize..(shstrtab.sh_offset + shstrtab.sh_size) as usize)?;
+    shdrs
+        .iter()
+        .find(|h| {
+            h.sh_type == elf::SHT_PROGBITS && elf::str_at(shstrtab, h.sh_name) == Some(".text")
+        })
+        .map(|h| h.sh_addralign)
+}
+
+/// DT_HASH is `[nbucket, nchain, ...]`, with one chain entry per symbol.
+fn sysv_hash_count(r: Reader, hash: usize) -> Result<usize, Error> {
+    Ok(r.u32(hash + 4)? as usize)
+}
+
+/// DT_GNU_HASH doesn't store the symbol count: it is one past the end of the chain that
+/// starts at the highest bucket.
+fn gnu_hash_count(r: Reader, hash: usize) -> Result<usize, Error> {
+    let nbuckets = r.u32(hash)? as usize;
+    let symoffset = r.u32(hash + 4)? as usize;
+    let bloom_size = r.u32(hash + 8)? as usize;
+    let buckets = hash + 16 + bloom_size * r.word_size();
+    let mut last = 0;
+    for i in 0..nbuckets {
+        last = last.max(r.u32(buckets + 4 * i)? as usize);
+    }
+    if last < symoffset {
+        return Ok(symoffset);
+    }
+    let chains = buckets + 4 * nbuckets;
+    while r.u32(chains + 4 * (last - symoffset))? & 1 == 0 {
+        last += 1;
+    }
+    Ok(last + 1)
+}
+
+/// Without a `.text` section, the function alignment is the largest power of two that all
+/// symbol addresses are a multiple of.
+fn inferred_alignment(syms: &[Sym]) -> u64 {
+    let mut align = 64;
+    for sym in syms.iter().filter(|s| s.st_value != 0) {
+        while sym.st_value % align != 0 {
+            align /= 2;
         }
-        ret.push(c.into());
+    }
+    align
+}
+
+fn collect_dynsyms(
+    syms: &[Sym],
+    versions: &[Option<String>],
+    strtab: &[u8],
+    base: u64,
+    align: u64,
+) -> Vec<Symbol> {
+    let mut ret = vec![];
+    for (ds, version) in syms.iter().zip(versions) {
+        if ds.st_value == 0 {
+            continue;
+        }
+        let sym_name = elf::str_at(strtab, ds.st_name).unwrap_or("");
+        let symsize = if (ds.st_size % align) == 0 {
+            ds.st_size
+        } else {
+            ds.st_size + (align - (ds.st_size % align))
+        };
+        ret.push(Symbol {
+            name: sym_name.to_string(),
+            address: (ds.st_value - base) as usize,
+            size: symsize as usize,
+            version: version.clone(),
+        });
     }
     ret
 }
 
+/// Ensures `header_bytes` is an ELF header for the running process' class, endianness and
+/// architecture, so that we never patch in opcodes for the wrong machine.
+fn check_identity(header_bytes: &[u8]) -> Result<(), Error> {
+    let class = ElfClass::from_ident(header_bytes)?;
+    if header_bytes.len() < class.header_size() {
+        return Err(Error::MalformedVdso("truncated ELF header".to_string()));
+    }
+    if class != ELF_CLASS {
+        return Err(Error::WrongArch(format!(
+            "{:?}, expected {:?}",
+            class, ELF_CLASS
+        )));
+    }
+    if header_bytes[elf::EI_DATA] != ELF_DATA {
+        return Err(Error::WrongArch(format!(
+            "ELF data encoding {}, expected {}",
+            header_bytes[elf::EI_DATA],
+            ELF_DATA
+        )));
+    }
+    // e_machine follows the identification and e_type; the endianness is now known to be ours
+    let machine = u16::from_ne_bytes([header_bytes[18], header_bytes[19]]);
+    if machine != ELF_MACHINE {
+        return Err(Error::WrongArch(format!(
+            "machine {}, expected {}",
+            elf::machine_name(machine),
+            elf::machine_name(ELF_MACHINE)
+        )));
+    }
+    Ok(())
+}
+
+/// Length of the `[vdso]` mapping starting at `base`, per /proc/self/maps.
+/// Where the vDSO of `pid` is mapped, and a snapshot of it.
+pub(crate) fn read_mapping(pid: libc::pid_t) -> Result<(usize, vDSO), Error> {
+    let os_error = |call, e: std::io::Error| Error::Os(call, e.raw_os_error().unwrap_or(0));
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))
+        .map_err(|e| os_error("read /proc/pid/maps", e))?;
+    let (start, end) = auxv::find_vdso_mapping(&maps)
+        .ok_or_else(|| Error::UnsupportedPlatform(format!("process {} has no vDSO", pid)))?;
+    let mem = fs::File::open(format!("/proc/{}/mem", pid))
+        .map_err(|e| os_error("open /proc/pid/mem", e))?;
+    let mut data = vec![0; end - start];
+    mem.read_exact_at(&mut data, start as u64)
+        .map_err(|e| os_error("read /proc/pid/mem", e))?;
+    Ok((start, vDSO::from_bytes(&data)?))
+}
+
+fn mapping_len(base: usize) -> Option<usize> {
+    match auxv::vdso_mapping() {
+        Ok((start, end)) if start == base => Some(end - start),
+        _ => None,
+    }
+}
+
+/// Length of the ELF image to read. The kernel maps the `PT_LOAD` segments, but the section
+/// headers (needed to find the symbols) usually come after them, in the same pages.
+fn image_len(
+    segments_len: usize,
+    sections_len: usize,
+    mapped_len: Option<usize>,
+    page_size: usize,
+) -> Result<usize, Error> {
+    // Without /proc, the mapping is at least the page-aligned segments
+    let readable = mapped_len.unwrap_or((segments_len + page_size - 1) & !(page_size - 1));
+    if segments_len == 0 || segments_len > readable {
+        return Err(Error::InconsistentSize {
+            computed: segments_len,
+            mapped: readable,
+        });
+    }
+    if sections_len > readable {
+        log::debug!(
+            "Section headers end at {:#x}, past the mapping ({:#x})",
+            sections_len,
+            readable
+        );
+        return Ok(segments_len);
+    }
+    Ok(segments_len.max(sections_len))
+}
+
+/// The alignment padding added in `dynsyms` may extend a symbol into the next one
+/// (`gettimeofday` into `time`, for example), which a stub must never overwrite.
+pub(crate) fn patchable_size(dynsyms: &[Symbol], sym: &Symbol) -> usize {
+    let next = dynsyms
+        .iter()
+        .map(|ds| ds.address)
+        .filter(|addr| *addr > sym.address)
+        .min();
+    match next {
+        Some(next) => sym.size.min(next - sym.address),
+        None => sym.size,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -202,69 +684,74 @@ mod tests {
     fn test_dynsyms() {
         let test_vdso =
             fs::read("src/test_files/test_vdso_elf_1").expect("Unable to read test file");
-        let a = vDSO {
-            avv: auxv::AuxVecValues {
-                vdso_base: 0,
-                page_size: 0x1000,
-            },
-            data: test_vdso,
-        };
+        let a = vDSO::from_bytes(&test_vdso).unwrap();
         let parsed = a.dynsyms();
         let expected = vec![
-            DynSym {
+            Symbol {
                 name: "clock_gettime".to_string(),
                 address: 3088,
                 size: 16,
+                version: Some("LINUX_2.6".to_string()),
             },
-            DynSym {
+            Symbol {
                 name: "__vdso_gettimeofday".to_string(),
                 address: 3024,
                 size: 16,
+                version: Some("LINUX_2.6".to_string()),
             },
-            DynSym {
+            Symbol {
                 name: "clock_getres".to_string(),
                 address: 3104,
                 size: 96,
+                version: Some("LINUX_2.6".to_string()),
             },
-            DynSym {
+            Symbol {
                 name: "__vdso_clock_getres".to_string(),
                 address: 3104,
                 size: 96,
+                version: Some("LINUX_2.6".to_string()),
             },
-            DynSym {
+            Symbol {
                 name: "gettimeofday".to_string(),
                 address: 3024,
                 size: 16,
+                version: Some("LINUX_2.6".to_string()),
             },
-            DynSym {
+            Symbol {
                 name: "__vdso_time".to_string(),
                 address: 3040,
                 size: 48,
+                version: Some("LINUX_2.6".to_string()),
             },
-            DynSym {
+            Symbol {
                 name: "__vdso_sgx_enter_enclave".to_string(),
                 address: 3248,
                 size: 160,
+                version: Some("LINUX_2.6".to_string()),
             },
-            DynSym {
+            Symbol {
                 name: "time".to_string(),
                 address: 3040,
                 size: 48,
+                version: Some("LINUX_2.6".to_string()),
             },
-            DynSym {
+            Symbol {
                 name: "__vdso_clock_gettime".to_string(),
                 address: 3088,
                 size: 16,
+                version: Some("LINUX_2.6".to_string()),
             },
-            DynSym {
+            Symbol {
                 name: "__vdso_getcpu".to_string(),
                 address: 3200,
                 size: 48,
+                version: Some("LINUX_2.6".to_string()),
             },
-            DynSym {
+            Symbol {
                 name: "getcpu".to_string(),
                 address: 3200,
                 size: 48,
+                version: Some("LINUX_2.6".to_string()),
             },
         ];
         assert_eq!(parsed, expected);
@@ -273,51 +760,284 @@ mod tests {
     fn test_dynsyms_riscv64() {
         let test_vdso =
             fs::read("src/test_files/test_vdso_elf_2").expect("Unable to read test file");
-        let a = vDSO {
-            avv: auxv::AuxVecValues {
-                vdso_base: 0,
-                page_size: 0x1000,
-            },
-            data: test_vdso,
-        };
+        let a = vDSO::from_bytes(&test_vdso).unwrap();
         let parsed = a.dynsyms();
         let expected = vec![
-            DynSym {
+            Symbol {
                 name: "".to_string(),
                 address: 1312,
                 size: 0,
+                version: None,
             },
-            DynSym {
+            Symbol {
                 name: "__vdso_gettimeofday".to_string(),
                 address: 2330,
                 size: 200,
+                version: Some("LINUX_4.15".to_string()),
             },
-            DynSym {
+            Symbol {
                 name: "__vdso_clock_getres".to_string(),
                 address: 2530,
                 size: 92,
+                version: Some("LINUX_4.15".to_string()),
             },
-            DynSym {
+            Symbol {
                 name: "__vdso_rt_sigreturn".to_string(),
                 address: 2048,
                 size: 8,
+                version: Some("LINUX_4.15".to_string()),
             },
-            DynSym {
+            Symbol {
                 name: "__vdso_clock_gettime".to_string(),
                 address: 2058,
                 size: 272,
+                version: Some("LINUX_4.15".to_string()),
             },
-            DynSym {
+            Symbol {
                 name: "__vdso_flush_icache".to_string(),
                 address: 2632,
                 size: 12,
+                version: Some("LINUX_4.15".to_string()),
             },
-            DynSym {
+            Symbol {
                 name: "__vdso_getcpu".to_string(),
                 address: 2620,
                 size: 12,
+                version: Some("LINUX_4.15".to_string()),
             },
         ];
         assert_eq!(parsed, expected);
     }
+
+    #[test]
+    fn test_from_bytes() {
+        let test_vdso =
+            fs::read("src/test_files/test_vdso_elf_1").expect("Unable to read test file");
+        let v = vDSO::from_bytes(&test_vdso).unwrap();
+        assert!(!v.is_live());
+        assert_eq!(v.class(), ElfClass::Elf64);
+        #[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
+        {
+            fn myclock(_clockid: i32) -> TimeSpec {
+                TimeSpec {
+                    seconds: 1,
+                    nanos: 0,
+                }
+            }
+            let entry = v.entry(Kind::GetTime).unwrap();
+            assert!(matches!(entry.overwrite(myclock), Err(Error::Offline)));
+        }
+        assert_eq!(v.restore(), Err(Error::Offline));
+
+        assert!(matches!(
+            vDSO::from_bytes(&test_vdso[..0x40]),
+            Err(Error::MalformedVdso(_))
+        ));
+        assert!(matches!(
+            vDSO::from_bytes(b"not an elf image"),
+            Err(Error::MalformedVdso(_))
+        ));
+    }
+
+    #[test]
+    fn test_dump_to_and_write_to() {
+        let test_vdso =
+            fs::read("src/test_files/test_vdso_elf_1").expect("Unable to read test file");
+        let v = vDSO::from_bytes(&test_vdso).unwrap();
+        let mut out = vec![];
+        v.write_to(&mut out).unwrap();
+        assert_eq!(out, test_vdso);
+
+        let path = std::env::temp_dir().join(format!("tpom-dump-{}", std::process::id()));
+        v.dump_to(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), test_vdso);
+        fs::remove_file(&path).unwrap();
+        assert!(v.dump_to("/nonexistent/vdso").is_err());
+    }
+
+    #[test]
+    fn test_verify_against() {
+        let test_vdso =
+            fs::read("src/test_files/test_vdso_elf_1").expect("Unable to read test file");
+        let baseline = vDSO::from_bytes(&test_vdso).unwrap();
+        assert!(baseline.verify_against(&baseline).is_clean());
+
+        let mut hooked = test_vdso.clone();
+        hooked[0xbe0] = 0xcc;
+        let integrity = vDSO::from_bytes(&hooked).unwrap().verify_against(&baseline);
+        assert!(!integrity.is_clean());
+        assert!(integrity.patched.is_empty());
+        let names: Vec<&str> = integrity.foreign.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["__vdso_time", "time"]);
+    }
+
+    #[test]
+    fn test_symbols_and_kinds() {
+        let test_vdso =
+            fs::read("src/test_files/test_vdso_elf_1").expect("Unable to read test file");
+        let v = vDSO::from_bytes(&test_vdso).unwrap();
+        assert_eq!(v.symbols().count(), 11);
+        let time = v.symbols().find(|s| s.name == "time").unwrap();
+        assert_eq!(time.version.as_deref(), Some("LINUX_2.6"));
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            v.kinds(),
+            vec![
+                Kind::GetTimeOfDay,
+                Kind::ClockGetRes,
+                Kind::Time,
+                Kind::GetTime
+            ]
+        );
+    }
+
+    #[test]
+    fn test_entry_by_name() {
+        let test_vdso =
+            fs::read("src/test_files/test_vdso_elf_1").expect("Unable to read test file");
+        let v = vDSO::from_bytes(&test_vdso).unwrap();
+        let alias = v.entry_by_name("clock_gettime").unwrap();
+        assert_eq!(alias.kind, Kind::GetTime);
+        assert_eq!(alias.name, "clock_gettime");
+        let prefixed = v.entry_by_name("__vdso_clock_gettime").unwrap();
+        assert_eq!(alias.addr, prefixed.addr);
+        assert_eq!(alias.size, prefixed.size);
+        assert_eq!(v.entry_by_name("time").unwrap().kind, Kind::Time);
+        assert!(v.entry_by_name("__vdso_getcpu").is_none());
+        assert!(v.entry_by_name("__kernel_clock_gettime").is_none());
+    }
+
+    #[test]
+    fn test_diff_and_patched_symbols() {
+        let test_vdso =
+            fs::read("src/test_files/test_vdso_elf_1").expect("Unable to read test file");
+        let v = vDSO::from_bytes(&test_vdso).unwrap();
+        assert_eq!(v.diff(&v), vec![]);
+        assert_eq!(v.patched_symbols(), vec![]);
+
+        let time = v.symbols().find(|s| s.name == "time").unwrap();
+        let mut patched = test_vdso.clone();
+        let jump = opcodes::generate_opcodes(0x12ff34ff56ff78ff, 0);
+        patched[time.address..][..jump.len()].copy_from_slice(&jump);
+        let p = vDSO::from_bytes(&patched).unwrap();
+        // `time` and its alias `__vdso_time`
+        let names = |syms: Vec<Symbol>| syms.into_iter().map(|s| s.name).collect::<Vec<_>>();
+        assert_eq!(names(p.diff(&v)), vec!["__vdso_time", "time"]);
+        assert_eq!(names(p.patched_symbols()), vec!["__vdso_time", "time"]);
+    }
+
+    #[test]
+    fn test_patchable_size_stops_at_next_symbol() {
+        let test_vdso =
+            fs::read("src/test_files/test_vdso_elf_2").expect("Unable to read test file");
+        let a = vDSO::from_bytes(&test_vdso).unwrap();
+        let dynsyms = a.dynsyms();
+        let size_of = |name: &str| {
+            let sym = dynsyms.iter().find(|ds| ds.name == name).unwrap();
+            patchable_size(dynsyms, sym)
+        };
+        // __vdso_getcpu starts 90 bytes after __vdso_clock_getres
+        assert_eq!(size_of("__vdso_clock_getres"), 90);
+        assert_eq!(size_of("__vdso_clock_gettime"), 272);
+    }
+
+    #[test]
+    fn test_image_len() {
+        // Values from an x86_64 6.x kernel
+        assert_eq!(image_len(0x1562, 0x1a60, Some(0x2000), 0x1000), Ok(0x1a60));
+        assert_eq!(image_len(0x1562, 0x1a60, None, 0x1000), Ok(0x1a60));
+        // Section headers outside of the mapping are ignored
+        assert_eq!(image_len(0x1562, 0x2100, Some(0x2000), 0x1000), Ok(0x1562));
+        assert_eq!(
+            image_len(0x2562, 0x1a60, Some(0x2000), 0x1000),
+            Err(Error::InconsistentSize {
+                computed: 0x2562,
+                mapped: 0x2000
+            })
+        );
+        assert!(image_len(0, 0x1a60, Some(0x2000), 0x1000).is_err());
+    }
+
+    #[test]
+    fn test_check_identity() {
+        let x86_64 = fs::read("src/test_files/test_vdso_elf_1").expect("Unable to read test file");
+        let riscv64 = fs::read("src/test_files/test_vdso_elf_2").expect("Unable to read test file");
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert_eq!(check_identity(&x86_64), Ok(()));
+            assert!(matches!(check_identity(&riscv64), Err(Error::WrongArch(_))));
+        }
+        #[cfg(target_arch = "riscv64")]
+        {
+            assert_eq!(check_identity(&riscv64), Ok(()));
+            assert!(matches!(check_identity(&x86_64), Err(Error::WrongArch(_))));
+        }
+        assert!(matches!(
+            check_identity(&x86_64[1..]),
+            Err(Error::MalformedVdso(_))
+        ));
+        assert!(matches!(
+            check_identity(&x86_64[..16]),
+            Err(Error::MalformedVdso(_))
+        ));
+    }
+
+    /// Zeroes e_shoff, e_shnum and e_shstrndx of an ELF64 image.
+    fn without_sections(data: &[u8]) -> Vec<u8> {
+        let mut stripped = data.to_vec();
+        stripped[0x28..0x30].fill(0);
+        stripped[0x3c..0x40].fill(0);
+        stripped
+    }
+
+    #[test]
+    fn test_dynsyms_without_sections() {
+        let test_vdso =
+            fs::read("src/test_files/test_vdso_elf_1").expect("Unable to read test file");
+        let stripped = vDSO::from_bytes(&without_sections(&test_vdso)).unwrap();
+        let full = vDSO::from_bytes(&test_vdso).unwrap();
+        assert_eq!(stripped.dynsyms(), full.dynsyms());
+
+        // RISC-V functions are only 2-byte aligned, though .text is 4-byte aligned; so the
+        // padded sizes differ
+        let test_vdso =
+            fs::read("src/test_files/test_vdso_elf_2").expect("Unable to read test file");
+        let stripped = vDSO::from_bytes(&without_sections(&test_vdso)).unwrap();
+        let full = vDSO::from_bytes(&test_vdso).unwrap();
+        for (a, b) in stripped.dynsyms().iter().zip(full.dynsyms().iter()) {
+            assert_eq!((&a.name, a.address), (&b.name, b.address));
+        }
+    }
+
+    #[test]
+    fn test_elf_class() {
+        let test_vdso =
+            fs::read("src/test_files/test_vdso_elf_1").expect("Unable to read test file");
+        assert_eq!(ElfClass::from_ident(&test_vdso), Ok(ElfClass::Elf64));
+        let mut elf32 = test_vdso.clone();
+        elf32[elf::EI_CLASS] = elf::ELFCLASS32;
+        assert_eq!(ElfClass::from_ident(&elf32), Ok(ElfClass::Elf32));
+        #[cfg(target_pointer_width = "64")]
+        assert!(matches!(check_identity(&elf32), Err(Error::WrongArch(_))));
+        elf32[elf::EI_CLASS] = 7;
+        assert!(ElfClass::from_ident(&elf32).is_err());
+    }
+
+    #[test]
+    fn test_hash_counts_agree() {
+        for file in [
+            "src/test_files/test_vdso_elf_1",
+            "src/test_files/test_vdso_elf_2",
+        ] {
+            let data = fs::read(file).expect("Unable to read test file");
+            let header = elf::parse_header(&data).unwrap();
+            let r = Reader::new(&data, &header);
+            let phdrs = elf::program_headers(&data, &header).unwrap();
+            let info = elf::dynamic_info(&data, &header, &phdrs).unwrap().unwrap();
+            // The fixtures are mapped at 0
+            let sysv = sysv_hash_count(r, info.hash.unwrap() as usize).unwrap();
+            let gnu = gnu_hash_count(r, info.gnu_hash.unwrap() as usize).unwrap();
+            assert_eq!(sysv, gnu);
+        }
+    }
 }
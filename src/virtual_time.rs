@@ -0,0 +1,457 @@
+//! A clock that skips the time slept: with [`enable`], `nanosleep` and `clock_nanosleep`
+//! trapped by [`crate::seccomp`] return immediately, and the clock jumps forward by the
+//! duration requested instead. An hour of retries and backoff then takes milliseconds, while
+//! the code under test still sees an hour pass.
+//!
+//! The clock is the real time plus the time skipped so far; install it with the callbacks in
+//! [`callbacks`]. CPU-time clocks are not shifted.
+//!
+//! Timers armed while enabled (`alarm`, `setitimer(ITIMER_REAL)` and `timer_create` on the
+//! wall and monotonic clocks) are kept by tpom instead of the kernel, and fire once the
+//! virtual clock reaches them; skipping a sleep past one fires it right away.
+//!
+//! ```no_run
+//! use tpom::{seccomp, vdso, virtual_time, Session};
+//! use std::time::{Duration, Instant};
+//!
+//! seccomp::install().unwrap();
+//! let v = vdso::vDSO::read().unwrap();
+//! let mut session = Session::new(&v);
+//! session.apply_all(&virtual_time::callbacks()).unwrap();
+//! virtual_time::enable();
+//!
+//! let start = Instant::now();
+//! std::thread::sleep(Duration::from_secs(3600));
+//! assert!(start.elapsed() >= Duration::from_secs(3600));
+//! ```
+use crate::trampolines::raw_clock_gettime;
+use crate::{Callback, Time, TimeSpec, TimeVal};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, Once};
+use std::thread;
+use std::time::Duration;
+
+const NANOS: i128 = 1_000_000_000;
+/// Ids handed out by the virtual `timer_create`, far above the kernel's.
+const FIRST_TIMER_ID: i32 = 0x4000_0000;
+// From `struct sigevent` and `struct siginfo`
+const SIGEV_SIGNAL: i32 = 0;
+const SIGEV_NONE: i32 = 1;
+const SIGEV_THREAD_ID: i32 = 4;
+const SI_TIMER: i32 = -2;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Nanoseconds skipped so far.
+static SKIPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Makes trapped sleeps return immediately, advancing the clock instead.
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Lets sleeps through to the kernel again; the time skipped so far stays skipped.
+pub fn disable() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Moves the clock forward, as if `by` had been slept.
+pub fn advance(by: Duration) {
+    SKIPPED.fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    TIMERS_CHANGED.notify_all();
+}
+
+/// The total time skipped.
+pub fn skipped() -> Duration {
+    Duration::from_nanos(SKIPPED.load(Ordering::SeqCst))
+}
+
+/// The callbacks reading the virtual clock.
+pub fn callbacks() -> [Callback; 3] {
+    [
+        Callback::GetTime(clock_gettime),
+        Callback::GetTimeOfDay(gettimeofday),
+        Callback::Time(time),
+    ]
+}
+
+fn shifted(clockid: libc::clockid_t) -> bool {
+    matches!(
+        clockid,
+        libc::CLOCK_REALTIME
+            | libc::CLOCK_REALTIME_COARSE
+            | libc::CLOCK_MONOTONIC
+            | libc::CLOCK_MONOTONIC_COARSE
+            | libc::CLOCK_MONOTONIC_RAW
+            | libc::CLOCK_BOOTTIME
+            | libc::CLOCK_TAI
+    )
+}
+
+/// Nanoseconds on the virtual `clockid`; `None` if the kernel doesn't know the clock.
+pub(crate) fn now(clockid: libc::clockid_t) -> Option<i128> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if raw_clock_gettime(clockid, &mut ts) != 0 {
+        return None;
+    }
+    let real = ts.tv_sec as i128 * NANOS + ts.tv_nsec as i128;
+    if !shifted(clockid) {
+        return Some(real);
+    }
+    Some(real + SKIPPED.load(Ordering::SeqCst) as i128)
+}
+
+/// Answers a sleep until `until` nanoseconds on `clockid` (or for that long, if not
+/// `absolute`); `false` while disabled, or for an unknown clock.
+pub(crate) fn sleep(clockid: libc::clockid_t, absolute: bool, until: i128) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    let duration = if absolute {
+        let Some(now) = now(clockid) else {
+            return false;
+        };
+        until - now
+    } else {
+        until
+    };
+    if duration > 0 {
+        SKIPPED.fetch_add(duration as u64, Ordering::SeqCst);
+        TIMERS_CHANGED.notify_all();
+    }
+    true
+}
+
+/// How an expiring timer is signalled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Notify {
+    None,
+    /// `kill(SIGALRM)`, for `alarm` and `setitimer`.
+    Alarm,
+    Process {
+        signo: i32,
+        value: u64,
+    },
+    Thread {
+        tid: i32,
+        signo: i32,
+        value: u64,
+    },
+}
+
+impl Notify {
+    /// Reads `struct sigevent`, as given to `timer_create`; SIGALRM without one.
+    pub(crate) fn from_sigevent(ev: Option<&libc::sigevent>, id: i32) -> Result<Notify, i32> {
+        let Some(ev) = ev else {
+            return Ok(Notify::Process {
+                signo: libc::SIGALRM,
+                value: id as u64,
+            });
+        };
+        let value = ev.sigev_value.sival_ptr as u64;
+        let valid = |signo: i32| (1..=libc::SIGRTMAX()).contains(&signo);
+        match ev.sigev_notify {
+            SIGEV_NONE => Ok(Notify::None),
+            SIGEV_SIGNAL if valid(ev.sigev_signo) => Ok(Notify::Process {
+                signo: ev.sigev_signo,
+                value,
+            }),
+            SIGEV_THREAD_ID if valid(ev.sigev_signo) => Ok(Notify::Thread {
+                tid: ev.sigev_notify_thread_id,
+                signo: ev.sigev_signo,
+                value,
+            }),
+            _ => Err(libc::EINVAL),
+        }
+    }
+}
+
+/// What `timer_settime` and `setitimer` set, and `timer_gettime` and `getitimer` return.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct Setting {
+    /// Nanoseconds until expiry; 0 when disarmed.
+    pub(crate) value: i128,
+    pub(crate) interval: i128,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TimerId {
+    /// The one `ITIMER_REAL`, shared by `alarm` and `setitimer`.
+    Alarm,
+    Posix(i32),
+}
+
+#[derive(Debug)]
+struct Timer {
+    id: TimerId,
+    clockid: libc::clockid_t,
+    notify: Notify,
+    /// When it expires, on the virtual `clockid`.
+    deadline: Option<i128>,
+    interval: i128,
+    overrun: i32,
+}
+
+struct Timers {
+    timers: Vec<Timer>,
+    next_id: i32,
+}
+
+static TIMERS: Mutex<Timers> = Mutex::new(Timers {
+    timers: vec![],
+    next_id: FIRST_TIMER_ID,
+});
+/// Wakes the timer thread, when timers are set or the clock jumps.
+static TIMERS_CHANGED: Condvar = Condvar::new();
+static TIMER_THREAD: Once = Once::new();
+
+/// Whether timer `id` was created by the virtual `timer_create`.
+pub(crate) fn is_virtual_timer(id: i32) -> bool {
+    id >= FIRST_TIMER_ID
+        && TIMERS
+            .lock()
+            .unwrap()
+            .timers
+            .iter()
+            .any(|t| t.id == TimerId::Posix(id))
+}
+
+pub(crate) fn timer_create(
+    clockid: libc::clockid_t,
+    ev: Option<&libc::sigevent>,
+) -> Result<i32, i32> {
+    let mut timers = TIMERS.lock().unwrap();
+    let id = timers.next_id;
+    let notify = Notify::from_sigevent(ev, id)?;
+    timers.next_id += 1;
+    timers.timers.push(Timer {
+        id: TimerId::Posix(id),
+        clockid,
+        notify,
+        deadline: None,
+        interval: 0,
+        overrun: 0,
+    });
+    Ok(id)
+}
+
+pub(crate) fn timer_delete(id: i32) {
+    TIMERS
+        .lock()
+        .unwrap()
+        .timers
+        .retain(|t| t.id != TimerId::Posix(id));
+}
+
+fn remaining(timer: &Timer) -> Setting {
+    let value = match (timer.deadline, now(timer.clockid)) {
+        // Expired but not yet fired: about to
+        (Some(deadline), Some(now)) => (deadline - now).max(1),
+        _ => 0,
+    };
+    Setting {
+        value,
+        interval: timer.interval,
+    }
+}
+
+/// The current setting of a timer; disarmed if it doesn't exist.
+pub(crate) fn timer_get(id: TimerId) -> Setting {
+    let timers = TIMERS.lock().unwrap();
+    timers
+        .timers
+        .iter()
+        .find(|t| t.id == id)
+        .map(remaining)
+        .unwrap_or_default()
+}
+
+pub(crate) fn timer_getoverrun(id: i32) -> i32 {
+    let timers = TIMERS.lock().unwrap();
+    timers
+        .timers
+        .iter()
+        .find(|t| t.id == TimerId::Posix(id))
+        .map_or(0, |t| t.overrun)
+}
+
+/// Arms (or with a zero value, disarms) a timer, returning its previous setting. `value` is
+/// a deadline on the timer's clock if `absolute`.
+pub(crate) fn timer_set(id: TimerId, absolute: bool, setting: Setting) -> Setting {
+    let mut timers = TIMERS.lock().unwrap();
+    if id == TimerId::Alarm && !timers.timers.iter().any(|t| t.id == id) {
+        timers.timers.push(Timer {
+            id,
+            clockid: libc::CLOCK_REALTIME,
+            notify: Notify::Alarm,
+            deadline: None,
+            interval: 0,
+            overrun: 0,
+        });
+    }
+    let Some(timer) = timers.timers.iter_mut().find(|t| t.id == id) else {
+        return Setting::default();
+    };
+    let previous = remaining(timer);
+    timer.deadline = match setting.value {
+        0 => None,
+        v if absolute => Some(v),
+        v => now(timer.clockid).map(|now| now + v),
+    };
+    timer.interval = setting.interval;
+    timer.overrun = 0;
+    drop(timers);
+    TIMER_THREAD.call_once(|| {
+        thread::Builder::new()
+            .name("tpom-timers".to_string())
+            .spawn(run_timers)
+            .expect("spawning the timer thread");
+    });
+    TIMERS_CHANGED.notify_all();
+    previous
+}
+
+/// Sends the signal of an expired timer.
+fn fire(notify: Notify, overrun: i32) {
+    // `struct siginfo` for a POSIX timer, on 64-bit platforms
+    #[repr(C)]
+    struct TimerInfo {
+        signo: i32,
+        errno: i32,
+        code: i32,
+        pad: i32,
+        tid: i32,
+        overrun: i32,
+        value: u64,
+        rest: [u8; 96],
+    }
+    let info = |signo: i32, value: u64| TimerInfo {
+        signo,
+        errno: 0,
+        code: SI_TIMER,
+        pad: 0,
+        tid: 0,
+        overrun,
+        value,
+        rest: [0; 96],
+    };
+    let pid = unsafe { libc::getpid() };
+    match notify {
+        Notify::None => {}
+        Notify::Alarm => {
+            unsafe { libc::kill(pid, libc::SIGALRM) };
+        }
+        Notify::Process { signo, value } => {
+            let info = info(signo, value);
+            unsafe {
+                libc::syscall(
+                    libc::SYS_rt_sigqueueinfo,
+                    pid,
+                    signo,
+                    &info as *const TimerInfo,
+                )
+            };
+        }
+        Notify::Thread { tid, signo, value } => {
+            let info = info(signo, value);
+            unsafe {
+                libc::syscall(
+                    libc::SYS_rt_tgsigqueueinfo,
+                    pid,
+                    tid,
+                    signo,
+                    &info as *const TimerInfo,
+                )
+            };
+        }
+    }
+}
+
+/// Fires the timers as the virtual clock reaches them, for the lifetime of the process.
+fn run_timers() {
+    let mut timers = TIMERS.lock().unwrap();
+    loop {
+        let mut next: Option<i128> = None;
+        for timer in timers.timers.iter_mut() {
+            let (Some(deadline), Some(now)) = (timer.deadline, now(timer.clockid)) else {
+                continue;
+            };
+            if deadline > now {
+                next = Some(next.map_or(deadline - now, |n| n.min(deadline - now)));
+                continue;
+            }
+            // Periodic timers count the periods missed since the deadline
+            let mut overrun = 0;
+            timer.deadline = if timer.interval > 0 {
+                let missed = (now - deadline) / timer.interval;
+                overrun = missed.min(i32::MAX as i128) as i32;
+                let deadline = deadline + (missed + 1) * timer.interval;
+                next = Some(next.map_or(deadline - now, |n| n.min(deadline - now)));
+                Some(deadline)
+            } else {
+                None
+            };
+            timer.overrun = overrun;
+            fire(timer.notify, overrun);
+        }
+        timers = match next {
+            Some(wait) => {
+                let wait = Duration::from_nanos(wait.min(u64::MAX as i128) as u64);
+                TIMERS_CHANGED.wait_timeout(timers, wait).unwrap().0
+            }
+            None => TIMERS_CHANGED.wait(timers).unwrap(),
+        };
+    }
+}
+
+fn clock_gettime(clockid: i32) -> TimeSpec {
+    let nanos = now(clockid).unwrap_or(0);
+    TimeSpec {
+        seconds: nanos.div_euclid(NANOS) as Time,
+        nanos: nanos.rem_euclid(NANOS) as i64,
+    }
+}
+
+fn gettimeofday() -> TimeVal {
+    let nanos = now(libc::CLOCK_REALTIME).unwrap_or(0);
+    TimeVal {
+        seconds: nanos.div_euclid(NANOS) as Time,
+        micros: (nanos.rem_euclid(NANOS) / 1000) as i64,
+    }
+}
+
+fn time() -> Time {
+    now(libc::CLOCK_REALTIME).unwrap_or(0).div_euclid(NANOS) as Time
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sleep_advances() {
+        assert!(!sleep(libc::CLOCK_MONOTONIC, false, NANOS));
+        enable();
+        let before = skipped();
+        assert!(sleep(libc::CLOCK_MONOTONIC, false, NANOS));
+        assert_eq!(skipped() - before, Duration::from_secs(1));
+
+        // Absolute sleeps skip up to the deadline, or not at all if it has passed
+        let deadline = now(libc::CLOCK_MONOTONIC).unwrap() + 5 * NANOS;
+        assert!(sleep(libc::CLOCK_MONOTONIC, true, deadline));
+        let skipped_now = skipped() - before;
+        assert!(skipped_now > Duration::from_millis(5900) && skipped_now <= Duration::from_secs(6));
+        assert!(sleep(libc::CLOCK_MONOTONIC, true, 0));
+        assert_eq!(skipped() - before, skipped_now);
+        disable();
+
+        let cpu = now(libc::CLOCK_PROCESS_CPUTIME_ID).unwrap();
+        assert!(cpu < now(libc::CLOCK_MONOTONIC).unwrap());
+    }
+}
@@ -0,0 +1,201 @@
+//! Read-only view of the `[vvar]` page, where the kernel publishes the data the vDSO's
+//! clock functions compute time from.
+//!
+//! The layout of `struct vdso_data` (`vdso_clock` since 6.15) is not ABI and changes with
+//! kernel versions and configuration; the known variants are tried, and the one whose
+//! `CLOCK_REALTIME` base matches the real clock is used. Inside a time namespace, the first
+//! page holds the namespace's offsets instead, and this fails to recognize it.
+use crate::auxv;
+use crate::error::Error;
+use crate::trampolines::raw_clock_gettime;
+use std::fs;
+
+/// One entry per clock id, up to `CLOCK_TAI`.
+const VDSO_BASES: usize = 12;
+/// Enough for each candidate layout.
+const READ_LEN: usize = 512;
+
+/// A base time as stored by the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Timestamp {
+    pub sec: u64,
+    /// For high resolution clocks, nanoseconds shifted left by [`VdsoData::shift`].
+    pub nsec: u64,
+}
+
+/// The decoded `vdso_data` for the high resolution clocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VdsoData {
+    /// Offset of the structure in the `[vvar]` page.
+    pub offset: usize,
+    /// The seqlock; odd while the kernel updates the data.
+    pub seq: u32,
+    /// How the counter is read from userspace; 0 means the vDSO makes the syscall. On x86,
+    /// 1 is the TSC, 2 the KVM pvclock and 3 the Hyper-V TSC page.
+    pub clock_mode: i32,
+    pub cycle_last: u64,
+    /// Only in kernels built with `CONFIG_GENERIC_VDSO_OVERFLOW_PROTECT`.
+    pub max_cycles: Option<u64>,
+    pub mask: u64,
+    pub mult: u32,
+    pub shift: u32,
+    /// Indexed by clock id.
+    pub basetime: [Timestamp; VDSO_BASES],
+}
+
+impl VdsoData {
+    pub fn realtime(&self) -> Timestamp {
+        self.basetime[libc::CLOCK_REALTIME as usize]
+    }
+
+    pub fn monotonic(&self) -> Timestamp {
+        self.basetime[libc::CLOCK_MONOTONIC as usize]
+    }
+}
+
+/// The `[vvar]` mapping's start and end, per /proc/self/maps.
+pub fn mapping() -> Result<(usize, usize), Error> {
+    let maps = fs::read_to_string("/proc/self/maps")
+        .map_err(|e| Error::Os("read /proc/self/maps", e.raw_os_error().unwrap_or(0)))?;
+    maps.lines()
+        .filter(|line| line.ends_with("[vvar]"))
+        .find_map(|line| {
+            let (start, end) = line.split(' ').next()?.split_once('-')?;
+            Some((
+                usize::from_str_radix(start, 16).ok()?,
+                usize::from_str_radix(end, 16).ok()?,
+            ))
+        })
+        .ok_or_else(|| Error::UnsupportedPlatform("no [vvar] mapping".to_string()))
+}
+
+/// Reads a consistent snapshot of the vDSO data, retrying while the kernel updates it.
+pub fn read() -> Result<VdsoData, Error> {
+    let (start, end) = mapping()?;
+    let page_size = auxv::read_aux_vec().map_or(0x1000, |avv| avv.page_size);
+    let len = READ_LEN.min(end - start).min(page_size);
+    loop {
+        let page = copy(start, len);
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        raw_clock_gettime(libc::CLOCK_REALTIME, &mut ts);
+        let data = recognize(&page, ts.tv_sec as u64)?;
+        // The seqlock must be even, and unchanged after the copy
+        let again = copy(start + data.offset, 4);
+        if data.seq % 2 == 0 && again == data.seq.to_ne_bytes() {
+            return Ok(data);
+        }
+        std::hint::spin_loop();
+    }
+}
+
+/// Copies `len` bytes of the live mapping at `addr`, which the kernel may be updating.
+fn copy(addr: usize, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| unsafe { std::ptr::read_volatile((addr + i) as *const u8) })
+        .collect()
+}
+
+/// Tries each known layout, comparing its realtime seconds to `now`.
+fn recognize(page: &[u8], now: u64) -> Result<VdsoData, Error> {
+    // Before 6.13, x86 placed the data 128 bytes into the page
+    for offset in [0, 128] {
+        for overflow_protect in [true, false] {
+            let Some(data) = decode(page, offset, overflow_protect) else {
+                continue;
+            };
+            if data.realtime().sec.abs_diff(now) <= 2 {
+                return Ok(data);
+            }
+        }
+    }
+    Err(Error::MalformedVdso(
+        "unrecognized vdso_data layout".to_string(),
+    ))
+}
+
+fn decode(page: &[u8], offset: usize, overflow_protect: bool) -> Option<VdsoData> {
+    let u32_at = |at: usize| -> Option<u32> {
+        Some(u32::from_ne_bytes(
+            page.get(offset + at..offset + at + 4)?.try_into().ok()?,
+        ))
+    };
+    let u64_at = |at: usize| -> Option<u64> {
+        Some(u64::from_ne_bytes(
+            page.get(offset + at..offset + at + 8)?.try_into().ok()?,
+        ))
+    };
+    let (max_cycles, rest) = if overflow_protect {
+        (Some(u64_at(16)?), 24)
+    } else {
+        (None, 16)
+    };
+    let mut basetime = [Timestamp::default(); VDSO_BASES];
+    for (i, ts) in basetime.iter_mut().enumerate() {
+        let at = rest + 16 + 16 * i;
+        *ts = Timestamp {
+            sec: u64_at(at)?,
+            nsec: u64_at(at + 8)?,
+        };
+    }
+    Some(VdsoData {
+        offset,
+        seq: u32_at(0)?,
+        clock_mode: u32_at(4)? as i32,
+        cycle_last: u64_at(8)?,
+        max_cycles,
+        mask: u64_at(rest)?,
+        mult: u32_at(rest + 8)?,
+        shift: u32_at(rest + 12)?,
+        basetime,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `vdso_data` with overflow protection at `offset`, realtime at `now`.
+    fn page(offset: usize, now: u64) -> Vec<u8> {
+        let mut page = vec![0; 4096];
+        let mut put = |at: usize, bytes: &[u8]| {
+            page[offset + at..offset + at + bytes.len()].copy_from_slice(bytes)
+        };
+        put(0, &4u32.to_ne_bytes());
+        put(4, &1u32.to_ne_bytes());
+        put(8, &1234u64.to_ne_bytes());
+        put(16, &5678u64.to_ne_bytes());
+        put(24, &u64::MAX.to_ne_bytes());
+        put(32, &0x400000u32.to_ne_bytes());
+        put(36, &23u32.to_ne_bytes());
+        put(40, &now.to_ne_bytes());
+        put(40 + 16, &100u64.to_ne_bytes());
+        page
+    }
+
+    #[test]
+    fn test_recognize() {
+        let now = 1_700_000_000;
+        let data = recognize(&page(0, now), now).unwrap();
+        assert_eq!(data.offset, 0);
+        assert_eq!(data.max_cycles, Some(5678));
+        assert_eq!((data.mult, data.shift), (0x400000, 23));
+        assert_eq!(data.realtime().sec, now);
+        assert_eq!(data.monotonic().sec, 100);
+
+        assert_eq!(recognize(&page(128, now), now).unwrap().offset, 128);
+        assert!(matches!(
+            recognize(&page(0, now), now + 3600),
+            Err(Error::MalformedVdso(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_live() {
+        let data = read().unwrap();
+        assert_eq!(data.seq % 2, 0);
+        assert!(data.mult > 0);
+    }
+}
@@ -0,0 +1,64 @@
+// Patches the vDSO of the whole process, so this lives apart from the other tests.
+mod tests {
+    use tpom::{chrome_trace, vdso, Kind, TVDSOFun, TimeSpec};
+
+    fn myclock(_clockid: i32) -> TimeSpec {
+        TimeSpec {
+            seconds: 111,
+            nanos: 333,
+        }
+    }
+
+    fn trace() -> String {
+        let mut out = vec![];
+        chrome_trace::write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn traces_intercepted_calls() {
+        let v = vdso::vDSO::read().unwrap();
+        let backup = v.entry(Kind::GetTime).unwrap().overwrite(myclock).unwrap();
+        chrome_trace::start().unwrap();
+        chrome_trace::start().unwrap();
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut ts) };
+        chrome_trace::stop();
+        unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut ts) };
+        backup.restore().unwrap();
+
+        let text = trace();
+        assert!(text.starts_with("{\"displayTimeUnit\":\"ns\",\"traceEvents\":[\n{"));
+        assert!(text.ends_with("}\n]}\n"), "{}", text);
+        let lines: Vec<&str> = text.lines().collect();
+        // The header, an instant and a counter, and the end
+        assert_eq!(lines.len(), 4, "{}", text);
+        assert!(lines[1].starts_with(
+            "{\"name\":\"clock_gettime\",\"cat\":\"tpom\",\"ph\":\"i\",\"s\":\"t\",\"ts\":"
+        ));
+        assert!(
+            lines[1].ends_with("\"args\":{\"clockid\":0,\"value\":\"111.000000333\"}},"),
+            "{}",
+            lines[1]
+        );
+        assert!(lines[2].starts_with("{\"name\":\"clock 0 offset\",\"cat\":\"tpom\",\"ph\":\"C\""));
+        let seconds: f64 = lines[2]
+            .rsplit_once("\"seconds\":")
+            .unwrap()
+            .1
+            .trim_end_matches('}')
+            .parse()
+            .unwrap();
+        assert!(seconds < -1e9, "{}", lines[2]);
+        assert_eq!(chrome_trace::dropped(), 0);
+
+        chrome_trace::clear();
+        assert_eq!(
+            trace(),
+            "{\"displayTimeUnit\":\"ns\",\"traceEvents\":[\n]}\n"
+        );
+    }
+}
@@ -0,0 +1,16 @@
+// Clearing the environment affects the whole process, so this lives apart from the other tests.
+mod tests {
+    use tpom::{auxv, vdso};
+
+    #[test]
+    fn works_without_environ() {
+        unsafe { libc::clearenv() };
+        let v = vdso::vDSO::read().unwrap();
+        assert!(!v.kinds().is_empty());
+        let pagesize = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        assert_eq!(auxv::get(auxv::AT_PAGESZ), Ok(pagesize));
+        assert!(auxv::iter()
+            .unwrap()
+            .any(|entry| entry == (auxv::AT_PAGESZ, pagesize)));
+    }
+}
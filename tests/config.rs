@@ -0,0 +1,51 @@
+// The live clock is process-wide, so this lives apart from the other tests.
+mod tests {
+    use std::fs;
+    use std::time::{Duration, Instant};
+    use tpom::control::State;
+    use tpom::live::{self, Command};
+
+    const NANOS: i64 = 1_000_000_000;
+
+    /// Waits for the watcher to apply a change.
+    fn wait_for(expected: impl Fn(&State) -> bool) -> State {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let state = live::execute(Command::Status).unwrap();
+            if expected(&state) || Instant::now() > deadline {
+                return state;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn changes_to_the_file_are_applied() {
+        let dir = std::env::temp_dir().join(format!("tpom-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("clock.toml");
+        fs::write(&path, "freeze = 1000\n").unwrap();
+
+        let state = tpom::config::watch(&path).unwrap();
+        assert_eq!((state.now, state.frozen), (1000 * NANOS, true));
+
+        fs::write(&path, "freeze = 2000\n").unwrap();
+        assert_eq!(wait_for(|s| s.now == 2000 * NANOS).now, 2000 * NANOS);
+
+        // A broken file leaves the clock as it was
+        fs::write(&path, "freeze = soon\n").unwrap();
+        fs::write(dir.join("other.toml"), "freeze = 3000\n").unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(live::execute(Command::Status).unwrap().now, 2000 * NANOS);
+
+        // Saved the way editors do, by renaming over it
+        let new = dir.join("clock.toml.new");
+        fs::write(&new, "offset = -3600\n").unwrap();
+        fs::rename(&new, &path).unwrap();
+        let state = wait_for(|s| !s.frozen);
+        assert!(!state.frozen);
+        assert!(state.now > 1_000_000_000 * NANOS);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
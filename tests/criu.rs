@@ -0,0 +1,33 @@
+// The checkpoint takes off every patch in the process, so this lives apart from the other tests.
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tpom::{criu, vdso, Kind, TVDSOFun, TimeSpec};
+
+    fn myclock(_clockid: i32) -> TimeSpec {
+        TimeSpec {
+            seconds: 111,
+            nanos: 333,
+        }
+    }
+
+    fn now() -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    }
+
+    #[test]
+    fn checkpoint_takes_off_and_puts_back_the_patches() {
+        let v = vdso::vDSO::read().unwrap();
+        let backup = v.entry(Kind::GetTime).unwrap().overwrite(myclock).unwrap();
+        assert_eq!(now(), Duration::new(111, 333));
+
+        let checkpoint = criu::pre_dump().unwrap();
+        assert!(now() > Duration::from_secs(1_000_000_000));
+        assert!(!checkpoint.restored());
+        checkpoint.post_restore().unwrap();
+        assert_eq!(now(), Duration::new(111, 333));
+
+        backup.restore().unwrap();
+        assert!(now() > Duration::from_secs(1_000_000_000));
+        assert!(!v.entry(Kind::GetTime).unwrap().is_patched());
+    }
+}
@@ -0,0 +1,27 @@
+#![cfg(feature = "ctor")]
+// Nothing else names the crate, which would leave it, and its constructor, out of the binary
+use tpom as _;
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Run with `TPOM_FREEZE` set: prints the time the program started with.
+#[test]
+fn frozen_target() {
+    if std::env::var_os("TPOM_FREEZE").is_none() {
+        return;
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    println!("\nseconds={}", now.as_secs());
+}
+
+#[test]
+fn environment_is_applied_on_load() {
+    let out = Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "frozen_target", "--nocapture"])
+        .env("TPOM_FREEZE", "1000")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.lines().any(|l| l == "seconds=1000"), "{}", stdout);
+}
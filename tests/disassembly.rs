@@ -0,0 +1,29 @@
+#![cfg(all(feature = "disassembler", target_arch = "x86_64"))]
+// Patches the vDSO of the whole process, so this lives apart from the other tests.
+mod tests {
+    use tpom::{vdso, Kind, TVDSOFun, TimeSpec};
+
+    fn myclock(_clockid: i32) -> TimeSpec {
+        TimeSpec {
+            seconds: 111,
+            nanos: 333,
+        }
+    }
+
+    #[test]
+    fn shows_the_patched_code() {
+        let v = vdso::vDSO::read().unwrap();
+        let og = v.entry(Kind::GetTime).unwrap();
+        assert!(!v.disassemble(&og.name).unwrap().changed());
+
+        let backup = og.overwrite(myclock).unwrap();
+        // A snapshot taken after patching still knows the original code
+        let d = vdso::vDSO::read().unwrap().disassemble(&og.name).unwrap();
+        assert!(d.changed());
+        assert!(d.installed[0].text.starts_with("movabs rax, "), "{}", d);
+        assert_eq!(d.installed[1].text, "jmp rax");
+        assert_eq!(d.original, v.disassemble(&og.name).unwrap().original);
+        backup.restore().unwrap();
+        assert!(!v.disassemble(&og.name).unwrap().changed());
+    }
+}
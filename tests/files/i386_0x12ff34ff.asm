@@ -0,0 +1,5 @@
+        global  _start
+        section .text
+_start:
+        mov     eax, 0x12ff34ff
+        jmp         eax
@@ -0,0 +1,94 @@
+/* A 32-bit target for tests/remote.rs: prints the seconds out of its vDSO's clock_gettime
+ * for every line read. It needs no 32-bit libc, being built with
+ *
+ *     gcc -m32 -O2 -static -nostdlib -ffreestanding -fno-pic -fno-stack-protector -s \
+ *         -o tests/files/i386_target tests/files/i386_target.c
+ */
+typedef unsigned int u32;
+
+struct timespec32 {
+    int sec;
+    int nsec;
+};
+
+static int sys(int nr, int a, int b, int c) {
+    int ret;
+    __asm__ volatile("int $0x80" : "=a"(ret) : "a"(nr), "b"(a), "c"(b), "d"(c) : "memory");
+    return ret;
+}
+
+static int eq(const char *a, const char *b) {
+    while (*a && *a == *b) {
+        a++;
+        b++;
+    }
+    return *a == *b;
+}
+
+/* The address of `name` in the vDSO mapped at `base`, through its section headers. */
+static void *vdso_sym(const unsigned char *base, const char *name) {
+    const unsigned char *shdrs = base + *(u32 *)(base + 32);
+    unsigned shentsize = *(unsigned short *)(base + 46);
+    unsigned shnum = *(unsigned short *)(base + 48);
+    for (unsigned i = 0; i < shnum; i++) {
+        const unsigned char *sh = shdrs + i * shentsize;
+        /* SHT_DYNSYM */
+        if (*(u32 *)(sh + 4) != 11)
+            continue;
+        const unsigned char *strsh = shdrs + *(u32 *)(sh + 24) * shentsize;
+        const char *strtab = (const char *)base + *(u32 *)(strsh + 16);
+        const unsigned char *syms = base + *(u32 *)(sh + 16);
+        for (u32 off = 0; off < *(u32 *)(sh + 20); off += 16) {
+            if (eq(strtab + *(u32 *)(syms + off), name))
+                return (void *)(base + *(u32 *)(syms + off + 4));
+        }
+    }
+    return 0;
+}
+
+int main(u32 *sp) {
+    /* argc, argv, NULL, envp, NULL, auxv */
+    u32 *p = sp + 1 + sp[0] + 1;
+    while (*p)
+        p++;
+    const unsigned char *base = 0;
+    for (p++; p[0]; p += 2) {
+        /* AT_SYSINFO_EHDR */
+        if (p[0] == 33)
+            base = (const unsigned char *)p[1];
+    }
+    if (!base)
+        return 1;
+    int (*gettime)(int, struct timespec32 *) = vdso_sym(base, "__vdso_clock_gettime");
+    if (!gettime)
+        return 2;
+
+    char c;
+    while (sys(3, 0, (int)&c, 1) == 1) {
+        if (c != '\n')
+            continue;
+        struct timespec32 ts;
+        gettime(0, &ts);
+        char buf[16];
+        int i = sizeof(buf);
+        buf[--i] = '\n';
+        u32 n = ts.sec;
+        do {
+            buf[--i] = '0' + n % 10;
+            n /= 10;
+        } while (n);
+        sys(4, 1, (int)(buf + i), sizeof(buf) - i);
+    }
+    return 0;
+}
+
+__asm__(".globl _start\n"
+        "_start:\n"
+        "    mov %esp, %eax\n"
+        "    and $-16, %esp\n"
+        "    sub $12, %esp\n"
+        "    push %eax\n"
+        "    call main\n"
+        "    mov %eax, %ebx\n"
+        "    mov $1, %eax\n"
+        "    int $0x80\n");
@@ -0,0 +1,40 @@
+#![cfg(target_arch = "x86_64")]
+use std::fs;
+use std::process::Command;
+use tpom::stubs::Stub;
+use tpom::{follow, Error, Kind, TimeSpec};
+
+#[test]
+fn every_program_in_the_tree_is_mocked() {
+    let path = std::env::temp_dir().join(format!("tpom-follow-{}", std::process::id()));
+    let out = fs::File::create(&path).unwrap();
+    // A program, one run by a child shell, and one run in the background by a fork
+    let script = "date +%s; sh -c 'date +%s'; (date +%s &); wait";
+    let mut programs = 0;
+    let status = follow::run(
+        Command::new("/bin/sh").args(["-c", script]).stdout(out),
+        |target| {
+            programs += 1;
+            let frozen = TimeSpec {
+                seconds: 2_000_000_000,
+                nanos: 0,
+            };
+            target.install(Kind::GetTime, &Stub::Constant(frozen))
+        },
+    )
+    .unwrap();
+    assert!(status.success());
+    let printed = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(printed, "2000000000\n".repeat(3));
+    // sh, three date and another sh
+    assert!(programs >= 5, "{}", programs);
+}
+
+#[test]
+fn failing_setup_kills_the_tree() {
+    let res = follow::run(Command::new("/bin/sleep").arg("10"), |_| {
+        Err(Error::InvalidConfig("no".to_string()))
+    });
+    assert_eq!(res.unwrap_err(), Error::InvalidConfig("no".to_string()));
+}
@@ -0,0 +1,59 @@
+// The fork handlers are process-wide, so these live apart from the other tests.
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tpom::{handle_fork, vdso, AfterFork, Kind, TVDSOFun, TimeSpec};
+
+    fn myclock(_clockid: i32) -> TimeSpec {
+        TimeSpec {
+            seconds: 111,
+            nanos: 333,
+        }
+    }
+
+    /// Forks, running `f` in the child; returns whether it was true there.
+    fn in_child(f: impl FnOnce() -> bool) -> bool {
+        match unsafe { libc::fork() } {
+            0 => unsafe { libc::_exit(if f() { 0 } else { 1 }) },
+            pid => {
+                assert!(pid > 0);
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+            }
+        }
+    }
+
+    fn now() -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    }
+
+    #[test]
+    fn children_keep_or_restore_the_patches() {
+        let v = vdso::vDSO::read().unwrap();
+        let backup = v.entry(Kind::GetTime).unwrap().overwrite(myclock).unwrap();
+        handle_fork(AfterFork::Keep);
+
+        // Forks while another thread reads the clock, and so the trampoline's state
+        let done = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    now();
+                }
+            });
+            for _ in 0..200 {
+                assert!(in_child(|| now() == Duration::new(111, 333)));
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+
+        handle_fork(AfterFork::Restore);
+        assert!(in_child(|| now() > Duration::from_secs(1_000_000_000)
+            && !v.entry(Kind::GetTime).unwrap().is_patched()));
+        assert_eq!(now(), Duration::new(111, 333));
+
+        backup.restore().unwrap();
+        assert!(now() > Duration::from_secs(1_000_000_000));
+    }
+}
@@ -0,0 +1,54 @@
+#![cfg(feature = "macros")]
+// Patches the vDSO of the whole process, so this lives apart from the other tests.
+mod tests {
+    use std::thread;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tpom::{vdso, Kind, TVDSOFun, TimeSpec};
+
+    fn myclock(_clockid: i32) -> TimeSpec {
+        TimeSpec {
+            seconds: 111,
+            nanos: 0,
+        }
+    }
+
+    fn now() -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    }
+
+    #[tpom::frozen_time("2022-03-01T00:00:00Z")]
+    fn freezes_the_time() {
+        assert_eq!(now(), Duration::from_secs(1_646_092_800));
+        thread::sleep(Duration::from_millis(1));
+        assert_eq!(now(), Duration::from_secs(1_646_092_800));
+    }
+
+    #[tpom::frozen_time("1000000000.5")]
+    #[test]
+    fn takes_seconds_and_a_test_attribute() -> Result<(), String> {
+        assert_eq!(now(), Duration::from_millis(1_000_000_000_500));
+        Ok(())
+    }
+
+    #[tpom::frozen_time("2022-03-01T00:00:00Z")]
+    #[should_panic(expected = "failed while frozen")]
+    fn unwinds_out_of_a_panic() {
+        panic!("failed while frozen");
+    }
+
+    #[test]
+    fn guard_restores_left_patches() {
+        let guard = tpom::test_guard();
+        let v = vdso::vDSO::read().unwrap();
+        let backup = v.entry(Kind::GetTime).unwrap().overwrite(myclock).unwrap();
+        std::mem::forget(backup);
+        assert_eq!(now(), Duration::from_secs(111));
+        drop(guard);
+        let _guard = tpom::test_guard();
+        assert!(now() > Duration::from_secs(1_600_000_000));
+    }
+
+    #[tpom::frozen_time("yesterday")]
+    #[should_panic(expected = "Could not freeze the time at \"yesterday\"")]
+    fn rejects_an_unknown_time() {}
+}
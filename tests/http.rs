@@ -0,0 +1,58 @@
+#![cfg(feature = "http")]
+// The live clock is process-wide, so this lives apart from the other tests.
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tpom::{http, live, vdso, Session};
+
+    fn now() -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    }
+
+    /// The status line and body of the answer.
+    fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut answer = String::new();
+        stream.read_to_string(&mut answer).unwrap();
+        let (head, body) = answer.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    }
+
+    #[test]
+    fn requests_steer_the_clock() {
+        let v = vdso::vDSO::read().unwrap();
+        let mut session = Session::new(&v);
+        session.apply_all(&live::callbacks()).unwrap();
+        let addr = http::listen("127.0.0.1:0").unwrap();
+
+        let (status, body) = request(addr, "POST", "/freeze", "1000");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(
+            body,
+            r#"{"time": 1000.000000000, "nanos": 1000000000000, "frozen": true, "speed": 1}"#
+        );
+        request(addr, "POST", "/advance", "-0.5");
+        assert_eq!(now(), Duration::from_millis(999_500));
+        let (_, body) = request(addr, "GET", "/time", "");
+        assert!(body.contains(r#""nanos": 999500000000"#), "{}", body);
+
+        let (status, body) = request(addr, "POST", "/scale", "fast");
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        assert!(body.starts_with(r#"{"error": "#), "{}", body);
+        assert_eq!(request(addr, "GET", "/", "").0, "HTTP/1.1 404 Not Found");
+
+        request(addr, "POST", "/restore", "");
+        assert!(now() > Duration::from_secs(1_000_000_000));
+        session.restore_all().unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+// Patches the vDSO of the whole process, so this lives apart from the other tests.
+mod tests {
+    #[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
+    use tpom::auxv::{self, AT_SYSINFO_EHDR};
+    use tpom::vdso::{self, vDSO};
+    use tpom::{Kind, TVDSOFun, TimeSpec};
+
+    fn myclock(_clockid: i32) -> TimeSpec {
+        TimeSpec {
+            seconds: 111,
+            nanos: 333,
+        }
+    }
+
+    /// Writes `code` at `offset` in the vDSO, as a hooking library would, returning what
+    /// was there.
+    #[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
+    fn hook(offset: usize, code: &[u8]) -> Vec<u8> {
+        let mut image = vec![];
+        vDSO::read().unwrap().write_to(&mut image).unwrap();
+        let base = auxv::get(AT_SYSINFO_EHDR).unwrap();
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let len = image.len().next_multiple_of(page);
+        let mprotect = |prot| unsafe { libc::mprotect(base as *mut libc::c_void, len, prot) };
+        assert_eq!(
+            mprotect(libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC),
+            0
+        );
+        let at = (base + offset) as *mut u8;
+        unsafe { std::ptr::copy_nonoverlapping(code.as_ptr(), at, code.len()) };
+        assert_eq!(mprotect(libc::PROT_READ | libc::PROT_EXEC), 0);
+        image[offset..offset + code.len()].to_vec()
+    }
+
+    #[test]
+    fn tells_tpom_patches_from_foreign_hooks() {
+        assert!(vdso::verify().unwrap().is_clean());
+
+        let v = vDSO::read().unwrap();
+        let backup = v.entry(Kind::GetTime).unwrap().overwrite(myclock).unwrap();
+        let integrity = vdso::verify().unwrap();
+        assert!(integrity.foreign.is_empty());
+        assert!(integrity
+            .patched
+            .iter()
+            .any(|s| s.name == "__vdso_clock_gettime"));
+        backup.restore().unwrap();
+        assert!(vdso::verify().unwrap().is_clean());
+
+        // getcpu is never called here, so it can hold anything for a moment
+        #[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
+        {
+            let getcpu = v.symbols().find(|s| s.name == "__vdso_getcpu").unwrap();
+            let saved = hook(getcpu.address, &[0xcc; 4]);
+            let integrity = vdso::verify().unwrap();
+            hook(getcpu.address, &saved);
+            assert!(integrity.patched.is_empty());
+            assert!(integrity.foreign.iter().any(|s| s.name == "__vdso_getcpu"));
+            assert!(vdso::verify().unwrap().is_clean());
+        }
+    }
+}
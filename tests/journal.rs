@@ -0,0 +1,51 @@
+// The journal records the patches of the whole process, so this lives apart from the other tests.
+mod tests {
+    use tpom::journal::{self, Operation};
+    use tpom::{vdso, Kind, TVDSOFun, TimeSpec};
+
+    fn myclock(_clockid: i32) -> TimeSpec {
+        TimeSpec {
+            seconds: 111,
+            nanos: 333,
+        }
+    }
+
+    #[test]
+    fn records_patches_and_restores() {
+        let v = vdso::vDSO::read().unwrap();
+        let og = v.entry(Kind::GetTime).unwrap();
+        og.overwrite(myclock).unwrap().restore().unwrap();
+        assert!(journal::entries().is_empty());
+
+        let path = std::env::temp_dir().join(format!("tpom-journal-{}", std::process::id()));
+        journal::keep_in_memory();
+        journal::append_to(&path).unwrap();
+        let backup = og.overwrite(myclock).unwrap();
+        backup.restore().unwrap();
+        journal::stop();
+        og.overwrite(myclock).unwrap().restore().unwrap();
+
+        assert!(journal::entries().is_empty());
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = lines.lines().collect();
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        for (line, op) in lines.iter().zip(["patch", "restore"]) {
+            let fields: Vec<&str> = line.split(' ').collect();
+            assert_eq!(fields[1], op);
+            assert_eq!(fields[2], og.name);
+            assert!(fields[4].starts_with("tests/journal.rs:"), "{}", line);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        journal::keep_in_memory();
+        og.overwrite(myclock).unwrap().restore().unwrap();
+        let entries = journal::entries();
+        journal::stop();
+        let ops: Vec<Operation> = entries.iter().map(|e| e.operation).collect();
+        assert_eq!(ops, [Operation::Patch, Operation::Restore]);
+        assert!(entries.iter().all(|e| e.symbol == og.name));
+        assert!(entries[0].caller.starts_with("tests/journal.rs:"));
+        assert_ne!(entries[0].hash, entries[1].hash);
+        assert!(entries[0].at > 1_700_000_000_000_000_000);
+    }
+}
@@ -0,0 +1,31 @@
+// Leaked patches last for the whole process, so these live apart from the other tests.
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tpom::{vdso, Callback, Error, Kind, Session, TVDSOFun, TimeSpec};
+
+    fn myclock(_clockid: i32) -> TimeSpec {
+        TimeSpec {
+            seconds: 111,
+            nanos: 333,
+        }
+    }
+
+    #[test]
+    fn leaked_session_stays_installed() {
+        let v = vdso::vDSO::read().unwrap();
+        let mut session = Session::new(&v);
+        session.overwrite(Callback::GetTime(myclock)).unwrap();
+        session.leak();
+
+        assert_eq!(
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+            Duration::new(111, 333)
+        );
+        let og = v.entry(Kind::GetTime).unwrap();
+        assert!(og.is_patched());
+        assert!(matches!(
+            og.overwrite(myclock),
+            Err(Error::AlreadyPatched(_))
+        ));
+    }
+}
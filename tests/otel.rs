@@ -0,0 +1,82 @@
+#![cfg(feature = "otel")]
+// Changes the live clock of the whole process, so this lives apart from the other tests.
+mod tests {
+    use opentelemetry::trace::{mark_span_as_active, Span, SpanContext, Status};
+    use opentelemetry::KeyValue;
+    use std::borrow::Cow;
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
+    use tpom::live;
+
+    type Events = Arc<Mutex<Vec<(String, Vec<KeyValue>)>>>;
+
+    /// Keeps the events added to it.
+    struct Recorder(Events, SpanContext);
+
+    impl Span for Recorder {
+        fn add_event_with_timestamp<T>(&mut self, name: T, _: SystemTime, attributes: Vec<KeyValue>)
+        where
+            T: Into<Cow<'static, str>>,
+        {
+            self.0
+                .lock()
+                .unwrap()
+                .push((name.into().into_owned(), attributes));
+        }
+
+        fn span_context(&self) -> &SpanContext {
+            &self.1
+        }
+
+        fn is_recording(&self) -> bool {
+            true
+        }
+
+        fn set_attribute(&mut self, _: KeyValue) {}
+
+        fn set_status(&mut self, _: Status) {}
+
+        fn update_name<T>(&mut self, _: T)
+        where
+            T: Into<Cow<'static, str>>,
+        {
+        }
+
+        fn add_link(&mut self, _: SpanContext, _: Vec<KeyValue>) {}
+
+        fn end_with_timestamp(&mut self, _: SystemTime) {}
+    }
+
+    #[test]
+    fn adds_events_to_the_active_span() {
+        let events = Events::default();
+        {
+            let _active =
+                mark_span_as_active(Recorder(events.clone(), SpanContext::empty_context()));
+            live::execute("freeze 1000".parse().unwrap()).unwrap();
+            live::execute("scale 10".parse().unwrap()).unwrap();
+            live::execute("status".parse().unwrap()).unwrap();
+            live::execute("restore".parse().unwrap()).unwrap();
+        }
+        live::execute("freeze".parse().unwrap()).unwrap();
+        live::execute("restore".parse().unwrap()).unwrap();
+
+        let events = events.lock().unwrap();
+        let names: Vec<&str> = events.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["tpom.freeze", "tpom.scale", "tpom.restore"]);
+        let attribute = |i: usize, key: &str| {
+            let (_, attributes) = &events[i];
+            let kv = attributes.iter().find(|kv| kv.key.as_str() == key);
+            kv.map(|kv| kv.value.to_string())
+        };
+        assert_eq!(
+            attribute(0, "tpom.argument").as_deref(),
+            Some("1000.000000000")
+        );
+        assert_eq!(attribute(0, "tpom.now").as_deref(), Some("1000.000000000"));
+        assert_eq!(attribute(0, "tpom.frozen").as_deref(), Some("true"));
+        assert_eq!(attribute(1, "tpom.speed").as_deref(), Some("10"));
+        assert_eq!(attribute(2, "tpom.argument"), None);
+        assert_eq!(attribute(2, "tpom.frozen").as_deref(), Some("false"));
+    }
+}
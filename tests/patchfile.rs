@@ -0,0 +1,44 @@
+// Patch files are applied to the whole process, so these live apart from the other tests.
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tpom::{patchfile, vdso, Error, Kind, TVDSOFun, TimeSpec};
+
+    fn myclock(_clockid: i32) -> TimeSpec {
+        TimeSpec {
+            seconds: 111,
+            nanos: 333,
+        }
+    }
+
+    fn mocked() -> bool {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap() == Duration::new(111, 333)
+    }
+
+    #[test]
+    fn records_apply_and_revert() {
+        let v = vdso::vDSO::read().unwrap();
+        let og = v.entry(Kind::GetTime).unwrap();
+        let backup = og.overwrite(myclock).unwrap();
+        let record = backup.record();
+        assert_eq!(record.symbol, og.name);
+        assert_eq!(record.original.len(), record.stub.len());
+        backup.restore().unwrap();
+        assert!(!mocked());
+
+        let path = std::env::temp_dir().join(format!("tpom-patch-{}", std::process::id()));
+        patchfile::save(&path, &[record]).unwrap();
+        patchfile::apply_patch_file(&path).unwrap();
+        assert!(mocked());
+        assert!(og.is_patched());
+        assert!(matches!(
+            patchfile::apply_patch_file(&path),
+            Err(Error::PatchMismatch(_))
+        ));
+
+        patchfile::revert_patch_file(&path).unwrap();
+        assert!(!mocked());
+        assert!(!og.is_patched());
+        assert!(patchfile::revert_patch_file(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+use std::process::{Command, Stdio};
+use tpom::process::CommandExt;
+use tpom::TimeSpec;
+
+fn uptime(contents: &str) -> f64 {
+    contents.split_whitespace().next().unwrap().parse().unwrap()
+}
+
+#[test]
+fn time_namespace_shifts_boottime() {
+    let zero = TimeSpec {
+        seconds: 0,
+        nanos: 0,
+    };
+    let day = TimeSpec {
+        seconds: 86400,
+        nanos: 0,
+    };
+    let child = match Command::new("cat")
+        .arg("/proc/uptime")
+        .time_namespace(zero, day)
+        .output()
+    {
+        // Not privileged, or no time namespaces in this kernel
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EPERM | libc::EINVAL)) => return,
+        res => res.unwrap(),
+    };
+    let child = uptime(&String::from_utf8(child.stdout).unwrap());
+    let own = uptime(&std::fs::read_to_string("/proc/uptime").unwrap());
+    assert!((child - own - 86400.0).abs() < 5.0, "{} vs {}", child, own);
+}
+
+#[cfg(feature = "preload")]
+#[test]
+fn preload_sets_the_environment() {
+    use std::ffi::OsStr;
+    use tpom::preload::Config;
+
+    let config = Config {
+        start: None,
+        offset: -60_000_000_000,
+        speed: 1.0,
+    };
+    let mut command = Command::new("true");
+    command
+        .env("LD_PRELOAD", "libother.so")
+        .preload("libtpom_preload.so", &config);
+    let env = |name: &str| {
+        command
+            .get_envs()
+            .find(|(k, _)| *k == name)
+            .and_then(|(_, v)| v)
+            .map(OsStr::to_os_string)
+    };
+    assert_eq!(
+        env("LD_PRELOAD"),
+        Some("libtpom_preload.so:libother.so".into())
+    );
+    assert_eq!(env("TPOM_OFFSET"), Some("-60.000000000".into()));
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn spawned_program_is_patched() {
+    use tpom::stubs::Stub;
+    use tpom::Kind;
+
+    let frozen = TimeSpec {
+        seconds: 2_000_000_000,
+        nanos: 0,
+    };
+    let child = Command::new("date")
+        .arg("+%s")
+        .stdout(Stdio::piped())
+        .spawn_patched(|target| target.install(Kind::GetTime, &Stub::Constant(frozen)))
+        .unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "2000000000\n");
+
+    let err = Command::new("true").spawn_patched(|_| Err(tpom::Error::Offline));
+    assert!(matches!(err, Err(tpom::Error::Offline)));
+}
@@ -1,11 +1,11 @@
 mod tests {
     use std::hint::black_box;
-    use std::sync::Mutex;
     use std::thread;
-    use std::time::{Duration, SystemTime};
-    use tpom::{vdso, Kind, TVDSOFun, TimeSpec};
-
-    static tm: Mutex<i32> = Mutex::new(0);
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tpom::testing::ClockAssertions;
+    use tpom::{
+        strict, vdso, Backend, Callback, Error, Kind, Session, TVDSOFun, TimeSpec, TimeVal,
+    };
 
     fn myclock(_clockid: i32) -> TimeSpec {
         TimeSpec {
@@ -14,41 +14,100 @@ mod tests {
         }
     }
 
+    fn mygttod() -> TimeVal {
+        TimeVal {
+            seconds: 1,
+            micros: 3,
+        }
+    }
+
+    fn reentrant_clock(_clockid: i32) -> TimeSpec {
+        // Calling into the patched vDSO from the callback must not recurse forever
+        let real = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        TimeSpec {
+            seconds: real.as_secs() as i64 + 1000,
+            nanos: 0,
+        }
+    }
+
+    fn panicking_clock(_clockid: i32) -> TimeSpec {
+        panic!("callback failed");
+    }
+
     #[test]
     fn regular_clock_produces_different_timestamps() {
-        let _guard = tm.lock().unwrap();
+        let _guard = tpom::test_guard();
         let time_a = SystemTime::now();
         thread::sleep(std::time::Duration::from_millis(1)); // clock in github actions is coarse
         let time_b = SystemTime::now();
         assert_ne!(time_a, time_b);
     }
 
+    #[test]
+    fn test_guard_restores_left_patches() {
+        let guard = tpom::test_guard();
+        let v = vdso::vDSO::read().unwrap();
+        let backup = v.entry(Kind::GetTime).unwrap().overwrite(myclock).unwrap();
+        std::mem::forget(backup);
+        let frozen = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        assert_eq!(frozen, Duration::new(111, 333));
+        drop(guard);
+        let _guard = tpom::test_guard();
+        let real = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        assert!(real > Duration::from_secs(1_600_000_000));
+    }
+
+    #[test]
+    fn clock_assertions_catch_reads() {
+        let _guard = tpom::test_guard();
+        let v = vdso::vDSO::read().unwrap();
+        let mut session = Session::new(&v);
+        session.overwrite(Callback::GetTime(myclock)).unwrap();
+        let clocks = ClockAssertions::new()
+            .forbid(libc::CLOCK_REALTIME)
+            .max_reads(2);
+        let _ = black_box(std::time::Instant::now());
+        clocks.assert();
+        let _ = black_box(SystemTime::now());
+        let _ = black_box(SystemTime::now());
+        assert_eq!(
+            clocks.violations(),
+            [
+                "clock 0 was read 2 times",
+                "the time was read 3 times, over 2"
+            ]
+        );
+        let result = std::panic::catch_unwind(|| clocks.assert());
+        assert!(result.is_err());
+        session.restore_all().unwrap();
+    }
+
     #[test]
     fn it_freezes_system_clock() {
-        let _guard = tm.lock().unwrap();
+        let _guard = tpom::test_guard();
         let v = vdso::vDSO::read().unwrap();
         let og = v
             .entry(Kind::GetTime)
             .ok_or("Could not find clock")
             .unwrap();
-        let backup = og.overwrite(myclock);
+        let backup = og.overwrite(myclock).unwrap();
 
         let time_a = SystemTime::now();
         thread::sleep(std::time::Duration::from_millis(1)); // clock in github actions is coarse
         let time_b = SystemTime::now();
         assert_eq!(time_a, time_b);
-        backup.restore();
+        backup.restore().unwrap();
     }
 
     #[test]
     fn it_works_many_threads() {
-        let _guard = tm.lock().unwrap();
+        let _guard = tpom::test_guard();
         let v = vdso::vDSO::read().unwrap();
         let og = v
             .entry(Kind::GetTime)
             .ok_or("Could not find clock")
             .unwrap();
-        let backup = og.overwrite(myclock);
+        let backup = og.overwrite(myclock).unwrap();
 
         thread::scope(|s| {
             for _ in 0..10 {
@@ -60,25 +119,267 @@ mod tests {
                 });
             }
         });
-        backup.restore();
+        backup.restore().unwrap();
         black_box(SystemTime::now());
     }
 
     #[test]
     fn it_works_after_setenv() {
-        let _guard = tm.lock().unwrap();
+        let _guard = tpom::test_guard();
         std::env::set_var("SOMETHING", "VALUE");
         let v = vdso::vDSO::read().unwrap();
         let og = v
             .entry(Kind::GetTime)
             .ok_or("Could not find clock")
             .unwrap();
-        let backup = og.overwrite(myclock);
+        let backup = og.overwrite(myclock).unwrap();
 
         let time_a = SystemTime::now();
         thread::sleep(std::time::Duration::from_millis(1)); // clock in github actions is coarse
         let time_b = SystemTime::now();
         assert_eq!(time_a, time_b);
-        backup.restore();
+        backup.restore().unwrap();
+    }
+
+    #[test]
+    fn reentrant_callback_uses_real_clock() {
+        let _guard = tpom::test_guard();
+        let real = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let v = vdso::vDSO::read().unwrap();
+        let og = v
+            .entry(Kind::GetTime)
+            .ok_or("Could not find clock")
+            .unwrap();
+        let backup = og.overwrite(reentrant_clock).unwrap();
+
+        let mocked = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        backup.restore().unwrap();
+        assert!(mocked.as_secs() >= real.as_secs() + 1000);
+        assert_eq!(mocked.subsec_nanos(), 0);
+    }
+
+    #[test]
+    fn double_patch_is_rejected() {
+        let _guard = tpom::test_guard();
+        let v = vdso::vDSO::read().unwrap();
+        let og = v
+            .entry(Kind::GetTime)
+            .ok_or("Could not find clock")
+            .unwrap();
+        let backup = og.overwrite(myclock).unwrap();
+        assert!(og.is_patched());
+        assert!(matches!(
+            og.overwrite(myclock),
+            Err(Error::AlreadyPatched(_))
+        ));
+        backup.restore().unwrap();
+        assert!(!og.is_patched());
+
+        // Once restored, the symbol can be patched again
+        let backup = og.overwrite(myclock).unwrap();
+        backup.restore().unwrap();
+    }
+
+    #[test]
+    fn session_restores_on_drop() {
+        let _guard = tpom::test_guard();
+        let v = vdso::vDSO::read().unwrap();
+        {
+            let mut session = Session::new(&v);
+            session.overwrite(Callback::GetTime(myclock)).unwrap();
+            session.overwrite(Callback::GetTimeOfDay(mygttod)).unwrap();
+
+            let mut tv = libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            };
+            unsafe { libc::gettimeofday(&mut tv, std::ptr::null_mut()) };
+            assert_eq!((tv.tv_sec, tv.tv_usec), (1, 3));
+            assert_eq!(
+                SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+                Duration::new(111, 333)
+            );
+        }
+        let time_a = SystemTime::now();
+        thread::sleep(std::time::Duration::from_millis(1)); // clock in github actions is coarse
+        let time_b = SystemTime::now();
+        assert_ne!(time_a, time_b);
+        assert!(!v.entry(Kind::GetTime).unwrap().is_patched());
+    }
+
+    #[test]
+    fn handles_outlive_vdso_and_cross_threads() {
+        let _guard = tpom::test_guard();
+        let og = {
+            let v = vdso::vDSO::read().unwrap();
+            v.entry(Kind::GetTime).unwrap()
+        };
+        let backup = thread::spawn(move || og.overwrite(myclock).unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+            Duration::new(111, 333)
+        );
+        thread::spawn(move || backup.restore().unwrap())
+            .join()
+            .unwrap();
+        assert_ne!(
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+            Duration::new(111, 333)
+        );
+    }
+
+    #[test]
+    fn panic_hook_restores_clock() {
+        let _guard = tpom::test_guard();
+        tpom::restore_on_panic();
+        let v = vdso::vDSO::read().unwrap();
+        let og = v.entry(Kind::GetTime).unwrap();
+        let _backup = og.overwrite(myclock).unwrap();
+
+        assert!(thread::spawn(|| panic!("test panic")).join().is_err());
+        assert!(!og.is_patched());
+        assert_ne!(
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+            Duration::new(111, 333)
+        );
+    }
+
+    #[test]
+    fn panicking_callback_falls_back_to_real_clock() {
+        let _guard = tpom::test_guard();
+        let v = vdso::vDSO::read().unwrap();
+        let og = v.entry(Kind::GetTime).unwrap();
+        let backup = og.overwrite(panicking_clock).unwrap();
+
+        let time_a = SystemTime::now();
+        thread::sleep(std::time::Duration::from_millis(1)); // clock in github actions is coarse
+        let time_b = SystemTime::now();
+        backup.restore().unwrap();
+        assert_ne!(time_a, time_b);
+        assert_eq!(
+            tpom::take_callback_panic().as_deref(),
+            Some("callback failed")
+        );
+        assert_eq!(tpom::take_callback_panic(), None);
+    }
+
+    #[test]
+    fn strict_mode_records_escapes() {
+        let _guard = tpom::test_guard();
+        let v = vdso::vDSO::read().unwrap();
+        let mut session = Session::new(&v);
+        session
+            .overwrite(Callback::GetTime(panicking_clock))
+            .unwrap();
+
+        strict::enable(strict::Action::Record);
+        black_box(SystemTime::now());
+        strict::disable();
+        session.restore_all().unwrap();
+        tpom::take_callback_panic();
+
+        let escapes = strict::take_escapes();
+        assert_eq!(escapes.len(), 1);
+        assert_eq!(escapes[0].function, "clock_gettime");
+        assert_eq!(escapes[0].reason, strict::Reason::Panicked);
+    }
+
+    #[test]
+    fn double_restore_is_noop() {
+        let _guard = tpom::test_guard();
+        let v = vdso::vDSO::read().unwrap();
+        let og = v.entry(Kind::GetTime).unwrap();
+        let first = og.overwrite(myclock).unwrap();
+        first.restore().unwrap();
+
+        // Restoring `first` again must not remove the newer patch
+        let second = og.overwrite(myclock).unwrap();
+        first.restore().unwrap();
+        assert!(og.is_patched());
+        assert_eq!(
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+            Duration::new(111, 333)
+        );
+        second.restore().unwrap();
+        assert!(!og.is_patched());
+    }
+
+    #[test]
+    fn apply_all_rolls_back_on_failure() {
+        let _guard = tpom::test_guard();
+        let v = vdso::vDSO::read().unwrap();
+        let mut session = Session::new(&v);
+        // Patching the same function twice fails on the second one
+        let res = session.apply_all(&[
+            Callback::GetTimeOfDay(mygttod),
+            Callback::GetTime(myclock),
+            Callback::GetTime(myclock),
+        ]);
+        assert!(matches!(res, Err(Error::AlreadyPatched(_))));
+        assert!(!v.entry(Kind::GetTime).unwrap().is_patched());
+        assert!(!v.entry(Kind::GetTimeOfDay).unwrap().is_patched());
+
+        session
+            .apply_all(&[Callback::GetTimeOfDay(mygttod), Callback::GetTime(myclock)])
+            .unwrap();
+        assert!(v.entry(Kind::GetTime).unwrap().is_patched());
+    }
+
+    #[test]
+    fn got_backend_redirects_libc_calls() {
+        let _guard = tpom::test_guard();
+        let v = vdso::vDSO::read().unwrap();
+        let mut session = Session::with_backend(&v, Backend::Got);
+        session.overwrite(Callback::GetTime(myclock)).unwrap();
+        // The vDSO is untouched; std calls clock_gettime through its GOT
+        assert!(!v.entry(Kind::GetTime).unwrap().is_patched());
+        assert_eq!(
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+            Duration::new(111, 333)
+        );
+        assert!(matches!(
+            session.overwrite(Callback::GetTime(myclock)),
+            Err(Error::AlreadyPatched(_))
+        ));
+        session.restore_all().unwrap();
+        assert_ne!(
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+            Duration::new(111, 333)
+        );
+    }
+
+    #[test]
+    fn current_mock_calls_the_callbacks() {
+        let _guard = tpom::test_guard();
+        let empty = tpom::current_mock();
+        assert!(empty.patched.is_empty());
+        assert_eq!(empty.clock(libc::CLOCK_REALTIME), None);
+
+        let v = vdso::vDSO::read().unwrap();
+        let mut session = Session::new(&v);
+        session
+            .apply_all(&[Callback::GetTime(myclock), Callback::GetTimeOfDay(mygttod)])
+            .unwrap();
+        let mock = tpom::current_mock();
+        assert_eq!(mock.patched, [Kind::GetTime, Kind::GetTimeOfDay]);
+        assert_eq!(
+            mock.clock(libc::CLOCK_MONOTONIC),
+            Some(TimeSpec {
+                seconds: 111,
+                nanos: 333
+            })
+        );
+        assert_eq!(
+            mock.time_of_day,
+            Some(TimeVal {
+                seconds: 1,
+                micros: 3
+            })
+        );
+        assert_eq!(mock.time, None);
+        session.restore_all().unwrap();
+        assert!(tpom::current_mock().patched.is_empty());
     }
 }
@@ -0,0 +1,241 @@
+#![cfg(target_arch = "x86_64")]
+use std::io::{BufRead, BufReader, Lines, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tpom::control::ControlPage;
+use tpom::stubs::Stub;
+use tpom::vdso::{vDSO, ElfClass};
+use tpom::{patchfile, remote, Kind, TimeSpec};
+
+/// Run as the target: prints the time for every line read.
+#[test]
+fn remote_target() {
+    if std::env::var_os("TPOM_REMOTE_TARGET").is_none() {
+        return;
+    }
+    let mut out = std::io::stdout();
+    for _ in std::io::stdin().lines() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        writeln!(out, "{}", now.as_secs()).unwrap();
+        out.flush().unwrap();
+    }
+}
+
+/// This test executable, running `remote_target`.
+struct Target {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Lines<BufReader<ChildStdout>>,
+}
+
+impl Target {
+    fn spawn() -> Target {
+        Target::run(
+            Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "remote_target",
+                    "--nocapture",
+                    "--test-threads=1",
+                ])
+                .env("TPOM_REMOTE_TARGET", "1"),
+        )
+    }
+
+    /// A program that behaves like `remote_target`.
+    fn run(command: &mut Command) -> Target {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        Target {
+            stdin: child.stdin.take().unwrap(),
+            lines: BufReader::new(child.stdout.take().unwrap()).lines(),
+            child,
+        }
+    }
+
+    fn pid(&self) -> i32 {
+        self.child.id() as i32
+    }
+
+    /// The target's time, in seconds.
+    fn ask(&mut self) -> u64 {
+        writeln!(self.stdin).unwrap();
+        // Skip the harness' own output, which may start the line
+        loop {
+            let line = self.lines.next().unwrap().unwrap();
+            if let Ok(secs) = line.rsplit(' ').next().unwrap().parse() {
+                return secs;
+            }
+        }
+    }
+}
+
+impl Drop for Target {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn freezes_time_in_a_subprocess() {
+    let mut child = Target::spawn();
+    assert!(child.ask() > 1_000_000_000);
+
+    let mut target = remote::attach(child.pid()).unwrap();
+    // mov qword [rsi], 1234; and qword [rsi + 8], 0; xor eax, eax; ret
+    let stub = [
+        0x48, 0xc7, 0x06, 0xd2, 0x04, 0x00, 0x00, 0x48, 0x83, 0x66, 0x08, 0x00, 0x31, 0xc0, 0xc3,
+    ];
+    target.overwrite(Kind::GetTime, &stub).unwrap();
+    assert_eq!(child.ask(), 1234);
+    assert!(target.overwrite(Kind::GetTime, &stub).is_err());
+
+    target.detach().unwrap();
+    assert!(child.ask() > 1_000_000_000);
+}
+
+#[test]
+fn restores_leaked_patches() {
+    let mut child = Target::spawn();
+    assert!(child.ask() > 1_000_000_000);
+    let mut target = remote::attach(child.pid()).unwrap();
+    let frozen = TimeSpec {
+        seconds: 2_000_000_000,
+        nanos: 0,
+    };
+    target
+        .install(Kind::GetTime, &Stub::Constant(frozen))
+        .unwrap();
+    target.leak();
+    assert_eq!(child.ask(), 2_000_000_000);
+
+    // As another run of the tool would
+    let mut target = remote::attach(child.pid()).unwrap();
+    let restored = target.restore_from(&vDSO::read().unwrap()).unwrap();
+    assert!(restored.iter().any(|s| s.name == "__vdso_clock_gettime"));
+    assert!(child.ask() > 1_000_000_000);
+    assert!(target
+        .restore_from(&vDSO::read().unwrap())
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn replays_patch_files() {
+    let mut child = Target::spawn();
+    assert!(child.ask() > 1_000_000_000);
+    let mut target = remote::attach(child.pid()).unwrap();
+    let frozen = TimeSpec {
+        seconds: 2_000_000_000,
+        nanos: 0,
+    };
+    target
+        .install(Kind::GetTime, &Stub::Constant(frozen))
+        .unwrap();
+    let path = std::env::temp_dir().join(format!("tpom-patch-{}", child.pid()));
+    patchfile::save(&path, &target.records()).unwrap();
+    target.leak();
+
+    let mut target = remote::attach(child.pid()).unwrap();
+    let reverted = target.revert_patch_file(&path).unwrap();
+    assert_eq!(reverted[0].symbol, "__vdso_clock_gettime");
+    assert!(child.ask() > 1_000_000_000);
+    assert!(target.revert_patch_file(&path).is_err());
+    target.apply_patch_file(&path).unwrap();
+    assert_eq!(child.ask(), 2_000_000_000);
+    // Patches applied from a file are restored like the others
+    target.detach().unwrap();
+    assert!(child.ask() > 1_000_000_000);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn installs_stubs_in_a_subprocess() {
+    let mut child = Target::spawn();
+    assert!(child.ask() > 1_000_000_000);
+
+    let mut target = remote::attach(child.pid()).unwrap();
+    let frozen = TimeSpec {
+        seconds: 2_000_000_000,
+        nanos: 5,
+    };
+    target
+        .install(Kind::GetTime, &Stub::Constant(frozen))
+        .unwrap();
+    assert_eq!(child.ask(), 2_000_000_000);
+    assert_eq!(child.ask(), 2_000_000_000);
+
+    // Too big for the function, the stub is jumped to
+    let ours = vDSO::read().unwrap();
+    let theirs = vDSO::read_from_pid(child.pid()).unwrap();
+    let names: Vec<_> = theirs
+        .patched_symbols()
+        .into_iter()
+        .map(|s| s.name)
+        .collect();
+    assert!(
+        names.contains(&"__vdso_clock_gettime".to_string()),
+        "{:?}",
+        names
+    );
+    assert_eq!(theirs.diff(&ours), theirs.patched_symbols());
+
+    target.detach().unwrap();
+    assert!(child.ask() < 2_000_000_000);
+    let theirs = vDSO::read_from_pid(child.pid()).unwrap();
+    assert_eq!(theirs.diff(&ours), vec![]);
+}
+
+#[test]
+fn control_page_changes_the_time_of_a_subprocess() {
+    let mut child = Target::spawn();
+    let real = child.ask();
+
+    let page = ControlPage::new().unwrap();
+    let mut target = remote::attach(child.pid()).unwrap();
+    let addr = target.share(&page).unwrap();
+    target.install(Kind::GetTime, &Stub::Control(addr)).unwrap();
+    assert!(child.ask().abs_diff(real) <= 1);
+
+    page.freeze(TimeSpec {
+        seconds: 2_000_000_000,
+        nanos: 0,
+    });
+    assert_eq!(child.ask(), 2_000_000_000);
+    page.offset(-86_400_000_000_000);
+    assert!(child.ask().abs_diff(real - 86_400) <= 1);
+
+    target.detach().unwrap();
+    assert!(child.ask().abs_diff(real) <= 1);
+}
+
+#[test]
+fn installs_stubs_in_a_32_bit_subprocess() {
+    // Built from i386_target.c
+    let mut child = Target::run(&mut Command::new(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/files/i386_target"
+    )));
+    assert!(child.ask() > 1_000_000_000);
+
+    let mut target = remote::attach(child.pid()).unwrap();
+    assert_eq!(target.vdso().class(), ElfClass::Elf32);
+    let frozen = TimeSpec {
+        seconds: 2_000_000_000,
+        nanos: 5,
+    };
+    target
+        .install(Kind::GetTime, &Stub::Constant(frozen))
+        .unwrap();
+    assert_eq!(child.ask(), 2_000_000_000);
+    assert!(target
+        .install(Kind::Time, &Stub::Control(target.base()))
+        .is_err());
+
+    target.detach().unwrap();
+    assert!(child.ask() < 2_000_000_000);
+}
@@ -0,0 +1,30 @@
+mod tests {
+    use std::process::Command;
+
+    fn tpom_run(args: &[&str]) -> (Option<i32>, String) {
+        let out = Command::new(env!("CARGO_BIN_EXE_tpom-run"))
+            .args(args)
+            .output()
+            .unwrap();
+        (out.status.code(), String::from_utf8(out.stdout).unwrap())
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn runs_the_tree_frozen() {
+        let frozen = ["--freeze", "2021-01-01T00:00:00Z", "--"];
+        assert_eq!(
+            tpom_run(&[&frozen[..], &["date", "+%s"]].concat()),
+            (Some(0), "1609459200\n".to_string())
+        );
+        // Programs run by the program too
+        let (code, out) = tpom_run(&[&frozen[..], &["sh", "-c", "date +%s; exit 3"]].concat());
+        assert_eq!((code, out.as_str()), (Some(3), "1609459200\n"));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(tpom_run(&["--freeze", "soon", "--", "true"]).0, Some(125));
+        assert_eq!(tpom_run(&["--", "/nonexistent"]).0, Some(125));
+    }
+}
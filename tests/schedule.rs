@@ -0,0 +1,81 @@
+// The live clock is process-wide, so this lives apart from the other tests.
+mod tests {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll, Wake};
+    use std::thread::{self, Thread};
+    use std::time::Duration;
+    use tpom::live::{self, Command};
+    use tpom::schedule;
+
+    const SECOND: i64 = 1_000_000_000;
+
+    /// Both tests move the one clock.
+    static CLOCK: Mutex<()> = Mutex::new(());
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Arc::new(Unpark(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        loop {
+            if let Poll::Ready(out) = future.as_mut().poll(&mut cx) {
+                return out;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn events_run_when_the_clock_reaches_them() {
+        let _clock = CLOCK.lock().unwrap();
+        live::execute(Command::Freeze(Some(1000 * SECOND))).unwrap();
+        let (tx, rx) = mpsc::channel();
+        for (at, name) in [(1060, "later"), (1030, "sooner"), (2000, "never")] {
+            let tx = tx.clone();
+            let id = schedule::at(at * SECOND, move || tx.send(name).unwrap()).unwrap();
+            if name == "never" {
+                assert!(schedule::cancel(id));
+            }
+        }
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        live::execute(Command::Advance(60 * SECOND)).unwrap();
+        let wait = Duration::from_secs(5);
+        assert_eq!(rx.recv_timeout(wait), Ok("sooner"));
+        assert_eq!(rx.recv_timeout(wait), Ok("later"));
+        live::execute(Command::Advance(3600 * SECOND)).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        // Due at once
+        schedule::at(0, move || tx.send("past").unwrap()).unwrap();
+        assert_eq!(rx.recv_timeout(wait), Ok("past"));
+        live::execute(Command::Restore).unwrap();
+    }
+
+    #[test]
+    fn futures_resolve_as_a_running_clock_passes() {
+        let _clock = CLOCK.lock().unwrap();
+        live::execute(Command::Freeze(Some(1000 * SECOND))).unwrap();
+        let reached = schedule::reached(1001 * SECOND).unwrap();
+        // A virtual second in a real millisecond; set on the page rather than by command,
+        // as another process would
+        let start = tpom::TimeSpec {
+            seconds: 1000,
+            nanos: 0,
+        };
+        live::page().unwrap().run(start, 1000.0);
+        block_on(reached);
+        assert!(live::execute(Command::Status).unwrap().now >= 1001 * SECOND);
+        live::execute(Command::Restore).unwrap();
+    }
+}
@@ -0,0 +1,271 @@
+#![cfg(feature = "seccomp")]
+// The seccomp filter can't be removed, so this lives apart from the other tests.
+mod tests {
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant, SystemTime};
+    use tpom::{seccomp, vdso, virtual_time, Callback, Session, TimeSpec};
+
+    static TM: Mutex<()> = Mutex::new(());
+
+    fn myclock(_clockid: i32) -> TimeSpec {
+        TimeSpec {
+            seconds: 111,
+            nanos: 333,
+        }
+    }
+
+    fn syscall_clock_gettime(clockid: libc::clockid_t) -> libc::timespec {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        let ret = unsafe { libc::syscall(libc::SYS_clock_gettime, clockid, &mut ts) };
+        assert_eq!(ret, 0);
+        ts
+    }
+
+    #[test]
+    fn syscalls_are_answered_from_callbacks() {
+        let _guard = TM.lock().unwrap();
+        seccomp::install().unwrap();
+        // Without a callback, the syscall goes through
+        assert!(syscall_clock_gettime(libc::CLOCK_REALTIME).tv_sec > 111);
+
+        let v = vdso::vDSO::read().unwrap();
+        let mut session = Session::new(&v);
+        session.overwrite(Callback::GetTime(myclock)).unwrap();
+        let ts = syscall_clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID);
+        assert_eq!((ts.tv_sec, ts.tv_nsec), (111, 333));
+
+        // A bad pointer fails like it would in the kernel
+        let ret = unsafe { libc::syscall(libc::SYS_clock_gettime, libc::CLOCK_REALTIME, 8usize) };
+        assert_eq!(ret, -1);
+        assert_eq!(
+            std::io::Error::last_os_error().raw_os_error(),
+            Some(libc::EFAULT)
+        );
+
+        let mut tv = libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_gettimeofday,
+                &mut tv,
+                std::ptr::null_mut::<libc::c_void>(),
+            )
+        };
+        assert_eq!(ret, 0);
+        assert!(tv.tv_sec > 111);
+        session.restore_all().unwrap();
+        assert!(syscall_clock_gettime(libc::CLOCK_REALTIME).tv_sec > 111);
+    }
+
+    #[test]
+    fn sleeps_skip_ahead_in_virtual_time() {
+        let _guard = TM.lock().unwrap();
+        seccomp::install().unwrap();
+        let v = vdso::vDSO::read().unwrap();
+        let mut session = Session::new(&v);
+        session.apply_all(&virtual_time::callbacks()).unwrap();
+        virtual_time::enable();
+
+        let (real, start, wall) = (Instant::now(), Instant::now(), SystemTime::now());
+        std::thread::sleep(Duration::from_secs(3600));
+        assert!(start.elapsed() >= Duration::from_secs(3600));
+        assert!(start.elapsed() < Duration::from_secs(3660));
+        assert!(wall.elapsed().unwrap() >= Duration::from_secs(3600));
+        assert!(virtual_time::skipped() >= Duration::from_secs(3600));
+
+        // An absolute deadline, on a clock the virtual time shifts
+        let mut deadline = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut deadline) };
+        deadline.tv_sec += 60;
+        let ret = unsafe {
+            libc::clock_nanosleep(
+                libc::CLOCK_MONOTONIC,
+                1, // TIMER_ABSTIME
+                &deadline,
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(ret, 0);
+        assert!(start.elapsed() >= Duration::from_secs(3660));
+
+        virtual_time::disable();
+        session.restore_all().unwrap();
+        assert!(real.elapsed() < Duration::from_secs(60));
+    }
+
+    fn cpu_clock(clockid: i32) -> TimeSpec {
+        TimeSpec {
+            seconds: 7,
+            nanos: clockid as i64 & 0xff,
+        }
+    }
+
+    #[test]
+    fn cpu_clocks_are_answered_without_patching() {
+        let _guard = TM.lock().unwrap();
+        seccomp::install().unwrap();
+        seccomp::mock_cpu_clocks(Some(cpu_clock));
+        let ts = syscall_clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID);
+        assert_eq!((ts.tv_sec, ts.tv_nsec), (7, 3));
+        // Through libc, which calls the unpatched vDSO, which makes the syscall
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut ts) };
+        assert_eq!((ts.tv_sec, ts.tv_nsec), (7, 2));
+        assert!(syscall_clock_gettime(libc::CLOCK_REALTIME).tv_sec > 7);
+
+        // With a patched vDSO, the CPU clocks still read the CPU callback
+        let v = vdso::vDSO::read().unwrap();
+        let mut session = Session::new(&v);
+        session.overwrite(Callback::GetTime(myclock)).unwrap();
+        unsafe { libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut ts) };
+        assert_eq!(ts.tv_sec, 7);
+        unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut ts) };
+        assert_eq!(ts.tv_sec, 111);
+        session.restore_all().unwrap();
+
+        seccomp::mock_cpu_clocks(None);
+        assert!(syscall_clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID).tv_sec < 7);
+    }
+
+    fn unsynced(_clockid: i32) -> seccomp::ClockStatus {
+        let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+        timex.status = libc::STA_UNSYNC;
+        timex.maxerror = 1234;
+        timex.freq = 1 << 16;
+        seccomp::ClockStatus {
+            state: libc::TIME_ERROR,
+            timex,
+        }
+    }
+
+    #[test]
+    fn adjtimex_reports_the_mocked_status() {
+        let _guard = TM.lock().unwrap();
+        seccomp::install().unwrap();
+        seccomp::mock_adjtimex(Some(unsynced));
+        let mut tx: libc::timex = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::adjtimex(&mut tx) }, libc::TIME_ERROR);
+        assert_eq!(tx.status, libc::STA_UNSYNC);
+        assert_eq!((tx.maxerror, tx.freq), (1234, 1 << 16));
+
+        let mut tx: libc::timex = unsafe { std::mem::zeroed() };
+        let ret = unsafe { libc::clock_adjtime(libc::CLOCK_REALTIME, &mut tx) };
+        assert_eq!(ret, libc::TIME_ERROR);
+        assert_eq!(tx.maxerror, 1234);
+
+        seccomp::mock_adjtimex(None);
+        let mut tx: libc::timex = unsafe { std::mem::zeroed() };
+        assert!(unsafe { libc::adjtimex(&mut tx) } >= 0);
+        assert_ne!(tx.maxerror, 1234);
+    }
+
+    static ALARMS: AtomicUsize = AtomicUsize::new(0);
+    static TIMER_VALUE: AtomicU64 = AtomicU64::new(0);
+
+    extern "C" fn on_alarm(_sig: libc::c_int) {
+        ALARMS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    extern "C" fn on_timer(_sig: libc::c_int, info: *mut libc::siginfo_t, _ctx: *mut libc::c_void) {
+        let value = unsafe { (*info).si_value().sival_ptr } as u64;
+        TIMER_VALUE.store(value, Ordering::SeqCst);
+    }
+
+    fn handle(signo: libc::c_int, handler: usize, flags: libc::c_int) {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = handler;
+        action.sa_flags = flags;
+        assert_eq!(
+            unsafe { libc::sigaction(signo, &action, std::ptr::null_mut()) },
+            0
+        );
+    }
+
+    /// Signals from the timer thread arrive shortly after the clock jumps; sleeping would
+    /// be skipped, so yield instead.
+    fn eventually(cond: impl Fn() -> bool) -> bool {
+        (0..10_000_000).any(|_| {
+            unsafe { libc::sched_yield() };
+            cond()
+        })
+    }
+
+    #[test]
+    fn timers_fire_in_virtual_time() {
+        let _guard = TM.lock().unwrap();
+        seccomp::install().unwrap();
+        let v = vdso::vDSO::read().unwrap();
+        let mut session = Session::new(&v);
+        session.apply_all(&virtual_time::callbacks()).unwrap();
+        virtual_time::enable();
+        handle(libc::SIGALRM, on_alarm as *const () as usize, 0);
+        handle(
+            libc::SIGUSR1,
+            on_timer as *const () as usize,
+            libc::SA_SIGINFO,
+        );
+
+        // An interval timer for a minute, with the sleep skipping past it
+        let minute = libc::itimerval {
+            it_interval: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            it_value: libc::timeval {
+                tv_sec: 60,
+                tv_usec: 0,
+            },
+        };
+        let ret = unsafe { libc::setitimer(libc::ITIMER_REAL, &minute, std::ptr::null_mut()) };
+        assert_eq!(ret, 0);
+        let mut pending: libc::itimerval = unsafe { std::mem::zeroed() };
+        unsafe { libc::getitimer(libc::ITIMER_REAL, &mut pending) };
+        assert!(pending.it_value.tv_sec > 50 && pending.it_value.tv_sec <= 60);
+        std::thread::sleep(Duration::from_secs(30));
+        assert_eq!(ALARMS.load(Ordering::SeqCst), 0);
+        std::thread::sleep(Duration::from_secs(31));
+        assert!(eventually(|| ALARMS.load(Ordering::SeqCst) == 1));
+
+        // A POSIX timer, signalling its value
+        let mut ev: libc::sigevent = unsafe { std::mem::zeroed() };
+        ev.sigev_notify = libc::SIGEV_SIGNAL;
+        ev.sigev_signo = libc::SIGUSR1;
+        ev.sigev_value.sival_ptr = 42 as *mut libc::c_void;
+        let mut timer: libc::timer_t = std::ptr::null_mut();
+        assert_eq!(
+            unsafe { libc::timer_create(libc::CLOCK_MONOTONIC, &mut ev, &mut timer) },
+            0
+        );
+        let hour = libc::itimerspec {
+            it_interval: libc::timespec {
+                tv_sec: 1,
+                tv_nsec: 0,
+            },
+            it_value: libc::timespec {
+                tv_sec: 3600,
+                tv_nsec: 0,
+            },
+        };
+        let ret = unsafe { libc::timer_settime(timer, 0, &hour, std::ptr::null_mut()) };
+        assert_eq!(ret, 0);
+        virtual_time::advance(Duration::from_secs(3605));
+        assert!(eventually(|| TIMER_VALUE.load(Ordering::SeqCst) == 42));
+        assert!(unsafe { libc::timer_getoverrun(timer) } >= 4);
+        assert_eq!(unsafe { libc::timer_delete(timer) }, 0);
+
+        virtual_time::disable();
+        session.restore_all().unwrap();
+    }
+}
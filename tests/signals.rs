@@ -0,0 +1,62 @@
+// Signal handlers and the live clock are process-wide, so this lives apart from the other
+// tests.
+mod tests {
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use tpom::live::{self, Command};
+    use tpom::{signals, vdso, Session};
+
+    /// Both tests move the one clock.
+    static CLOCK: Mutex<()> = Mutex::new(());
+
+    fn now() -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    }
+
+    /// Waits for the commands' thread to get the time to `f`.
+    fn wait_until(f: impl Fn(Duration) -> bool) {
+        let start = Instant::now();
+        while !f(now()) {
+            assert!(start.elapsed() < Duration::from_secs(5), "at {:?}", now());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn signals_steer_the_clock() {
+        let _clock = CLOCK.lock().unwrap();
+        let v = vdso::vDSO::read().unwrap();
+        let mut session = Session::new(&v);
+        session.apply_all(&live::callbacks()).unwrap();
+        live::execute(Command::Freeze(Some(1_000_000_000_000))).unwrap();
+
+        signals::on_signal(libc::SIGUSR1, Command::Advance(60_000_000_000)).unwrap();
+        signals::on_signal(libc::SIGUSR2, Command::Restore).unwrap();
+        unsafe { libc::raise(libc::SIGUSR1) };
+        wait_until(|now| now == Duration::from_secs(1060));
+        unsafe { libc::raise(libc::SIGUSR1) };
+        wait_until(|now| now == Duration::from_secs(1120));
+
+        unsafe { libc::raise(libc::SIGUSR2) };
+        wait_until(|now| now > Duration::from_secs(1_000_000_000));
+        session.restore_all().unwrap();
+    }
+
+    #[test]
+    fn hangups_reload_the_configuration() {
+        let _clock = CLOCK.lock().unwrap();
+        let path = std::env::temp_dir().join(format!("tpom-hangup-{}.toml", std::process::id()));
+        std::fs::write(&path, "freeze = 5000\n").unwrap();
+        let state = signals::reload_on_hangup(&path).unwrap();
+        assert_eq!(state.now, 5_000_000_000_000);
+
+        std::fs::write(&path, "freeze = 6000\nspeed = 0\n").unwrap();
+        unsafe { libc::raise(libc::SIGHUP) };
+        let start = Instant::now();
+        while live::execute(Command::Status).unwrap().now != 6_000_000_000_000 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+#![cfg(feature = "socket")]
+// The live clock is process-wide, so this lives apart from the other tests.
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixStream};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tpom::{live, socket, vdso, Session};
+
+    fn now() -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    }
+
+    #[test]
+    fn commands_steer_the_clock() {
+        let v = vdso::vDSO::read().unwrap();
+        let mut session = Session::new(&v);
+        session.apply_all(&live::callbacks()).unwrap();
+
+        let name = format!("tpom-test-{}", std::process::id());
+        socket::listen(&name).unwrap();
+        let addr = SocketAddr::from_abstract_name(&name).unwrap();
+        let stream = UnixStream::connect_addr(&addr).unwrap();
+        let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+        let mut ask = |command: &str| {
+            writeln!(&stream, "{}", command).unwrap();
+            lines.next().unwrap().unwrap()
+        };
+
+        assert_eq!(
+            ask("freeze 1000"),
+            "ok time=1000.000000000 frozen=true speed=1"
+        );
+        assert_eq!(now(), Duration::from_secs(1000));
+        assert_eq!(
+            ask("advance 60.5"),
+            "ok time=1060.500000000 frozen=true speed=1"
+        );
+        assert_eq!(now(), Duration::from_millis(1_060_500));
+        assert!(ask("advance").starts_with("error "));
+        assert!(ask("rewind 1").starts_with("error "));
+
+        assert!(ask("restore").starts_with("ok "));
+        assert!(now() > Duration::from_secs(1_000_000_000));
+        session.restore_all().unwrap();
+    }
+}
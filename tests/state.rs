@@ -0,0 +1,29 @@
+#![cfg(feature = "serde")]
+// The live clock is process-wide, so this lives apart from the other tests.
+mod tests {
+    use tpom::live::{self, Command, MockState};
+
+    #[test]
+    fn state_survives_serialization() {
+        live::execute(Command::Freeze(Some(1_000_000_000_000))).unwrap();
+        live::execute(Command::Scale(3.0)).unwrap();
+        let saved = serde_json::to_string(&MockState::capture().unwrap()).unwrap();
+
+        live::execute(Command::Restore).unwrap();
+        let state: MockState = serde_json::from_str(&saved).unwrap();
+        let restored = state.restore().unwrap();
+        assert_eq!(restored.now, 1_000_000_000_000);
+        assert!(restored.frozen);
+        assert_eq!(restored.speed, 3.0);
+
+        live::execute(Command::Restore).unwrap();
+        let running = MockState {
+            frozen: false,
+            ..state
+        };
+        let restored = running.restore().unwrap();
+        assert!(!restored.frozen);
+        // Three times the real time elapsed since the capture
+        assert!(restored.now > 1_000_000_000_000);
+    }
+}
@@ -0,0 +1,41 @@
+// Patches the vDSO of the whole process, so this lives apart from the other tests.
+mod tests {
+    use std::sync::atomic::{AtomicI64, Ordering};
+    use tpom::{vdso, Kind, TVDSOFun, TimeSpec};
+
+    static NOW: AtomicI64 = AtomicI64::new(1_000_000);
+
+    fn myclock(_clockid: i32) -> TimeSpec {
+        TimeSpec {
+            seconds: NOW.load(Ordering::Relaxed),
+            nanos: 0,
+        }
+    }
+
+    #[test]
+    fn reports_simulated_time() {
+        assert_eq!(tpom::time_report(), None);
+        let v = vdso::vDSO::read().unwrap();
+        let backup = v.entry(Kind::GetTime).unwrap().overwrite(myclock).unwrap();
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut ts) };
+        NOW.fetch_add(30 * 86400, Ordering::Relaxed);
+
+        let report = tpom::time_report().unwrap();
+        assert_eq!(report.simulated, 30 * 86400 * 1_000_000_000);
+        assert!(report.wall.as_secs() < 10, "{:?}", report);
+        assert!(report.ratio() > 1000.0, "{:?}", report);
+        assert!(report
+            .to_string()
+            .starts_with("simulated 30d 0h 0m 0.000s in "));
+
+        backup.restore().unwrap();
+        // The mock is gone, the last time read is what remains
+        assert_eq!(tpom::time_report().unwrap().simulated, 0);
+        tpom::reset_time_report();
+        assert_eq!(tpom::time_report(), None);
+    }
+}
@@ -0,0 +1,30 @@
+use std::process::Command;
+use tpom::{timens, Error, TimeSpec};
+
+fn uptime(contents: &str) -> f64 {
+    contents.split_whitespace().next().unwrap().parse().unwrap()
+}
+
+#[test]
+fn children_see_shifted_boottime() {
+    let zero = TimeSpec {
+        seconds: 0,
+        nanos: 0,
+    };
+    let day = TimeSpec {
+        seconds: 86400,
+        nanos: 0,
+    };
+    match timens::unshare_with(&zero, &day) {
+        // Not privileged, or no time namespaces in this kernel
+        Err(Error::Os(_, errno)) if errno == libc::EPERM || errno == libc::EINVAL => return,
+        res => res.unwrap(),
+    }
+    let child = Command::new("cat").arg("/proc/uptime").output().unwrap();
+    let child = uptime(&String::from_utf8(child.stdout).unwrap());
+    let own = uptime(&std::fs::read_to_string("/proc/uptime").unwrap());
+    assert!((child - own - 86400.0).abs() < 5.0, "{} vs {}", child, own);
+
+    // The namespace has been entered, its offsets are fixed
+    assert!(timens::set_offset(libc::CLOCK_BOOTTIME, &zero).is_err());
+}
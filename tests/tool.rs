@@ -0,0 +1,43 @@
+mod tests {
+    use std::process::Command;
+
+    fn tpom(args: &[&str]) -> (bool, String) {
+        let out = Command::new(env!("CARGO_BIN_EXE_tpom"))
+            .args(args)
+            .output()
+            .unwrap();
+        (out.status.success(), String::from_utf8(out.stdout).unwrap())
+    }
+
+    #[test]
+    fn inspects_dumps_and_diffs() {
+        let (ok, out) = tpom(&["inspect"]);
+        assert!(ok);
+        assert!(out.lines().any(|l| l.ends_with("patchable")), "{}", out);
+
+        let (ok, path) = tpom(&["dump"]);
+        assert!(ok);
+        // The image of another process, which patches nothing either
+        let (ok, out) = tpom(&["diff", path.trim()]);
+        assert_eq!((ok, out.as_str()), (true, ""));
+        let (ok, out) = tpom(&["analyze", "--file", path.trim()]);
+        assert!(ok);
+        assert!(out.contains("overwrite in place"), "{}", out);
+        std::fs::remove_file(path.trim()).unwrap();
+
+        let path = std::env::temp_dir().join(format!("tpom-tool-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert_eq!(tpom(&["dump", path]), (true, format!("{}\n", path)));
+        assert_eq!(tpom(&["diff", path]), (true, String::new()));
+        std::fs::remove_file(path).unwrap();
+        assert!(!tpom(&["dump", "/nonexistent/vdso"]).0);
+    }
+
+    #[test]
+    fn patches_itself() {
+        let (ok, out) = tpom(&["patch", "--freeze", "1000"]);
+        assert!(ok);
+        assert!(out.contains("tv_sec: 1000,"), "{}", out);
+        assert!(!tpom(&["restore"]).0);
+    }
+}
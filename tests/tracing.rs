@@ -0,0 +1,86 @@
+#![cfg(feature = "tracing")]
+// Patches the vDSO of the whole process, so this lives apart from the other tests.
+mod tests {
+    use std::fmt::Write as _;
+    use std::sync::{Arc, Mutex};
+    use tpom::{live, vdso, Session};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Keeps a line per span opened and event emitted.
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<String>>>);
+
+    struct Fields<'a>(&'a mut String);
+
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+
+    impl Subscriber for Lines {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut line = format!("span {}", span.metadata().name());
+            span.record(&mut Fields(&mut line));
+            let mut lines = self.0.lock().unwrap();
+            lines.push(line);
+            Id::from_u64(lines.len() as u64)
+        }
+
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut line = "event".to_string();
+            event.record(&mut Fields(&mut line));
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn traces_patches_and_changes() {
+        let lines = Lines::default();
+        tracing::subscriber::with_default(lines.clone(), || {
+            let v = vdso::vDSO::read().unwrap();
+            let mut session = Session::new(&v);
+            session.apply_all(&live::callbacks()).unwrap();
+            live::execute("advance 60".parse().unwrap()).unwrap();
+            live::execute("restore".parse().unwrap()).unwrap();
+            session.restore_all().unwrap();
+        });
+        let lines = lines.0.lock().unwrap();
+        let has = |text: &str| lines.iter().any(|l| l.contains(text));
+        assert!(has("event message=new session backend=Vdso"), "{:?}", lines);
+        assert!(has("span apply_all backend=Vdso"), "{:?}", lines);
+        assert!(
+            has("message=patched symbol=__vdso_clock_gettime"),
+            "{:?}",
+            lines
+        );
+        assert!(
+            has("message=mocked clock changed command=Advance(60000000000)"),
+            "{:?}",
+            lines
+        );
+        assert!(has("span restore_all"), "{:?}", lines);
+        let restored = lines
+            .iter()
+            .filter(|l| l.contains("message=restored"))
+            .count();
+        assert_eq!(restored, 3, "{:?}", lines);
+        assert!(lines
+            .iter()
+            .all(|l| !l.contains("caller=") || l.contains("tests/tracing.rs")));
+    }
+}
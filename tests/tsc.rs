@@ -0,0 +1,40 @@
+#![cfg(target_arch = "x86_64")]
+use std::arch::x86_64::_rdtsc;
+use std::thread;
+use std::time::Duration;
+use tpom::{tsc, vdso, Callback, Session, TimeSpec};
+
+fn frozen(_clockid: i32) -> TimeSpec {
+    TimeSpec {
+        seconds: 1000,
+        nanos: 0,
+    }
+}
+
+#[test]
+fn rdtsc_follows_the_mocked_clock() {
+    let v = vdso::vDSO::read().unwrap();
+    tsc::trap().unwrap();
+    let hz = tsc::frequency().unwrap();
+    // 10ms worth of cycles
+    let slack = hz / 100;
+
+    // Not mocked: the real TSC, read from the handler
+    let a = unsafe { _rdtsc() };
+    thread::sleep(Duration::from_millis(50));
+    let b = unsafe { _rdtsc() };
+    assert!(b - a > 4 * slack, "{} cycles at {} Hz", b - a, hz);
+
+    let mut session = Session::new(&v);
+    session.overwrite(Callback::GetTime(frozen)).unwrap();
+    let a = unsafe { _rdtsc() };
+    thread::sleep(Duration::from_millis(50));
+    let b = unsafe { _rdtsc() };
+    assert!(a.abs_diff(b) < slack, "{} and {}", a, b);
+    session.restore_all().unwrap();
+
+    tsc::untrap().unwrap();
+    let a = unsafe { _rdtsc() };
+    thread::sleep(Duration::from_millis(50));
+    assert!(unsafe { _rdtsc() } - a > 4 * slack);
+}